@@ -0,0 +1,38 @@
+//! Time of checking a large program against compiling it, which `--check` saves.
+//!
+//! Run with `cargo bench --bench check`.
+
+use std::time::{Duration, Instant};
+
+use rust9cc::{check, compile_with_options, CompileOptions};
+
+/// Runs `f` `runs` times, returning the fastest run.
+fn fastest(runs: usize, mut f: impl FnMut()) -> Duration {
+    (0..runs)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let stmt = "a = a * 3 + (b - 1) / 2; if (a > 100) { a = a - 100; } else b = b + 1;\n";
+    let input = format!("int a; int b;\n{}", stmt.repeat(20_000));
+    let options = CompileOptions::default();
+
+    let checked = fastest(5, || check(&input).unwrap());
+    let compiled = fastest(5, || {
+        compile_with_options(&input, &options).unwrap();
+    });
+
+    println!("input: {} bytes", input.len());
+    println!("check: {:?}", checked);
+    println!("compile: {:?}", compiled);
+    println!(
+        "saved: {:.1}%",
+        100.0 * (1.0 - checked.as_secs_f64() / compiled.as_secs_f64())
+    );
+}
@@ -0,0 +1,77 @@
+//! Peak heap memory of parsing a large program, from collected tokens and from
+//! the lazy tokenizer which the driver uses.
+//!
+//! Run with `cargo bench --bench memory`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rust9cc::limits::ResourceLimits;
+use rust9cc::parse::{parse_slice_with_limits, parse_streaming_with_limits};
+use rust9cc::token::tokenize;
+
+/// Allocator keeping track of the bytes in use and their peak.
+struct Counting;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(current, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Runs `f`, returning the peak bytes it allocated on top of those already in use.
+fn peak_bytes(f: impl FnOnce()) -> usize {
+    let base = CURRENT.load(Ordering::SeqCst);
+    PEAK.store(base, Ordering::SeqCst);
+    f();
+    PEAK.load(Ordering::SeqCst) - base
+}
+
+/// Fully parenthesized expression of `2^depth` literals, so that it is wide but shallow.
+fn balanced(depth: u32) -> String {
+    if depth == 0 {
+        return "1".to_string();
+    }
+    let op = ["+", "*"][depth as usize % 2];
+    format!("({} {} {})", balanced(depth - 1), op, balanced(depth - 1))
+}
+
+fn main() {
+    // About half of the default token limit, like an input the driver accepts.
+    let stmt = format!("a = {};\n", balanced(8));
+    let input = format!("int a;\n{}", stmt.repeat(500));
+    let limits = ResourceLimits::default();
+
+    let collected = peak_bytes(|| {
+        let tokens = tokenize(&input).unwrap();
+        parse_slice_with_limits(&tokens, &limits).unwrap();
+    });
+    let streaming = peak_bytes(|| {
+        parse_streaming_with_limits(&input, &limits).unwrap();
+    });
+
+    println!("input: {} bytes", input.len());
+    println!("collect-then-parse: {} bytes peak", collected);
+    println!("streaming: {} bytes peak", streaming);
+    println!(
+        "saved: {} bytes ({:.1}%)",
+        collected.saturating_sub(streaming),
+        100.0 * collected.saturating_sub(streaming) as f64 / collected as f64
+    );
+}
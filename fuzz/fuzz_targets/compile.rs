@@ -0,0 +1,22 @@
+#![no_main]
+use std::thread;
+
+use libfuzzer_sys::fuzz_target;
+
+use rust9cc::compile_to_asm;
+use rust9cc::limits::STACK_SIZE;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(input) = std::str::from_utf8(data) {
+        // The default limits allow nesting deeper than the main thread has stack for.
+        let input = input.to_string();
+        thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                let _ = compile_to_asm(&input);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+});
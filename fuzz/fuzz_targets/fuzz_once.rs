@@ -0,0 +1,6 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use rust9cc::fuzz_once;
+
+fuzz_target!(|data: &[u8]| fuzz_once(data));
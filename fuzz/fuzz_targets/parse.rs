@@ -0,0 +1,91 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use rust9cc::limits::ResourceLimits;
+use rust9cc::parse::parse_slice_with_limits;
+use rust9cc::token::{Loc, Name, Token, TokenKind};
+
+/// Decodes one token kind per byte, reading a literal from the following 8 bytes and the
+/// letter of an identifier from the following byte.
+fn decode(data: &[u8]) -> Vec<Token> {
+    let mut kinds = Vec::new();
+    let mut bytes = data.iter();
+    while let Some(b) = bytes.next() {
+        kinds.push(match b % 46 {
+            0 => {
+                let mut literal = [0; 8];
+                for (byte, b) in literal.iter_mut().zip(bytes.by_ref()) {
+                    *byte = *b;
+                }
+                TokenKind::Num(u64::from_le_bytes(literal))
+            }
+            1 => TokenKind::Plus,
+            2 => TokenKind::Minus,
+            3 => TokenKind::Mul,
+            4 => TokenKind::Div,
+            5 => TokenKind::LParen,
+            6 => TokenKind::RParen,
+            7 => TokenKind::Eq,
+            8 => TokenKind::Neq,
+            9 => TokenKind::Lt,
+            10 => TokenKind::Leq,
+            11 => TokenKind::Gt,
+            12 => TokenKind::Geq,
+            13 => TokenKind::Ident((b'a' + bytes.next().unwrap_or(&0) % 26) as char),
+            14 => TokenKind::Semicolon,
+            15 => TokenKind::Assign,
+            16 => TokenKind::If,
+            17 => TokenKind::Else,
+            18 => TokenKind::While,
+            19 => TokenKind::For,
+            20 => TokenKind::Do,
+            21 => TokenKind::LBrace,
+            22 => TokenKind::RBrace,
+            23 => TokenKind::Break,
+            24 => TokenKind::Continue,
+            25 => TokenKind::Switch,
+            26 => TokenKind::Case,
+            27 => TokenKind::Default,
+            28 => TokenKind::Colon,
+            29 => TokenKind::Func(Name::new("f").unwrap()),
+            30 => TokenKind::Comma,
+            31 => TokenKind::Return,
+            32 => TokenKind::Amp,
+            33 => TokenKind::Int,
+            34 => TokenKind::Char,
+            35 => TokenKind::Not,
+            36 => TokenKind::Pipe,
+            37 => TokenKind::Caret,
+            38 => TokenKind::Tilde,
+            39 => TokenKind::Shl,
+            40 => TokenKind::Shr,
+            41 => TokenKind::Bool,
+            42 => TokenKind::AndAnd,
+            43 => TokenKind::OrOr,
+            44 => TokenKind::Void,
+            _ => TokenKind::Eof,
+        });
+    }
+    kinds.push(TokenKind::Eof);
+    kinds
+        .into_iter()
+        .enumerate()
+        .map(|(col, kind)| Token {
+            kind,
+            loc: Loc {
+                line: 1,
+                col: col + 1,
+            },
+        })
+        .collect()
+}
+
+fuzz_target!(|data: &[u8]| {
+    let tokens = decode(data);
+    // Runs on the main thread rather than on a stack of `STACK_SIZE`, so nests less deeply.
+    let limits = ResourceLimits {
+        max_ast_depth: 1000,
+        ..ResourceLimits::default()
+    };
+    let _ = parse_slice_with_limits(&tokens, &limits);
+});
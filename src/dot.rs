@@ -1,3 +1,9 @@
+use std::io::{self, Write};
+
+use anyhow::Result;
+
+use crate::parse::{children, lvar_name, NodeKind};
+use crate::unparse::symbol;
 use crate::Node;
 
 struct Counter {
@@ -9,10 +15,6 @@ impl Counter {
         Counter { count: 0 }
     }
 
-    fn get(&self) -> u64 {
-        self.count
-    }
-
     fn inc(&mut self) {
         self.count += 1;
     }
@@ -28,28 +30,180 @@ impl Iterator for Counter {
     }
 }
 
-/// Recursively prints AST in dot language.
-fn do_dot(node: &Node, counter: &mut Counter) {
-    let node_id: u64 = counter.next().unwrap();
-
-    // Print this node.
-    println!("{}[label=\"{:?}\"];", node_id, node.kind);
+/// Returns the label of a node of `kind`: its operator, value, variable or keyword.
+fn label(kind: NodeKind) -> String {
+    let text = match kind {
+        NodeKind::Num(num) => return num.to_string(),
+        NodeKind::LVar { offset } => return lvar_name(offset).to_string(),
+        NodeKind::Case(value) => return format!("case {}", value),
+        NodeKind::Call { name } => return format!("{}()", name),
+        NodeKind::Function { name } => return format!("function {}", name),
+        NodeKind::If => "if",
+        NodeKind::While => "while",
+        NodeKind::For => "for",
+        NodeKind::DoWhile => "do",
+        NodeKind::Block => "{}",
+        NodeKind::Break => "break",
+        NodeKind::Continue => "continue",
+        NodeKind::Return => "return",
+        NodeKind::Addr => "&",
+        NodeKind::Deref => "*",
+        NodeKind::Not => "!",
+        NodeKind::BitNot => "~",
+        NodeKind::Decl => "decl",
+        NodeKind::Switch => "switch",
+        NodeKind::Default => "default",
+        _ => symbol(kind).unwrap_or_default(),
+    };
+    text.to_string()
+}
 
-    // Print children.
-    if let Some(lhs) = node.lhs.as_ref() {
-        println!("{} -> {};", node_id, counter.get());
-        do_dot(lhs, counter);
-    }
-    if let Some(rhs) = node.rhs.as_ref() {
-        println!("{} -> {};", node_id, counter.get());
-        do_dot(rhs, counter);
+/// Writes AST in dot language, `root` having the id `root_id`.
+///
+/// Children are numbered before their own children. Runs iteratively, so that deep
+/// trees do not overflow the stack.
+fn do_dot(
+    root: &Node,
+    root_id: u64,
+    counter: &mut Counter,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    writeln!(out, "{}[label=\"{}\"];", root_id, label(root.kind))?;
+    let mut stack: Vec<(u64, &Node)> = children(root).map(|child| (root_id, child)).collect();
+    stack.reverse();
+    while let Some((parent_id, node)) = stack.pop() {
+        let node_id = counter.next().unwrap();
+        writeln!(out, "{} -> {};", parent_id, node_id)?;
+        writeln!(out, "{}[label=\"{}\"];", node_id, label(node.kind))?;
+        let first = stack.len();
+        stack.extend(children(node).map(|child| (node_id, child)));
+        stack[first..].reverse();
     }
+    Ok(())
 }
 
-/// Prints AST in Graphviz dot language.
-pub fn dotify_ast(root: &Node) {
-    println!("digraph G {{");
+/// Writes AST in Graphviz dot language into `out`.
+pub fn write_dot(root: &Node, out: &mut impl Write) -> Result<()> {
+    writeln!(out, "digraph G {{")?;
     let mut counter = Counter::new();
-    do_dot(root, &mut counter);
-    println!("}}");
-}
\ No newline at end of file
+    let root_id = counter.next().unwrap();
+    do_dot(root, root_id, &mut counter, out)?;
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+/// Returns AST in Graphviz dot language.
+pub fn dot_to_string(root: &Node) -> Result<String> {
+    let mut out = Vec::new();
+    write_dot(root, &mut out)?;
+    Ok(String::from_utf8(out)?)
+}
+
+/// Prints AST in Graphviz dot language.
+pub fn dotify_ast(root: &Node) -> Result<()> {
+    write_dot(root, &mut io::stdout().lock())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dot::*;
+    use crate::parse::parse_streaming;
+    use crate::token::Loc;
+
+    #[test]
+    fn test_dot_to_string() -> Result<()> {
+        // 2 * (a = 3), built by hand.
+        let loc = Loc { line: 1, col: 1 };
+        let assign = Node::new(
+            NodeKind::Assign,
+            loc,
+            Node::new(NodeKind::LVar { offset: 8 }, loc, None, None).make_ref(),
+            Node::new_num(3, loc).make_ref(),
+        );
+        let root = Node::new(
+            NodeKind::Mul,
+            loc,
+            Node::new_num(2, loc).make_ref(),
+            assign.make_ref(),
+        );
+        let expected = concat!(
+            "digraph G {\n",
+            "0[label=\"*\"];\n",
+            "0 -> 1;\n",
+            "1[label=\"2\"];\n",
+            "0 -> 2;\n",
+            "2[label=\"=\"];\n",
+            "2 -> 3;\n",
+            "3[label=\"a\"];\n",
+            "2 -> 4;\n",
+            "4[label=\"3\"];\n",
+            "}\n"
+        );
+        assert_eq!(dot_to_string(&root)?, expected);
+
+        let mut out = Vec::new();
+        write_dot(&root, &mut out)?;
+        assert_eq!(out, expected.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary_children() -> Result<()> {
+        let dot = dot_to_string(&parse_streaming("1+2*3;")?)?;
+        let edges: Vec<&str> = dot.lines().filter(|line| line.contains("->")).collect();
+        assert_eq!(edges, vec!["0 -> 1;", "0 -> 2;", "2 -> 3;", "2 -> 4;"]);
+        for label in ["0[label=\"+\"]", "1[label=\"1\"]", "2[label=\"*\"]"].iter() {
+            assert!(dot.contains(label), "{}", dot);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_if_children() -> Result<()> {
+        let dot = dot_to_string(&parse_streaming("if (2 < 1) 3;")?)?;
+        let edges: Vec<&str> = dot.lines().filter(|line| line.contains("->")).collect();
+        assert_eq!(edges, vec!["0 -> 1;", "1 -> 2;", "1 -> 3;", "0 -> 4;"]);
+        assert!(dot.contains("0[label=\"if\"];"), "{}", dot);
+        assert!(dot.contains("1[label=\"<\"];"), "{}", dot);
+        assert!(dot.contains("4[label=\"3\"];"), "{}", dot);
+        Ok(())
+    }
+
+    #[test]
+    fn test_if_else_children() -> Result<()> {
+        let dot = dot_to_string(&parse_streaming("if (1) 2; else 3;")?)?;
+        let edges: Vec<&str> = dot.lines().filter(|line| line.contains("->")).collect();
+        assert_eq!(edges, vec!["0 -> 1;", "0 -> 2;", "0 -> 3;"]);
+        assert!(dot.contains("3[label=\"3\"];"), "{}", dot);
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_children() -> Result<()> {
+        let dot = dot_to_string(&parse_streaming("{ 1; 2; 3; }")?)?;
+        let edges: Vec<&str> = dot.lines().filter(|line| line.contains("->")).collect();
+        assert_eq!(edges, vec!["0 -> 1;", "0 -> 2;", "0 -> 3;"]);
+        assert!(dot.contains("0[label=\"{}\"];"), "{}", dot);
+        Ok(())
+    }
+
+    #[test]
+    fn test_labels() -> Result<()> {
+        let dot = dot_to_string(&parse_streaming("int a = -5 + 2 / (3 == 4);")?)?;
+        let labels: Vec<&str> = dot
+            .lines()
+            .filter_map(|line| line.split("[label=\"").nth(1))
+            .map(|rest| rest.trim_end_matches("\"];"))
+            .collect();
+        assert_eq!(
+            labels,
+            vec!["decl", "a", "=", "a", "+", "-5", "/", "2", "==", "3", "4"]
+        );
+
+        let dot = dot_to_string(&parse_streaming("while (1) do break; while (0);")?)?;
+        assert!(dot.contains("0[label=\"while\"];"), "{}", dot);
+        assert!(dot.contains("2[label=\"do\"];"), "{}", dot);
+        assert!(dot.contains("4[label=\"break\"];"), "{}", dot);
+        Ok(())
+    }
+}
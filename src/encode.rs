@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::ir::{Cond, Instr, Label, Mem, Reg};
+
+use anyhow::{anyhow, Result};
+
+/// Encodes `instrs` into x86-64 machine code.
+///
+/// Jumps always take 32-bit displacements, which are filled in once every label is placed.
+pub fn encode(instrs: &[Instr]) -> Result<Vec<u8>> {
+    let mut code = Vec::new();
+    let mut labels: HashMap<Label, usize> = HashMap::new();
+    // Ends of the jumps and their targets.
+    let mut jumps: Vec<(usize, Label)> = Vec::new();
+    for instr in instrs.iter() {
+        encode_instr(*instr, &mut code)?;
+        match *instr {
+            Instr::Label(label) if labels.insert(label, code.len()).is_some() => {
+                return Err(anyhow!("Label {} is placed twice", label));
+            }
+            Instr::Jmp(label) | Instr::Jcc(_, label) => jumps.push((code.len(), label)),
+            _ => (),
+        }
+    }
+    for (end, label) in jumps.into_iter() {
+        let target = *labels
+            .get(&label)
+            .ok_or_else(|| anyhow!("Label {} is not placed", label))?;
+        let disp = i32::try_from(target as i64 - end as i64)?;
+        code[end - 4..end].copy_from_slice(&disp.to_le_bytes());
+    }
+    Ok(code)
+}
+
+/// Appends the machine code of `instr` to `code`.
+///
+/// Jumps are appended with a zero displacement, which `encode` fills in.
+pub fn encode_instr(instr: Instr, code: &mut Vec<u8>) -> Result<()> {
+    match instr {
+        Instr::Endbr64 => code.extend_from_slice(&[0xf3, 0x0f, 0x1e, 0xfa]),
+        Instr::PushImm(imm) => {
+            if let Ok(imm) = i8::try_from(imm) {
+                code.extend_from_slice(&[0x6a, imm as u8]);
+            } else if let Ok(imm) = i32::try_from(imm) {
+                code.push(0x68);
+                code.extend_from_slice(&imm.to_le_bytes());
+            } else {
+                return Err(anyhow!("Immediate {} does not fit in 32 bits", imm));
+            }
+        }
+        Instr::MovImm(reg, imm) => {
+            if let Ok(imm) = i32::try_from(imm) {
+                code.extend_from_slice(&[rex(true, 0, reg.code()), 0xc7, modrm(0, reg.code())]);
+                code.extend_from_slice(&imm.to_le_bytes());
+            } else {
+                // movabs
+                code.extend_from_slice(&[rex(true, 0, reg.code()), 0xb8 + (reg.code() & 7)]);
+                code.extend_from_slice(&imm.to_le_bytes());
+            }
+        }
+        Instr::Push(reg) => short_reg(0x50, reg, code),
+        Instr::Pop(reg) => short_reg(0x58, reg, code),
+        Instr::Mov(dst, src) => mr(&[0x89], dst, src, code),
+        Instr::Add(dst, src) => mr(&[0x01], dst, src, code),
+        Instr::AddImm(dst, imm) => alu_imm(0, dst, imm, code),
+        Instr::Sub(dst, src) => mr(&[0x29], dst, src, code),
+        Instr::SubImm(dst, imm) => alu_imm(5, dst, imm, code),
+        Instr::And(dst, src) => mr(&[0x21], dst, src, code),
+        Instr::AndImm(dst, imm) => alu_imm(4, dst, imm, code),
+        Instr::Or(dst, src) => mr(&[0x09], dst, src, code),
+        Instr::Xor(dst, src) => mr(&[0x31], dst, src, code),
+        Instr::Not(reg) => {
+            code.extend_from_slice(&[rex(true, 0, reg.code()), 0xf7, modrm(2, reg.code())]);
+        }
+        Instr::Sal(reg) => {
+            code.extend_from_slice(&[rex(true, 0, reg.code()), 0xd3, modrm(4, reg.code())]);
+        }
+        Instr::Sar(reg) => {
+            code.extend_from_slice(&[rex(true, 0, reg.code()), 0xd3, modrm(7, reg.code())]);
+        }
+        Instr::Cmp(lhs, rhs) => mr(&[0x39], lhs, rhs, code),
+        Instr::CmpImm(lhs, imm) => alu_imm(7, lhs, imm, code),
+        Instr::Imul(dst, src) => mr(&[0x0f, 0xaf], src, dst, code),
+        // The three-operand form, with the register as both the source and destination.
+        Instr::ImulImm(dst, imm) => {
+            let modrm = modrm(dst.code(), dst.code());
+            let rex = rex(true, dst.code(), dst.code());
+            if let Ok(imm) = i8::try_from(imm) {
+                code.extend_from_slice(&[rex, 0x6b, modrm, imm as u8]);
+            } else {
+                code.extend_from_slice(&[rex, 0x69, modrm]);
+                code.extend_from_slice(&imm.to_le_bytes());
+            }
+        }
+        Instr::Cqo => code.extend_from_slice(&[0x48, 0x99]),
+        Instr::Idiv(reg) => {
+            code.extend_from_slice(&[rex(true, 0, reg.code()), 0xf7, modrm(7, reg.code())]);
+        }
+        Instr::Set(cond, reg) => {
+            // Without REX, 4 to 7 would mean ah, ch, dh and bh.
+            if reg.code() >= 4 {
+                code.push(rex(false, 0, reg.code()));
+            }
+            code.extend_from_slice(&[0x0f, setcc_opcode(cond), modrm(0, reg.code())]);
+        }
+        Instr::Movzb(dst, src) => mr(&[0x0f, 0xb6], src, dst, code),
+        Instr::Lea(dst, mem) => rm_mem(&[0x8d], dst, mem, code),
+        Instr::Load(dst, mem) => rm_mem(&[0x8b], dst, mem, code),
+        Instr::Store(mem, src) => rm_mem(&[0x89], src, mem, code),
+        Instr::LoadByte(dst, mem) => rm_mem(&[0x0f, 0xbe], dst, mem, code),
+        Instr::StoreByte(mem, src) => {
+            // Without REX, 4 to 7 would mean ah, ch, dh and bh.
+            if src.code() >= 4 || mem.base.code() >= 8 {
+                code.push(rex(false, src.code(), mem.base.code()));
+            }
+            code.push(0x88);
+            mem_operand(src, mem, code);
+        }
+        Instr::Label(_) => (),
+        Instr::Jmp(_) => code.extend_from_slice(&[0xe9, 0, 0, 0, 0]),
+        // jcc rel32 is 0x0f followed by the opcode of setcc less 0x10.
+        Instr::Jcc(cond, _) => {
+            code.extend_from_slice(&[0x0f, setcc_opcode(cond) - 0x10, 0, 0, 0, 0])
+        }
+        Instr::Call(name) => {
+            return Err(anyhow!(
+                "Calling '{}' needs a relocation, which object files do not support yet",
+                name
+            ));
+        }
+        Instr::Ret => code.push(0xc3),
+    }
+    Ok(())
+}
+
+/// Encodes a 64-bit arithmetic instruction on `reg` and an immediate, whose operation is
+/// `ext` in the ModRM byte.
+fn alu_imm(ext: u8, reg: Reg, imm: i32, code: &mut Vec<u8>) {
+    if let Ok(imm) = i8::try_from(imm) {
+        code.extend_from_slice(&[rex(true, 0, reg.code()), 0x83, modrm(ext, reg.code())]);
+        code.push(imm as u8);
+    } else {
+        code.extend_from_slice(&[rex(true, 0, reg.code()), 0x81, modrm(ext, reg.code())]);
+        code.extend_from_slice(&imm.to_le_bytes());
+    }
+}
+
+fn setcc_opcode(cond: Cond) -> u8 {
+    match cond {
+        Cond::E => 0x94,
+        Cond::Ne => 0x95,
+        Cond::L => 0x9c,
+        Cond::Ge => 0x9d,
+        Cond::Le => 0x9e,
+        Cond::G => 0x9f,
+    }
+}
+
+/// REX prefix extending the ModRM `reg` and `rm` fields.
+fn rex(wide: bool, reg: u8, rm: u8) -> u8 {
+    0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | rm >> 3
+}
+
+/// ModRM byte addressing registers directly.
+fn modrm(reg: u8, rm: u8) -> u8 {
+    0xc0 | (reg & 7) << 3 | rm & 7
+}
+
+/// Encodes an instruction with the register in the low bits of the opcode.
+fn short_reg(opcode: u8, reg: Reg, code: &mut Vec<u8>) {
+    if reg.code() >= 8 {
+        code.push(rex(false, 0, reg.code()));
+    }
+    code.push(opcode + (reg.code() & 7));
+}
+
+/// Encodes a 64-bit instruction taking `rm` and `reg` in its ModRM byte.
+fn mr(opcode: &[u8], rm: Reg, reg: Reg, code: &mut Vec<u8>) {
+    code.push(rex(true, reg.code(), rm.code()));
+    code.extend_from_slice(opcode);
+    code.push(modrm(reg.code(), rm.code()));
+}
+
+/// Encodes a 64-bit instruction taking `reg` and the memory operand `mem` in its ModRM byte.
+fn rm_mem(opcode: &[u8], reg: Reg, mem: Mem, code: &mut Vec<u8>) {
+    code.push(rex(true, reg.code(), mem.base.code()));
+    code.extend_from_slice(opcode);
+    mem_operand(reg, mem, code);
+}
+
+/// Encodes the ModRM byte of `reg` and the memory operand `mem`, followed by the SIB byte
+/// and the displacement if needed.
+fn mem_operand(reg: Reg, mem: Mem, code: &mut Vec<u8>) {
+    let base = mem.base.code();
+    let disp8 = i8::try_from(mem.disp);
+    // Without a displacement, rbp and r13 would mean rip-relative addressing.
+    let mode = match disp8 {
+        Ok(0) if base & 7 != 5 => 0,
+        Ok(_) => 1,
+        Err(_) => 2,
+    };
+    code.push(mode << 6 | (reg.code() & 7) << 3 | base & 7);
+    // rsp and r12 take a SIB byte.
+    if base & 7 == 4 {
+        code.push(0x24);
+    }
+    match (mode, disp8) {
+        (1, Ok(disp)) => code.push(disp as u8),
+        (2, _) => code.extend_from_slice(&mem.disp.to_le_bytes()),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encode::*;
+    use crate::ir::{LabelKind, Labels};
+
+    #[test]
+    fn test_encode() -> Result<()> {
+        // Expected bytes are taken from GNU as.
+        let mem = Mem::new;
+        let cases: &[(Instr, &[u8])] = &[
+            (Instr::Endbr64, &[0xf3, 0x0f, 0x1e, 0xfa]),
+            (Instr::PushImm(5), &[0x6a, 0x05]),
+            (Instr::PushImm(456), &[0x68, 0xc8, 0x01, 0x00, 0x00]),
+            (Instr::PushImm(-2), &[0x6a, 0xfe]),
+            (Instr::PushImm(-200), &[0x68, 0x38, 0xff, 0xff, 0xff]),
+            (
+                Instr::MovImm(Reg::Rax, -200),
+                &[0x48, 0xc7, 0xc0, 0x38, 0xff, 0xff, 0xff],
+            ),
+            (Instr::Push(Reg::Rax), &[0x50]),
+            (Instr::Push(Reg::R12), &[0x41, 0x54]),
+            (Instr::Pop(Reg::Rdi), &[0x5f]),
+            (Instr::Mov(Reg::Rax, Reg::Rdi), &[0x48, 0x89, 0xf8]),
+            (
+                Instr::MovImm(Reg::Rdi, 5),
+                &[0x48, 0xc7, 0xc7, 0x05, 0x00, 0x00, 0x00],
+            ),
+            (
+                Instr::MovImm(Reg::Rax, i64::MAX),
+                &[0x48, 0xb8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f],
+            ),
+            (
+                Instr::MovImm(Reg::R9, 1 << 31),
+                &[0x49, 0xb9, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00],
+            ),
+            (Instr::Add(Reg::Rax, Reg::Rdi), &[0x48, 0x01, 0xf8]),
+            (Instr::Sub(Reg::R9, Reg::R10), &[0x4d, 0x29, 0xd1]),
+            (Instr::Imul(Reg::Rax, Reg::Rdi), &[0x48, 0x0f, 0xaf, 0xc7]),
+            (Instr::ImulImm(Reg::Rdi, 8), &[0x48, 0x6b, 0xff, 0x08]),
+            (
+                Instr::ImulImm(Reg::R9, 1000),
+                &[0x4d, 0x69, 0xc9, 0xe8, 0x03, 0x00, 0x00],
+            ),
+            (Instr::Cqo, &[0x48, 0x99]),
+            (Instr::Idiv(Reg::Rdi), &[0x48, 0xf7, 0xff]),
+            (Instr::And(Reg::Rax, Reg::Rdi), &[0x48, 0x21, 0xf8]),
+            (Instr::Or(Reg::Rax, Reg::Rdi), &[0x48, 0x09, 0xf8]),
+            (Instr::Xor(Reg::R9, Reg::R10), &[0x4d, 0x31, 0xd1]),
+            (Instr::Not(Reg::Rax), &[0x48, 0xf7, 0xd0]),
+            (Instr::Not(Reg::R12), &[0x49, 0xf7, 0xd4]),
+            (Instr::Sal(Reg::Rax), &[0x48, 0xd3, 0xe0]),
+            (Instr::Sar(Reg::Rax), &[0x48, 0xd3, 0xf8]),
+            (Instr::Sar(Reg::R9), &[0x49, 0xd3, 0xf9]),
+            (Instr::Cmp(Reg::Rax, Reg::Rdi), &[0x48, 0x39, 0xf8]),
+            (Instr::CmpImm(Reg::Rax, 0), &[0x48, 0x83, 0xf8, 0x00]),
+            (
+                Instr::CmpImm(Reg::R12, 1000),
+                &[0x49, 0x81, 0xfc, 0xe8, 0x03, 0x00, 0x00],
+            ),
+            (Instr::Set(Cond::E, Reg::Rax), &[0x0f, 0x94, 0xc0]),
+            (Instr::Set(Cond::Ge, Reg::Rsi), &[0x40, 0x0f, 0x9d, 0xc6]),
+            (Instr::Movzb(Reg::Rax, Reg::Rax), &[0x48, 0x0f, 0xb6, 0xc0]),
+            (Instr::Movzb(Reg::R8, Reg::Rdi), &[0x4c, 0x0f, 0xb6, 0xc7]),
+            (Instr::SubImm(Reg::Rsp, 8), &[0x48, 0x83, 0xec, 0x08]),
+            (Instr::AddImm(Reg::Rsp, 8), &[0x48, 0x83, 0xc4, 0x08]),
+            (Instr::AndImm(Reg::Rax, 15), &[0x48, 0x83, 0xe0, 0x0f]),
+            (
+                Instr::AndImm(Reg::Rdi, -4096),
+                &[0x48, 0x81, 0xe7, 0x00, 0xf0, 0xff, 0xff],
+            ),
+            (
+                Instr::SubImm(Reg::Rsp, 208),
+                &[0x48, 0x81, 0xec, 0xd0, 0x00, 0x00, 0x00],
+            ),
+            (
+                Instr::Lea(Reg::Rax, mem(Reg::Rbp, -8)),
+                &[0x48, 0x8d, 0x45, 0xf8],
+            ),
+            (
+                Instr::Lea(Reg::Rax, mem(Reg::Rbp, -208)),
+                &[0x48, 0x8d, 0x85, 0x30, 0xff, 0xff, 0xff],
+            ),
+            (Instr::Load(Reg::Rax, mem(Reg::Rax, 0)), &[0x48, 0x8b, 0x00]),
+            (
+                Instr::Load(Reg::Rdi, mem(Reg::Rbp, 0)),
+                &[0x48, 0x8b, 0x7d, 0x00],
+            ),
+            (
+                Instr::Load(Reg::Rax, mem(Reg::Rsp, 0)),
+                &[0x48, 0x8b, 0x04, 0x24],
+            ),
+            (
+                Instr::Store(mem(Reg::Rax, 0), Reg::Rdi),
+                &[0x48, 0x89, 0x38],
+            ),
+            (
+                Instr::Store(mem(Reg::R12, 8), Reg::R8),
+                &[0x4d, 0x89, 0x44, 0x24, 0x08],
+            ),
+            (
+                Instr::Store(mem(Reg::R13, 0), Reg::Rax),
+                &[0x49, 0x89, 0x45, 0x00],
+            ),
+            (
+                Instr::LoadByte(Reg::Rax, mem(Reg::Rax, 0)),
+                &[0x48, 0x0f, 0xbe, 0x00],
+            ),
+            (
+                Instr::LoadByte(Reg::Rdi, mem(Reg::Rbp, -8)),
+                &[0x48, 0x0f, 0xbe, 0x7d, 0xf8],
+            ),
+            (
+                Instr::LoadByte(Reg::R9, mem(Reg::R13, 0)),
+                &[0x4d, 0x0f, 0xbe, 0x4d, 0x00],
+            ),
+            (Instr::StoreByte(mem(Reg::Rax, 0), Reg::Rax), &[0x88, 0x00]),
+            (
+                Instr::StoreByte(mem(Reg::Rax, 0), Reg::Rdi),
+                &[0x40, 0x88, 0x38],
+            ),
+            (
+                Instr::StoreByte(mem(Reg::R12, 8), Reg::R8),
+                &[0x45, 0x88, 0x44, 0x24, 0x08],
+            ),
+            (Instr::Ret, &[0xc3]),
+        ];
+        for (instr, expected) in cases.iter() {
+            assert_eq!(encode(&[*instr])?, *expected, "{}", instr);
+        }
+
+        assert!(encode(&[Instr::PushImm(1 << 31)]).is_err());
+        // Calls need relocations.
+        let name = crate::token::Name::new("foo").unwrap();
+        assert!(encode(&[Instr::Call(name)]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_jumps() -> Result<()> {
+        let mut labels = Labels::new();
+        let (top, end) = (labels.fresh(LabelKind::End), labels.fresh(LabelKind::End));
+        let instrs = [
+            Instr::Label(top),
+            Instr::Jcc(Cond::E, end),
+            Instr::Jmp(end),
+            Instr::MovImm(Reg::Rax, 1),
+            Instr::Label(end),
+            Instr::Jcc(Cond::Ne, top),
+        ];
+        #[rustfmt::skip]
+        let expected = [
+            0x0f, 0x84, 0x0c, 0x00, 0x00, 0x00,
+            0xe9, 0x07, 0x00, 0x00, 0x00,
+            0x48, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00,
+            0x0f, 0x85, 0xe8, 0xff, 0xff, 0xff,
+        ];
+        assert_eq!(encode(&instrs)?, expected);
+
+        assert!(encode(&[Instr::Jmp(top)]).is_err());
+        assert!(encode(&[Instr::Label(top), Instr::Label(top)]).is_err());
+        Ok(())
+    }
+}
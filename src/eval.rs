@@ -0,0 +1,972 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::opt::fold_constants;
+use crate::parse::{is_chained, lvar_name, Node, NodeKind};
+use crate::token::Loc;
+use crate::ty::Ty;
+use crate::CompileError;
+
+/// Values of variables, by name.
+pub type Env = HashMap<char, i64>;
+
+/// Parses a variable definition `name=value`.
+pub fn parse_var(spec: &str) -> Result<(char, i64)> {
+    let invalid = || anyhow!("invalid variable '{}', expected e.g. a=1", spec);
+    let mut parts = spec.splitn(2, '=');
+    let name = parts.next().ok_or_else(invalid)?;
+    let value = parts.next().ok_or_else(invalid)?;
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(name), None) if name.is_ascii_lowercase() => {
+            Ok((name, value.trim().parse().map_err(|_| invalid())?))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// What arithmetic does on overflow.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub enum Overflow {
+    /// Fails with an error.
+    #[default]
+    Checked,
+    /// Wraps around in two's complement, like the generated code does.
+    ///
+    /// Division still fails, since `idiv` traps on overflow.
+    Wrapping,
+}
+
+/// Evaluates AST into a value.
+pub fn eval(node: &Node) -> Result<i64> {
+    eval_with_env(node, &Env::new())
+}
+
+/// Evaluates AST into a value, reading variables from `env`.
+pub fn eval_with_env(node: &Node, env: &Env) -> Result<i64> {
+    eval_with_overflow(node, env, Overflow::Checked)
+}
+
+/// Same as `eval_with_env`, doing `overflow` on overflow.
+///
+/// Assignments update a copy of `env`, seen by the statements after them.
+pub fn eval_with_overflow(node: &Node, env: &Env, overflow: Overflow) -> Result<i64> {
+    match exec(node, &mut env.clone(), overflow)? {
+        Flow::Value(value) | Flow::Return(value) => Ok(value),
+        Flow::Break(loc) => {
+            Err(CompileError::Eval("'break' outside of a loop".to_string(), loc).into())
+        }
+        Flow::Continue(loc) => {
+            Err(CompileError::Eval("'continue' outside of a loop".to_string(), loc).into())
+        }
+    }
+}
+
+/// How a statement finishes running.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Flow {
+    /// Ran to the end, taking the value.
+    Value(i64),
+    /// Left by the `break` at the location, skipping the rest up to the innermost loop.
+    Break(Loc),
+    /// Left by the `continue` at the location, skipping the rest of the loop body.
+    Continue(Loc),
+    /// Left by a `return` of the value, skipping everything else.
+    Return(i64),
+}
+
+/// Runs the statement `node`, returning the value of the last expression evaluated.
+///
+/// Only recurses into nested statements, so that long expressions and sequences do not
+/// overflow the stack.
+fn exec(node: &Node, env: &mut Env, overflow: Overflow) -> Result<Flow> {
+    match node.kind {
+        NodeKind::Seq => {
+            // Sequences lean left, with the first statement at the bottom.
+            let mut stmts = Vec::new();
+            let mut first = node;
+            while first.kind == NodeKind::Seq {
+                stmts.push(
+                    first
+                        .rhs
+                        .as_deref()
+                        .context("Expect non null rhs, but is null.")?,
+                );
+                first = first
+                    .lhs
+                    .as_deref()
+                    .context("Expect non null lhs, but is null.")?;
+            }
+            exec_all(
+                std::iter::once(first).chain(stmts.into_iter().rev()),
+                env,
+                overflow,
+            )
+        }
+        NodeKind::If => {
+            let cond = node
+                .cond
+                .as_deref()
+                .context("Expect non null cond, but is null.")?;
+            let cond = eval_expr(cond, env, overflow)?;
+            if cond == 0 {
+                return match node.rhs.as_deref() {
+                    Some(els) => exec(els, env, overflow),
+                    None => Ok(Flow::Value(cond)),
+                };
+            }
+            exec(
+                node.lhs
+                    .as_deref()
+                    .context("Expect non null lhs, but is null.")?,
+                env,
+                overflow,
+            )
+        }
+        NodeKind::While | NodeKind::For => {
+            let body = node
+                .lhs
+                .as_deref()
+                .context("Expect non null lhs, but is null.")?;
+            if let Some(init) = node.init.as_deref() {
+                eval_expr(init, env, overflow)?;
+            }
+            loop {
+                if let Some(cond) = node.cond.as_deref() {
+                    if eval_expr(cond, env, overflow)? == 0 {
+                        return Ok(Flow::Value(0));
+                    }
+                }
+                match exec(body, env, overflow)? {
+                    Flow::Break(_) => return Ok(Flow::Value(0)),
+                    Flow::Return(value) => return Ok(Flow::Return(value)),
+                    _ => (),
+                }
+                if let Some(inc) = node.inc.as_deref() {
+                    eval_expr(inc, env, overflow)?;
+                }
+            }
+        }
+        NodeKind::Block => exec_all(node.stmts.iter().map(|stmt| &**stmt), env, overflow),
+        NodeKind::DoWhile => {
+            let body = node
+                .lhs
+                .as_deref()
+                .context("Expect non null lhs, but is null.")?;
+            let cond = node
+                .cond
+                .as_deref()
+                .context("Expect non null cond, but is null.")?;
+            loop {
+                match exec(body, env, overflow)? {
+                    Flow::Break(_) => return Ok(Flow::Value(0)),
+                    Flow::Return(value) => return Ok(Flow::Return(value)),
+                    _ => (),
+                }
+                if eval_expr(cond, env, overflow)? == 0 {
+                    return Ok(Flow::Value(0));
+                }
+            }
+        }
+        NodeKind::Switch => {
+            let cond = node
+                .cond
+                .as_deref()
+                .context("Expect non null cond, but is null.")?;
+            let value = eval_expr(cond, env, overflow)?;
+            let first = node
+                .stmts
+                .iter()
+                .position(|arm| arm.kind == NodeKind::Case(value))
+                .or_else(|| {
+                    node.stmts
+                        .iter()
+                        .position(|arm| arm.kind == NodeKind::Default)
+                });
+            let first = match first {
+                Some(first) => first,
+                None => return Ok(Flow::Value(0)),
+            };
+            // Falls through the arms after the first one.
+            let stmts = node.stmts[first..]
+                .iter()
+                .flat_map(|arm| arm.stmts.iter().map(|stmt| &**stmt));
+            match exec_all(stmts, env, overflow)? {
+                flow @ Flow::Continue(_) | flow @ Flow::Return(_) => Ok(flow),
+                _ => Ok(Flow::Value(0)),
+            }
+        }
+        NodeKind::Break => Ok(Flow::Break(node.loc)),
+        NodeKind::Continue => Ok(Flow::Continue(node.loc)),
+        NodeKind::Return => match node.lhs.as_deref() {
+            Some(value) => eval_expr(value, env, overflow).map(Flow::Return),
+            None => Ok(Flow::Return(0)),
+        },
+        NodeKind::Decl => {
+            if let Some(init) = node.rhs.as_deref() {
+                eval_expr(init, env, overflow)?;
+            }
+            Ok(Flow::Value(0))
+        }
+        NodeKind::Function { .. } => Ok(Flow::Value(0)),
+        _ => eval_expr(node, env, overflow).map(Flow::Value),
+    }
+}
+
+/// Runs `stmts` in order until one jumps out of them, taking the value of the last one, and 0 if
+/// there is none.
+fn exec_all<'a>(
+    stmts: impl Iterator<Item = &'a Node>,
+    env: &mut Env,
+    overflow: Overflow,
+) -> Result<Flow> {
+    let mut flow = Flow::Value(0);
+    for stmt in stmts {
+        flow = exec(stmt, env, overflow)?;
+        if !matches!(flow, Flow::Value(_)) {
+            break;
+        }
+    }
+    Ok(flow)
+}
+
+/// Evaluates the expression `node`, assigning to variables in `env`.
+fn eval_expr(node: &Node, env: &mut Env, overflow: Overflow) -> Result<i64> {
+    let env = RefCell::new(env);
+    node.fold_pruned(
+        // A call is not evaluated, so neither are its arguments.
+        |node| {
+            matches!(
+                node.kind,
+                NodeKind::LogAnd | NodeKind::LogOr | NodeKind::Call { .. }
+            )
+        },
+        |node| match node.kind {
+            NodeKind::Num(num) => Ok(num),
+            NodeKind::LogAnd | NodeKind::LogOr => {
+                eval_logical(node, &mut env.borrow_mut(), overflow)
+            }
+            NodeKind::LVar { offset } => {
+                let name = lvar_name(offset);
+                env.borrow().get(&name).copied().ok_or_else(|| {
+                    CompileError::Eval(format!("undefined variable '{}'", name), node.loc).into()
+                })
+            }
+            NodeKind::Call { name } => {
+                let message = format!("cannot evaluate the call of '{}'", name);
+                Err(CompileError::Eval(message, node.loc).into())
+            }
+            kind => Err(anyhow!("Expected a leaf but got {:?}", kind)),
+        },
+        |node, values| {
+            if let NodeKind::Addr | NodeKind::Deref = node.kind {
+                let message = "cannot evaluate pointers".to_string();
+                return Err(CompileError::Eval(message, node.loc).into());
+            }
+            if let NodeKind::Not | NodeKind::BitNot = node.kind {
+                return match <[Result<i64>; 1]>::try_from(values) {
+                    Ok([operand]) => apply_unop(node.kind, operand?),
+                    Err(_) => Err(anyhow!("Expected one operand of {:?}", node.kind)),
+                };
+            }
+            let (lhs, rhs) = match <[Result<i64>; 2]>::try_from(values) {
+                Ok([lhs, rhs]) => (lhs, rhs),
+                Err(_) => return Err(anyhow!("Expected binary operator but got {:?}", node.kind)),
+            };
+            match (node.kind, node.lhs.as_deref()) {
+                // The left side is a variable, whose value before the assignment is not needed.
+                (
+                    NodeKind::Assign,
+                    Some(Node {
+                        kind: NodeKind::LVar { offset },
+                        ty,
+                        ..
+                    }),
+                ) => {
+                    // A `_Bool` takes 0 or 1, which is also the value of the assignment.
+                    let value = match ty {
+                        Some(Ty::Bool) => (rhs? != 0) as i64,
+                        _ => rhs?,
+                    };
+                    // A `char` keeps the low byte, while the assignment takes the whole value.
+                    let stored = match ty {
+                        Some(Ty::Char) => i64::from(value as i8),
+                        _ => value,
+                    };
+                    env.borrow_mut().insert(lvar_name(*offset), stored);
+                    Ok(value)
+                }
+                _ => apply_binop(node.kind, lhs?, rhs?, node.loc, overflow),
+            }
+        },
+    )
+}
+
+/// Evaluates the chain of `&&` and `||` down the left of `node`, skipping the right sides
+/// which cannot change its value.
+fn eval_logical(node: &Node, env: &mut Env, overflow: Overflow) -> Result<i64> {
+    let mut chain = Vec::new();
+    let mut first = node;
+    while let NodeKind::LogAnd | NodeKind::LogOr = first.kind {
+        chain.push(first);
+        first = first
+            .lhs
+            .as_deref()
+            .context("Expect non null lhs, but is null.")?;
+    }
+    let mut value = eval_expr(first, env, overflow)?;
+    for node in chain.into_iter().rev() {
+        value = match (node.kind, value != 0) {
+            (NodeKind::LogAnd, false) => 0,
+            (NodeKind::LogOr, true) => 1,
+            _ => {
+                let rhs = node
+                    .rhs
+                    .as_deref()
+                    .context("Expect non null rhs, but is null.")?;
+                (eval_expr(rhs, env, overflow)? != 0) as i64
+            }
+        };
+    }
+    Ok(value)
+}
+
+/// Evaluates a tree made only of constants, with the same checks as constant folding.
+///
+/// Fails at the first node which is not a constant.
+pub fn eval_const(node: &Node) -> Result<i64> {
+    eval_const_with_overflow(node, Overflow::Checked)
+}
+
+/// Same as `eval_const`, doing `overflow` on overflow.
+pub fn eval_const_with_overflow(node: &Node, overflow: Overflow) -> Result<i64> {
+    match node.kind {
+        NodeKind::Num(num) => return Ok(num),
+        NodeKind::LVar { offset } => {
+            let message = format!("'{}' is not a constant", lvar_name(offset));
+            return Err(CompileError::Eval(message, node.loc).into());
+        }
+        NodeKind::Call { name } => {
+            let message = format!("'{}()' is not a constant", name);
+            return Err(CompileError::Eval(message, node.loc).into());
+        }
+        NodeKind::Addr | NodeKind::Deref => {
+            let message = "pointers are not constants".to_string();
+            return Err(CompileError::Eval(message, node.loc).into());
+        }
+        NodeKind::If
+        | NodeKind::While
+        | NodeKind::For
+        | NodeKind::DoWhile
+        | NodeKind::Block
+        | NodeKind::Break
+        | NodeKind::Continue
+        | NodeKind::Switch
+        | NodeKind::Case(_)
+        | NodeKind::Default
+        | NodeKind::Return
+        | NodeKind::Function { .. } => {
+            let message = format!("{} is not a constant", statement_name(node.kind));
+            return Err(CompileError::Eval(message, node.loc).into());
+        }
+        NodeKind::Not | NodeKind::BitNot => {
+            let operand = node
+                .lhs
+                .as_ref()
+                .context("Expect non null lhs, but is null.")?;
+            return apply_unop(node.kind, eval_const_with_overflow(operand, overflow)?);
+        }
+        _ => (),
+    }
+
+    // Goes down the chain of operators on the left in a loop, so that long expressions do
+    // not overflow the stack.
+    let mut chain = vec![node];
+    let mut first = node
+        .lhs
+        .as_deref()
+        .context("Expect non null lhs, but is null.")?;
+    while is_chained(first.kind) {
+        chain.push(first);
+        first = first
+            .lhs
+            .as_deref()
+            .context("Expect non null lhs, but is null.")?;
+    }
+    let mut value = eval_const_with_overflow(first, overflow)?;
+    for node in chain.into_iter().rev() {
+        let rhs = eval_const_with_overflow(
+            node.rhs
+                .as_ref()
+                .context("Expect non null rhs, but is null.")?,
+            overflow,
+        )?;
+        value = apply_binop(node.kind, value, rhs, node.loc, overflow)?;
+    }
+    Ok(value)
+}
+
+/// Returns how errors call the statement of `kind`.
+fn statement_name(kind: NodeKind) -> &'static str {
+    match kind {
+        NodeKind::If => "'if'",
+        NodeKind::While => "'while'",
+        NodeKind::For => "'for'",
+        NodeKind::DoWhile => "'do'",
+        NodeKind::Break => "'break'",
+        NodeKind::Continue => "'continue'",
+        NodeKind::Switch => "'switch'",
+        NodeKind::Case(_) => "'case'",
+        NodeKind::Default => "'default'",
+        NodeKind::Return => "'return'",
+        NodeKind::Function { .. } => "a function definition",
+        _ => "a block",
+    }
+}
+
+/// Folds the constant subtrees of `node` and evaluates the rest.
+///
+/// To evaluate the same tree repeatedly, fold it once with `fold_constants` and `eval` the result.
+pub fn eval_folded(node: Node) -> Result<i64> {
+    let (node, _) = fold_constants(node);
+    eval(&node)
+}
+
+/// Applies a binary operator at `loc`, doing `overflow` on overflow.
+pub fn apply_binop(
+    kind: NodeKind,
+    lhs: i64,
+    rhs: i64,
+    loc: Loc,
+    overflow: Overflow,
+) -> Result<i64> {
+    let wrapping = overflow == Overflow::Wrapping;
+    let overflow = |op: &str| CompileError::Eval(format!("integer overflow in {}", op), loc);
+    let value = match kind {
+        NodeKind::Add if wrapping => lhs.wrapping_add(rhs),
+        NodeKind::Sub if wrapping => lhs.wrapping_sub(rhs),
+        NodeKind::Mul if wrapping => lhs.wrapping_mul(rhs),
+        NodeKind::Add => lhs.checked_add(rhs).ok_or_else(|| overflow("addition"))?,
+        NodeKind::Sub => lhs
+            .checked_sub(rhs)
+            .ok_or_else(|| overflow("subtraction"))?,
+        NodeKind::Mul => lhs
+            .checked_mul(rhs)
+            .ok_or_else(|| overflow("multiplication"))?,
+        NodeKind::Div => {
+            if rhs == 0 {
+                return Err(CompileError::Eval("division by zero".to_string(), loc).into());
+            }
+            lhs.checked_div(rhs).ok_or_else(|| overflow("division"))?
+        }
+        // Comparisons yield 1 or 0 as in C, which is what setcc and movzb produce.
+        NodeKind::Eq => (lhs == rhs) as i64,
+        NodeKind::Neq => (lhs != rhs) as i64,
+        NodeKind::Lt => (lhs < rhs) as i64,
+        NodeKind::Leq => (lhs <= rhs) as i64,
+        NodeKind::Gt => (lhs > rhs) as i64,
+        NodeKind::Geq => (lhs >= rhs) as i64,
+        NodeKind::BitAnd => lhs & rhs,
+        NodeKind::BitOr => lhs | rhs,
+        NodeKind::BitXor => lhs ^ rhs,
+        // Like `sal` and `sar`, which only use the low 6 bits of the count.
+        NodeKind::Shl if wrapping => lhs.wrapping_shl((rhs & 63) as u32),
+        NodeKind::Shr if wrapping => lhs.wrapping_shr((rhs & 63) as u32),
+        NodeKind::Shl | NodeKind::Shr if !(0..64).contains(&rhs) => {
+            let message = "shift count out of range".to_string();
+            return Err(CompileError::Eval(message, loc).into());
+        }
+        NodeKind::Shl => {
+            let value = lhs << rhs;
+            if value >> rhs != lhs {
+                return Err(overflow("left shift").into());
+            }
+            value
+        }
+        NodeKind::Shr => lhs >> rhs,
+        NodeKind::LogAnd => (lhs != 0 && rhs != 0) as i64,
+        NodeKind::LogOr => (lhs != 0 || rhs != 0) as i64,
+        NodeKind::Seq => rhs,
+        NodeKind::Num(_)
+        | NodeKind::LVar { .. }
+        | NodeKind::Assign
+        | NodeKind::If
+        | NodeKind::While
+        | NodeKind::For
+        | NodeKind::DoWhile
+        | NodeKind::Block
+        | NodeKind::Break
+        | NodeKind::Continue
+        | NodeKind::Return
+        | NodeKind::Switch
+        | NodeKind::Case(_)
+        | NodeKind::Default
+        | NodeKind::Call { .. }
+        | NodeKind::Function { .. }
+        | NodeKind::Addr
+        | NodeKind::Deref
+        | NodeKind::Not
+        | NodeKind::BitNot
+        | NodeKind::Decl => {
+            return Err(anyhow!("Expected binary operator but got {:?}", kind));
+        }
+    };
+    Ok(value)
+}
+
+/// Applies a unary operator, which cannot overflow.
+pub fn apply_unop(kind: NodeKind, operand: i64) -> Result<i64> {
+    match kind {
+        NodeKind::Not => Ok((operand == 0) as i64),
+        NodeKind::BitNot => Ok(!operand),
+        kind => Err(anyhow!("Expected unary operator but got {:?}", kind)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error_at;
+    use crate::eval::*;
+    use crate::parse::{node_count, parse_streaming};
+
+    /// Evaluates `input` expecting an error, and returns its message and location.
+    fn eval_err(input: &str) -> (String, Loc) {
+        error_at(eval(&parse_streaming(input).unwrap()))
+    }
+
+    #[test]
+    fn test_wrapping() -> Result<()> {
+        let root = parse_streaming("9223372036854775807 + 1;")?;
+        assert_eq!(
+            eval_with_overflow(&root, &Env::new(), Overflow::Wrapping)?,
+            i64::MIN
+        );
+        assert_eq!(
+            eval_const_with_overflow(&root, Overflow::Wrapping)?,
+            i64::MIN
+        );
+        assert_eq!(
+            eval(&root).unwrap_err().to_string(),
+            "integer overflow in addition"
+        );
+        assert!(eval_const(&root).is_err());
+
+        let root = parse_streaming("-9223372036854775808 / -1;")?;
+        assert!(eval_const_with_overflow(&root, Overflow::Wrapping).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_const() -> Result<()> {
+        assert_eq!(eval_const(&parse_streaming("(1-2)*(3+4);")?)?, -7);
+
+        let root = parse_streaming("int b; 1 + 2*b;")?;
+        let err = eval_const(root.rhs.as_deref().context("No rhs")?).unwrap_err();
+        match err.downcast_ref::<CompileError>() {
+            Some(CompileError::Eval(message, loc)) => {
+                assert_eq!(message, "'b' is not a constant");
+                assert_eq!(*loc, Loc { line: 1, col: 14 });
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+
+        // Agrees with constant folding.
+        for input in ["2*3+1;", "(5 > 3) + 1;", "100/(2+3);"].iter() {
+            let root = parse_streaming(input)?;
+            let value = eval_const(&root)?;
+            let (folded, _) = fold_constants(root);
+            assert_eq!(folded.kind, NodeKind::Num(value), "{}", input);
+        }
+        assert!(eval_const(&parse_streaming("1/0;")?).is_err());
+
+        // Statements are not constants, even if they only hold constants.
+        for (input, message) in [
+            ("if (1) 2;", "'if' is not a constant"),
+            ("{1;}", "a block is not a constant"),
+            ("return 3;", "'return' is not a constant"),
+            ("while (0) 1;", "'while' is not a constant"),
+            ("for (;;) break;", "'for' is not a constant"),
+            ("do 1; while (0);", "'do' is not a constant"),
+            ("switch (1) { case 1: 2; }", "'switch' is not a constant"),
+            (
+                "f(a) { return a; }",
+                "a function definition is not a constant",
+            ),
+            ("1; if (1) 2;", "'if' is not a constant"),
+        ]
+        .iter()
+        {
+            let (actual, _) = error_at(eval_const(&parse_streaming(input)?));
+            assert_eq!(actual, *message, "{}", input);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval() -> Result<()> {
+        assert_eq!(eval(&parse_streaming("2*(1+23)-456/7;")?)?, -17);
+        assert_eq!(
+            eval(&parse_streaming("-9223372036854775807-1;")?)?,
+            i64::MIN
+        );
+        assert_eq!(eval(&parse_streaming("0x10 + 1;")?)?, 17);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_folded() -> Result<()> {
+        let input = "2*(1+23)-456/7;";
+        assert_eq!(
+            eval_folded(parse_streaming(input)?)?,
+            eval(&parse_streaming(input)?)?
+        );
+        assert_eq!(eval_folded(parse_streaming("2*3+1;")?)?, 7);
+
+        // Folds into a negative literal.
+        let (folded, _) = fold_constants(parse_streaming(input)?);
+        assert_eq!(node_count(&folded), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_with_env() -> Result<()> {
+        let env: Env = [('a', 3), ('b', 4), ('c', 1)].iter().copied().collect();
+        assert_eq!(
+            eval_with_env(&parse_streaming("int a; int b; int c; a*b+c;")?, &env)?,
+            13
+        );
+
+        let err = eval_with_env(&parse_streaming("int a; int d; a + d;")?, &env).unwrap_err();
+        assert_eq!(err.to_string(), "undefined variable 'd'");
+
+        assert_eq!(parse_var("x=-5")?, ('x', -5));
+        for spec in ["x", "xy=1", "X=1", "x=", "x=y"].iter() {
+            assert!(parse_var(spec).is_err(), "{}", spec);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_assign() -> Result<()> {
+        assert_eq!(eval(&parse_streaming("int a = 3; a + 1;")?)?, 4);
+        assert_eq!(
+            eval(&parse_streaming("int a; int b; a = b = 2; a * b;")?)?,
+            4
+        );
+        assert_eq!(eval(&parse_streaming("int a = 1; a = a + 2;")?)?, 3);
+        assert_eq!(
+            eval_err("int a; int b; a = b;"),
+            (
+                "undefined variable 'b'".to_string(),
+                Loc { line: 1, col: 19 }
+            )
+        );
+        assert!(eval_const(&parse_streaming("int a; a = 1;")?).is_err());
+        // A char keeps the low byte, sign-extended.
+        assert_eq!(eval(&parse_streaming("char c; c = 300; c;")?)?, 44);
+        assert_eq!(eval(&parse_streaming("char c = 255; c + 1;")?)?, 0);
+        assert_eq!(eval(&parse_streaming("char c; c = 300;")?)?, 300);
+        assert_eq!(eval(&parse_streaming("_Bool b; b = 5; b;")?)?, 1);
+        assert_eq!(eval(&parse_streaming("_Bool b = 7; b = 0; b;")?)?, 0);
+        assert_eq!(eval(&parse_streaming("_Bool b; b = -256;")?)?, 1);
+        // Variables have no addresses here, even when defined.
+        assert_eq!(
+            eval_err("int a = 1; *&a;"),
+            (
+                "cannot evaluate pointers".to_string(),
+                Loc { line: 1, col: 12 }
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_if() -> Result<()> {
+        assert_eq!(
+            eval(&parse_streaming("int a = 3; if (1 < 2) a = 5; a;")?)?,
+            5
+        );
+        assert_eq!(
+            eval(&parse_streaming("int a = 3; if (2 < 1) a = 5; a;")?)?,
+            3
+        );
+        // The body is not evaluated when skipped.
+        assert_eq!(eval(&parse_streaming("if (0) 1 / 0;")?)?, 0);
+        assert_eq!(eval(&parse_streaming("if (2) if (3) 4;")?)?, 4);
+        assert_eq!(eval(&parse_streaming("if (0) 1; else 1 + 1;")?)?, 2);
+        assert_eq!(
+            eval(&parse_streaming("if (0) 1; else if (0) 2; else 3;")?)?,
+            3
+        );
+        assert_eq!(eval(&parse_streaming("if (1) 1; else 1 / 0;")?)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_while() -> Result<()> {
+        let input = "int i = 0; while (i < 10) i = i + 1; i;";
+        assert_eq!(eval(&parse_streaming(input)?)?, 10);
+        assert_eq!(eval(&parse_streaming("while (0) 1 / 0;")?)?, 0);
+        let input = "int s = 0; int i = 0; while (i < 3) while (i < 3) s = s + (i = i + 1); s;";
+        assert_eq!(eval(&parse_streaming(input)?)?, 6);
+
+        let input = "int s = 0; int i; for (i = 0; i < 5; i = i + 1) s = s + i; s;";
+        assert_eq!(eval(&parse_streaming(input)?)?, 10);
+        assert_eq!(
+            eval(&parse_streaming("int i; for (i = 0; i < 3;) i = i + 1;")?)?,
+            0
+        );
+
+        let input = "int i = 0; do i = i + 1; while (i < 0); i;";
+        assert_eq!(eval(&parse_streaming(input)?)?, 1);
+        let input = "int i = 0; do i = i + 1; while (i < 5); i;";
+        assert_eq!(eval(&parse_streaming(input)?)?, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_block() -> Result<()> {
+        assert_eq!(eval(&parse_streaming("{ 1; 2; }")?)?, 2);
+        assert_eq!(eval(&parse_streaming("{}")?)?, 0);
+        let input = "int s = 0; int i = 0; while (i < 3) { i = i + 1; s = s + i; } s;";
+        assert_eq!(eval(&parse_streaming(input)?)?, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_break() -> Result<()> {
+        let input = "int i; for (i = 0;; i = i + 1) if (i == 7) break; i;";
+        assert_eq!(eval(&parse_streaming(input)?)?, 7);
+        // Only the innermost loop is left.
+        let input =
+            "int s = 0; int i; for (i = 0; i < 3; i = i + 1) while (1) { s = s + 1; break; s = 9; } s;";
+        assert_eq!(eval(&parse_streaming(input)?)?, 3);
+        let input = "int i = 0; do { i = i + 1; if (i > 2) break; } while (1); i;";
+        assert_eq!(eval(&parse_streaming(input)?)?, 3);
+        assert_eq!(eval(&parse_streaming("while (1) break;")?)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_switch() -> Result<()> {
+        let switch = "switch (a) { case 1: x = x + 1; case 2: x = x + 2; case 3: x = x + 4; }";
+        let with_break =
+            "switch (a) { case 1: x = x + 1; break; case 2: x = x + 2; break; case 3: x = x + 4; }";
+        for (a, fallthrough, separate) in [(1, 7, 1), (2, 6, 2), (3, 4, 4), (4, 0, 0)].iter() {
+            for (input, expected) in [(switch, fallthrough), (with_break, separate)].iter() {
+                let root = parse_streaming(&format!("int a; int x = 0; {} x;", input))?;
+                let env: Env = vec![('a', *a)].into_iter().collect();
+                assert_eq!(eval_with_env(&root, &env)?, **expected, "{} a={}", input, a);
+            }
+        }
+
+        let input = "int x; switch (5) { case 1: x = 1; default: x = 2; case 3: x = x + 3; } x;";
+        assert_eq!(eval(&parse_streaming(input)?)?, 5);
+        // Continues the enclosing loop, rather than ending the switch.
+        let input = "int s = 0; int i; for (i = 0; i < 5; i = i + 1) switch (i) { case 1: continue; case 3: s = s + 10; break; default: s = s + 1; } s;";
+        assert_eq!(eval(&parse_streaming(input)?)?, 13);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_return() -> Result<()> {
+        for (input, expected) in [
+            ("return 5; 6;", 5),
+            ("int a = 2; if (a) return a * 3; 1;", 6),
+            ("int i; for (i = 0;; i = i + 1) if (i == 4) return i;", 4),
+            (
+                "int i = 0; do { i = i + 1; { return i + 10; } } while (1);",
+                11,
+            ),
+            ("switch (1) { case 1: while (1) return 7; default: 8; }", 7),
+        ]
+        .iter()
+        {
+            assert_eq!(eval(&parse_streaming(input)?)?, *expected, "{}", input);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_call() {
+        for (input, name, col) in [
+            ("f();", 'f', 1),
+            ("1 + f(2, 3);", 'f', 5),
+            ("f(a, b) { return a; } f(1, 2 * 3);", 'f', 23),
+            ("g(f(1));", 'g', 1),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                eval_err(input),
+                (
+                    format!("cannot evaluate the call of '{}'", name),
+                    Loc { line: 1, col: *col }
+                ),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_eval_continue() -> Result<()> {
+        let input = "int s = 0; int i; for (i = 0; i < 10; i = i + 1) { if (i == 5) continue; s = s + 1; } s;";
+        assert_eq!(eval(&parse_streaming(input)?)?, 9);
+        let input =
+            "int s = 0; int i = 0; while (i < 5) { i = i + 1; if (i == 2) continue; s = s + i; } s;";
+        assert_eq!(eval(&parse_streaming(input)?)?, 13);
+        // Goes on with the condition, which ends the loop.
+        let input = "int i = 0; do { i = i + 1; continue; i = 9; } while (i < 3); i;";
+        assert_eq!(eval(&parse_streaming(input)?)?, 3);
+
+        assert_eq!(
+            eval_err("if (1) continue;"),
+            (
+                "'continue' outside of a loop".to_string(),
+                Loc { line: 1, col: 8 }
+            )
+        );
+
+        assert_eq!(
+            eval_err("int a = 1; break;"),
+            (
+                "'break' outside of a loop".to_string(),
+                Loc { line: 1, col: 12 }
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_comparison() -> Result<()> {
+        assert_eq!(eval(&parse_streaming("3<5;")?)?, 1);
+        assert_eq!(eval(&parse_streaming("1==1;")?)?, 1);
+        assert_eq!(eval(&parse_streaming("5<3;")?)?, 0);
+        assert_eq!(eval(&parse_streaming("(3<5) + 10;")?)?, 11);
+        assert_eq!(eval(&parse_streaming("(2 == 2) * 7 - (1 >= 2);")?)?, 7);
+        assert_eq!(
+            eval(&parse_streaming("(5 != 3) + (5 > 3) + (3 <= 3);")?)?,
+            3
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_unary() -> Result<()> {
+        assert_eq!(eval(&parse_streaming("- -3;")?)?, 3);
+        assert_eq!(eval(&parse_streaming("+ -1;")?)?, -1);
+        assert_eq!(eval(&parse_streaming("-!1 + -~1;")?)?, 2);
+        assert_eq!(eval_const(&parse_streaming("- - -5;")?)?, -5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_not() -> Result<()> {
+        assert_eq!(eval(&parse_streaming("!0;")?)?, 1);
+        assert_eq!(eval(&parse_streaming("!5;")?)?, 0);
+        assert_eq!(eval(&parse_streaming("!!-3 + !(2 < 1);")?)?, 2);
+        assert_eq!(eval(&parse_streaming("int a; a = 0; !a;")?)?, 1);
+        assert_eq!(eval_const(&parse_streaming("!(1 - 1) * 4;")?)?, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_bitwise() -> Result<()> {
+        assert_eq!(eval(&parse_streaming("6 & 3;")?)?, 2);
+        assert_eq!(eval(&parse_streaming("5 | 2;")?)?, 7);
+        assert_eq!(eval(&parse_streaming("5 ^ 1;")?)?, 4);
+        assert_eq!(eval(&parse_streaming("~0;")?)?, -1);
+        // `&` binds tighter than `^`, which binds tighter than `|`, all looser than `==`.
+        assert_eq!(eval(&parse_streaming("1 | 6 ^ 3 & 5;")?)?, 7);
+        assert_eq!(eval(&parse_streaming("3 & 2 == 2;")?)?, 1);
+        assert_eq!(eval_const(&parse_streaming("~5 & 0xff;")?)?, 250);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_shift() -> Result<()> {
+        assert_eq!(eval(&parse_streaming("1 << 4;")?)?, 16);
+        assert_eq!(eval(&parse_streaming("256 >> 2;")?)?, 64);
+        assert_eq!(eval(&parse_streaming("-16 >> 2;")?)?, -4);
+        // Binds looser than `+` and tighter than `<`.
+        assert_eq!(eval(&parse_streaming("1 << 1 + 2 < 9;")?)?, 1);
+
+        assert_eq!(
+            eval_err("1 << 64;"),
+            (
+                "shift count out of range".to_string(),
+                Loc { line: 1, col: 3 }
+            )
+        );
+        assert_eq!(
+            eval_err("1 << 63;"),
+            (
+                "integer overflow in left shift".to_string(),
+                Loc { line: 1, col: 3 }
+            )
+        );
+        let root = parse_streaming("1 << 65;")?;
+        assert_eq!(
+            eval_with_overflow(&root, &Env::new(), Overflow::Wrapping)?,
+            2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_logical() -> Result<()> {
+        assert_eq!(eval(&parse_streaming("2 && 3;")?)?, 1);
+        assert_eq!(eval(&parse_streaming("2 && 0;")?)?, 0);
+        assert_eq!(eval(&parse_streaming("0 || 0;")?)?, 0);
+        assert_eq!(eval(&parse_streaming("0 || -5;")?)?, 1);
+        assert_eq!(eval(&parse_streaming("1 || 0 && 0;")?)?, 1);
+        assert_eq!(eval(&parse_streaming("(1 || 0) + 1;")?)?, 2);
+
+        // The right side is skipped once the left side decides the value.
+        assert_eq!(eval(&parse_streaming("0 && (1/0);")?)?, 0);
+        assert_eq!(eval(&parse_streaming("1 || 1/0 || 1/0;")?)?, 1);
+        assert_eq!(
+            eval(&parse_streaming(
+                "int a = 0; 0 && (a = 1); 1 || (a = 2); a;"
+            )?)?,
+            0
+        );
+        assert_eq!(
+            eval(&parse_streaming(
+                "int a = 0; 1 && (a = 3); 0 || (a = a + 1); a;"
+            )?)?,
+            4
+        );
+        assert_eq!(
+            eval_err("1 && (1/0);"),
+            ("division by zero".to_string(), Loc { line: 1, col: 8 })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_overflow() {
+        assert_eq!(
+            eval_err("(-9223372036854775807-1) / -1;"),
+            (
+                "integer overflow in division".to_string(),
+                Loc { line: 1, col: 26 }
+            )
+        );
+        assert_eq!(
+            eval_err("9223372036854775807 + 1;"),
+            (
+                "integer overflow in addition".to_string(),
+                Loc { line: 1, col: 21 }
+            )
+        );
+        assert_eq!(
+            eval_err("1 / (2-2);"),
+            ("division by zero".to_string(), Loc { line: 1, col: 3 })
+        );
+    }
+}
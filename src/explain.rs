@@ -0,0 +1,123 @@
+use crate::dot::dot_to_string;
+use crate::eval::eval;
+use crate::limits::Limit;
+use crate::parse::parse_slice_with_limits;
+use crate::token::{Token, Tokenizer};
+use crate::unparse::{sexpr, tree};
+use crate::{analyze, gen_with_options, CompileOptions, Diagnostic};
+
+/// Every intermediate form of one input.
+///
+/// When a stage fails, the forms of the previous stages are still filled in and the
+/// error is in `diagnostics`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Explanation {
+    pub source: String,
+    pub tokens: Vec<Token>,
+    pub ast_dot: Option<String>,
+    pub ast_tree: Option<String>,
+    pub sexpr: Option<String>,
+    pub asm: Option<String>,
+    /// Value of the input, unless evaluating fails, e.g. on division by zero.
+    pub eval: Option<i64>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Runs each stage on `source` once, rendering the same AST in every form.
+pub fn explain(source: &str, options: &CompileOptions) -> Explanation {
+    let mut explanation = Explanation {
+        source: source.to_string(),
+        ..Explanation::default()
+    };
+
+    // Keeps the tokens before a tokenize error.
+    let tokens = &mut explanation.tokens;
+    let res = options.limits.check_input(source).and_then(|_| {
+        for token in Tokenizer::with_options(source, options.tokenize) {
+            tokens.push(token?);
+            options.limits.check(Limit::Tokens, tokens.len())?;
+        }
+        Ok(())
+    });
+    if let Err(err) = res {
+        explanation.diagnostics.push(err.into());
+        return explanation;
+    }
+
+    let root = match parse_slice_with_limits(&explanation.tokens, &options.limits)
+        .and_then(|root| analyze(&root, options).map(|_| root))
+    {
+        Ok(root) => root,
+        Err(err) => {
+            explanation.diagnostics.push(err.into());
+            return explanation;
+        }
+    };
+    match dot_to_string(&root) {
+        Ok(dot) => explanation.ast_dot = Some(dot),
+        Err(err) => explanation.diagnostics.push(err.into()),
+    }
+    explanation.ast_tree = Some(tree(&root));
+    explanation.sexpr = Some(sexpr(&root));
+    explanation.eval = eval(&root).ok();
+    match gen_with_options(&root, options) {
+        Ok(asm) => explanation.asm = Some(asm),
+        Err(err) => explanation.diagnostics.push(err.into()),
+    }
+    explanation
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::explain::*;
+    use crate::token::{tokenize, Loc};
+
+    #[test]
+    fn test_explain() {
+        let options = CompileOptions::default();
+        let explanation = explain("1+2*3;", &options);
+        assert_eq!(explanation.source, "1+2*3;");
+        assert_eq!(explanation.tokens, tokenize("1+2*3;").unwrap());
+        assert!(explanation.ast_dot.unwrap().starts_with("digraph G {\n"));
+        assert_eq!(
+            explanation.ast_tree.unwrap(),
+            "Add\n  Num(1)\n  Mul\n    Num(2)\n    Num(3)\n"
+        );
+        assert_eq!(explanation.sexpr.unwrap(), "(+ 1 (* 2 3))");
+        assert!(explanation.asm.unwrap().contains("imul rax, rdi"));
+        assert_eq!(explanation.eval, Some(7));
+        assert!(explanation.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_explain_failure() {
+        let options = CompileOptions::default();
+        let explanation = explain("1 + (2", &options);
+        assert_eq!(explanation.tokens.len(), 5);
+        assert_eq!(explanation.ast_tree, None);
+        assert_eq!(explanation.asm, None);
+        assert_eq!(explanation.eval, None);
+        assert_eq!(explanation.diagnostics.len(), 1);
+
+        let explanation = explain("1 + $", &options);
+        assert_eq!(explanation.tokens.len(), 2);
+        assert_eq!(
+            explanation.diagnostics[0].loc,
+            Some(Loc { line: 1, col: 5 })
+        );
+
+        // Uninitialized variables cannot be evaluated, but code is still generated.
+        let explanation = explain("int a; a + 1;", &options);
+        assert_eq!(explanation.sexpr.unwrap(), "(; (decl int a) (+ a 1))");
+        assert!(explanation.asm.is_some());
+        assert_eq!(explanation.eval, None);
+        assert!(explanation.diagnostics.is_empty());
+
+        let explanation = explain("a + 1;", &options);
+        assert_eq!(explanation.ast_tree, None);
+        assert_eq!(
+            explanation.diagnostics[0].loc,
+            Some(Loc { line: 1, col: 1 })
+        );
+    }
+}
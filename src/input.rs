@@ -0,0 +1,87 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::CompileError;
+
+const BOM: char = '\u{feff}';
+
+/// Removes a leading byte order mark, which editors do not count as a column.
+pub fn strip_bom(input: &str) -> &str {
+    input.strip_prefix(BOM).unwrap_or(input)
+}
+
+/// Decodes the bytes of a source file, without its byte order mark.
+pub fn decode_source(bytes: Vec<u8>) -> Result<String> {
+    let source = String::from_utf8(bytes).map_err(|_| CompileError::InvalidEncoding)?;
+    Ok(match source.strip_prefix(BOM) {
+        Some(rest) => rest.to_string(),
+        None => source,
+    })
+}
+
+/// Reads the source file at `path`.
+pub fn read_source(path: &Path) -> Result<String> {
+    let bytes = fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+    decode_source(bytes)
+}
+
+/// Reads the source file at `path`, or stdin if `path` is `-`.
+pub fn read_input(path: &str) -> Result<String> {
+    if path != "-" {
+        return read_source(Path::new(path));
+    }
+    let mut bytes = Vec::new();
+    io::stdin()
+        .read_to_end(&mut bytes)
+        .context("cannot read stdin")?;
+    decode_source(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::*;
+    use crate::token::{tokenize, Loc};
+
+    use std::env;
+
+    /// Writes `bytes` into a temporary file and reads it back as a source.
+    fn read_bytes(name: &str, bytes: &[u8]) -> Result<String> {
+        let path = env::temp_dir().join(format!("rust9cc-input-{}-{}", std::process::id(), name));
+        fs::write(&path, bytes)?;
+        let res = read_source(&path);
+        fs::remove_file(&path)?;
+        res
+    }
+
+    #[test]
+    fn test_bom() -> Result<()> {
+        let source = read_bytes("bom", b"\xef\xbb\xbf1 +\n $")?;
+        assert_eq!(source, "1 +\n $");
+
+        let err = tokenize(&source).unwrap_err();
+        match err.downcast_ref::<CompileError>() {
+            Some(CompileError::Tokenize(_, loc)) => assert_eq!(*loc, Loc { line: 2, col: 2 }),
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        assert_eq!(strip_bom("\u{feff}1+2"), "1+2");
+        assert_eq!(strip_bom("1+2"), "1+2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_utf16() {
+        let utf16: Vec<u8> = "\u{feff}1+2"
+            .encode_utf16()
+            .flat_map(|unit| unit.to_le_bytes().to_vec())
+            .collect();
+        let err = read_bytes("utf16", &utf16).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "input is not valid UTF-8 (did you save as UTF-16?)"
+        );
+    }
+}
@@ -0,0 +1,664 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::token::Name;
+
+/// 64-bit general purpose registers, in the order of their encoding.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Reg {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+const REGS: [Reg; 16] = [
+    Reg::Rax,
+    Reg::Rcx,
+    Reg::Rdx,
+    Reg::Rbx,
+    Reg::Rsp,
+    Reg::Rbp,
+    Reg::Rsi,
+    Reg::Rdi,
+    Reg::R8,
+    Reg::R9,
+    Reg::R10,
+    Reg::R11,
+    Reg::R12,
+    Reg::R13,
+    Reg::R14,
+    Reg::R15,
+];
+
+impl Reg {
+    /// Returns the register number used in the machine code.
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        const NAMES: [&str; 16] = [
+            "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11",
+            "r12", "r13", "r14", "r15",
+        ];
+        NAMES[self as usize]
+    }
+
+    /// Returns the name of the low byte of the register.
+    pub fn byte_name(self) -> &'static str {
+        const NAMES: [&str; 16] = [
+            "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b",
+            "r12b", "r13b", "r14b", "r15b",
+        ];
+        NAMES[self as usize]
+    }
+}
+
+/// Conditions of `setcc`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Cond {
+    E,
+    Ne,
+    L,
+    Le,
+    G,
+    Ge,
+}
+
+const CONDS: [Cond; 6] = [Cond::E, Cond::Ne, Cond::L, Cond::Le, Cond::G, Cond::Ge];
+
+impl Cond {
+    pub fn name(self) -> &'static str {
+        match self {
+            Cond::E => "e",
+            Cond::Ne => "ne",
+            Cond::L => "l",
+            Cond::Le => "le",
+            Cond::G => "g",
+            Cond::Ge => "ge",
+        }
+    }
+}
+
+/// What a local label marks, which also names it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum LabelKind {
+    Begin,
+    Else,
+    End,
+    /// Where `continue` jumps, if not to the beginning of the loop.
+    Continue,
+    /// Arm of a switch.
+    Case,
+    /// Call made after aligning the stack.
+    Call,
+    /// Where `&&` goes once an operand is 0.
+    False,
+    /// Where `||` goes once an operand is not 0.
+    True,
+}
+
+const LABEL_KINDS: [LabelKind; 8] = [
+    LabelKind::Begin,
+    LabelKind::Else,
+    LabelKind::End,
+    LabelKind::Continue,
+    LabelKind::Case,
+    LabelKind::Call,
+    LabelKind::False,
+    LabelKind::True,
+];
+
+impl LabelKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            LabelKind::Begin => "begin",
+            LabelKind::Else => "else",
+            LabelKind::End => "end",
+            LabelKind::Continue => "continue",
+            LabelKind::Case => "case",
+            LabelKind::Call => "call",
+            LabelKind::False => "false",
+            LabelKind::True => "true",
+        }
+    }
+}
+
+/// Local label, unique within a function.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Label {
+    pub kind: LabelKind,
+    pub id: usize,
+}
+
+impl Label {
+    /// Returns the name in the assembly with `prefix` prepended, e.g. `.L.end.0`.
+    pub fn asm_name(self, prefix: &str) -> String {
+        format!(".L.{}{}.{}", prefix, self.kind.name(), self.id)
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.asm_name(""))
+    }
+}
+
+/// Memory operand at `disp` bytes from the address in `base`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Mem {
+    pub base: Reg,
+    pub disp: i32,
+}
+
+impl Mem {
+    pub fn new(base: Reg, disp: i32) -> Self {
+        Mem { base, disp }
+    }
+}
+
+impl fmt::Display for Mem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.disp {
+            0 => write!(f, "[{}]", self.base.name()),
+            disp => write!(f, "[{}{:+}]", self.base.name(), disp),
+        }
+    }
+}
+
+/// Instructions the code generator emits.
+///
+/// The same sequence is either printed as assembly or encoded into machine code.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Instr {
+    /// Marks a valid target of indirect branches for Intel CET.
+    Endbr64,
+    /// Pushes a sign-extended 32-bit immediate.
+    PushImm(i64),
+    Push(Reg),
+    Pop(Reg),
+    Mov(Reg, Reg),
+    /// Becomes `movabs` for immediates which do not fit in 32 bits.
+    MovImm(Reg, i64),
+    Add(Reg, Reg),
+    AddImm(Reg, i32),
+    Sub(Reg, Reg),
+    SubImm(Reg, i32),
+    And(Reg, Reg),
+    AndImm(Reg, i32),
+    Or(Reg, Reg),
+    Xor(Reg, Reg),
+    /// Flips all the bits of the register.
+    Not(Reg),
+    /// Shifts the register left by `cl`.
+    Sal(Reg),
+    /// Shifts the register right by `cl`, copying the sign bit.
+    Sar(Reg),
+    Imul(Reg, Reg),
+    ImulImm(Reg, i32),
+    Cqo,
+    Idiv(Reg),
+    Cmp(Reg, Reg),
+    CmpImm(Reg, i32),
+    /// Sets the low byte of the register to the condition.
+    Set(Cond, Reg),
+    /// Zero-extends the low byte of the second register into the first.
+    Movzb(Reg, Reg),
+    /// Loads the address of the operand.
+    Lea(Reg, Mem),
+    Load(Reg, Mem),
+    Store(Mem, Reg),
+    /// Sign-extends the byte at the operand into the register.
+    LoadByte(Reg, Mem),
+    /// Stores the low byte of the register.
+    StoreByte(Mem, Reg),
+    Label(Label),
+    Jmp(Label),
+    /// Jumps if the condition holds.
+    Jcc(Cond, Label),
+    /// Calls the external function.
+    Call(Name),
+    Ret,
+}
+
+impl Instr {
+    /// Returns the assembly of the instruction with `label_prefix` prepended to its label.
+    pub fn asm(self, label_prefix: &str) -> Asm<'_> {
+        Asm {
+            instr: self,
+            label_prefix,
+        }
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.asm("").fmt(f)
+    }
+}
+
+/// Assembly of an instruction, returned by `Instr::asm`.
+pub struct Asm<'a> {
+    instr: Instr,
+    label_prefix: &'a str,
+}
+
+impl<'a> fmt::Display for Asm<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = |label: Label| label.asm_name(self.label_prefix);
+        match self.instr {
+            Instr::Endbr64 => write!(f, "endbr64"),
+            Instr::PushImm(imm) => write!(f, "push {}", imm),
+            Instr::Push(reg) => write!(f, "push {}", reg.name()),
+            Instr::Pop(reg) => write!(f, "pop {}", reg.name()),
+            Instr::Mov(dst, src) => write!(f, "mov {}, {}", dst.name(), src.name()),
+            Instr::MovImm(dst, imm) if i32::try_from(imm).is_err() => {
+                write!(f, "movabs {}, {}", dst.name(), imm)
+            }
+            Instr::MovImm(dst, imm) => write!(f, "mov {}, {}", dst.name(), imm),
+            Instr::Add(dst, src) => write!(f, "add {}, {}", dst.name(), src.name()),
+            Instr::AddImm(dst, imm) => write!(f, "add {}, {}", dst.name(), imm),
+            Instr::Sub(dst, src) => write!(f, "sub {}, {}", dst.name(), src.name()),
+            Instr::SubImm(dst, imm) => write!(f, "sub {}, {}", dst.name(), imm),
+            Instr::And(dst, src) => write!(f, "and {}, {}", dst.name(), src.name()),
+            Instr::AndImm(dst, imm) => write!(f, "and {}, {}", dst.name(), imm),
+            Instr::Or(dst, src) => write!(f, "or {}, {}", dst.name(), src.name()),
+            Instr::Xor(dst, src) => write!(f, "xor {}, {}", dst.name(), src.name()),
+            Instr::Not(reg) => write!(f, "not {}", reg.name()),
+            Instr::Sal(reg) => write!(f, "sal {}, cl", reg.name()),
+            Instr::Sar(reg) => write!(f, "sar {}, cl", reg.name()),
+            Instr::Imul(dst, src) => write!(f, "imul {}, {}", dst.name(), src.name()),
+            Instr::ImulImm(dst, imm) => write!(f, "imul {}, {}", dst.name(), imm),
+            Instr::Cqo => write!(f, "cqo"),
+            Instr::Idiv(reg) => write!(f, "idiv {}", reg.name()),
+            Instr::Cmp(lhs, rhs) => write!(f, "cmp {}, {}", lhs.name(), rhs.name()),
+            Instr::CmpImm(lhs, imm) => write!(f, "cmp {}, {}", lhs.name(), imm),
+            Instr::Set(cond, reg) => write!(f, "set{} {}", cond.name(), reg.byte_name()),
+            Instr::Movzb(dst, src) => write!(f, "movzb {}, {}", dst.name(), src.byte_name()),
+            Instr::Lea(dst, mem) => write!(f, "lea {}, {}", dst.name(), mem),
+            Instr::Load(dst, mem) => write!(f, "mov {}, {}", dst.name(), mem),
+            Instr::Store(mem, src) => write!(f, "mov {}, {}", mem, src.name()),
+            Instr::LoadByte(dst, mem) => write!(f, "movsx {}, byte ptr {}", dst.name(), mem),
+            Instr::StoreByte(mem, src) => write!(f, "mov {}, {}", mem, src.byte_name()),
+            Instr::Label(target) => write!(f, "{}:", label(target)),
+            Instr::Jmp(target) => write!(f, "jmp {}", label(target)),
+            Instr::Jcc(cond, target) => write!(f, "j{} {}", cond.name(), label(target)),
+            Instr::Call(name) => write!(f, "call {}", name),
+            Instr::Ret => write!(f, "ret"),
+        }
+    }
+}
+
+/// Operand of an instruction in the textual IR.
+enum Operand {
+    Reg(Reg),
+    Cond(Cond),
+    Imm(i64),
+    Mem(Mem),
+    Label(Label),
+    Name(Name),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(reg) => write!(f, "{:?}", reg),
+            Operand::Cond(cond) => write!(f, "{:?}", cond),
+            Operand::Imm(imm) => write!(f, "Imm({})", imm),
+            Operand::Mem(Mem { base, disp: 0 }) => write!(f, "[{:?}]", base),
+            Operand::Mem(Mem { base, disp }) => write!(f, "[{:?}{:+}]", base, disp),
+            Operand::Label(label) => write!(f, "{:?}({})", label.kind, label.id),
+            Operand::Name(name) => write!(f, "Name({})", name),
+        }
+    }
+}
+
+impl Operand {
+    fn parse(s: &str) -> Result<Self> {
+        if let Some(imm) = s.strip_prefix("Imm(").and_then(|s| s.strip_suffix(')')) {
+            return Ok(Operand::Imm(imm.parse()?));
+        }
+        if let Some(name) = s.strip_prefix("Name(").and_then(|s| s.strip_suffix(')')) {
+            return Name::new(name)
+                .map(Operand::Name)
+                .ok_or_else(|| anyhow!("invalid name '{}'", name));
+        }
+        if let Some(mem) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            let (base, disp) = match mem.find(['+', '-']) {
+                Some(i) => (&mem[..i], mem[i..].trim_start_matches('+').parse()?),
+                None => (mem, 0),
+            };
+            return match Operand::parse(base)? {
+                Operand::Reg(base) => Ok(Operand::Mem(Mem { base, disp })),
+                _ => Err(anyhow!("unknown operand '{}'", s)),
+            };
+        }
+        for kind in LABEL_KINDS.iter() {
+            let name = format!("{:?}(", kind);
+            if let Some(id) = s.strip_prefix(&name).and_then(|s| s.strip_suffix(')')) {
+                let id = id.parse()?;
+                return Ok(Operand::Label(Label { kind: *kind, id }));
+            }
+        }
+        if let Some(reg) = REGS.iter().find(|reg| format!("{:?}", reg) == s) {
+            return Ok(Operand::Reg(*reg));
+        }
+        if let Some(cond) = CONDS.iter().find(|cond| format!("{:?}", cond) == s) {
+            return Ok(Operand::Cond(*cond));
+        }
+        Err(anyhow!("unknown operand '{}'", s))
+    }
+}
+
+/// Splits `instr` into its name and operands in the textual IR.
+fn ir_parts(instr: Instr) -> (&'static str, Vec<Operand>) {
+    use Operand::{Imm, Reg as R};
+    match instr {
+        Instr::Endbr64 => ("Endbr64", vec![]),
+        Instr::PushImm(imm) => ("Push", vec![Imm(imm)]),
+        Instr::Push(reg) => ("Push", vec![R(reg)]),
+        Instr::Pop(reg) => ("Pop", vec![R(reg)]),
+        Instr::Mov(dst, src) => ("Mov", vec![R(dst), R(src)]),
+        Instr::MovImm(dst, imm) => ("Mov", vec![R(dst), Imm(imm)]),
+        Instr::Add(dst, src) => ("Add", vec![R(dst), R(src)]),
+        Instr::AddImm(dst, imm) => ("Add", vec![R(dst), Imm(imm.into())]),
+        Instr::Sub(dst, src) => ("Sub", vec![R(dst), R(src)]),
+        Instr::SubImm(dst, imm) => ("Sub", vec![R(dst), Imm(imm.into())]),
+        Instr::And(dst, src) => ("And", vec![R(dst), R(src)]),
+        Instr::AndImm(dst, imm) => ("And", vec![R(dst), Imm(imm.into())]),
+        Instr::Or(dst, src) => ("Or", vec![R(dst), R(src)]),
+        Instr::Xor(dst, src) => ("Xor", vec![R(dst), R(src)]),
+        Instr::Not(reg) => ("Not", vec![R(reg)]),
+        Instr::Sal(reg) => ("Sal", vec![R(reg)]),
+        Instr::Sar(reg) => ("Sar", vec![R(reg)]),
+        Instr::Imul(dst, src) => ("Imul", vec![R(dst), R(src)]),
+        Instr::ImulImm(dst, imm) => ("Imul", vec![R(dst), Imm(imm.into())]),
+        Instr::Cqo => ("Cqo", vec![]),
+        Instr::Idiv(reg) => ("Idiv", vec![R(reg)]),
+        Instr::Cmp(lhs, rhs) => ("Cmp", vec![R(lhs), R(rhs)]),
+        Instr::CmpImm(lhs, imm) => ("Cmp", vec![R(lhs), Imm(imm.into())]),
+        Instr::Set(cond, reg) => ("Set", vec![Operand::Cond(cond), R(reg)]),
+        Instr::Movzb(dst, src) => ("Movzb", vec![R(dst), R(src)]),
+        Instr::Lea(dst, mem) => ("Lea", vec![R(dst), Operand::Mem(mem)]),
+        Instr::Load(dst, mem) => ("Load", vec![R(dst), Operand::Mem(mem)]),
+        Instr::Store(mem, src) => ("Store", vec![Operand::Mem(mem), R(src)]),
+        Instr::LoadByte(dst, mem) => ("LoadByte", vec![R(dst), Operand::Mem(mem)]),
+        Instr::StoreByte(mem, src) => ("StoreByte", vec![Operand::Mem(mem), R(src)]),
+        Instr::Label(label) => ("Label", vec![Operand::Label(label)]),
+        Instr::Jmp(label) => ("Jmp", vec![Operand::Label(label)]),
+        Instr::Jcc(cond, label) => ("Jcc", vec![Operand::Cond(cond), Operand::Label(label)]),
+        Instr::Call(name) => ("Call", vec![Operand::Name(name)]),
+        Instr::Ret => ("Ret", vec![]),
+    }
+}
+
+/// Prints `instrs` one per line in the textual IR, e.g. `Push Imm(5)` or `Pop Rdi`.
+///
+/// Unlike the assembly, the form does not depend on the syntax of any assembler.
+pub fn ir_to_string(instrs: &[Instr]) -> String {
+    let mut out = String::new();
+    for instr in instrs.iter() {
+        let (name, operands) = ir_parts(*instr);
+        out.push_str(name);
+        let operands: Vec<String> = operands.iter().map(Operand::to_string).collect();
+        if !operands.is_empty() {
+            out.push(' ');
+            out.push_str(&operands.join(", "));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Parses the textual IR printed by `ir_to_string`, ignoring blank lines.
+pub fn parse_ir(text: &str) -> Result<Vec<Instr>> {
+    let mut instrs = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (name, operands) = match line.find(' ') {
+            Some(i) => (&line[..i], line[i + 1..].split(", ").collect()),
+            None => (line, Vec::new()),
+        };
+        let operands = operands
+            .into_iter()
+            .map(Operand::parse)
+            .collect::<Result<Vec<Operand>>>()
+            .with_context(|| format!("invalid instruction '{}'", line))?;
+        use Operand::{Imm, Reg as R};
+        let instr = match (name, operands.as_slice()) {
+            ("Endbr64", []) => Instr::Endbr64,
+            ("Push", [Imm(imm)]) => Instr::PushImm(*imm),
+            ("Push", [R(reg)]) => Instr::Push(*reg),
+            ("Pop", [R(reg)]) => Instr::Pop(*reg),
+            ("Mov", [R(dst), R(src)]) => Instr::Mov(*dst, *src),
+            ("Mov", [R(dst), Imm(imm)]) => Instr::MovImm(*dst, *imm),
+            ("Add", [R(dst), R(src)]) => Instr::Add(*dst, *src),
+            ("Add", [R(dst), Imm(imm)]) => Instr::AddImm(*dst, i32::try_from(*imm)?),
+            ("Sub", [R(dst), R(src)]) => Instr::Sub(*dst, *src),
+            ("Sub", [R(dst), Imm(imm)]) => Instr::SubImm(*dst, i32::try_from(*imm)?),
+            ("And", [R(dst), R(src)]) => Instr::And(*dst, *src),
+            ("And", [R(dst), Imm(imm)]) => Instr::AndImm(*dst, i32::try_from(*imm)?),
+            ("Or", [R(dst), R(src)]) => Instr::Or(*dst, *src),
+            ("Xor", [R(dst), R(src)]) => Instr::Xor(*dst, *src),
+            ("Not", [R(reg)]) => Instr::Not(*reg),
+            ("Sal", [R(reg)]) => Instr::Sal(*reg),
+            ("Sar", [R(reg)]) => Instr::Sar(*reg),
+            ("Imul", [R(dst), R(src)]) => Instr::Imul(*dst, *src),
+            ("Imul", [R(dst), Imm(imm)]) => Instr::ImulImm(*dst, i32::try_from(*imm)?),
+            ("Cqo", []) => Instr::Cqo,
+            ("Idiv", [R(reg)]) => Instr::Idiv(*reg),
+            ("Cmp", [R(lhs), R(rhs)]) => Instr::Cmp(*lhs, *rhs),
+            ("Cmp", [R(lhs), Imm(imm)]) => Instr::CmpImm(*lhs, i32::try_from(*imm)?),
+            ("Set", [Operand::Cond(cond), R(reg)]) => Instr::Set(*cond, *reg),
+            ("Movzb", [R(dst), R(src)]) => Instr::Movzb(*dst, *src),
+            ("Lea", [R(dst), Operand::Mem(mem)]) => Instr::Lea(*dst, *mem),
+            ("Load", [R(dst), Operand::Mem(mem)]) => Instr::Load(*dst, *mem),
+            ("Store", [Operand::Mem(mem), R(src)]) => Instr::Store(*mem, *src),
+            ("LoadByte", [R(dst), Operand::Mem(mem)]) => Instr::LoadByte(*dst, *mem),
+            ("StoreByte", [Operand::Mem(mem), R(src)]) => Instr::StoreByte(*mem, *src),
+            ("Label", [Operand::Label(label)]) => Instr::Label(*label),
+            ("Jmp", [Operand::Label(label)]) => Instr::Jmp(*label),
+            ("Jcc", [Operand::Cond(cond), Operand::Label(label)]) => Instr::Jcc(*cond, *label),
+            ("Call", [Operand::Name(name)]) => Instr::Call(*name),
+            ("Ret", []) => Instr::Ret,
+            _ => return Err(anyhow!("invalid instruction '{}'", line)),
+        };
+        instrs.push(instr);
+    }
+    Ok(instrs)
+}
+
+/// Generates local labels unique within a function.
+#[derive(Debug, Default, Clone)]
+pub struct Labels {
+    count: usize,
+    /// Labels `break` and `continue` jump to in the loops and switches being generated,
+    /// innermost last.
+    loops: Vec<(Label, Option<Label>)>,
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a new label of `kind`.
+    pub fn fresh(&mut self, kind: LabelKind) -> Label {
+        let label = Label {
+            kind,
+            id: self.count,
+        };
+        self.count += 1;
+        label
+    }
+
+    /// Makes `end` and `next` the labels `break` and `continue` jump to, until the
+    /// matching `leave_loop`.
+    pub fn enter_loop(&mut self, end: Label, next: Label) {
+        self.loops.push((end, Some(next)));
+    }
+
+    /// Makes `end` the label `break` jumps to, until the matching `leave_loop`, while
+    /// `continue` still goes to the enclosing loop.
+    pub fn enter_switch(&mut self, end: Label) {
+        let next = self.continue_label();
+        self.loops.push((end, next));
+    }
+
+    pub fn leave_loop(&mut self) {
+        self.loops.pop();
+    }
+
+    /// Returns the end label of the innermost loop or switch, if any.
+    pub fn break_label(&self) -> Option<Label> {
+        self.loops.last().map(|(end, _)| *end)
+    }
+
+    /// Returns the label of the innermost loop where the next iteration starts, if any.
+    pub fn continue_label(&self) -> Option<Label> {
+        self.loops.last().and_then(|(_, next)| *next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::*;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Instr::PushImm(3).to_string(), "push 3");
+        assert_eq!(Instr::Pop(Reg::R12).to_string(), "pop r12");
+        assert_eq!(Instr::Add(Reg::Rax, Reg::Rdi).to_string(), "add rax, rdi");
+        assert_eq!(Instr::MovImm(Reg::Rdi, 7).to_string(), "mov rdi, 7");
+        assert_eq!(
+            Instr::MovImm(Reg::Rax, 1 << 31).to_string(),
+            "movabs rax, 2147483648"
+        );
+        assert_eq!(Instr::Set(Cond::Le, Reg::Rax).to_string(), "setle al");
+        assert_eq!(
+            Instr::Movzb(Reg::Rax, Reg::Rsi).to_string(),
+            "movzb rax, sil"
+        );
+        let mem = Mem::new;
+        assert_eq!(
+            Instr::Lea(Reg::Rax, mem(Reg::Rbp, -8)).to_string(),
+            "lea rax, [rbp-8]"
+        );
+        assert_eq!(
+            Instr::Load(Reg::Rax, mem(Reg::Rax, 0)).to_string(),
+            "mov rax, [rax]"
+        );
+        assert_eq!(
+            Instr::Store(mem(Reg::Rsp, 16), Reg::Rdi).to_string(),
+            "mov [rsp+16], rdi"
+        );
+        assert_eq!(
+            Instr::LoadByte(Reg::Rax, mem(Reg::Rbp, -8)).to_string(),
+            "movsx rax, byte ptr [rbp-8]"
+        );
+        assert_eq!(
+            Instr::StoreByte(mem(Reg::Rax, 0), Reg::Rdi).to_string(),
+            "mov [rax], dil"
+        );
+        assert_eq!(Instr::AndImm(Reg::Rax, 15).to_string(), "and rax, 15");
+        assert_eq!(Instr::Xor(Reg::Rax, Reg::Rdi).to_string(), "xor rax, rdi");
+        assert_eq!(Instr::Not(Reg::Rax).to_string(), "not rax");
+        assert_eq!(Instr::Sar(Reg::Rax).to_string(), "sar rax, cl");
+        assert_eq!(Instr::ImulImm(Reg::Rdi, 8).to_string(), "imul rdi, 8");
+        let name = Name::new("foo").unwrap();
+        assert_eq!(Instr::Call(name).to_string(), "call foo");
+    }
+
+    #[test]
+    fn test_ir() -> Result<()> {
+        let end = Label {
+            kind: LabelKind::End,
+            id: 3,
+        };
+        let instrs = vec![
+            Instr::Endbr64,
+            Instr::PushImm(5),
+            Instr::Pop(Reg::Rdi),
+            Instr::MovImm(Reg::R8, -7),
+            Instr::Cmp(Reg::Rax, Reg::Rdi),
+            Instr::Set(Cond::Ge, Reg::Rax),
+            Instr::SubImm(Reg::Rsp, 208),
+            Instr::Lea(Reg::Rax, Mem::new(Reg::Rbp, -8)),
+            Instr::Store(Mem::new(Reg::Rax, 0), Reg::Rdi),
+            Instr::CmpImm(Reg::Rax, 0),
+            Instr::Jcc(Cond::E, end),
+            Instr::Jmp(end),
+            Instr::Label(end),
+            Instr::AndImm(Reg::Rax, 15),
+            Instr::Or(Reg::Rax, Reg::Rdi),
+            Instr::Not(Reg::Rax),
+            Instr::Sal(Reg::Rax),
+            Instr::AddImm(Reg::Rsp, 8),
+            Instr::ImulImm(Reg::Rdi, 8),
+            Instr::LoadByte(Reg::Rax, Mem::new(Reg::Rbp, -8)),
+            Instr::StoreByte(Mem::new(Reg::Rax, 0), Reg::Rdi),
+            Instr::Call(Name::new("foo").context("Not a name")?),
+            Instr::Ret,
+        ];
+        let text = ir_to_string(&instrs);
+        assert_eq!(
+            text,
+            concat!(
+                "Endbr64\nPush Imm(5)\nPop Rdi\nMov R8, Imm(-7)\nCmp Rax, Rdi\nSet Ge, Rax\n",
+                "Sub Rsp, Imm(208)\nLea Rax, [Rbp-8]\nStore [Rax], Rdi\nCmp Rax, Imm(0)\n",
+                "Jcc E, End(3)\nJmp End(3)\nLabel End(3)\nAnd Rax, Imm(15)\nOr Rax, Rdi\n",
+                "Not Rax\nSal Rax\nAdd Rsp, Imm(8)\n",
+                "Imul Rdi, Imm(8)\nLoadByte Rax, [Rbp-8]\nStoreByte [Rax], Rdi\n",
+                "Call Name(foo)\nRet\n"
+            )
+        );
+        assert_eq!(parse_ir(&text)?, instrs);
+        assert_eq!(
+            parse_ir("Jmp Continue(2)")?,
+            vec![Instr::Jmp(Label {
+                kind: LabelKind::Continue,
+                id: 2
+            })]
+        );
+
+        assert!(parse_ir("Pop Imm(1)").is_err());
+        assert!(parse_ir("Push Rzz").is_err());
+        assert_eq!(
+            parse_ir("Load Rdi, [R12+16]")?,
+            vec![Instr::Load(Reg::Rdi, Mem::new(Reg::R12, 16))]
+        );
+        assert!(parse_ir("Load Rdi, [Ge]").is_err());
+        assert!(parse_ir("Jmp End(x)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut labels = Labels::new();
+        let first = labels.fresh(LabelKind::End);
+        let second = labels.fresh(LabelKind::End);
+        assert_ne!(first, second);
+        assert_eq!(first.asm_name(""), ".L.end.0");
+        assert_eq!(second.asm_name("unit1_"), ".L.unit1_end.1");
+
+        assert_eq!(Instr::Label(second).to_string(), ".L.end.1:");
+        assert_eq!(
+            Instr::Jcc(Cond::E, first).asm("unit1_").to_string(),
+            "je .L.unit1_end.0"
+        );
+    }
+}
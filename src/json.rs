@@ -0,0 +1,227 @@
+use std::fmt::Write;
+
+use crate::parse::{Node, NodeKind};
+use crate::token::{Loc, RenderOptions, Token, TokenKind};
+
+/// Serializes a value into compact JSON, with locations shown following `options`.
+pub trait ToJson {
+    fn to_json(&self, options: RenderOptions, out: &mut String);
+
+    fn to_json_string(&self, options: RenderOptions) -> String {
+        let mut out = String::new();
+        self.to_json(options, &mut out);
+        out
+    }
+}
+
+impl ToJson for str {
+    fn to_json(&self, _: RenderOptions, out: &mut String) {
+        out.push('"');
+        for c in self.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+}
+
+impl ToJson for String {
+    fn to_json(&self, options: RenderOptions, out: &mut String) {
+        self.as_str().to_json(options, out);
+    }
+}
+
+impl ToJson for u64 {
+    fn to_json(&self, _: RenderOptions, out: &mut String) {
+        write!(out, "{}", self).unwrap();
+    }
+}
+
+impl ToJson for i64 {
+    fn to_json(&self, _: RenderOptions, out: &mut String) {
+        write!(out, "{}", self).unwrap();
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn to_json(&self, options: RenderOptions, out: &mut String) {
+        match self {
+            Some(value) => value.to_json(options, out),
+            None => out.push_str("null"),
+        }
+    }
+}
+
+impl<T: ToJson + ?Sized> ToJson for Box<T> {
+    fn to_json(&self, options: RenderOptions, out: &mut String) {
+        self.as_ref().to_json(options, out);
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn to_json(&self, options: RenderOptions, out: &mut String) {
+        self.as_slice().to_json(options, out);
+    }
+}
+
+impl<T: ToJson> ToJson for [T] {
+    fn to_json(&self, options: RenderOptions, out: &mut String) {
+        out.push('[');
+        for (i, value) in self.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            value.to_json(options, out);
+        }
+        out.push(']');
+    }
+}
+
+/// Writes `{"key":value,...}`.
+fn write_object(options: RenderOptions, out: &mut String, fields: &[(&str, &dyn ToJson)]) {
+    out.push('{');
+    for (i, (key, value)) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        key.to_json(options, out);
+        out.push(':');
+        value.to_json(options, out);
+    }
+    out.push('}');
+}
+
+impl ToJson for Loc {
+    fn to_json(&self, options: RenderOptions, out: &mut String) {
+        let loc = self.display(options);
+        write!(out, "{{\"line\":{},\"col\":{}}}", loc.line, loc.col).unwrap();
+    }
+}
+
+impl ToJson for TokenKind {
+    fn to_json(&self, options: RenderOptions, out: &mut String) {
+        match self {
+            TokenKind::Num(num) => write_object(options, out, &[("Num", num)]),
+            TokenKind::Ident(name) => write_object(options, out, &[("Ident", &name.to_string())]),
+            TokenKind::Func(name) => write_object(options, out, &[("Func", &name.to_string())]),
+            _ => format!("{:?}", self).to_json(options, out),
+        }
+    }
+}
+
+impl ToJson for Token {
+    fn to_json(&self, options: RenderOptions, out: &mut String) {
+        write_object(options, out, &[("kind", &self.kind), ("loc", &self.loc)]);
+    }
+}
+
+impl ToJson for NodeKind {
+    fn to_json(&self, options: RenderOptions, out: &mut String) {
+        match self {
+            NodeKind::Num(num) => write_object(options, out, &[("Num", num)]),
+            NodeKind::LVar { offset } => write_object(options, out, &[("LVar", &(*offset as u64))]),
+            NodeKind::Case(value) => write_object(options, out, &[("Case", value)]),
+            NodeKind::Call { name } => write_object(options, out, &[("Call", &name.to_string())]),
+            NodeKind::Function { name } => {
+                write_object(options, out, &[("Function", &name.to_string())])
+            }
+            _ => format!("{:?}", self).to_json(options, out),
+        }
+    }
+}
+
+impl ToJson for Node {
+    /// Writes `init`, `cond`, `inc` and `stmts` only for the nodes which have them.
+    fn to_json(&self, options: RenderOptions, out: &mut String) {
+        let mut fields: Vec<(&str, &dyn ToJson)> = vec![("kind", &self.kind), ("loc", &self.loc)];
+        for (name, child) in [
+            ("init", &self.init),
+            ("cond", &self.cond),
+            ("inc", &self.inc),
+        ]
+        .iter()
+        {
+            if child.is_some() {
+                fields.push((name, *child));
+            }
+        }
+        fields.push(("lhs", &self.lhs));
+        fields.push(("rhs", &self.rhs));
+        if matches!(
+            self.kind,
+            NodeKind::Block
+                | NodeKind::Switch
+                | NodeKind::Case(_)
+                | NodeKind::Default
+                | NodeKind::Call { .. }
+                | NodeKind::Function { .. }
+        ) {
+            fields.push(("stmts", &self.stmts));
+        }
+        write_object(options, out, &fields);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json::*;
+    use crate::parse::parse_streaming;
+    use crate::token::tokenize;
+    use anyhow::Result;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            "a\"b\\c\nd\u{1}".to_json_string(RenderOptions::default()),
+            r#""a\"b\\c\nd\u0001""#
+        );
+    }
+
+    #[test]
+    fn test_tokens_to_json() -> Result<()> {
+        assert_eq!(
+            tokenize("1+2")?.to_json_string(RenderOptions::default()),
+            concat!(
+                r#"[{"kind":{"Num":1},"loc":{"line":1,"col":1}},"#,
+                r#"{"kind":"Plus","loc":{"line":1,"col":2}},"#,
+                r#"{"kind":{"Num":2},"loc":{"line":1,"col":3}},"#,
+                r#"{"kind":"Eof","loc":{"line":1,"col":4}}]"#
+            )
+        );
+        let zero_based = RenderOptions {
+            zero_based_locs: true,
+        };
+        assert_eq!(
+            tokenize("\n 1")?[0].to_json_string(zero_based),
+            r#"{"kind":{"Num":1},"loc":{"line":1,"col":1}}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_node_to_json() -> Result<()> {
+        assert_eq!(
+            parse_streaming("1+2;")?.to_json_string(RenderOptions::default()),
+            concat!(
+                r#"{"kind":"Add","loc":{"line":1,"col":2},"#,
+                r#""lhs":{"kind":{"Num":1},"loc":{"line":1,"col":1},"lhs":null,"rhs":null},"#,
+                r#""rhs":{"kind":{"Num":2},"loc":{"line":1,"col":3},"lhs":null,"rhs":null}}"#
+            )
+        );
+        let json = parse_streaming("for (;1;) 1;")?.to_json_string(RenderOptions::default());
+        assert!(
+            json.starts_with(r#"{"kind":"For","loc":{"line":1,"col":1},"cond":{"#),
+            "{}",
+            json
+        );
+        assert!(!json.contains("init"), "{}", json);
+        Ok(())
+    }
+}
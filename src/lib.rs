@@ -1,80 +1,2196 @@
 pub mod dot;
+pub mod encode;
+pub mod eval;
+pub mod explain;
+pub mod input;
+pub mod ir;
+pub mod json;
+pub mod limits;
+pub mod lint;
+pub mod listing;
+pub mod obj;
+pub mod opt;
 pub mod parse;
+pub mod repl;
+pub mod selftest;
+pub mod sema;
+pub mod strict;
+pub mod target;
 pub mod token;
+pub mod ty;
+pub mod unparse;
 
 use self::parse::*;
 
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt::{self, Write};
+use std::time::{Duration, Instant};
+
 use anyhow::{anyhow, Context, Result};
+use eval::{eval_const, Overflow};
+use ir::{ir_to_string, Cond, Instr, Label, LabelKind, Labels, Mem, Reg};
+use limits::{check_recursion_depth, collect_with_limits, Limit, ResourceLimits};
+use opt::{fold_constants, fold_constants_with_overflow, peephole, PassReport};
+use target::{check_supported, Target};
 use thiserror::Error;
-use token::Loc;
+use token::{
+    tokenize_into, Loc, Name, RenderOptions, Token, TokenStream, TokenizeOptions, Tokenizer,
+};
+use ty::Ty;
 
 #[derive(Error, Debug)]
 pub enum CompileError {
     #[error("unable to tokenize '{0}'")]
     Tokenize(String, Loc),
+    #[error("integer literal '{0}' is too large")]
+    NumberOverflow(String, Loc),
+    #[error("{0}")]
+    Parse(String, Loc),
+    #[error("{0}")]
+    Eval(String, Loc),
+    /// Program which parses but has no meaning, such as `break` outside of a loop.
+    #[error("{0}")]
+    Semantic(String, Loc),
+    #[error("resource limit exceeded: {0} > {1}")]
+    LimitExceeded(Limit, usize),
+    #[error("expression too deeply nested to compile")]
+    TooDeeplyNested,
+    #[error("input is not valid UTF-8 (did you save as UTF-16?)")]
+    InvalidEncoding,
     #[error("unknown error")]
     Unknown,
 }
 
+impl CompileError {
+    /// Returns the location the error points at, if any.
+    pub fn loc(&self) -> Option<Loc> {
+        match self {
+            CompileError::Tokenize(_, loc)
+            | CompileError::NumberOverflow(_, loc)
+            | CompileError::Parse(_, loc)
+            | CompileError::Eval(_, loc)
+            | CompileError::Semantic(_, loc) => Some(*loc),
+            CompileError::LimitExceeded(_, _)
+            | CompileError::TooDeeplyNested
+            | CompileError::InvalidEncoding
+            | CompileError::Unknown => None,
+        }
+    }
+}
+
+/// Compiles expressions while reusing its buffers across calls.
+#[derive(Debug, Default)]
+pub struct Compiler {
+    tokens: Vec<Token>,
+    asm: String,
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compiles `input` and writes the assembly into `out`, which is cleared first.
+    pub fn compile_into(&mut self, input: &str, out: &mut String) -> Result<()> {
+        tokenize_into(input, &mut self.tokens)?;
+        let root = parse_slice(&self.tokens)?;
+        out.clear();
+        emit(&root, out)
+    }
+
+    /// Compiles `input` into the internal output buffer.
+    pub fn compile(&mut self, input: &str) -> Result<&str> {
+        let mut asm = std::mem::take(&mut self.asm);
+        let res = self.compile_into(input, &mut asm);
+        self.asm = asm;
+        res?;
+        Ok(&self.asm)
+    }
+}
+
+/// Approximate memory held by each stage of a compilation.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct MemoryReport {
+    pub token_bytes: usize,
+    pub ast_nodes: usize,
+    pub ast_bytes: usize,
+    pub asm_bytes: usize,
+}
+
+impl MemoryReport {
+    pub fn new(tokens: &[Token], root: &Node, asm: &str) -> Self {
+        let ast_nodes = node_count(root);
+        MemoryReport {
+            token_bytes: std::mem::size_of_val(tokens),
+            ast_nodes,
+            ast_bytes: ast_nodes * std::mem::size_of::<Node>(),
+            asm_bytes: asm.len(),
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.token_bytes + self.ast_bytes + self.asm_bytes
+    }
+}
+
+/// Sizes of the data flowing through each stage of a compilation.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Summary {
+    pub input_bytes: usize,
+    pub tokens: usize,
+    pub ast_nodes: usize,
+    pub ast_depth: usize,
+    pub instrs: usize,
+    pub output_bytes: usize,
+}
+
+impl Summary {
+    pub fn new(input: &str, tokens: &[Token], root: &Node, instrs: &[Instr], asm: &str) -> Self {
+        Summary {
+            input_bytes: input.len(),
+            tokens: tokens.len(),
+            ast_nodes: node_count(root),
+            ast_depth: ast_depth(root),
+            instrs: instrs.len(),
+            output_bytes: asm.len(),
+        }
+    }
+
+    /// Writes the summary as a block of assembly comments, one pair per line.
+    pub fn to_asm_comment(&self) -> String {
+        self.to_string()
+            .split(' ')
+            .map(|pair| format!("# {}\n", pair))
+            .collect()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "input_bytes={} tokens={} ast_nodes={} ast_depth={} instrs={} output_bytes={}",
+            self.input_bytes,
+            self.tokens,
+            self.ast_nodes,
+            self.ast_depth,
+            self.instrs,
+            self.output_bytes
+        )
+    }
+}
+
+/// Options for a compilation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileOptions {
+    pub limits: ResourceLimits,
+    pub tokenize: TokenizeOptions,
+    pub render: RenderOptions,
+    /// 0 disables the optimization passes, 1 enables all of them.
+    pub opt_level: u8,
+    /// Aligns functions to 16 bytes.
+    pub align_functions: bool,
+    /// Rejects constructs whose behavior depends on the implementation.
+    pub strict: bool,
+    /// Starts functions with `endbr64` for Intel CET.
+    pub cet: bool,
+    /// Prepended to every local label. Outputs compiled with distinct prefixes can be
+    /// concatenated into a single assembly file.
+    pub label_prefix: String,
+    /// Symbol of the generated function. Without one, only the body is emitted, leaving
+    /// the result on the stack instead of returning it.
+    pub entry: Option<String>,
+    /// What constant arithmetic does on overflow. The generated code always wraps.
+    pub overflow: Overflow,
+    /// Ends the assembly of `compile_with_summary` with the summary as a comment block.
+    /// `output_bytes` counts the assembly before it.
+    pub summary_comment: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            limits: ResourceLimits::default(),
+            tokenize: TokenizeOptions::default(),
+            render: RenderOptions::default(),
+            opt_level: 0,
+            align_functions: true,
+            strict: false,
+            cet: false,
+            label_prefix: String::new(),
+            entry: Some("main".to_string()),
+            overflow: Overflow::Checked,
+            summary_comment: false,
+        }
+    }
+}
+
+/// Tokenizes `input` following `options`.
+pub fn tokenize_with_options(input: &str, options: &CompileOptions) -> Result<Vec<Token>> {
+    options.limits.check_input(input)?;
+    collect_with_limits(
+        Tokenizer::with_options(input, options.tokenize),
+        &options.limits,
+    )
+}
+
+/// Parses `input` into AST following `options`, tokenizing it lazily.
+pub fn parse_with_options(input: &str, options: &CompileOptions) -> Result<Node> {
+    options.limits.check_input(input)?;
+    let stream = TokenStream::from_tokenizer(Tokenizer::with_options(input, options.tokenize));
+    parse_stream_with_limits(stream, &options.limits)
+}
+
+/// Runs the front end on arbitrary bytes, for fuzzing. Only panics are bugs.
+pub fn fuzz_once(data: &[u8]) {
+    let input = String::from_utf8_lossy(data);
+    let _ = token::tokenize(&input);
+    let _ = parse_streaming(&input);
+}
+
+/// Compiles `input` into assembly.
+pub fn compile_to_asm(input: &str) -> Result<String> {
+    let asm = compile_with_options(input, &CompileOptions::default())?;
+    if cfg!(debug_assertions) {
+        assert_folds_to_const(input);
+    }
+    Ok(asm)
+}
+
+/// Checks that folding a constant `input` and generating code for it pushes the value
+/// `eval_const` computes.
+fn assert_folds_to_const(input: &str) {
+    let root = match parse_streaming(input) {
+        Ok(root) => root,
+        Err(_) => return,
+    };
+    let value = match eval_const(&root) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    let (folded, _) = fold_constants(root);
+    if let NodeKind::Num(num) = folded.kind {
+        assert_eq!(num, value, "{}", input);
+        let instrs = gen_instrs(&folded).unwrap();
+        assert!(
+            matches!(
+                instrs.first(),
+                Some(Instr::PushImm(imm)) | Some(Instr::MovImm(Reg::Rax, imm)) if *imm == num
+            ),
+            "{}",
+            input
+        );
+    }
+}
+
+/// Compiles `input` into assembly following `options`.
+pub fn compile_with_options(input: &str, options: &CompileOptions) -> Result<String> {
+    Ok(compile_with_report(input, options)?.0)
+}
+
+/// Same as `compile_with_options`, also returning what each optimization pass did.
+pub fn compile_with_report(
+    input: &str,
+    options: &CompileOptions,
+) -> Result<(String, Vec<PassReport>)> {
+    let mut reports = Vec::new();
+    let root = parse_with_options(input, options)?;
+    analyze(&root, options)?;
+    let root = optimize(root, options, &mut reports)?;
+    let asm = gen_with_report(&root, options, &mut reports)?;
+    Ok((asm, reports))
+}
+
+/// Runs the checks enabled by `options` on the AST, along with the ones every program
+/// has to pass to compile.
+pub fn analyze(node: &Node, options: &CompileOptions) -> Result<()> {
+    sema::check_semantics(node, options)?;
+    if options.strict {
+        strict::check_strict(node)?;
+    }
+    Ok(())
+}
+
+/// Runs the AST passes enabled by `options`, appending what they did to `reports`.
+pub fn optimize(
+    node: Node,
+    options: &CompileOptions,
+    reports: &mut Vec<PassReport>,
+) -> Result<Node> {
+    if options.opt_level == 0 {
+        return Ok(node);
+    }
+    let (node, report) = fold_constants_with_overflow(node, options.overflow);
+    reports.push(report);
+    Ok(node)
+}
+
+/// Wall-clock time spent in each phase of a compilation.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Timings {
+    pub tokenize: Duration,
+    pub parse: Duration,
+    pub optimize: Duration,
+    pub codegen: Duration,
+}
+
+/// Same as `compile_with_options`, measuring the time of each phase and the memory held by
+/// each stage, and appending what the optimization passes did to `reports`.
+///
+/// The input is tokenized up front so that tokenizing and parsing are timed separately.
+pub fn compile_with_timings(
+    input: &str,
+    options: &CompileOptions,
+    reports: &mut Vec<PassReport>,
+) -> Result<(String, Timings, MemoryReport)> {
+    let start = Instant::now();
+    let tokens = tokenize_with_options(input, options)?;
+    let tokenized = Instant::now();
+    let root = parse_slice_with_limits(&tokens, &options.limits)?;
+    analyze(&root, options)?;
+    let parsed = Instant::now();
+    let root = optimize(root, options, reports)?;
+    let optimized = Instant::now();
+    let asm = gen_with_report(&root, options, reports)?;
+    let generated = Instant::now();
+
+    let timings = Timings {
+        tokenize: tokenized - start,
+        parse: parsed - tokenized,
+        optimize: optimized - parsed,
+        codegen: generated - optimized,
+    };
+    let memory = MemoryReport::new(&tokens, &root, &asm);
+    Ok((asm, timings, memory))
+}
+
+/// Generates the assembly for `node` following `options`.
+pub fn gen_with_options(node: &Node, options: &CompileOptions) -> Result<String> {
+    gen_with_report(node, options, &mut Vec::new())
+}
+
+/// Same as `gen_with_options`, appending what the optimization passes did to `reports`.
+pub fn gen_with_report(
+    node: &Node,
+    options: &CompileOptions,
+    reports: &mut Vec<PassReport>,
+) -> Result<String> {
+    let instrs = gen_instrs_with_options(node, options, reports)?;
+    program_to_asm(node, &instrs, options)
+}
+
+/// Generates the instructions of the entry function for `node`, running the passes
+/// enabled by `options`.
+pub fn gen_instrs_with_options(
+    node: &Node,
+    options: &CompileOptions,
+    reports: &mut Vec<PassReport>,
+) -> Result<Vec<Instr>> {
+    let mut instrs = gen_located_with_options(node, options)?;
+    if options.opt_level > 0 {
+        let (optimized, report) = peephole(instrs);
+        instrs = optimized;
+        reports.push(report);
+    }
+    Ok(instrs.into_iter().map(|(instr, _)| instr).collect())
+}
+
+/// Same as `compile_with_options`, also summarizing the sizes at each stage and appending
+/// what the optimization passes did to `reports`.
+pub fn compile_with_summary(
+    input: &str,
+    options: &CompileOptions,
+    reports: &mut Vec<PassReport>,
+) -> Result<(String, Summary)> {
+    let tokens = tokenize_with_options(input, options)?;
+    let root = parse_slice_with_limits(&tokens, &options.limits)?;
+    analyze(&root, options)?;
+    let root = optimize(root, options, reports)?;
+    let instrs = gen_instrs_with_options(&root, options, reports)?;
+    let mut asm = program_to_asm(&root, &instrs, options)?;
+    let summary = Summary::new(input, &tokens, &root, &instrs, &asm);
+    if options.summary_comment {
+        asm.push_str(&summary.to_asm_comment());
+    }
+    Ok((asm, summary))
+}
+
+/// Writes `instrs` as the assembly of the entry function.
+pub fn instrs_to_asm(instrs: &[Instr], options: &CompileOptions) -> Result<String> {
+    let mut asm = String::new();
+    emit_instrs(instrs, options, &mut asm)?;
+    options.limits.check(Limit::OutputBytes, asm.len())?;
+    Ok(asm)
+}
+
+/// Writes `instrs` as the IR of the entry function, followed by the IR of each function
+/// defined in `node` after a line with its name.
+pub fn program_to_ir(node: &Node, instrs: &[Instr], options: &CompileOptions) -> Result<String> {
+    let mut ir = ir_to_string(instrs);
+    for (name, instrs) in gen_functions(node, options)? {
+        writeln!(ir, "\n{}:", name)?;
+        ir.push_str(&ir_to_string(&instrs));
+    }
+    options.limits.check(Limit::OutputBytes, ir.len())?;
+    Ok(ir)
+}
+
+/// Same as `instrs_to_asm`, followed by the assembly of the functions defined in `node`.
+pub fn program_to_asm(node: &Node, instrs: &[Instr], options: &CompileOptions) -> Result<String> {
+    let mut asm = String::new();
+    emit_instrs(instrs, options, &mut asm)?;
+    emit_functions(node, options, &mut asm)?;
+    options.limits.check(Limit::OutputBytes, asm.len())?;
+    Ok(asm)
+}
+
+/// Compiles `input` into a relocatable object defining `main`.
+pub fn compile_to_obj(input: &str) -> Result<Vec<u8>> {
+    let root = parse_streaming(input)?;
+    gen_obj_with_options(&root, &CompileOptions::default())
+}
+
+/// Generates the object file for `node` following `options`.
+pub fn gen_obj_with_options(node: &Node, options: &CompileOptions) -> Result<Vec<u8>> {
+    let entry = options
+        .entry
+        .as_deref()
+        .context("An object file needs an entry symbol")?;
+    let instrs = gen_instrs_with_options(node, options, &mut Vec::new())?;
+    let mut functions = vec![(entry.to_string(), instrs)];
+    functions.extend(gen_functions(node, options)?);
+    let obj = obj::write_functions(&functions)?;
+    options.limits.check(Limit::OutputBytes, obj.len())?;
+    Ok(obj)
+}
+
+/// Version of this crate.
+pub fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// Capabilities of this build, including the cargo features compiled in.
+pub fn features() -> &'static [&'static str] {
+    if cfg!(feature = "obj") {
+        &["x86-64", "obj"]
+    } else {
+        &["x86-64"]
+    }
+}
+
+/// Name of the function `gen_multi` generates for the `index`th node.
+pub fn multi_entry(index: usize) -> String {
+    format!("expr{}", index)
+}
+
+/// Names of the entry functions `gen_multi` generates for `count` nodes, which no node
+/// may define again.
+pub fn multi_entries(count: usize) -> HashSet<String> {
+    (0..count).map(multi_entry).collect()
+}
+
+/// Generates an exported function for each node, named by `multi_entry`, along with the
+/// functions the nodes define.
+///
+/// The local labels of each function are prefixed with its name to keep them apart.
+pub fn gen_multi(nodes: &[Node], options: &CompileOptions) -> Result<String> {
+    let mut defined = multi_entries(nodes.len());
+    let mut asm = String::new();
+    for (index, node) in nodes.iter().enumerate() {
+        check_redefinitions(node, &mut defined)?;
+        let options = CompileOptions {
+            entry: Some(multi_entry(index)),
+            label_prefix: format!("{}{}.", options.label_prefix, multi_entry(index)),
+            ..options.clone()
+        };
+        let instrs = gen_instrs_with_options(node, &options, &mut Vec::new())?;
+        emit_instrs(&instrs, &options, &mut asm)?;
+        emit_functions(node, &options, &mut asm)?;
+    }
+    options.limits.check(Limit::OutputBytes, asm.len())?;
+    Ok(asm)
+}
+
+/// Same as `gen_multi`, writing an object file.
+pub fn gen_multi_obj(nodes: &[Node], options: &CompileOptions) -> Result<Vec<u8>> {
+    let mut defined = multi_entries(nodes.len());
+    let mut functions = Vec::new();
+    for (index, node) in nodes.iter().enumerate() {
+        check_redefinitions(node, &mut defined)?;
+        let options = CompileOptions {
+            entry: Some(multi_entry(index)),
+            ..options.clone()
+        };
+        let instrs = gen_instrs_with_options(node, &options, &mut Vec::new())?;
+        functions.push((multi_entry(index), instrs));
+        functions.extend(gen_functions(node, &options)?);
+    }
+    let obj = obj::write_functions(&functions)?;
+    options.limits.check(Limit::OutputBytes, obj.len())?;
+    Ok(obj)
+}
+
 pub fn gen(node: &Node) -> Result<()> {
-    println!(".intel_syntax noprefix");
-    println!(".globl main");
-    println!("main:");
+    gen_to_writer(node, &mut std::io::stdout().lock())
+}
+
+/// Writes the assembly for `node` into `out`.
+///
+/// Nothing is written when generating fails, so `out` never holds half a program.
+pub fn gen_to_writer<W: std::io::Write>(node: &Node, out: &mut W) -> Result<()> {
+    out.write_all(gen_to_string(node)?.as_bytes())?;
+    Ok(())
+}
+
+/// Generates the assembly for `node` as a string.
+pub fn gen_to_string(node: &Node) -> Result<String> {
+    let mut out = String::new();
+    emit(node, &mut out)?;
+    Ok(out)
+}
+
+/// Writes the whole assembly for `node` into `out`.
+fn emit(node: &Node, out: &mut String) -> Result<()> {
+    let options = CompileOptions::default();
+    emit_instrs(&gen_instrs(node)?, &options, out)?;
+    emit_functions(node, &options, out)
+}
+
+/// Writes the assembly of each function defined in `node` into `out`.
+///
+/// The local labels of each function are prefixed with its name to keep them apart.
+fn emit_functions(node: &Node, options: &CompileOptions, out: &mut String) -> Result<()> {
+    for (name, instrs) in gen_functions(node, options)? {
+        let options = CompileOptions {
+            label_prefix: format!("{}{}.", options.label_prefix, name),
+            entry: Some(name),
+            ..options.clone()
+        };
+        emit_instrs(&instrs, &options, out)?;
+    }
+    Ok(())
+}
+
+/// Writes the assembly of the entry function made of `instrs` into `out`.
+fn emit_instrs(instrs: &[Instr], options: &CompileOptions, out: &mut String) -> Result<()> {
+    emit_header(options, out)?;
+    for instr in instrs.iter() {
+        emit_instr(*instr, options, out)?;
+    }
+
+    Ok(())
+}
+
+/// Writes a line of assembly for `instr`, indenting all but labels.
+pub(crate) fn emit_instr(instr: Instr, options: &CompileOptions, out: &mut String) -> Result<()> {
+    let indent = match instr {
+        Instr::Label(_) => "",
+        _ => "  ",
+    };
+    writeln!(out, "{}{}", indent, instr.asm(&options.label_prefix))?;
+    Ok(())
+}
+
+/// Writes the directives and the label preceding the instructions of the entry function.
+///
+/// Writes nothing without an entry, so that the body can be included into another file.
+pub(crate) fn emit_header(options: &CompileOptions, out: &mut String) -> Result<()> {
+    let entry = match options.entry.as_deref() {
+        Some(entry) => entry,
+        None => return Ok(()),
+    };
+    writeln!(out, ".intel_syntax noprefix")?;
+    writeln!(out, ".globl {}", entry)?;
+    if options.align_functions {
+        // Pads with nops up to a 16-byte boundary.
+        writeln!(out, ".p2align 4, 0x90")?;
+    }
+    writeln!(out, "{}:", entry)?;
+    Ok(())
+}
+
+/// Generates the instructions of `main` for `node`.
+pub fn gen_instrs(node: &Node) -> Result<Vec<Instr>> {
+    Ok(gen_located(node)?
+        .into_iter()
+        .map(|(instr, _)| instr)
+        .collect())
+}
+
+/// Same as `gen_instrs`, pairing each instruction with the location of the node it comes from.
+///
+/// Programs with variables get a stack frame holding all of them.
+pub fn gen_located(node: &Node) -> Result<Vec<(Instr, Loc)>> {
+    check_recursion_depth(node)?;
+    check_supported(node, Target::X86_64)?;
+    let loc = node.loc;
+    let frame = needs_frame(node);
+    let mut instrs = Vec::new();
+    if frame {
+        instrs.push((Instr::Push(Reg::Rbp), loc));
+        instrs.push((Instr::Mov(Reg::Rbp, Reg::Rsp), loc));
+        instrs.push((Instr::SubImm(Reg::Rsp, FRAME_SIZE), loc));
+    }
+    gen_main(node, &mut instrs, &mut Labels::new())?;
+    instrs.push((Instr::Pop(Reg::Rax), loc));
+    if frame {
+        instrs.push((Instr::Mov(Reg::Rsp, Reg::Rbp), loc));
+        instrs.push((Instr::Pop(Reg::Rbp), loc));
+    }
+    instrs.push((Instr::Ret, loc));
+    Ok(instrs)
+}
+
+/// Bytes reserved on the stack for the variables `a` to `z`.
+const FRAME_SIZE: i32 = 26 * 8;
+
+/// Returns whether `node` reads or writes any variable, or returns, which restores rsp
+/// from rbp wherever it is.
+///
+/// The functions defined in `node` have frames of their own.
+fn needs_frame(node: &Node) -> bool {
+    node.fold(
+        |node| matches!(node.kind, NodeKind::LVar { .. }),
+        |node, children| match node.kind {
+            NodeKind::Function { .. } => false,
+            NodeKind::Return => true,
+            _ => children.into_iter().any(|uses| uses),
+        },
+    )
+}
+
+/// Returns the functions defined at the top level of `node`, in order.
+pub fn functions(node: &Node) -> Vec<&Node> {
+    let mut functions = Vec::new();
+    let mut stack = vec![node];
+    while let Some(node) = stack.pop() {
+        match node.kind {
+            NodeKind::Function { .. } => functions.push(node),
+            NodeKind::Seq => stack.extend(node.rhs.iter().chain(node.lhs.iter()).map(|n| &**n)),
+            _ => (),
+        }
+    }
+    functions
+}
+
+/// Generates the function defined by `node`, with a frame holding all the variables.
+///
+/// The parameters are stored from their registers into the slots of their variables
+/// before the body runs, which falls back to returning the value of its last statement.
+fn gen_function(node: &Node, options: &CompileOptions) -> Result<Vec<(Instr, Loc)>> {
+    let loc = node.loc;
+    let regs = ARG_REGS.get(..node.stmts.len()).with_context(|| {
+        format!(
+            "Expect at most {} parameters, but got {}.",
+            ARG_REGS.len(),
+            node.stmts.len()
+        )
+    })?;
+    let mut instrs = Vec::new();
+    if options.cet {
+        instrs.push((Instr::Endbr64, loc));
+    }
+    instrs.push((Instr::Push(Reg::Rbp), loc));
+    instrs.push((Instr::Mov(Reg::Rbp, Reg::Rsp), loc));
+    instrs.push((Instr::SubImm(Reg::Rsp, FRAME_SIZE), loc));
+    for (param, reg) in node.stmts.iter().zip(regs) {
+        if let NodeKind::LVar { offset } = param.kind {
+            let disp = -i32::try_from(offset)?;
+            instrs.push((Instr::Store(Mem::new(Reg::Rbp, disp), *reg), param.loc));
+        }
+    }
+    gen_main(
+        node.lhs
+            .as_ref()
+            .context("Expect non null lhs, but is null.")?,
+        &mut instrs,
+        &mut Labels::new(),
+    )?;
+    instrs.push((Instr::Pop(Reg::Rax), loc));
+    instrs.push((Instr::Mov(Reg::Rsp, Reg::Rbp), loc));
+    instrs.push((Instr::Pop(Reg::Rbp), loc));
+    instrs.push((Instr::Ret, loc));
+    Ok(instrs)
+}
 
-    gen_main(node)?;
+/// Returns the name and the instructions of each function defined in `node`, running the
+/// passes enabled by `options`.
+pub fn gen_functions(node: &Node, options: &CompileOptions) -> Result<Vec<(String, Vec<Instr>)>> {
+    Ok(gen_located_functions(node, options)?
+        .into_iter()
+        .map(|(name, instrs)| (name, instrs.into_iter().map(|(instr, _)| instr).collect()))
+        .collect())
+}
+
+/// Name of a function and its instructions, each paired with the location of the node
+/// it comes from.
+pub type LocatedFunction = (String, Vec<(Instr, Loc)>);
 
-    println!("  pop rax");
-    println!("  ret");
+/// Same as `gen_functions`, keeping the location of each instruction.
+pub fn gen_located_functions(
+    node: &Node,
+    options: &CompileOptions,
+) -> Result<Vec<LocatedFunction>> {
+    let mut out = Vec::new();
+    for function in functions(node) {
+        let name = match function.kind {
+            NodeKind::Function { name } => name.to_string(),
+            _ => continue,
+        };
+        if options.entry.as_deref() == Some(name.as_str()) {
+            let message = format!("'{}' is already the entry function", name);
+            return Err(CompileError::Semantic(message, function.loc).into());
+        }
+        let mut instrs = gen_function(function, options)?;
+        if options.opt_level > 0 {
+            instrs = peephole(instrs).0;
+        }
+        out.push((name, instrs));
+    }
+    Ok(out)
+}
 
+/// Fails if `node` defines a function whose name is already in `defined`, adding the
+/// names of its functions otherwise.
+///
+/// Keeps the functions of separately parsed nodes apart when they go into one output.
+pub fn check_redefinitions(node: &Node, defined: &mut HashSet<String>) -> Result<()> {
+    for function in functions(node) {
+        if let NodeKind::Function { name } = function.kind {
+            if !defined.insert(name.to_string()) {
+                let message = format!("redefinition of '{}'", name);
+                return Err(CompileError::Semantic(message, function.loc).into());
+            }
+        }
+    }
     Ok(())
 }
 
-fn gen_main(node: &Node) -> Result<()> {
-    if let NodeKind::Num(num) = node.kind {
-        println!("  push {}", num);
-        return Ok(());
+/// Same as `gen_located`, following `options`.
+///
+/// Without an entry, the result is left on the stack. Variables are then addressed from
+/// the rbp of the enclosing function, which has to reserve their space.
+pub fn gen_located_with_options(
+    node: &Node,
+    options: &CompileOptions,
+) -> Result<Vec<(Instr, Loc)>> {
+    if options.entry.is_none() {
+        check_recursion_depth(node)?;
+        check_supported(node, Target::X86_64)?;
+        let mut instrs = Vec::new();
+        gen_main(node, &mut instrs, &mut Labels::new())?;
+        return Ok(instrs);
+    }
+    let mut instrs = gen_located(node)?;
+    if options.cet {
+        instrs.insert(0, (Instr::Endbr64, node.loc));
+    }
+    Ok(instrs)
+}
+
+/// Generates `node` and the chained nodes down its `lhs`, such as the statements of a long
+/// program or the operators of `1 + 2 + 3`, without recursing on the `lhs`.
+///
+/// Labels are taken from the outermost node in, as if each one generated its `lhs` itself.
+fn gen_chain(node: &Node, out: &mut Vec<(Instr, Loc)>, labels: &mut Labels) -> Result<()> {
+    let mut chain = Vec::new();
+    let mut first = node;
+    while is_chained(first.kind) {
+        let short = match first.kind {
+            NodeKind::LogAnd => Some(labels.fresh(LabelKind::False)),
+            NodeKind::LogOr => Some(labels.fresh(LabelKind::True)),
+            _ => None,
+        };
+        chain.push((first, short));
+        first = first
+            .lhs
+            .as_ref()
+            .context("Expect non null lhs, but is null.")?;
+    }
+    if chain.is_empty() {
+        return Err(anyhow!("Expected binary operator but got {:?}", node.kind));
+    }
+    gen_main(first, out, labels)?;
+    for (node, short) in chain.into_iter().rev() {
+        let loc = node.loc;
+        let rhs = node
+            .rhs
+            .as_ref()
+            .context("Expect non null rhs, but is null.")?;
+        match (node.kind, short) {
+            (_, Some(short)) => gen_logical(node, short, rhs, out, labels)?,
+            (NodeKind::Seq, _) => {
+                // Discards the value of the previous statement.
+                out.push((Instr::Pop(Reg::Rax), loc));
+                gen_main(rhs, out, labels)?;
+            }
+            _ => {
+                gen_main(rhs, out, labels)?;
+                gen_binary(node, out)?;
+            }
+        }
     }
+    Ok(())
+}
+
+/// Generates `&&` or `||` once its `lhs` is pushed, which pushes 0 or 1 and skips `rhs`
+/// once `lhs` decides it by jumping to `short`.
+fn gen_logical(
+    node: &Node,
+    short: Label,
+    rhs: &Node,
+    out: &mut Vec<(Instr, Loc)>,
+    labels: &mut Labels,
+) -> Result<()> {
+    let loc = node.loc;
+    // `&&` jumps out on the first 0, and `||` on the first operand which is not 0.
+    let (cond, taken, fallen) = match node.kind {
+        NodeKind::LogAnd => (Cond::E, 0, 1),
+        _ => (Cond::Ne, 1, 0),
+    };
+    let end = Label {
+        kind: LabelKind::End,
+        ..short
+    };
+    out.push((Instr::Pop(Reg::Rax), loc));
+    out.push((Instr::CmpImm(Reg::Rax, 0), loc));
+    out.push((Instr::Jcc(cond, short), loc));
+    gen_main(rhs, out, labels)?;
+    out.push((Instr::Pop(Reg::Rax), loc));
+    out.push((Instr::CmpImm(Reg::Rax, 0), loc));
+    out.push((Instr::Jcc(cond, short), loc));
+    out.push((Instr::PushImm(fallen), loc));
+    out.push((Instr::Jmp(end), loc));
+    out.push((Instr::Label(short), loc));
+    out.push((Instr::PushImm(taken), loc));
+    out.push((Instr::Label(end), loc));
+    Ok(())
+}
 
+/// Generates a `While` or `For` loop, which pushes 0 once it ends or breaks.
+///
+/// `continue` goes to the condition of a `While`, and to the increment of a `For`.
+fn gen_loop(node: &Node, out: &mut Vec<(Instr, Loc)>, labels: &mut Labels) -> Result<()> {
+    let loc = node.loc;
+    let begin = labels.fresh(LabelKind::Begin);
+    let end = Label {
+        kind: LabelKind::End,
+        ..begin
+    };
+    let next = match node.kind {
+        NodeKind::For => Label {
+            kind: LabelKind::Continue,
+            ..begin
+        },
+        _ => begin,
+    };
+    if let Some(init) = node.init.as_ref() {
+        gen_main(init, out, labels)?;
+        out.push((Instr::Pop(Reg::Rax), loc));
+    }
+    out.push((Instr::Label(begin), loc));
+    if let Some(cond) = node.cond.as_ref() {
+        gen_main(cond, out, labels)?;
+        out.push((Instr::Pop(Reg::Rax), loc));
+        out.push((Instr::CmpImm(Reg::Rax, 0), loc));
+        out.push((Instr::Jcc(Cond::E, end), loc));
+    }
+    labels.enter_loop(end, next);
     gen_main(
         node.lhs
             .as_ref()
-            .context("Expect non null lhs, but is null.")?
-            .as_ref(),
+            .context("Expect non null lhs, but is null.")?,
+        out,
+        labels,
     )?;
+    labels.leave_loop();
+    // Discards the values of the body and the increment.
+    out.push((Instr::Pop(Reg::Rax), loc));
+    if next != begin {
+        out.push((Instr::Label(next), loc));
+    }
+    if let Some(inc) = node.inc.as_ref() {
+        gen_main(inc, out, labels)?;
+        out.push((Instr::Pop(Reg::Rax), loc));
+    }
+    out.push((Instr::Jmp(begin), loc));
+    out.push((Instr::Label(end), loc));
+    out.push((Instr::PushImm(0), loc));
+    Ok(())
+}
+
+/// Generates a `Switch`, which pushes 0 once it ends or breaks.
+///
+/// Compares the value with each `Case` in turn and jumps to the arm it matches. The arms
+/// follow in order, so that each one falls through to the next.
+fn gen_switch(node: &Node, out: &mut Vec<(Instr, Loc)>, labels: &mut Labels) -> Result<()> {
+    let loc = node.loc;
     gen_main(
-        node.rhs
+        node.cond
             .as_ref()
-            .context("Expect non null rhs, but is null.")?
-            .as_ref(),
+            .context("Expect non null cond, but is null.")?,
+        out,
+        labels,
     )?;
+    out.push((Instr::Pop(Reg::Rax), loc));
+    let arms: Vec<(&Node, Label)> = node
+        .stmts
+        .iter()
+        .map(|arm| (arm.as_ref(), labels.fresh(LabelKind::Case)))
+        .collect();
+    let end = labels.fresh(LabelKind::End);
+    let mut default = end;
+    for (arm, label) in arms.iter() {
+        match arm.kind {
+            NodeKind::Case(value) => {
+                match i32::try_from(value) {
+                    Ok(imm) => out.push((Instr::CmpImm(Reg::Rax, imm), arm.loc)),
+                    Err(_) => {
+                        // cmp only takes 32-bit immediates.
+                        out.push((Instr::MovImm(Reg::Rdi, value), arm.loc));
+                        out.push((Instr::Cmp(Reg::Rax, Reg::Rdi), arm.loc));
+                    }
+                }
+                out.push((Instr::Jcc(Cond::E, *label), arm.loc));
+            }
+            _ => default = *label,
+        }
+    }
+    out.push((Instr::Jmp(default), loc));
+
+    labels.enter_switch(end);
+    for (arm, label) in arms.iter() {
+        out.push((Instr::Label(*label), arm.loc));
+        for stmt in arm.stmts.iter() {
+            gen_main(stmt, out, labels)?;
+            out.push((Instr::Pop(Reg::Rax), stmt.loc));
+        }
+    }
+    labels.leave_loop();
+    out.push((Instr::Label(end), loc));
+    out.push((Instr::PushImm(0), loc));
+    Ok(())
+}
+
+/// Registers taking the arguments of a call, in order.
+const ARG_REGS: [Reg; 6] = [Reg::Rdi, Reg::Rsi, Reg::Rdx, Reg::Rcx, Reg::R8, Reg::R9];
+
+/// Generates a call of `name`, which pushes the value the function returns.
+///
+/// The arguments are evaluated in order onto the stack and popped into their registers.
+/// The ABI needs rsp to be a multiple of 16 at the call, while it only is a multiple of 8
+/// with the values on the stack, so it is aligned when needed at run time.
+fn gen_call(
+    node: &Node,
+    name: Name,
+    out: &mut Vec<(Instr, Loc)>,
+    labels: &mut Labels,
+) -> Result<()> {
+    let loc = node.loc;
+    let regs = ARG_REGS.get(..node.stmts.len()).with_context(|| {
+        format!(
+            "Expect at most {} arguments, but got {}.",
+            ARG_REGS.len(),
+            node.stmts.len()
+        )
+    })?;
+    for arg in node.stmts.iter() {
+        gen_main(arg, out, labels)?;
+    }
+    for reg in regs.iter().rev() {
+        out.push((Instr::Pop(*reg), loc));
+    }
+    let misaligned = labels.fresh(LabelKind::Call);
+    let end = Label {
+        kind: LabelKind::End,
+        ..misaligned
+    };
+    out.push((Instr::Mov(Reg::Rax, Reg::Rsp), loc));
+    out.push((Instr::AndImm(Reg::Rax, 15), loc));
+    out.push((Instr::Jcc(Cond::Ne, misaligned), loc));
+    // Variadic functions take the number of vector registers used in al.
+    out.push((Instr::MovImm(Reg::Rax, 0), loc));
+    out.push((Instr::Call(name), loc));
+    out.push((Instr::Jmp(end), loc));
+    out.push((Instr::Label(misaligned), loc));
+    out.push((Instr::SubImm(Reg::Rsp, 8), loc));
+    out.push((Instr::MovImm(Reg::Rax, 0), loc));
+    out.push((Instr::Call(name), loc));
+    out.push((Instr::AddImm(Reg::Rsp, 8), loc));
+    out.push((Instr::Label(end), loc));
+    out.push((Instr::Push(Reg::Rax), loc));
+    Ok(())
+}
+
+fn gen_main(node: &Node, out: &mut Vec<(Instr, Loc)>, labels: &mut Labels) -> Result<()> {
+    let loc = node.loc;
+    match node.kind {
+        NodeKind::Num(num) => {
+            if i32::try_from(num).is_ok() {
+                out.push((Instr::PushImm(num), loc));
+            } else {
+                // push only takes 32-bit immediates.
+                out.push((Instr::MovImm(Reg::Rax, num), loc));
+                out.push((Instr::Push(Reg::Rax), loc));
+            }
+            return Ok(());
+        }
+        NodeKind::LVar { .. } => {
+            gen_lval(node, out, labels)?;
+            out.push((Instr::Pop(Reg::Rax), loc));
+            out.push((load(&node.ty, Reg::Rax, Mem::new(Reg::Rax, 0)), loc));
+            out.push((Instr::Push(Reg::Rax), loc));
+            return Ok(());
+        }
+        NodeKind::Assign => {
+            gen_lval(
+                node.lhs
+                    .as_ref()
+                    .context("Expect non null lhs, but is null.")?,
+                out,
+                labels,
+            )?;
+            gen_main(
+                node.rhs
+                    .as_ref()
+                    .context("Expect non null rhs, but is null.")?,
+                out,
+                labels,
+            )?;
+            out.push((Instr::Pop(Reg::Rdi), loc));
+            out.push((Instr::Pop(Reg::Rax), loc));
+            // A `_Bool` is 1 for any value but 0, which is also the value of the assignment.
+            if node.ty == Some(Ty::Bool) {
+                out.push((Instr::CmpImm(Reg::Rdi, 0), loc));
+                out.push((Instr::Set(Cond::Ne, Reg::Rdi), loc));
+                out.push((Instr::Movzb(Reg::Rdi, Reg::Rdi), loc));
+            }
+            out.push((store(&node.ty, Mem::new(Reg::Rax, 0), Reg::Rdi), loc));
+            out.push((Instr::Push(Reg::Rdi), loc));
+            return Ok(());
+        }
+        NodeKind::If => {
+            let end = labels.fresh(LabelKind::End);
+            let skip = match node.rhs {
+                Some(_) => Label {
+                    kind: LabelKind::Else,
+                    ..end
+                },
+                None => end,
+            };
+            gen_main(
+                node.cond
+                    .as_ref()
+                    .context("Expect non null cond, but is null.")?,
+                out,
+                labels,
+            )?;
+            // Leaves the condition on the stack as the value when the body is skipped.
+            out.push((Instr::Load(Reg::Rax, Mem::new(Reg::Rsp, 0)), loc));
+            out.push((Instr::CmpImm(Reg::Rax, 0), loc));
+            out.push((Instr::Jcc(Cond::E, skip), loc));
+            out.push((Instr::Pop(Reg::Rax), loc));
+            gen_main(
+                node.lhs
+                    .as_ref()
+                    .context("Expect non null lhs, but is null.")?,
+                out,
+                labels,
+            )?;
+            if let Some(els) = node.rhs.as_ref() {
+                out.push((Instr::Jmp(end), loc));
+                out.push((Instr::Label(skip), loc));
+                out.push((Instr::Pop(Reg::Rax), loc));
+                gen_main(els, out, labels)?;
+            }
+            out.push((Instr::Label(end), loc));
+            return Ok(());
+        }
+        NodeKind::While | NodeKind::For => return gen_loop(node, out, labels),
+        NodeKind::Block => {
+            if node.stmts.is_empty() {
+                out.push((Instr::PushImm(0), loc));
+            }
+            for (i, stmt) in node.stmts.iter().enumerate() {
+                if i > 0 {
+                    // Discards the value of the previous statement.
+                    out.push((Instr::Pop(Reg::Rax), loc));
+                }
+                gen_main(stmt, out, labels)?;
+            }
+            return Ok(());
+        }
+        NodeKind::DoWhile => {
+            let begin = labels.fresh(LabelKind::Begin);
+            let end = Label {
+                kind: LabelKind::End,
+                ..begin
+            };
+            let next = Label {
+                kind: LabelKind::Continue,
+                ..begin
+            };
+            out.push((Instr::Label(begin), loc));
+            labels.enter_loop(end, next);
+            gen_main(
+                node.lhs
+                    .as_ref()
+                    .context("Expect non null lhs, but is null.")?,
+                out,
+                labels,
+            )?;
+            labels.leave_loop();
+            out.push((Instr::Pop(Reg::Rax), loc));
+            out.push((Instr::Label(next), loc));
+            gen_main(
+                node.cond
+                    .as_ref()
+                    .context("Expect non null cond, but is null.")?,
+                out,
+                labels,
+            )?;
+            out.push((Instr::Pop(Reg::Rax), loc));
+            out.push((Instr::CmpImm(Reg::Rax, 0), loc));
+            out.push((Instr::Jcc(Cond::Ne, begin), loc));
+            out.push((Instr::Label(end), loc));
+            out.push((Instr::PushImm(0), loc));
+            return Ok(());
+        }
+        NodeKind::Break => {
+            // Statements pop their values before the next one, so the stack is as it was
+            // at the beginning of the loop or switch.
+            let end = labels
+                .break_label()
+                .context("Expect a loop or switch to break out of, but is none.")?;
+            out.push((Instr::Jmp(end), loc));
+            return Ok(());
+        }
+        NodeKind::Switch => return gen_switch(node, out, labels),
+        NodeKind::Return => {
+            // A void function leaves rax as it is.
+            if let Some(value) = node.lhs.as_ref() {
+                gen_main(value, out, labels)?;
+                out.push((Instr::Pop(Reg::Rax), loc));
+            }
+            out.push((Instr::Mov(Reg::Rsp, Reg::Rbp), loc));
+            out.push((Instr::Pop(Reg::Rbp), loc));
+            out.push((Instr::Ret, loc));
+            return Ok(());
+        }
+        NodeKind::Call { name } => return gen_call(node, name, out, labels),
+        NodeKind::Addr => {
+            return gen_lval(
+                node.lhs
+                    .as_ref()
+                    .context("Expect non null lhs, but is null.")?,
+                out,
+                labels,
+            )
+        }
+        NodeKind::Deref => {
+            gen_main(
+                node.lhs
+                    .as_ref()
+                    .context("Expect non null lhs, but is null.")?,
+                out,
+                labels,
+            )?;
+            out.push((Instr::Pop(Reg::Rax), loc));
+            out.push((load(&node.ty, Reg::Rax, Mem::new(Reg::Rax, 0)), loc));
+            out.push((Instr::Push(Reg::Rax), loc));
+            return Ok(());
+        }
+        NodeKind::Not => {
+            gen_main(
+                node.lhs
+                    .as_ref()
+                    .context("Expect non null lhs, but is null.")?,
+                out,
+                labels,
+            )?;
+            out.push((Instr::Pop(Reg::Rax), loc));
+            out.push((Instr::CmpImm(Reg::Rax, 0), loc));
+            out.push((Instr::Set(Cond::E, Reg::Rax), loc));
+            out.push((Instr::Movzb(Reg::Rax, Reg::Rax), loc));
+            out.push((Instr::Push(Reg::Rax), loc));
+            return Ok(());
+        }
+        NodeKind::BitNot => {
+            gen_main(
+                node.lhs
+                    .as_ref()
+                    .context("Expect non null lhs, but is null.")?,
+                out,
+                labels,
+            )?;
+            out.push((Instr::Pop(Reg::Rax), loc));
+            out.push((Instr::Not(Reg::Rax), loc));
+            out.push((Instr::Push(Reg::Rax), loc));
+            return Ok(());
+        }
+        NodeKind::Decl => {
+            if let Some(init) = node.rhs.as_deref() {
+                gen_main(init, out, labels)?;
+                out.push((Instr::Pop(Reg::Rax), loc));
+            }
+            out.push((Instr::PushImm(0), loc));
+            return Ok(());
+        }
+        NodeKind::Function { .. } => {
+            out.push((Instr::PushImm(0), loc));
+            return Ok(());
+        }
+        NodeKind::Continue => {
+            let next = labels
+                .continue_label()
+                .context("Expect a loop to continue, but is none.")?;
+            out.push((Instr::Jmp(next), loc));
+            return Ok(());
+        }
+        _ => (),
+    }
+    gen_chain(node, out, labels)
+}
 
-    // Binary operation.
-    println!("  pop rdi");
-    println!("  pop rax");
+/// Generates the binary operator of `node` once both operands are pushed.
+fn gen_binary(node: &Node, out: &mut Vec<(Instr, Loc)>) -> Result<()> {
+    let loc = node.loc;
+    out.push((Instr::Pop(Reg::Rdi), loc));
+    out.push((Instr::Pop(Reg::Rax), loc));
+    let lhs_size = pointee_size(&node.lhs)?;
+    let rhs_size = pointee_size(&node.rhs)?;
     match node.kind {
-        NodeKind::Add => println!("  add rax, rdi"),
-        NodeKind::Sub => println!("  sub rax, rdi"),
-        NodeKind::Mul => println!("  imul rax, rdi"),
+        NodeKind::Add => {
+            // Scales the integer added to a pointer by the size of what it points to.
+            match (lhs_size, rhs_size) {
+                (Some(size), None) => out.push((Instr::ImulImm(Reg::Rdi, size), loc)),
+                (None, Some(size)) => out.push((Instr::ImulImm(Reg::Rax, size), loc)),
+                _ => (),
+            }
+            out.push((Instr::Add(Reg::Rax, Reg::Rdi), loc));
+        }
+        NodeKind::Sub => {
+            if let (Some(size), None) = (lhs_size, rhs_size) {
+                out.push((Instr::ImulImm(Reg::Rdi, size), loc));
+            }
+            out.push((Instr::Sub(Reg::Rax, Reg::Rdi), loc));
+            // The difference of two pointers counts the values between them.
+            if let (Some(size), Some(_)) = (lhs_size, rhs_size) {
+                out.push((Instr::MovImm(Reg::Rdi, size.into()), loc));
+                out.push((Instr::Cqo, loc));
+                out.push((Instr::Idiv(Reg::Rdi), loc));
+            }
+        }
+        NodeKind::Mul => out.push((Instr::Imul(Reg::Rax, Reg::Rdi), loc)),
+        NodeKind::BitAnd => out.push((Instr::And(Reg::Rax, Reg::Rdi), loc)),
+        NodeKind::BitOr => out.push((Instr::Or(Reg::Rax, Reg::Rdi), loc)),
+        NodeKind::BitXor => out.push((Instr::Xor(Reg::Rax, Reg::Rdi), loc)),
+        NodeKind::Shl => {
+            out.push((Instr::Mov(Reg::Rcx, Reg::Rdi), loc));
+            out.push((Instr::Sal(Reg::Rax), loc));
+        }
+        NodeKind::Shr => {
+            out.push((Instr::Mov(Reg::Rcx, Reg::Rdi), loc));
+            out.push((Instr::Sar(Reg::Rax), loc));
+        }
         NodeKind::Div => {
-            println!("  cqo");
-            println!("  idiv rdi");
+            out.push((Instr::Cqo, loc));
+            out.push((Instr::Idiv(Reg::Rdi), loc));
         }
         _ => {
-            return Err(anyhow!(format!(
-                "Expected binary operator but got {:?}",
-                node.kind
-            )));
+            let (lhs, rhs, cond) = comparison(node.kind).ok_or_else(|| {
+                anyhow!(format!("Expected binary operator but got {:?}", node.kind))
+            })?;
+            out.push((Instr::Cmp(lhs, rhs), loc));
+            out.push((Instr::Set(cond, Reg::Rax), loc));
+            out.push((Instr::Movzb(Reg::Rax, Reg::Rax), loc));
         }
     }
-    println!("  push rax");
+    out.push((Instr::Push(Reg::Rax), loc));
 
     Ok(())
 }
 
-pub fn display_compile_error(source: &str, loc: Loc, message: &str) {
-    let line = *source.split("\n").skip(loc.line).peekable().peek().unwrap();
-    println!("Compile error at line {}", loc.line);
-    println!("{}", line);
-    let spaces = ' '.to_string().repeat(loc.col);
-    println!("{}^ {}", spaces, message);
+/// Returns the instruction loading a value of type `ty` at `mem` into `dst`.
+fn load(ty: &Option<Ty>, dst: Reg, mem: Mem) -> Instr {
+    match ty {
+        Some(Ty::Char) | Some(Ty::Bool) => Instr::LoadByte(dst, mem),
+        _ => Instr::Load(dst, mem),
+    }
+}
+
+/// Returns the instruction storing `src` at `mem` as a value of type `ty`.
+fn store(ty: &Option<Ty>, mem: Mem, src: Reg) -> Instr {
+    match ty {
+        Some(Ty::Char) | Some(Ty::Bool) => Instr::StoreByte(mem, src),
+        _ => Instr::Store(mem, src),
+    }
+}
+
+/// Returns the size of the values `node` points to, if it is a pointer.
+fn pointee_size(node: &Option<NodeRef>) -> Result<Option<i32>> {
+    let base = node
+        .as_ref()
+        .and_then(|node| node.ty.as_ref())
+        .and_then(Ty::base);
+    match base {
+        Some(base) => Ok(Some(i32::try_from(base.size())?)),
+        None => Ok(None),
+    }
+}
+
+/// Pushes the address of the variable or dereference `node`.
+///
+/// The parser only accepts lvalues where an address is taken, so anything else is a bug.
+fn gen_lval(node: &Node, out: &mut Vec<(Instr, Loc)>, labels: &mut Labels) -> Result<()> {
+    match node.kind {
+        // The address is the value of the operand.
+        NodeKind::Deref => gen_main(
+            node.lhs
+                .as_ref()
+                .context("Expect non null lhs, but is null.")?,
+            out,
+            labels,
+        ),
+        NodeKind::LVar { offset } => {
+            let disp = -i32::try_from(offset)?;
+            out.push((Instr::Lea(Reg::Rax, Mem::new(Reg::Rbp, disp)), node.loc));
+            out.push((Instr::Push(Reg::Rax), node.loc));
+            Ok(())
+        }
+        kind => Err(anyhow!("Expect an lvalue, but got {:?}.", kind)),
+    }
+}
+
+/// Returns the operands of `cmp` and the condition of `setcc` for a comparison of rax
+/// with rdi.
+///
+/// `>` and `>=` swap the operands to reuse `<` and `<=`.
+fn comparison(kind: NodeKind) -> Option<(Reg, Reg, Cond)> {
+    let (rax, rdi) = (Reg::Rax, Reg::Rdi);
+    match kind {
+        NodeKind::Eq => Some((rax, rdi, Cond::E)),
+        NodeKind::Neq => Some((rax, rdi, Cond::Ne)),
+        NodeKind::Lt => Some((rax, rdi, Cond::L)),
+        NodeKind::Leq => Some((rax, rdi, Cond::Le)),
+        NodeKind::Gt => Some((rdi, rax, Cond::L)),
+        NodeKind::Geq => Some((rdi, rax, Cond::Le)),
+        _ => None,
+    }
+}
+
+/// An error to report to the user.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub loc: Option<Loc>,
+}
+
+impl From<anyhow::Error> for Diagnostic {
+    fn from(err: anyhow::Error) -> Self {
+        Diagnostic {
+            message: err.to_string(),
+            loc: err
+                .downcast_ref::<CompileError>()
+                .and_then(CompileError::loc),
+        }
+    }
+}
+
+/// Returns the message and location of the error which `result` fails with.
+#[cfg(test)]
+pub(crate) fn error_at<T: fmt::Debug>(result: Result<T>) -> (String, Loc) {
+    let Diagnostic { message, loc } = Diagnostic::from(result.unwrap_err());
+    match loc {
+        Some(loc) => (message, loc),
+        None => panic!("no location for error '{}'", message),
+    }
+}
+
+/// Checks that `source` is a valid program without generating code.
+pub fn check(source: &str) -> std::result::Result<(), Vec<Diagnostic>> {
+    check_with_options(source, &CompileOptions::default())
+}
+
+/// Same as `check`, following `options`.
+pub fn check_with_options(
+    source: &str,
+    options: &CompileOptions,
+) -> std::result::Result<(), Vec<Diagnostic>> {
+    parse_with_options(source, options)
+        .and_then(|root| analyze(&root, options))
+        .map_err(|err| vec![Diagnostic::from(err)])
+}
+
+/// Prints `diagnostic`, pointing at its location in `source` if any.
+pub fn display_diagnostic(source: &str, diagnostic: &Diagnostic, options: RenderOptions) {
+    match diagnostic.loc {
+        Some(loc) => display_compile_error(source, loc, &diagnostic.message, options),
+        None => println!("{}", diagnostic.message),
+    }
+}
+
+/// Prints `message` below the line of `source` at `loc`, with a caret at its column.
+pub fn display_compile_error(source: &str, loc: Loc, message: &str, options: RenderOptions) {
+    print!("{}", format_compile_error(source, loc, message, options));
+}
+
+/// Same as `display_compile_error`, returning the text instead of printing it.
+///
+/// Without a caret when `loc` is not in `source`, e.g. when the source is not at hand.
+pub fn format_compile_error(
+    source: &str,
+    loc: Loc,
+    message: &str,
+    options: RenderOptions,
+) -> String {
+    let header = format!("Compile error at line {}\n", loc.display(options).line);
+    let line = loc
+        .line
+        .checked_sub(1)
+        .and_then(|index| token::lines(source).get(index).copied())
+        // The column after the last character is where the input ends.
+        .filter(|line| 1 <= loc.col && loc.col <= line.chars().count() + 1);
+    match line {
+        Some(line) => format!(
+            "{}{}\n{}^ {}\n",
+            header,
+            line,
+            " ".repeat(loc.col - 1),
+            message
+        ),
+        None => format!("{}{}\n", header, message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_compiler_reuses_buffers() -> Result<()> {
+        let mut compiler = Compiler::new();
+        let mut out = String::new();
+
+        compiler.compile_into("2 * (1+23) - 456 / 7;", &mut out)?;
+        let token_capacity = compiler.tokens.capacity();
+        let out_capacity = out.capacity();
+
+        for i in 0..10000 {
+            compiler.compile_into(format!("{}+{};", i, i % 7).as_str(), &mut out)?;
+            assert_eq!(compiler.tokens.capacity(), token_capacity);
+            assert_eq!(out.capacity(), out_capacity);
+        }
+        assert!(out.contains("  add rax, rdi"));
+
+        compiler.compile_into("1+2;", &mut out)?;
+        assert_eq!(compiler.compile("1+2;")?, out);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_compile_error() {
+        let at = |line, col| Loc { line, col };
+        let options = RenderOptions::default();
+        assert_eq!(
+            format_compile_error("1;\n2 +;", at(2, 4), "expected a number", options),
+            "Compile error at line 2\n2 +;\n   ^ expected a number\n"
+        );
+        assert_eq!(
+            format_compile_error(
+                "1;\n2 +;",
+                at(2, 4),
+                "expected a number",
+                RenderOptions {
+                    zero_based_locs: true
+                }
+            ),
+            "Compile error at line 1\n2 +;\n   ^ expected a number\n"
+        );
+        assert_eq!(
+            format_compile_error("1 +", at(1, 4), "expected a number", options),
+            "Compile error at line 1\n1 +\n   ^ expected a number\n"
+        );
+        // Locations outside of the source, such as in an empty one, get no caret.
+        for loc in [at(0, 1), at(3, 1), at(1, 0), at(1, 5)].iter() {
+            assert_eq!(
+                format_compile_error("1 +", *loc, "oops", options),
+                format!("Compile error at line {}\noops\n", loc.line)
+            );
+        }
+        assert_eq!(
+            format_compile_error("", at(2, 3), "oops", options),
+            "Compile error at line 2\noops\n"
+        );
+    }
+
+    #[test]
+    fn test_align_functions() -> Result<()> {
+        let mut options = CompileOptions::default();
+        let asm = compile_with_options("1;", &options)?;
+        assert!(asm.contains(".p2align 4, 0x90\nmain:\n"), "{}", asm);
+
+        options.align_functions = false;
+        let asm = compile_with_options("1;", &options)?;
+        assert!(!asm.contains(".p2align"), "{}", asm);
+        assert!(asm.contains(".globl main\nmain:\n"), "{}", asm);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cet() -> Result<()> {
+        let mut options = CompileOptions::default();
+        let asm = compile_with_options("1+2;", &options)?;
+        assert!(!asm.contains("endbr64"));
+
+        options.cet = true;
+        let asm = compile_with_options("1+2;", &options)?;
+        assert!(asm.contains("main:\n  endbr64\n  push 1\n"), "{}", asm);
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry() -> Result<()> {
+        let mut options = CompileOptions {
+            entry: Some("helper".to_string()),
+            ..CompileOptions::default()
+        };
+        let asm = compile_with_options("1+2;", &options)?;
+        assert!(asm.contains(".globl helper\n"), "{}", asm);
+        assert!(asm.contains("\nhelper:\n"), "{}", asm);
+        assert!(!asm.contains("main"), "{}", asm);
+
+        options.entry = None;
+        options.cet = true;
+        let asm = compile_with_options("1+2;", &options)?;
+        assert_eq!(
+            asm,
+            "  push 1\n  push 2\n  pop rdi\n  pop rax\n  add rax, rdi\n  push rax\n"
+        );
+        assert!(gen_obj_with_options(&parse_streaming("1;")?, &options).is_err());
+
+        // Without a frame to leave, there is nothing to return from.
+        assert_eq!(
+            error_at(compile_with_options("1;\nif (1) return 2;", &options)),
+            (
+                "'return' needs an entry function".to_string(),
+                Loc { line: 2, col: 8 }
+            )
+        );
+        let asm = compile_with_options("f(a) { return a; } f(1);", &options)?;
+        assert!(!asm.starts_with(".intel_syntax"), "{}", asm);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gen_multi() -> Result<()> {
+        let nodes = vec![parse_streaming("1+2;")?, parse_streaming("3*4;")?];
+        let asm = gen_multi(&nodes, &CompileOptions::default())?;
+        let expr0 = asm.find(".globl expr0\n").unwrap();
+        let expr1 = asm.find(".globl expr1\n").unwrap();
+        assert!(expr0 < expr1, "{}", asm);
+        assert!(asm[expr0..expr1].contains("add rax, rdi"), "{}", asm);
+        assert!(!asm[expr0..expr1].contains("imul"), "{}", asm);
+        assert!(asm[expr1..].contains("imul rax, rdi"), "{}", asm);
+        assert!(!asm[expr1..].contains("add"), "{}", asm);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gen_to_writer() -> Result<()> {
+        let mut out = Vec::new();
+        gen_to_writer(&parse_streaming("42;")?, &mut out)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            ".intel_syntax noprefix\n.globl main\n.p2align 4, 0x90\nmain:\n  push 42\n  pop rax\n  ret\n"
+        );
+
+        let mut out = Vec::new();
+        let root = parse_streaming("1;")?;
+        gen_to_writer(&root, &mut out)?;
+        assert_eq!(out, gen_to_string(&root)?.into_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_version() {
+        assert_eq!(version(), env!("CARGO_PKG_VERSION"));
+        assert!(features().contains(&"x86-64"));
+        assert_eq!(features().contains(&"obj"), cfg!(feature = "obj"));
+    }
+
+    #[test]
+    fn test_check() {
+        assert_eq!(check("2*(1+23)-456/7;"), Ok(()));
+        assert_eq!(
+            check("1 + $;"),
+            Err(vec![Diagnostic {
+                message: "unable to tokenize '$'".to_string(),
+                loc: Some(Loc { line: 1, col: 5 }),
+            }])
+        );
+        let diagnostics = check("1 +;").unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "expected an expression");
+        assert_eq!(diagnostics[0].loc, Some(Loc { line: 1, col: 4 }));
+
+        // Fails whenever compiling fails, with the same error.
+        for input in [
+            "if (1) break;",
+            "continue;",
+            "g(1, 2, 3, 4, 5, 6, 7);",
+            "f(a, b, c, d, e, g, h) { return a; }",
+        ]
+        .iter()
+        {
+            let err = compile_to_asm(input).unwrap_err();
+            assert_eq!(check(input), Err(vec![Diagnostic::from(err)]), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_compile_to_asm_no_panic() {
+        // Inputs found by fuzzing.
+        for input in ["", "(", ")", "1+", "((1)", "1é", "9999999999999999999999"].iter() {
+            assert!(compile_to_asm(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_comparison() -> Result<()> {
+        let cases = [
+            ("2 < 3;", "cmp rax, rdi\n  setl al\n"),
+            ("5 >= 5;", "cmp rdi, rax\n  setle al\n"),
+            ("1 != 2;", "cmp rax, rdi\n  setne al\n"),
+        ];
+        for (input, expected) in cases.iter() {
+            let asm = compile_to_asm(input)?;
+            assert!(asm.contains(expected), "{}:\n{}", input, asm);
+            assert!(asm.contains("movzb rax, al\n"), "{}:\n{}", input, asm);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_assign() -> Result<()> {
+        let root = parse_streaming("int a; a = 3;")?;
+        let assign = root.rhs.as_deref().context("No rhs")?;
+        let mut instrs = Vec::new();
+        gen_main(assign, &mut instrs, &mut Labels::new())?;
+        let instrs: Vec<Instr> = instrs.into_iter().map(|(instr, _)| instr).collect();
+        assert_eq!(
+            instrs,
+            vec![
+                Instr::Lea(Reg::Rax, Mem::new(Reg::Rbp, -8)),
+                Instr::Push(Reg::Rax),
+                Instr::PushImm(3),
+                Instr::Pop(Reg::Rdi),
+                Instr::Pop(Reg::Rax),
+                Instr::Store(Mem::new(Reg::Rax, 0), Reg::Rdi),
+                Instr::Push(Reg::Rdi),
+            ]
+        );
+
+        // Assignments to the same variable share its slot.
+        let instrs = gen_instrs(&parse_streaming("int a = 1; int b = 2; a = 3; a;")?)?;
+        let slots: Vec<i32> = instrs
+            .iter()
+            .filter_map(|instr| match instr {
+                Instr::Lea(Reg::Rax, mem) => Some(mem.disp),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(slots, vec![-8, -16, -8, -8]);
+        assert_eq!(
+            instrs[..3],
+            [
+                Instr::Push(Reg::Rbp),
+                Instr::Mov(Reg::Rbp, Reg::Rsp),
+                Instr::SubImm(Reg::Rsp, 208),
+            ]
+        );
+
+        // Initializers store to the variable, and the declaration takes the value 0.
+        let asm = compile_to_asm("int a = 3;")?;
+        assert!(
+            asm.contains("  mov [rax], rdi\n  push rdi\n  pop rax\n  push 0\n"),
+            "{}",
+            asm
+        );
+
+        // Chars are loaded and stored one byte at a time.
+        let asm = compile_to_asm("char c = 65; c;")?;
+        assert!(asm.contains("  pop rax\n  mov [rax], dil\n"), "{}", asm);
+        assert!(asm.contains("  movsx rax, byte ptr [rax]\n"), "{}", asm);
+        assert!(!compile_to_asm("int a = 65; a;")?.contains("movsx"));
+
+        // Values are converted to 0 or 1 before they are stored in a `_Bool`.
+        let asm = compile_to_asm("_Bool b = 5; b;")?;
+        assert!(
+            asm.contains("  cmp rdi, 0\n  setne dil\n  movzb rdi, dil\n  mov [rax], dil\n"),
+            "{}",
+            asm
+        );
+        assert!(!compile_to_asm("char c = 5; c;")?.contains("setne"));
+
+        // Programs without variables need no frame.
+        assert_eq!(gen_instrs(&parse_streaming("1;")?)?.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_logical() -> Result<()> {
+        let asm = compile_to_asm("int a = 1; int b = 0; a && b;")?;
+        assert!(
+            asm.contains("  cmp rax, 0\n  je .L.false.0\n  push 1\n  jmp .L.end.0\n.L.false.0:\n  push 0\n.L.end.0:\n"),
+            "{}",
+            asm
+        );
+        assert_eq!(asm.matches("je .L.false.0").count(), 2, "{}", asm);
+
+        // Labels are numbered along with the other statements.
+        let asm = compile_to_asm("int a; if (a) 1; a || 0;")?;
+        assert!(asm.contains("  jne .L.true.1\n"), "{}", asm);
+        assert!(asm.contains(".L.true.1:\n  push 1\n.L.end.1:\n"), "{}", asm);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gen_chain() -> Result<()> {
+        // Long chains down the left side are generated in a loop.
+        let loc = Loc { line: 1, col: 1 };
+        let mut root = Node::new_num(1, loc);
+        for kind in [NodeKind::Add, NodeKind::LogOr, NodeKind::Seq]
+            .iter()
+            .cycle()
+            .take(60_000)
+        {
+            root = Node::new(
+                *kind,
+                loc,
+                root.make_ref(),
+                Node::new_num(2, loc).make_ref(),
+            );
+        }
+        let mut instrs = Vec::new();
+        gen_main(&root, &mut instrs, &mut Labels::new())?;
+        assert_eq!(
+            instrs.first().map(|(instr, _)| instr),
+            Some(&Instr::PushImm(1))
+        );
+
+        // The operators of `||` take their labels from the outermost one in.
+        let asm = compile_to_asm("1 || 2 || 3;")?;
+        assert!(
+            asm.contains("  push 1\n  pop rax\n  cmp rax, 0\n  jne .L.true.1\n"),
+            "{}",
+            asm
+        );
+        assert!(
+            asm.contains("  push 3\n  pop rax\n  cmp rax, 0\n  jne .L.true.0\n"),
+            "{}",
+            asm
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_if() -> Result<()> {
+        let asm = compile_to_asm("int a = 1; if (a < 2) a = 3; if (a) a = a + 1; a;")?;
+        for expected in [
+            "  mov rax, [rsp]\n  cmp rax, 0\n  je .L.end.0\n  pop rax\n",
+            "\n.L.end.0:\n",
+            "  je .L.end.1\n",
+            "\n.L.end.1:\n",
+        ]
+        .iter()
+        {
+            assert!(asm.contains(expected), "{}", asm);
+        }
+        assert!(!asm.contains(".L.end.2"), "{}", asm);
+
+        // The else label takes the number of the end label of the same statement.
+        let asm = compile_to_asm("if (1) 2; else 3;")?;
+        for expected in [
+            "  je .L.else.0\n",
+            "  jmp .L.end.0\n.L.else.0:\n  pop rax\n",
+            "\n.L.end.0:\n",
+        ]
+        .iter()
+        {
+            assert!(asm.contains(expected), "{}", asm);
+        }
+        // Sequential if/else statements each place their own pair of labels once.
+        let asm = compile_to_asm("if (1) 2; else 3; if (4) 5; else 6;")?;
+        for label in [".L.else.0:", ".L.end.0:", ".L.else.1:", ".L.end.1:"].iter() {
+            assert_eq!(asm.matches(label).count(), 1, "{}\n{}", label, asm);
+        }
+
+        let options = CompileOptions {
+            label_prefix: "unit1_".to_string(),
+            ..CompileOptions::default()
+        };
+        let asm = compile_with_options("if (1) 2;", &options)?;
+        assert!(asm.contains("  je .L.unit1_end.0\n"), "{}", asm);
+        assert!(asm.contains("\n.L.unit1_end.0:\n"), "{}", asm);
+        Ok(())
+    }
+
+    #[test]
+    fn test_loops() -> Result<()> {
+        // Loops share the numbering with if statements.
+        let asm = compile_to_asm("int a; int b; while (a) a = 0; if (1) 2; while (b) b = 0;")?;
+        for expected in [
+            "\n.L.begin.0:\n",
+            "  jmp .L.begin.0\n.L.end.0:\n",
+            "\n.L.end.1:\n",
+            "\n.L.begin.2:\n",
+            "  je .L.end.2\n",
+            "  jmp .L.begin.2\n.L.end.2:\n",
+        ]
+        .iter()
+        {
+            assert!(asm.contains(expected), "{}", asm);
+        }
+
+        let asm = compile_to_asm("int i; for (i = 0; i < 3; i = i + 1) 1;")?;
+        for expected in [
+            "  pop rax\n.L.begin.0:\n",
+            "  pop rax\n  cmp rax, 0\n  je .L.end.0\n",
+            "  jmp .L.begin.0\n.L.end.0:\n  push 0\n",
+        ]
+        .iter()
+        {
+            assert!(asm.contains(expected), "{}", asm);
+        }
+        // Without a condition, the loop only jumps back to its beginning.
+        let asm = compile_to_asm("for (;;) 1;")?;
+        assert!(!asm.contains("  je "), "{}", asm);
+        let begin = asm.find(".L.begin.0:\n").context("No begin")?;
+        let jump = asm.find("  jmp .L.begin.0\n").context("No jump")?;
+        assert!(begin < jump, "{}", asm);
+
+        let asm = compile_to_asm("int a; do a = 1; while (a);")?;
+        assert!(asm.contains("\n.L.begin.0:\n"), "{}", asm);
+        assert!(
+            asm.contains("  cmp rax, 0\n  jne .L.begin.0\n.L.end.0:\n  push 0\n"),
+            "{}",
+            asm
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_block() -> Result<()> {
+        let asm = compile_to_asm("{ 1; 2; }")?;
+        assert!(asm.contains("  push 1\n  pop rax\n  push 2\n"), "{}", asm);
+        assert!(compile_to_asm("{}")?.contains("  push 0\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_break_continue() -> Result<()> {
+        // Jumps to the end of the innermost loop, which pushes the value of the loop.
+        let asm = compile_to_asm("while (1) { for (;;) break; break; }")?;
+        assert!(
+            asm.contains("  jmp .L.end.1\n  pop rax\n.L.continue.1:\n  jmp .L.begin.1\n.L.end.1:\n  push 0\n"),
+            "{}",
+            asm
+        );
+        assert!(asm.contains("  pop rax\n  jmp .L.end.0\n"), "{}", asm);
+        // Goes to the increment of a `for`, and to the condition of a `while`.
+        let asm = compile_to_asm("int i; for (;; i = i + 1) continue;")?;
+        assert!(
+            asm.contains("  jmp .L.continue.0\n  pop rax\n.L.continue.0:\n"),
+            "{}",
+            asm
+        );
+        let asm = compile_to_asm("while (1) continue;")?;
+        assert!(
+            asm.contains("  jmp .L.begin.0\n  pop rax\n  jmp .L.begin.0\n"),
+            "{}",
+            asm
+        );
+        assert!(!asm.contains(".L.continue"), "{}", asm);
+        let asm = compile_to_asm("do continue; while (0);")?;
+        assert!(
+            asm.contains("  jmp .L.continue.0\n  pop rax\n.L.continue.0:\n  push 0\n"),
+            "{}",
+            asm
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_switch() -> Result<()> {
+        // Compares with each case before the arms, which fall through to the next one.
+        let asm = compile_to_asm("int a; switch (a) { case 1: 2; case 4294967296: default: 3; }")?;
+        for expected in [
+            "  pop rax\n  cmp rax, 1\n  je .L.case.0\n",
+            "  movabs rdi, 4294967296\n  cmp rax, rdi\n  je .L.case.1\n  jmp .L.case.2\n",
+            ".L.case.0:\n  push 2\n  pop rax\n.L.case.1:\n.L.case.2:\n  push 3\n  pop rax\n",
+            ".L.end.3:\n  push 0\n",
+        ]
+        .iter()
+        {
+            assert!(asm.contains(expected), "{}", asm);
+        }
+        let asm = compile_to_asm("int a; switch (a) { case 1: break; }")?;
+        assert!(asm.contains("  je .L.case.0\n  jmp .L.end.1\n"), "{}", asm);
+        assert!(asm.contains(".L.case.0:\n  jmp .L.end.1\n"), "{}", asm);
+        let err = compile_to_asm("switch (1) { case 1: continue; }").unwrap_err();
+        assert_eq!(err.to_string(), "'continue' outside of a loop");
+        Ok(())
+    }
+
+    #[test]
+    fn test_jump_outside_loop() -> Result<()> {
+        let err = compile_to_asm("continue;").unwrap_err();
+        assert_eq!(err.to_string(), "'continue' outside of a loop");
+
+        assert_eq!(
+            error_at(compile_to_asm("if (1) break;")),
+            (
+                "'break' outside of a loop".to_string(),
+                Loc { line: 1, col: 8 }
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_return() -> Result<()> {
+        // Returns restore rsp from rbp, so they always take a frame.
+        let asm = compile_to_asm("return 5;")?;
+        assert!(
+            asm.contains("  push rbp\n  mov rbp, rsp\n  sub rsp, 208\n  push 5\n"),
+            "{}",
+            asm
+        );
+        assert!(
+            asm.contains("  push 5\n  pop rax\n  mov rsp, rbp\n  pop rbp\n  ret\n"),
+            "{}",
+            asm
+        );
+        let options = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let asm = compile_with_options("return 5;", &options)?;
+        assert!(
+            asm.contains("  mov rax, 5\n  mov rsp, rbp\n  pop rbp\n  ret\n"),
+            "{}",
+            asm
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_call() -> Result<()> {
+        // Calls are aligned at run time, since how many values are on the stack differs.
+        let asm = compile_to_asm("1 + foo();")?;
+        for expected in [
+            "  mov rax, rsp\n  and rax, 15\n  jne .L.call.0\n  mov rax, 0\n  call foo\n",
+            "  jmp .L.end.0\n.L.call.0:\n  sub rsp, 8\n  mov rax, 0\n  call foo\n",
+            "  add rsp, 8\n.L.end.0:\n  push rax\n",
+        ]
+        .iter()
+        {
+            assert!(asm.contains(expected), "{}", asm);
+        }
+        // Arguments are evaluated in order and popped into their registers in reverse.
+        let asm = compile_to_asm("f(1, 2, 3, 4, 5, 6);")?;
+        assert!(
+            asm.contains(concat!(
+                "  push 6\n  pop r9\n  pop r8\n  pop rcx\n  pop rdx\n  pop rsi\n  pop rdi\n",
+                "  mov rax, rsp\n"
+            )),
+            "{}",
+            asm
+        );
+        assert_eq!(
+            error_at(compile_to_asm("f(1, 2, 3, 4, 5, 6, 7);")),
+            (
+                "calls with more than 6 arguments are not supported".to_string(),
+                Loc { line: 1, col: 21 }
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_labels() -> Result<()> {
+        // Each function of the same file has its own labels.
+        let nodes = vec![parse_streaming("if (1) 2;")?, parse_streaming("if (3) 4;")?];
+        let asm = gen_multi(&nodes, &CompileOptions::default())?;
+        assert!(asm.contains("\n.L.expr0.end.0:\n"), "{}", asm);
+        assert!(asm.contains("\n.L.expr1.end.0:\n"), "{}", asm);
+        Ok(())
+    }
+
+    #[test]
+    fn test_function() -> Result<()> {
+        // Functions follow the entry, storing their parameters before the body.
+        let asm = compile_to_asm("f(a, b) { if (a) b; } f(1, 2);")?;
+        assert!(
+            asm.contains(concat!(
+                ".globl f\n.p2align 4, 0x90\nf:\n  push rbp\n  mov rbp, rsp\n  sub rsp, 208\n",
+                "  mov [rbp-8], rdi\n  mov [rbp-16], rsi\n"
+            )),
+            "{}",
+            asm
+        );
+        assert!(asm.contains("\n.L.f.end.0:\n"), "{}", asm);
+        // The entry only uses variables of its own.
+        assert!(
+            asm.starts_with(
+                ".intel_syntax noprefix\n.globl main\n.p2align 4, 0x90\nmain:\n  push 0\n"
+            ),
+            "{}",
+            asm
+        );
+        assert_eq!(
+            error_at(compile_to_asm("f(a, b, c, d, e, f, g) { a; }")),
+            (
+                "functions with more than 6 parameters are not supported".to_string(),
+                Loc { line: 1, col: 21 }
+            )
+        );
+
+        let err = compile_to_asm("main() { 1; }").unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<CompileError>(),
+                Some(CompileError::Semantic(message, _))
+                    if message == "'main' is already the entry function"
+            ),
+            "{:?}",
+            err
+        );
+
+        // Functions defined by another input are redefinitions too.
+        let mut defined = multi_entries(1);
+        check_redefinitions(&parse_streaming("f() { 1; }")?, &mut defined)?;
+        let err =
+            check_redefinitions(&parse_streaming("1;\nf() { 2; }")?, &mut defined).unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<CompileError>(),
+                Some(CompileError::Semantic(message, Loc { line: 2, col: 1 }))
+                    if message == "redefinition of 'f'"
+            ),
+            "{:?}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pointer_arith() -> Result<()> {
+        // Integers added to pointers are scaled, and pointer differences divided.
+        let asm = compile_to_asm("int *p; p + 2;")?;
+        assert!(
+            asm.contains("  pop rdi\n  pop rax\n  imul rdi, 8\n  add rax, rdi\n"),
+            "{}",
+            asm
+        );
+        let asm = compile_to_asm("int *p; 2 + p;")?;
+        assert!(asm.contains("  imul rax, 8\n  add rax, rdi\n"), "{}", asm);
+        let asm = compile_to_asm("int *p; int *q; p - q;")?;
+        assert!(
+            asm.contains("  sub rax, rdi\n  mov rdi, 8\n  cqo\n  idiv rdi\n"),
+            "{}",
+            asm
+        );
+        assert!(!compile_to_asm("int a; a + 2;")?.contains("imul"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fuzz_once_no_panic() {
+        // Inputs which used to panic.
+        let inputs: [&[u8]; 8] = [
+            b"",
+            b"(",
+            b")",
+            b"1+$",
+            b"1+",
+            "1+\u{3042}".as_bytes(),
+            b"\xff\xfe",
+            b"((1)",
+        ];
+        for input in inputs.iter() {
+            fuzz_once(input);
+        }
+    }
+
+    fn memory_report(input: &str) -> Result<MemoryReport> {
+        let tokens = token::tokenize(input)?;
+        let root = parse_into_ast(&mut tokens.iter().copied().peekable())?;
+        let asm = gen_to_string(&root)?;
+        Ok(MemoryReport::new(&tokens, &root, &asm))
+    }
+
+    #[test]
+    fn test_summary() -> Result<()> {
+        let (asm, summary) =
+            compile_with_summary("1+2*3;", &CompileOptions::default(), &mut Vec::new())?;
+        assert_eq!(asm.len(), 194);
+        assert_eq!(
+            summary,
+            Summary {
+                input_bytes: 6,
+                tokens: 7,
+                ast_nodes: 5,
+                ast_depth: 3,
+                instrs: 13,
+                output_bytes: asm.len(),
+            }
+        );
+        let options = CompileOptions {
+            summary_comment: true,
+            ..CompileOptions::default()
+        };
+        let (commented, _) = compile_with_summary("1+2*3;", &options, &mut Vec::new())?;
+        assert_eq!(
+            commented.strip_prefix(asm.as_str()),
+            Some(concat!(
+                "# input_bytes=6\n# tokens=7\n# ast_nodes=5\n",
+                "# ast_depth=3\n# instrs=13\n# output_bytes=194\n"
+            ))
+        );
+        assert_eq!(
+            summary.to_string(),
+            format!(
+                "input_bytes=6 tokens=7 ast_nodes=5 ast_depth=3 instrs=13 output_bytes={}",
+                asm.len()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_report() -> Result<()> {
+        let small = memory_report("1+2*3;")?;
+        assert!(small.token_bytes > 0);
+        assert!(small.ast_bytes > 0);
+        assert!(small.asm_bytes > 0);
+        assert_eq!(small.ast_nodes, 5);
+
+        let large = memory_report((vec!["1+2*3"; 100].join("+") + ";").as_str())?;
+        for (s, l) in [
+            (small.token_bytes, large.token_bytes),
+            (small.ast_bytes, large.ast_bytes),
+            (small.asm_bytes, large.asm_bytes),
+        ]
+        .iter()
+        {
+            assert!(s * 50 < *l && *l < s * 200, "{} vs {}", s, l);
+        }
+
+        Ok(())
+    }
 }
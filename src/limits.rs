@@ -0,0 +1,347 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::parse::{ast_depth, node_count, Node};
+use crate::token::{Token, TokenCursor, TokenKind, Tokenizer};
+use crate::CompileError;
+
+/// Kind of resource limited by `ResourceLimits`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Limit {
+    InputBytes,
+    Tokens,
+    AstNodes,
+    AstDepth,
+    OutputBytes,
+}
+
+impl Limit {
+    pub fn name(self) -> &'static str {
+        match self {
+            Limit::InputBytes => "input-bytes",
+            Limit::Tokens => "tokens",
+            Limit::AstNodes => "ast-nodes",
+            Limit::AstDepth => "ast-depth",
+            Limit::OutputBytes => "output-bytes",
+        }
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Limit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [
+            Limit::InputBytes,
+            Limit::Tokens,
+            Limit::AstNodes,
+            Limit::AstDepth,
+            Limit::OutputBytes,
+        ]
+        .iter()
+        .copied()
+        .find(|limit| limit.name() == s)
+        .ok_or_else(|| anyhow!("Unknown limit '{}'", s))
+    }
+}
+
+/// Upper bounds on the resources a single compilation may use.
+///
+/// The default `ast-depth` needs a stack of `STACK_SIZE`, more than the main thread has.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ResourceLimits {
+    pub max_input_bytes: usize,
+    pub max_tokens: usize,
+    pub max_ast_nodes: usize,
+    pub max_ast_depth: usize,
+    pub max_output_bytes: usize,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        ResourceLimits {
+            max_input_bytes: 16 << 20,
+            max_tokens: 1 << 20,
+            max_ast_nodes: 1 << 21,
+            max_ast_depth: 5000,
+            max_output_bytes: 256 << 20,
+        }
+    }
+}
+
+impl ResourceLimits {
+    pub fn get(&self, limit: Limit) -> usize {
+        match limit {
+            Limit::InputBytes => self.max_input_bytes,
+            Limit::Tokens => self.max_tokens,
+            Limit::AstNodes => self.max_ast_nodes,
+            Limit::AstDepth => self.max_ast_depth,
+            Limit::OutputBytes => self.max_output_bytes,
+        }
+    }
+
+    pub fn set(&mut self, limit: Limit, value: usize) {
+        let max = match limit {
+            Limit::InputBytes => &mut self.max_input_bytes,
+            Limit::Tokens => &mut self.max_tokens,
+            Limit::AstNodes => &mut self.max_ast_nodes,
+            Limit::AstDepth => &mut self.max_ast_depth,
+            Limit::OutputBytes => &mut self.max_output_bytes,
+        };
+        *max = value;
+    }
+
+    /// Updates limits from comma separated `name=value` pairs, e.g. `ast-nodes=100000`.
+    pub fn update(&mut self, spec: &str) -> Result<()> {
+        for pair in spec.split(',') {
+            let (name, value) = pair
+                .split_once('=')
+                .with_context(|| format!("Expected name=value, but got '{}'", pair))?;
+            let value = value
+                .parse()
+                .with_context(|| format!("Invalid value for limit '{}'", name))?;
+            self.set(name.parse()?, value);
+        }
+        Ok(())
+    }
+
+    /// Fails if `value` exceeds `limit`.
+    pub fn check(&self, limit: Limit, value: usize) -> Result<()> {
+        let max = self.get(limit);
+        if value > max {
+            return Err(CompileError::LimitExceeded(limit, max).into());
+        }
+        Ok(())
+    }
+
+    pub fn check_input(&self, input: &str) -> Result<()> {
+        self.check(Limit::InputBytes, input.len())
+    }
+
+    pub fn check_ast(&self, node: &Node) -> Result<()> {
+        self.check(Limit::AstNodes, node_count(node))?;
+        self.check(Limit::AstDepth, ast_depth(node))
+    }
+}
+
+/// Deepest tree the recursive passes, such as codegen, accept.
+///
+/// Matches the default `ast-depth` limit, so that whatever parses by default compiles.
+/// Needs a stack of `STACK_SIZE`; unoptimized builds overflow 8 MiB at about 1300.
+pub const MAX_RECURSION_DEPTH: usize = 5000;
+
+/// Stack the compiler runs on, enough for the default limits with room to spare.
+///
+/// Only real nesting takes stack, since chains of statements and operators are walked in
+/// loops.
+pub const STACK_SIZE: usize = 64 << 20;
+
+/// Nesting counted for each parenthesis.
+///
+/// The parser goes through every precedence level on each parenthesis, which takes
+/// several times the stack of a nested statement.
+const PAREN_NESTING: usize = 4;
+
+/// Fails when a recursive pass over `node` could overflow the stack.
+pub fn check_recursion_depth(node: &Node) -> Result<()> {
+    if ast_depth(node) > MAX_RECURSION_DEPTH {
+        return Err(CompileError::TooDeeplyNested.into());
+    }
+    Ok(())
+}
+
+/// Tokenizes `input`, failing when `limits` are exceeded.
+pub fn tokenize_with_limits(input: &str, limits: &ResourceLimits) -> Result<Vec<Token>> {
+    limits.check_input(input)?;
+    collect_with_limits(Tokenizer::new(input), limits)
+}
+
+/// Collects the tokens of `tokenizer`, failing when `limits` are exceeded.
+pub fn collect_with_limits(tokenizer: Tokenizer, limits: &ResourceLimits) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    for token in tokenizer {
+        tokens.push(token?);
+        limits.check(Limit::Tokens, tokens.len())?;
+    }
+    Ok(tokens)
+}
+
+/// Cursor counting the tokens consumed by the parser.
+///
+/// Once a limit is exceeded, it stops yielding tokens and keeps the error aside
+/// like `TokenStream` does. Nesting of parentheses, statements and unary operators counts
+/// toward the AST depth, since the parser recurses on it.
+pub struct LimitedCursor<'a, Tokens> {
+    tokens: Tokens,
+    limits: &'a ResourceLimits,
+    count: usize,
+    nesting: usize,
+    error: Option<anyhow::Error>,
+}
+
+impl<'a, Tokens> LimitedCursor<'a, Tokens> {
+    pub fn new(tokens: Tokens, limits: &'a ResourceLimits) -> Self {
+        LimitedCursor {
+            tokens,
+            limits,
+            count: 0,
+            nesting: 0,
+            error: None,
+        }
+    }
+
+    /// Takes the error of an exceeded limit, if any.
+    pub fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.error.take()
+    }
+}
+
+impl<'a, Tokens> TokenCursor for LimitedCursor<'a, Tokens>
+where
+    Tokens: TokenCursor,
+{
+    fn peek_nth(&mut self, n: usize) -> Option<&Token> {
+        if self.error.is_some() {
+            return None;
+        }
+        self.tokens.peek_nth(n)
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        if self.error.is_some() {
+            return None;
+        }
+        let token = self.tokens.advance()?;
+        self.count += 1;
+        match token.kind {
+            TokenKind::LParen => self.nesting += PAREN_NESTING,
+            TokenKind::RParen => self.nesting = self.nesting.saturating_sub(PAREN_NESTING),
+            _ => (),
+        }
+        let res = self
+            .limits
+            .check(Limit::Tokens, self.count)
+            .and_then(|_| self.limits.check(Limit::AstDepth, self.nesting));
+        if let Err(err) = res {
+            self.error = Some(err);
+        }
+        Some(token)
+    }
+
+    fn enter(&mut self) {
+        self.nesting += 1;
+        if let Err(err) = self.limits.check(Limit::AstDepth, self.nesting) {
+            self.error.get_or_insert(err);
+        }
+    }
+
+    fn leave(&mut self) {
+        self.nesting = self.nesting.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dot::dot_to_string;
+    use crate::limits::*;
+    use crate::parse::NodeKind;
+    use crate::token::Loc;
+    use crate::{compile_with_options, gen, CompileOptions};
+
+    /// Compiles `input` with a single limit set, returning the error message.
+    fn compile_err(input: &str, spec: &str) -> String {
+        let mut options = CompileOptions::default();
+        options.limits.update(spec).unwrap();
+        compile_with_options(input, &options)
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn test_update() -> Result<()> {
+        let mut limits = ResourceLimits::default();
+        limits.update("ast-nodes=100000,tokens=7")?;
+        assert_eq!(limits.max_ast_nodes, 100000);
+        assert_eq!(limits.max_tokens, 7);
+        assert!(limits.update("nodes=1").is_err());
+        assert!(limits.update("tokens").is_err());
+        assert!(limits.update("tokens=x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_each_limit() {
+        let input = "1+2+3;";
+        for spec in [
+            "input-bytes=4",
+            "tokens=4",
+            "ast-nodes=4",
+            "ast-depth=1",
+            "output-bytes=100",
+        ]
+        .iter()
+        {
+            let name = spec.split('=').next().unwrap();
+            assert!(compile_err(input, spec).contains(name), "{}", spec);
+        }
+        assert!(compile_err("((((1))));", "ast-depth=3").contains("ast-depth"));
+        assert!(compile_with_options(input, &CompileOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_tokenize_with_limits() {
+        let mut limits = ResourceLimits::default();
+        limits.set(Limit::Tokens, 2);
+        assert!(tokenize_with_limits("1", &limits).is_ok());
+        let err = tokenize_with_limits("1+2", &limits).unwrap_err();
+        assert!(err.to_string().contains("tokens"));
+    }
+
+    #[test]
+    fn test_deep_input() {
+        // Long enough to overflow the stack if anything recursed on the chain, which counts
+        // as a single level of nesting.
+        let input = vec!["1"; 200_000].join("+") + ";";
+        let mut options = CompileOptions::default();
+        options.limits.update("ast-depth=4").unwrap();
+        assert!(compile_with_options(&input, &options).is_ok());
+    }
+
+    #[test]
+    fn test_deep_statements() {
+        // Nested statements do not overflow the stack of the parser either.
+        let input = "if (1) ".repeat(200_000) + "1;";
+        assert!(compile_err(&input, "ast-depth=1000").contains("ast-depth"));
+        // Test threads have smaller stacks than the main thread.
+        let input = "!".repeat(200_000) + "1;";
+        assert!(compile_err(&input, "ast-depth=100").contains("ast-depth"));
+    }
+
+    #[test]
+    fn test_recursion_depth() {
+        // Built directly, since the parser rejects it. Nested on the right, which codegen
+        // recurses on.
+        let loc = Loc { line: 1, col: 1 };
+        let mut root = Node::new_num(1, loc);
+        for _ in 0..50_000 {
+            root = Node::new(
+                NodeKind::Add,
+                loc,
+                Node::new_num(1, loc).make_ref(),
+                root.make_ref(),
+            );
+        }
+        let message = "expression too deeply nested to compile";
+        assert_eq!(gen(&root).unwrap_err().to_string(), message);
+        // Writing the tree does not recurse.
+        assert!(dot_to_string(&root).is_ok());
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::Result;
+
+use crate::parse::{parse_slice_with_limits, structurally_eq};
+use crate::token::{Loc, Token, TokenKind};
+use crate::{tokenize_with_options, CompileOptions};
+
+/// Returns the locations of the opening parentheses which do not change the AST.
+///
+/// Each pair of parentheses is dropped in turn and kept dropped if the input still parses
+/// into the same tree, so that only one of doubled parentheses is reported. The input is
+/// tokenized and parsed following `options`.
+pub fn find_redundant_parens(input: &str, options: &CompileOptions) -> Result<Vec<Loc>> {
+    let mut tokens = tokenize_with_options(input, options)?;
+    let root = parse_slice_with_limits(&tokens, &options.limits)?;
+
+    let mut redundant = Vec::new();
+    let mut open = 0;
+    while open < tokens.len() {
+        if tokens[open].kind != TokenKind::LParen {
+            open += 1;
+            continue;
+        }
+        let close = match matching_paren(&tokens, open) {
+            Some(close) => close,
+            None => break,
+        };
+        let mut rest: Vec<Token> = tokens.clone();
+        rest.remove(close);
+        rest.remove(open);
+        match parse_slice_with_limits(&rest, &options.limits) {
+            Ok(node) if structurally_eq(&node, &root) => {
+                redundant.push(tokens[open].loc);
+                tokens = rest;
+            }
+            _ => open += 1,
+        }
+    }
+    Ok(redundant)
+}
+
+/// Returns the index of the parenthesis closing the one at `open`.
+fn matching_paren(tokens: &[Token], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        match token.kind {
+            TokenKind::LParen => depth += 1,
+            TokenKind::RParen => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lint::*;
+
+    #[test]
+    fn test_find_redundant_parens() -> Result<()> {
+        let loc = |col| Loc { line: 1, col };
+        let options = CompileOptions::default();
+        assert_eq!(find_redundant_parens("(1)+2;", &options)?, vec![loc(1)]);
+        assert_eq!(find_redundant_parens("1+(2*3);", &options)?, vec![loc(3)]);
+        assert_eq!(find_redundant_parens("(1+2)*3;", &options)?, vec![]);
+        assert_eq!(find_redundant_parens("((1+2))*3;", &options)?, vec![loc(1)]);
+        assert_eq!(find_redundant_parens("1-(2-3);", &options)?, vec![]);
+        // Without parentheses, `-1` is a negative literal rather than a subtraction.
+        assert_eq!(
+            find_redundant_parens("-(1) + (-2);", &options)?,
+            vec![loc(8)]
+        );
+        assert!(find_redundant_parens("(1;", &options).is_err());
+
+        // Follows the options of the compilation.
+        let mut options = CompileOptions::default();
+        options.tokenize.hash_comments = true;
+        assert_eq!(find_redundant_parens("(1)+2; # x", &options)?, vec![loc(1)]);
+        options.limits.update("ast-depth=4")?;
+        let err = find_redundant_parens("((1));", &options).unwrap_err();
+        assert!(err.to_string().contains("ast-depth"), "{}", err);
+        Ok(())
+    }
+}
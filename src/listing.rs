@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use crate::ir::Instr;
+use crate::parse::Node;
+use crate::token::{lines, Loc};
+use crate::{
+    emit_header, emit_instr, gen_located_functions, gen_located_with_options, CompileOptions,
+};
+
+/// Generates the assembly for `node` with the source lines of `input` as comments.
+///
+/// Each source line is shown once per function, before the first instruction generated
+/// from it, along with the range of columns its nodes start at.
+pub fn listing(input: &str, node: &Node, options: &CompileOptions) -> Result<String> {
+    let lines = lines(input);
+    let mut out = String::new();
+    let instrs = gen_located_with_options(node, options)?;
+    list_instrs(&lines, &instrs, options, &mut out)?;
+    for (name, instrs) in gen_located_functions(node, options)? {
+        let options = CompileOptions {
+            label_prefix: format!("{}{}.", options.label_prefix, name),
+            entry: Some(name),
+            ..options.clone()
+        };
+        list_instrs(&lines, &instrs, &options, &mut out)?;
+    }
+    Ok(out)
+}
+
+/// Writes the function made of `instrs` into `out`, with the source `lines` as comments.
+fn list_instrs(
+    lines: &[&str],
+    instrs: &[(Instr, Loc)],
+    options: &CompileOptions,
+    out: &mut String,
+) -> Result<()> {
+    let mut cols: BTreeMap<usize, (usize, usize)> = BTreeMap::new();
+    for (_, loc) in instrs.iter() {
+        let range = cols.entry(loc.line).or_insert((loc.col, loc.col));
+        range.0 = range.0.min(loc.col);
+        range.1 = range.1.max(loc.col);
+    }
+
+    emit_header(options, out)?;
+    for (instr, loc) in instrs.iter() {
+        if let Some((first, last)) = cols.remove(&loc.line) {
+            let line = lines.get(loc.line - 1).copied().unwrap_or("");
+            let first = Loc { col: first, ..*loc }.display(options.render);
+            let last = Loc { col: last, ..*loc }.display(options.render);
+            writeln!(
+                out,
+                "# line {}, col {}-{}: {}",
+                first.line,
+                first.col,
+                last.col,
+                line.trim_end()
+            )?;
+        }
+        emit_instr(*instr, options, out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::listing::*;
+    use crate::parse::parse_streaming;
+
+    #[test]
+    fn test_listing() -> Result<()> {
+        let input = "1 +\n  2 * 3\n  - 4;";
+        let expected = "\
+.intel_syntax noprefix
+.globl main
+.p2align 4, 0x90
+main:
+# line 1, col 1-3: 1 +
+  push 1
+# line 2, col 3-7:   2 * 3
+  push 2
+  push 3
+  pop rdi
+  pop rax
+  imul rax, rdi
+  push rax
+  pop rdi
+  pop rax
+  add rax, rdi
+  push rax
+# line 3, col 3-5:   - 4;
+  push 4
+  pop rdi
+  pop rax
+  sub rax, rdi
+  push rax
+  pop rax
+  ret
+";
+        assert_eq!(
+            listing(input, &parse_streaming(input)?, &CompileOptions::default())?,
+            expected
+        );
+        Ok(())
+    }
+}
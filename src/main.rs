@@ -1,65 +1,659 @@
+use std::fs::File;
+use std::io::{self, Write};
 use std::process::exit;
+use std::thread;
 
-use clap::{App, Arg};
-use rust9cc::CompileError;
-use rust9cc::display_compile_error;
-use rust9cc::gen;
-use rust9cc::parse::parse_into_ast;
-use rust9cc::dot::dotify_ast;
-use rust9cc::token::Token;
-use rust9cc::token::tokenize;
+use clap::{App, Arg, ArgMatches};
+use rust9cc::analyze;
+use rust9cc::check_with_options;
+use rust9cc::compile_with_summary;
+use rust9cc::compile_with_timings;
+use rust9cc::display_diagnostic;
+use rust9cc::dot::write_dot;
+use rust9cc::eval::{eval_const_with_overflow, eval_with_overflow, parse_var, Env, Overflow};
+use rust9cc::gen_instrs_with_options;
+use rust9cc::gen_obj_with_options;
+use rust9cc::gen_with_report;
+use rust9cc::input::{read_input, strip_bom};
+use rust9cc::limits::STACK_SIZE;
+use rust9cc::lint::find_redundant_parens;
+use rust9cc::listing::listing;
+use rust9cc::opt::PassReport;
+use rust9cc::optimize;
+use rust9cc::parse::parse_slice_with_limits;
+use rust9cc::parse_with_options;
+use rust9cc::repl::Repl;
+use rust9cc::selftest;
+use rust9cc::token::{dump_tokens, RenderOptions};
+use rust9cc::tokenize_with_options;
+use rust9cc::unparse::{unparse_with, Parens};
+use rust9cc::CompileOptions;
+use rust9cc::Diagnostic;
+use rust9cc::MemoryReport;
+use rust9cc::Summary;
+use rust9cc::Timings;
+use rust9cc::{check_redefinitions, gen_multi, gen_multi_obj, multi_entries};
+use rust9cc::{program_to_asm, program_to_ir};
 
 const MODE_AST: &str = "ast";
 const MODE_TOKEN: &str = "token";
 const MODE_X86: &str = "x86";
+const MODE_LISTING: &str = "listing";
+const MODE_PRETTY: &str = "pretty";
+const MODE_LINT: &str = "lint";
+const MODE_CHECK: &str = "check";
+const MODE_IR: &str = "ir";
+const MODE_CONST: &str = "const";
+const MODE_EVAL: &str = "eval";
+
+const EMIT_ASM: &str = "asm";
+const EMIT_OBJ: &str = "obj";
 
 fn main() {
+    // The main thread has too little stack for the deepest programs the limits allow.
+    let compiler = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run)
+        .expect("Failed to start the compiler thread");
+    if compiler.join().is_err() {
+        exit(101);
+    }
+}
+
+fn run() {
     let matches = App::new("rust9cc")
         .version("0.0.1")
         .arg(
             Arg::with_name("mode")
                 .long("mode")
-                .possible_values(&[MODE_AST, MODE_TOKEN, MODE_X86])
+                .possible_values(&[MODE_AST, MODE_TOKEN, MODE_X86, MODE_LISTING, MODE_PRETTY, MODE_LINT, MODE_CHECK, MODE_IR, MODE_CONST, MODE_EVAL])
                 .default_value(MODE_X86),
         )
+        .arg(
+            Arg::with_name("parens")
+                .long("parens")
+                .possible_values(&["full", "minimal"])
+                .default_value("minimal")
+                .help("Sets where --mode pretty puts parentheses."),
+        )
+        .arg(
+            Arg::with_name("cet")
+                .long("cet")
+                .help("Starts functions with endbr64 for Intel CET."),
+        )
+        .arg(
+            Arg::with_name("label-prefix")
+                .long("label-prefix")
+                .takes_value(true)
+                .help("Prepends a prefix to local labels, so that outputs compiled with distinct prefixes can be concatenated."),
+        )
+        .arg(
+            Arg::with_name("entry")
+                .long("entry")
+                .takes_value(true)
+                .default_value("main")
+                .help("Sets the symbol of the generated function."),
+        )
+        .arg(
+            Arg::with_name("no-entry")
+                .long("no-entry")
+                .conflicts_with("entry")
+                .help("Writes only the body of the function, leaving the result on the stack."),
+        )
+        .arg(
+            Arg::with_name("emit")
+                .long("emit")
+                .possible_values(&[EMIT_ASM, EMIT_OBJ])
+                .default_value(EMIT_ASM)
+                .help("Writes assembly, or an ELF object without an external assembler."),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Writes the output to FILE instead of stdout."),
+        )
+        .arg(
+            Arg::with_name("opt-level")
+                .short("O")
+                .takes_value(true)
+                .possible_values(&["0", "1"])
+                .default_value("0")
+                .help("Sets the optimization level."),
+        )
+        .arg(
+            Arg::with_name("optimize")
+                .long("optimize")
+                .help("Folds constants and eliminates pushes and pops, the same as -O 1."),
+        )
+        .arg(
+            Arg::with_name("opt-report")
+                .long("opt-report")
+                .help("Prints what each optimization pass did to stderr."),
+        )
+        .arg(
+            Arg::with_name("align-functions")
+                .long("align-functions")
+                .takes_value(true)
+                .possible_values(&["on", "off"])
+                .default_value("on")
+                .help("Aligns functions to 16 bytes."),
+        )
+        .arg(
+            Arg::with_name("dump-tokens-on-error")
+                .long("dump-tokens-on-error")
+                .help("Prints the tokens to stderr when parsing fails."),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .help("Prints approximate memory usage to stderr."),
+        )
+        .arg(
+            Arg::with_name("summary")
+                .long("summary")
+                .help("Prints the size of the data at each stage to stderr."),
+        )
+        .arg(
+            Arg::with_name("summary-comment")
+                .long("summary-comment")
+                .help("Ends the assembly of --summary and --stats with the summary as comments."),
+        )
+        .arg(
+            Arg::with_name("timings")
+                .long("timings")
+                .help("Prints the time spent in each phase to stderr."),
+        )
+        .arg(
+            Arg::with_name("hash-comments")
+                .long("hash-comments")
+                .help("Treats # up to the end of the line as a comment."),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("Only checks the syntax, same as --mode check."),
+        )
+        .arg(
+            Arg::with_name("wrapping")
+                .long("wrapping")
+                .help("Wraps around on overflow when evaluating or folding instead of failing."),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .help("Rejects constructs whose behavior depends on the implementation."),
+        )
+        .arg(
+            Arg::with_name("limits")
+                .long("limits")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Sets resource limits, e.g. ast-nodes=100000,ast-depth=100."),
+        )
+        .arg(
+            Arg::with_name("repl")
+                .long("repl")
+                .help("Evaluates statements interactively."),
+        )
+        .arg(
+            Arg::with_name("selftest")
+                .long("selftest")
+                .takes_value(true)
+                .possible_values(&["gcc"])
+                .help("Compares against the system C compiler on random expressions."),
+        )
+        .arg(
+            Arg::with_name("eval")
+                .long("eval")
+                .takes_value(true)
+                .value_name("EXPR")
+                .help("Evaluates a program and prints the value of its last statement."),
+        )
+        .arg(
+            Arg::with_name("var")
+                .long("var")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Sets a declared variable for --eval and --mode eval, e.g. a=3."),
+        )
+        .arg(
+            Arg::with_name("zero-based-locs")
+                .long("zero-based-locs")
+                .help("Shows lines and columns counting from 0 instead of 1."),
+        )
+        .arg(
+            Arg::with_name("multi")
+                .long("multi")
+                .help("Compiles each input into an exported function expr0, expr1, ..."),
+        )
+        .arg(
+            Arg::with_name("file")
+                .long("file")
+                .takes_value(true)
+                .value_name("PATH")
+                .conflicts_with("INPUT")
+                .help("Reads the input program from the file at PATH, or stdin if PATH is -."),
+        )
         .arg(
             Arg::with_name("INPUT")
-                .help("Input expression.")
-                .required(true)
+                .help("Input program.")
+                .required_unless_one(&["repl", "selftest", "eval", "file"])
                 .index(1),
         )
+        .arg(
+            Arg::with_name("MORE")
+                .help("More input programs for --multi.")
+                .multiple(true)
+                .requires("multi")
+                .index(2),
+        )
         .get_matches();
 
-    let input = matches.value_of("INPUT").unwrap();
-    let tokens = match tokenize(input) {
-        Ok(tokens) => tokens,
-        Err(err) => match err.downcast_ref::<CompileError>() {
-            Some(CompileError::Tokenize(_, loc)) => {
-                display_compile_error(input, *loc, err.to_string().as_str());
-                exit(1);
-            },
-            _ => {
-                println!("{}", err);
-                exit(1);
-            }
+    let mut options = CompileOptions::default();
+    options.render.zero_based_locs = matches.is_present("zero-based-locs");
+    options.tokenize.hash_comments = matches.is_present("hash-comments");
+    options.align_functions = matches.value_of("align-functions") == Some("on");
+    options.strict = matches.is_present("strict");
+    options.overflow = if matches.is_present("wrapping") {
+        Overflow::Wrapping
+    } else {
+        Overflow::Checked
+    };
+    options.cet = matches.is_present("cet");
+    options.summary_comment = matches.is_present("summary-comment");
+    options.label_prefix = matches
+        .value_of("label-prefix")
+        .unwrap_or_default()
+        .to_string();
+    options.entry = if matches.is_present("no-entry") {
+        None
+    } else {
+        matches.value_of("entry").map(str::to_string)
+    };
+    options.opt_level = if matches.is_present("optimize") {
+        1
+    } else {
+        matches.value_of("opt-level").unwrap().parse().unwrap()
+    };
+    for spec in matches.values_of("limits").into_iter().flatten() {
+        if let Err(err) = options.limits.update(spec) {
+            exit_with_error("", err, options.render);
         }
+    }
+
+    if matches.is_present("repl") {
+        Repl::new()
+            .run(io::stdin().lock(), &mut io::stdout())
+            .unwrap();
+        return;
+    }
+
+    if matches.is_present("selftest") {
+        run_selftest();
+    }
+
+    let mut out = open_output(matches.value_of("output"));
+    if let Some(input) = matches.value_of("eval") {
+        run_eval(
+            input,
+            matches.values_of("var").into_iter().flatten(),
+            &options,
+            &mut out,
+        );
+    }
+
+    let source;
+    let input = match matches.value_of("file") {
+        Some(path) => {
+            source =
+                read_input(path).unwrap_or_else(|err| exit_with_error("", err, options.render));
+            &source
+        }
+        None => strip_bom(matches.value_of("INPUT").unwrap()),
+    };
+    let mode = if matches.is_present("check") {
+        MODE_CHECK
+    } else {
+        matches.value_of("mode").unwrap()
     };
+    if mode == MODE_EVAL {
+        run_eval(
+            input,
+            matches.values_of("var").into_iter().flatten(),
+            &options,
+            &mut out,
+        );
+    }
+    let emit_obj = matches.value_of("emit") == Some(EMIT_OBJ);
+    let mut reports = Vec::new();
+    run_mode(
+        &matches,
+        input,
+        mode,
+        emit_obj,
+        &options,
+        &mut out,
+        &mut reports,
+    );
+    if matches.is_present("opt-report") {
+        print_opt_report(&reports, options.render);
+    }
+}
+
+/// Writes the output of `mode` for `input` to `out`, appending what the optimization passes
+/// did to `reports`.
+fn run_mode(
+    matches: &ArgMatches,
+    input: &str,
+    mode: &str,
+    emit_obj: bool,
+    options: &CompileOptions,
+    mut out: &mut dyn Write,
+    reports: &mut Vec<PassReport>,
+) {
+    if matches.is_present("multi") {
+        let inputs = std::iter::once(input).chain(matches.values_of("MORE").into_iter().flatten());
+        run_multi(inputs, options, emit_obj, out, reports);
+        return;
+    }
 
-    let mode = matches.value_of("mode").unwrap();
     if mode == MODE_TOKEN {
-        for token in tokens.iter() {
-            println!("{:?}", token);
+        let tokens = tokenize_with_options(input, options)
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        dump_tokens(&tokens, options.render, &mut out).unwrap();
+        return;
+    }
+
+    if mode == MODE_CHECK {
+        if let Err(diagnostics) = check_with_options(input, options) {
+            for diagnostic in diagnostics.iter() {
+                display_diagnostic(input, diagnostic, options.render);
+            }
+            exit(1);
+        }
+        return;
+    }
+
+    if mode == MODE_LINT {
+        let locs = find_redundant_parens(input, options)
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        for loc in locs.iter() {
+            let loc = loc.display(options.render);
+            writeln!(
+                out,
+                "warning at line {}, col {}: redundant parentheses",
+                loc.line, loc.col
+            )
+            .unwrap();
+        }
+        return;
+    }
+
+    if mode == MODE_X86 && !emit_obj && matches.is_present("stats") {
+        // Keeps every intermediate result alive to account for them.
+        let tokens = tokenize_with_options(input, options)
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        let root = parse_slice_with_limits(&tokens, &options.limits)
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        analyze(&root, options).unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        let root = optimize(root, options, reports)
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        let instrs = gen_instrs_with_options(&root, options, reports)
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        let asm = program_to_asm(&root, &instrs, options)
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        let summary = Summary::new(input, &tokens, &root, &instrs, &asm);
+        write!(out, "{}", asm).unwrap();
+        if options.summary_comment {
+            write!(out, "{}", summary.to_asm_comment()).unwrap();
+        }
+        print_memory_report(&MemoryReport::new(&tokens, &root, &asm));
+        eprintln!("{}", summary);
+        return;
+    }
+
+    if mode == MODE_X86 && !emit_obj && matches.is_present("summary") {
+        let (asm, summary) = compile_with_summary(input, options, reports)
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        write!(out, "{}", asm).unwrap();
+        eprintln!("{}", summary);
+        return;
+    }
+
+    if mode == MODE_X86 && !emit_obj && matches.is_present("timings") {
+        let (asm, timings, memory) = compile_with_timings(input, options, reports)
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        write!(out, "{}", asm).unwrap();
+        print_timings(&timings);
+        print_memory_report(&memory);
+        return;
+    }
+
+    let root = parse_with_options(input, options).unwrap_or_else(|err| {
+        if matches.is_present("dump-tokens-on-error") {
+            // Parse errors only, since there are no tokens to show on tokenize errors.
+            if let Ok(tokens) = tokenize_with_options(input, options) {
+                dump_tokens(&tokens, options.render, &mut io::stderr()).unwrap();
+            }
         }
+        exit_with_error(input, err, options.render)
+    });
+    analyze(&root, options).unwrap_or_else(|err| exit_with_error(input, err, options.render));
+    if mode == MODE_CONST {
+        let value = eval_const_with_overflow(&root, options.overflow)
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        writeln!(out, "{}", value).unwrap();
         return;
     }
 
-    let tokens = &mut tokens.into_iter().peekable();
-    let root = parse_into_ast(tokens).unwrap();
+    let root = optimize(root, options, reports)
+        .unwrap_or_else(|err| exit_with_error(input, err, options.render));
 
     if mode == MODE_AST {
-        dotify_ast(&root);
+        write_dot(&root, &mut out)
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        return;
+    }
+
+    if mode == MODE_PRETTY {
+        let parens: Parens = matches.value_of("parens").unwrap().parse().unwrap();
+        writeln!(out, "{}", unparse_with(&root, parens)).unwrap();
         return;
     }
 
-    gen(&root).unwrap();
+    if mode == MODE_IR {
+        let ir = gen_instrs_with_options(&root, options, reports)
+            .and_then(|instrs| program_to_ir(&root, &instrs, options))
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        write!(out, "{}", ir).unwrap();
+        return;
+    }
+
+    if mode == MODE_LISTING {
+        let asm = listing(input, &root, options)
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        write!(out, "{}", asm).unwrap();
+        return;
+    }
+
+    if emit_obj {
+        let obj = gen_obj_with_options(&root, options)
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        out.write_all(&obj).unwrap();
+        return;
+    }
+
+    let asm = gen_with_report(&root, options, reports)
+        .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+    write!(out, "{}", asm).unwrap();
+}
+
+/// Opens the output to the file at `path`, or stdout without a path.
+fn open_output(path: Option<&str>) -> Box<dyn Write> {
+    match path {
+        Some(path) => Box::new(OutputFile {
+            path: path.to_string(),
+            buf: Some(Vec::new()),
+        }),
+        None => Box::new(io::stdout()),
+    }
+}
+
+/// Output kept in memory until it is complete, and then written to the file at `path`.
+///
+/// Errors exit without dropping it, so that a failed compilation leaves an existing file
+/// as it was instead of truncating it.
+struct OutputFile {
+    path: String,
+    buf: Option<Vec<u8>>,
+}
+
+impl OutputFile {
+    /// Writes the output to the file, exiting on failure. Only the first call writes.
+    fn write_file(&mut self) {
+        let buf = match self.buf.take() {
+            Some(buf) => buf,
+            None => return,
+        };
+        let mut file = File::create(&self.path).unwrap_or_else(|err| {
+            println!("Cannot open {}: {}", self.path, err);
+            exit(1);
+        });
+        if let Err(err) = file.write_all(&buf) {
+            println!("Cannot write {}: {}", self.path, err);
+            exit(1);
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self.buf.as_mut() {
+            Some(buf) => buf.write(data),
+            None => Err(io::Error::other("output already written")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_file();
+        Ok(())
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        self.write_file();
+    }
+}
+
+/// Compiles each of `inputs` into a function of its own and writes the output to `out`.
+fn run_multi<'a>(
+    inputs: impl Iterator<Item = &'a str>,
+    options: &CompileOptions,
+    emit_obj: bool,
+    out: &mut dyn Write,
+    reports: &mut Vec<PassReport>,
+) {
+    let inputs: Vec<&str> = inputs.map(strip_bom).collect();
+    let mut defined = multi_entries(inputs.len());
+    let mut nodes = Vec::new();
+    for input in inputs {
+        let root = parse_with_options(input, options)
+            .and_then(|root| analyze(&root, options).map(|_| root))
+            .and_then(|root| check_redefinitions(&root, &mut defined).map(|_| root))
+            .and_then(|root| optimize(root, options, reports))
+            // Reports codegen errors here, where the input they point into is known.
+            .and_then(|root| gen_instrs_with_options(&root, options, reports).map(|_| root))
+            .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        nodes.push(root);
+    }
+    if emit_obj {
+        let obj = gen_multi_obj(&nodes, options)
+            .unwrap_or_else(|err| exit_with_error("", err, options.render));
+        out.write_all(&obj).unwrap();
+    } else {
+        let asm = gen_multi(&nodes, options)
+            .unwrap_or_else(|err| exit_with_error("", err, options.render));
+        write!(out, "{}", asm).unwrap();
+    }
+}
+
+/// Evaluates `input` with the variables defined by `specs`, writes the value to `out` and exits.
+fn run_eval<'a>(
+    input: &str,
+    specs: impl Iterator<Item = &'a str>,
+    options: &CompileOptions,
+    out: &mut dyn Write,
+) -> ! {
+    let mut env = Env::new();
+    for spec in specs {
+        let (name, value) =
+            parse_var(spec).unwrap_or_else(|err| exit_with_error(input, err, options.render));
+        env.insert(name, value);
+    }
+    let value = parse_with_options(input, options)
+        .and_then(|root| eval_with_overflow(&root, &env, options.overflow))
+        .unwrap_or_else(|err| exit_with_error(input, err, options.render));
+    writeln!(out, "{}", value).unwrap();
+    out.flush().unwrap();
+    exit(0);
+}
+
+/// Runs the differential test configured by environment variables and exits.
+fn run_selftest() -> ! {
+    let config = selftest::Config::from_env();
+    match selftest::run(&config) {
+        Ok(mismatches) if mismatches.is_empty() => {
+            println!(
+                "{} expressions passed (seed {})",
+                config.iterations, config.seed
+            );
+            exit(0);
+        }
+        Ok(mismatches) => {
+            for m in mismatches.iter() {
+                println!(
+                    "{} => {} expected, but got {}",
+                    m.expr, m.expected, m.actual
+                );
+            }
+            println!("mismatches written to {}", config.dump_dir.display());
+        }
+        Err(err) => println!("{}", err),
+    }
+    exit(1);
+}
+
+fn print_memory_report(report: &MemoryReport) {
+    eprintln!("tokens: {} bytes", report.token_bytes);
+    eprintln!(
+        "ast: {} bytes ({} nodes)",
+        report.ast_bytes, report.ast_nodes
+    );
+    eprintln!("asm: {} bytes", report.asm_bytes);
+    eprintln!("total: {} bytes", report.total());
+}
+
+fn print_timings(timings: &Timings) {
+    eprintln!("tokenize: {:?}", timings.tokenize);
+    eprintln!("parse: {:?}", timings.parse);
+    eprintln!("optimize: {:?}", timings.optimize);
+    eprintln!("codegen: {:?}", timings.codegen);
+}
+
+fn print_opt_report(reports: &[PassReport], options: RenderOptions) {
+    for report in reports.iter() {
+        eprintln!("{}", report.display(options));
+    }
+}
+
+/// Displays a compile error and exits.
+fn exit_with_error(input: &str, err: anyhow::Error, options: RenderOptions) -> ! {
+    display_diagnostic(input, &Diagnostic::from(err), options);
+    exit(1);
 }
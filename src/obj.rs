@@ -0,0 +1,50 @@
+#[cfg(feature = "obj")]
+use crate::encode::encode;
+use crate::ir::Instr;
+
+use anyhow::Result;
+#[cfg(feature = "obj")]
+use object::write::{Object, StandardSection, Symbol, SymbolSection};
+#[cfg(feature = "obj")]
+use object::{
+    Architecture, BinaryFormat, Endianness, SectionKind, SymbolFlags, SymbolKind, SymbolScope,
+};
+
+/// Writes `instrs` as the body of `entry` into a relocatable x86-64 ELF object.
+pub fn write_object(instrs: &[Instr], entry: &str) -> Result<Vec<u8>> {
+    write_functions(&[(entry.to_string(), instrs.to_vec())])
+}
+
+/// Writes each function, given by name and body, into a relocatable x86-64 ELF object.
+#[cfg(not(feature = "obj"))]
+pub fn write_functions(_functions: &[(String, Vec<Instr>)]) -> Result<Vec<u8>> {
+    Err(anyhow::anyhow!(
+        "object files are not supported by this build, which lacks the obj feature"
+    ))
+}
+
+/// Writes each function, given by name and body, into a relocatable x86-64 ELF object.
+#[cfg(feature = "obj")]
+pub fn write_functions(functions: &[(String, Vec<Instr>)]) -> Result<Vec<u8>> {
+    let mut obj = Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+    let text = obj.section_id(StandardSection::Text);
+    for (name, instrs) in functions.iter() {
+        let code = encode(instrs)?;
+        let offset = obj.append_section_data(text, &code, 16);
+        obj.add_symbol(Symbol {
+            name: name.as_bytes().to_vec(),
+            value: offset,
+            size: code.len() as u64,
+            kind: SymbolKind::Text,
+            scope: SymbolScope::Linkage,
+            weak: false,
+            section: SymbolSection::Section(text),
+            flags: SymbolFlags::None,
+        });
+    }
+
+    // Marks the stack as non-executable, which the linker otherwise warns about.
+    obj.add_section(Vec::new(), b".note.GNU-stack".to_vec(), SectionKind::Note);
+
+    Ok(obj.write()?)
+}
@@ -0,0 +1,226 @@
+use std::fmt;
+
+use crate::eval::{eval_const_with_overflow, Overflow};
+use crate::ir::Instr;
+use crate::parse::{walk_mut, Node, NodeKind};
+use crate::token::{Loc, RenderOptions};
+
+/// What an optimization pass rewrote.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PassReport {
+    pub pass: &'static str,
+    /// Describes a rewrite, e.g. "nodes folded".
+    pub action: &'static str,
+    /// Location of each rewrite.
+    pub locs: Vec<Loc>,
+}
+
+impl PassReport {
+    fn new(pass: &'static str, action: &'static str) -> Self {
+        PassReport {
+            pass,
+            action,
+            locs: Vec::new(),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.locs.len()
+    }
+
+    /// Returns the report to show to users, e.g. "constant-folding: 2 nodes folded at lines 1, 3".
+    pub fn display(&self, options: RenderOptions) -> DisplayPassReport<'_> {
+        DisplayPassReport {
+            report: self,
+            options,
+        }
+    }
+}
+
+/// `PassReport` as shown to users, with its locations shown following `options`.
+pub struct DisplayPassReport<'a> {
+    report: &'a PassReport,
+    options: RenderOptions,
+}
+
+impl fmt::Display for DisplayPassReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let report = self.report;
+        write!(f, "{}: {} {}", report.pass, report.count(), report.action)?;
+        if !report.locs.is_empty() {
+            let lines: Vec<String> = report
+                .locs
+                .iter()
+                .map(|loc| loc.display(self.options).line.to_string())
+                .collect();
+            write!(f, " at lines {}", lines.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Replaces operations on two numbers by their result.
+///
+/// Operations that fail, like a division by zero, are left for run time, which reaches them
+/// only if they are evaluated at all.
+pub fn fold_constants(node: Node) -> (Node, PassReport) {
+    fold_constants_with_overflow(node, Overflow::Checked)
+}
+
+/// Same as `fold_constants`, doing `overflow` on overflow.
+pub fn fold_constants_with_overflow(mut node: Node, overflow: Overflow) -> (Node, PassReport) {
+    let mut report = PassReport::new("constant-folding", "nodes folded");
+    walk_mut(&mut node, &mut |node| fold(node, overflow, &mut report));
+    (node, report)
+}
+
+/// Folds `node` whose children are already folded.
+///
+/// Statements with a condition are kept, even if it is constant.
+fn fold(node: &mut Node, overflow: Overflow, report: &mut PassReport) {
+    let (lhs, rhs) = match (&node.cond, &node.lhs, &node.rhs) {
+        (None, Some(lhs), Some(rhs)) => (lhs.kind, rhs.kind),
+        _ => return,
+    };
+    let value = match (node.kind, lhs, rhs) {
+        // The right side is never evaluated, whatever it is.
+        (NodeKind::LogAnd, NodeKind::Num(0), _) => 0,
+        (NodeKind::LogOr, NodeKind::Num(lhs), _) if lhs != 0 => 1,
+        (_, NodeKind::Num(_), NodeKind::Num(_)) => match eval_const_with_overflow(node, overflow) {
+            Ok(value) => value,
+            Err(_) => return,
+        },
+        _ => return,
+    };
+    report.locs.push(node.loc);
+    node.kind = NodeKind::Num(value);
+    node.lhs = None;
+    node.rhs = None;
+}
+
+/// Replaces a push immediately followed by a pop with a move, or nothing.
+pub fn peephole(instrs: Vec<(Instr, Loc)>) -> (Vec<(Instr, Loc)>, PassReport) {
+    let mut report = PassReport::new("peephole", "push/pop pairs eliminated");
+    let mut out: Vec<(Instr, Loc)> = Vec::with_capacity(instrs.len());
+    for (instr, loc) in instrs.into_iter() {
+        let dst = match instr {
+            Instr::Pop(dst) => dst,
+            _ => {
+                out.push((instr, loc));
+                continue;
+            }
+        };
+        match out.last().map(|(instr, _)| *instr) {
+            Some(Instr::Push(src)) => {
+                out.pop();
+                if src != dst {
+                    out.push((Instr::Mov(dst, src), loc));
+                }
+            }
+            Some(Instr::PushImm(imm)) => {
+                out.pop();
+                out.push((Instr::MovImm(dst, imm), loc));
+            }
+            _ => {
+                out.push((instr, loc));
+                continue;
+            }
+        }
+        report.locs.push(loc);
+    }
+    (out, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::gen_located;
+    use crate::ir::Reg;
+    use crate::opt::*;
+    use crate::parse::{parse_streaming, structurally_eq};
+
+    #[test]
+    fn test_fold_constants() -> Result<()> {
+        for (input, expected) in [
+            ("2*3+1;", "7;"),
+            ("2*(1+23)-456/7;", "-17;"),
+            ("(1-2)*(3+4);", "-7;"),
+            ("int a; a*(2-5);", "int a; a*-3;"),
+            ("(5 > 3) + 1;", "2;"),
+            ("int a; a; 1+2;", "int a; a; 3;"),
+            ("int a = 2*3; a;", "int a = 6; a;"),
+            ("if (1 < 2) 3 * 4;", "if (1) 12;"),
+            ("1 == 2;", "0;"),
+            (
+                "int a; for (a = 1 + 1; 2 > a; ) a = 3 - 1;",
+                "int a; for (a = 2; 2 > a; ) a = 2;",
+            ),
+            // Short-circuits past whatever is on the right.
+            ("0 && 1/0;", "0;"),
+            ("int a; 3 || a;", "int a; 1;"),
+            ("int a; 1 && a;", "int a; 1 && a;"),
+            // Leaves what fails for run time, which may never reach it.
+            ("1 + 2/0;", "1 + 2/0;"),
+            ("if (0) 1/0; 3;", "if (0) 1/0; 3;"),
+            (
+                "return 0 || 9223372036854775807+1;",
+                "return 0 || 9223372036854775807+1;",
+            ),
+        ]
+        .iter()
+        {
+            let (folded, _) = fold_constants(parse_streaming(input)?);
+            assert!(
+                structurally_eq(&folded, &parse_streaming(expected)?),
+                "{}",
+                input
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_peephole() -> Result<()> {
+        let instrs = gen_located(&parse_streaming("1\n+ 2;")?)?;
+        let (instrs, report) = peephole(instrs);
+        let instrs: Vec<Instr> = instrs.into_iter().map(|(instr, _)| instr).collect();
+        assert_eq!(
+            instrs,
+            vec![
+                Instr::PushImm(1),
+                Instr::MovImm(Reg::Rdi, 2),
+                Instr::Pop(Reg::Rax),
+                Instr::Add(Reg::Rax, Reg::Rdi),
+                Instr::Ret,
+            ]
+        );
+        assert_eq!(report.count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_report() -> Result<()> {
+        let input = "(1 - 2)\n* (3 +\n4);";
+        let (folded, fold_report) = fold_constants(parse_streaming(input)?);
+        assert_eq!(
+            fold_report.display(RenderOptions::default()).to_string(),
+            "constant-folding: 3 nodes folded at lines 1, 2, 2"
+        );
+        let zero_based = RenderOptions {
+            zero_based_locs: true,
+        };
+        assert_eq!(
+            fold_report.display(zero_based).to_string(),
+            "constant-folding: 3 nodes folded at lines 0, 1, 1"
+        );
+
+        let (_, report) = peephole(gen_located(&folded)?);
+        assert_eq!(
+            report.display(RenderOptions::default()).to_string(),
+            "peephole: 1 push/pop pairs eliminated at lines 2"
+        );
+        Ok(())
+    }
+}
@@ -1,8 +1,11 @@
-use std::iter::Peekable;
+use std::collections::HashSet;
 
+use crate::limits::{LimitedCursor, ResourceLimits};
 use crate::token::*;
+use crate::ty::{add_types, Ty};
+use crate::CompileError;
 
-use anyhow::{anyhow, Result};
+use anyhow::{Context, Result};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum NodeKind {
@@ -16,150 +19,1090 @@ pub enum NodeKind {
     Leq,
     Gt,
     Geq,
-    Num(u64),
+    /// Stores `rhs` into the variable `lhs`, taking the value stored.
+    Assign,
+    /// Statement `lhs` followed by `rhs`, whose value it takes.
+    Seq,
+    /// Statement `lhs` run only if `cond` is nonzero, and otherwise the optional
+    /// statement `rhs`.
+    ///
+    /// Takes the value of the statement run, and 0 if there is none.
+    If,
+    /// Statement `lhs` run as long as `cond` is nonzero.
+    ///
+    /// Takes the value 0 of the last `cond`.
+    While,
+    /// Loop running `init` once, and then statement `lhs` followed by `inc` as long as
+    /// `cond` is nonzero or absent.
+    ///
+    /// Takes the value 0, like `While`.
+    For,
+    /// Statement `lhs` run once, and then again as long as `cond` is nonzero.
+    ///
+    /// Takes the value 0, like `While`.
+    DoWhile,
+    /// Statements `stmts` run in order.
+    ///
+    /// Takes the value of the last statement, and 0 if there is none.
+    Block,
+    /// Jumps out of the innermost loop or switch, which then takes the value 0.
+    ///
+    /// Only parsed as a statement; whether it is in a loop is checked when it runs.
+    Break,
+    /// Skips the rest of the body of the innermost loop, going on with its increment or
+    /// condition.
+    ///
+    /// Checked like `Break`.
+    Continue,
+    /// Arms `stmts` run from the first whose label matches `cond`, falling through to the
+    /// ones after it, or from the `Default` arm if none matches.
+    ///
+    /// Takes the value 0, like `While`.
+    Switch,
+    /// Leaves the function with the value of `lhs`, if any.
+    ///
+    /// Only parsed as a statement. Without an entry function, it leaves the enclosing one.
+    Return,
+    /// Arm of a `Switch` labeled with the value, running the statements `stmts`.
+    Case(i64),
+    /// Arm of a `Switch` taken when no `Case` matches.
+    Default,
+    /// Literal, negative if written right after unary minus.
+    Num(i64),
+    /// Single-letter local variable at `offset` bytes below the frame base.
+    LVar {
+        offset: usize,
+    },
+    /// Address of the variable or dereference `lhs`.
+    Addr,
+    /// Value at the address `lhs`, which can also be assigned to.
+    Deref,
+    /// 1 if `lhs` is 0, and 0 otherwise.
+    Not,
+    BitAnd,
+    BitOr,
+    BitXor,
+    /// `lhs` shifted left by `rhs` bits.
+    Shl,
+    /// `lhs` shifted right by `rhs` bits, keeping its sign.
+    Shr,
+    /// `lhs` with all the bits flipped.
+    BitNot,
+    /// 1 if both `lhs` and `rhs` are not 0, where `rhs` is only evaluated if `lhs` is not 0.
+    LogAnd,
+    /// 1 if `lhs` or `rhs` is not 0, where `rhs` is only evaluated if `lhs` is 0.
+    LogOr,
+    /// Declaration of the variable `lhs`, whose `ty` is the type declared, initialized by
+    /// the assignment `rhs` if any.
+    ///
+    /// Only parsed as a statement, which takes the value 0.
+    Decl,
+    /// Call of the external function `name` with the arguments `stmts`, taking the value it
+    /// returns.
+    Call {
+        name: Name,
+    },
+    /// Definition of the function `name` taking the parameters `stmts`, which are
+    /// variables, and running the block `lhs`. Its `ty` is `void` if it returns nothing.
+    ///
+    /// Only parsed at the top level, where it runs nothing and takes the value 0.
+    Function {
+        name: Name,
+    },
+}
+
+/// Returns the stack offset of the variable `name`, which is a lowercase letter.
+pub fn lvar_offset(name: char) -> usize {
+    (name as usize - 'a' as usize + 1) * 8
+}
+
+/// Returns the name of the variable at `offset`.
+pub fn lvar_name(offset: usize) -> char {
+    (b'a' + (offset / 8 - 1) as u8) as char
 }
 
 pub type NodeRef = Box<Node>;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Node {
     pub kind: NodeKind,
+    /// Location of the token this node is made from.
+    pub loc: Loc,
+    /// Type of the value of an expression, set by `add_types` once parsed.
+    pub ty: Option<Ty>,
+    /// Expression run once before the loop of a `for` statement.
+    pub init: Option<NodeRef>,
+    /// Condition of a statement, which comes before `lhs` and `rhs`.
+    pub cond: Option<NodeRef>,
+    /// Expression run after each iteration of a `for` statement.
+    pub inc: Option<NodeRef>,
     pub lhs: Option<NodeRef>,
     pub rhs: Option<NodeRef>,
+    /// Statements of a block, arguments of a call or parameters of a function, which come
+    /// after the other children.
+    pub stmts: Vec<NodeRef>,
 }
 
 impl Node {
-    pub fn new(kind: NodeKind, lhs: Option<NodeRef>, rhs: Option<NodeRef>) -> Node {
-        Self { kind, lhs, rhs }
+    pub fn new(kind: NodeKind, loc: Loc, lhs: Option<NodeRef>, rhs: Option<NodeRef>) -> Node {
+        Self {
+            kind,
+            loc,
+            ty: None,
+            init: None,
+            cond: None,
+            inc: None,
+            lhs,
+            rhs,
+            stmts: Vec::new(),
+        }
+    }
+
+    pub fn new_num(num: i64, loc: Loc) -> Node {
+        Self::new(NodeKind::Num(num), loc, None, None)
+    }
+
+    pub fn new_if(loc: Loc, cond: Node, then: Node, els: Option<Node>) -> Node {
+        Self {
+            kind: NodeKind::If,
+            loc,
+            ty: None,
+            init: None,
+            cond: cond.make_ref(),
+            inc: None,
+            lhs: then.make_ref(),
+            rhs: els.and_then(Node::make_ref),
+            stmts: Vec::new(),
+        }
+    }
+
+    pub fn new_while(loc: Loc, cond: Node, body: Node) -> Node {
+        Self {
+            kind: NodeKind::While,
+            loc,
+            ty: None,
+            init: None,
+            cond: cond.make_ref(),
+            inc: None,
+            lhs: body.make_ref(),
+            rhs: None,
+            stmts: Vec::new(),
+        }
+    }
+
+    pub fn new_do_while(loc: Loc, body: Node, cond: Node) -> Node {
+        Self {
+            kind: NodeKind::DoWhile,
+            loc,
+            ty: None,
+            init: None,
+            cond: cond.make_ref(),
+            inc: None,
+            lhs: body.make_ref(),
+            rhs: None,
+            stmts: Vec::new(),
+        }
+    }
+
+    pub fn new_block(loc: Loc, stmts: Vec<Node>) -> Node {
+        Self {
+            kind: NodeKind::Block,
+            loc,
+            ty: None,
+            init: None,
+            cond: None,
+            inc: None,
+            lhs: None,
+            rhs: None,
+            stmts: stmts.into_iter().map(Box::new).collect(),
+        }
     }
 
-    pub fn new_num(num: u64) -> Node {
+    pub fn new_switch(loc: Loc, cond: Node, arms: Vec<Node>) -> Node {
         Self {
-            kind: NodeKind::Num(num),
+            kind: NodeKind::Switch,
+            loc,
+            ty: None,
+            init: None,
+            cond: cond.make_ref(),
+            inc: None,
             lhs: None,
             rhs: None,
+            stmts: arms.into_iter().map(Box::new).collect(),
+        }
+    }
+
+    pub fn new_call(loc: Loc, name: Name, args: Vec<Node>) -> Node {
+        Self {
+            kind: NodeKind::Call { name },
+            loc,
+            ty: None,
+            init: None,
+            cond: None,
+            inc: None,
+            lhs: None,
+            rhs: None,
+            stmts: args.into_iter().map(Box::new).collect(),
+        }
+    }
+
+    pub fn new_function(loc: Loc, name: Name, params: Vec<Node>, body: Node) -> Node {
+        Self {
+            kind: NodeKind::Function { name },
+            loc,
+            ty: None,
+            init: None,
+            cond: None,
+            inc: None,
+            lhs: body.make_ref(),
+            rhs: None,
+            stmts: params.into_iter().map(Box::new).collect(),
+        }
+    }
+
+    /// Returns an arm of a switch, whose `kind` is `Case` or `Default`.
+    pub fn new_arm(kind: NodeKind, loc: Loc, stmts: Vec<Node>) -> Node {
+        Self {
+            kind,
+            loc,
+            ty: None,
+            init: None,
+            cond: None,
+            inc: None,
+            lhs: None,
+            rhs: None,
+            stmts: stmts.into_iter().map(Box::new).collect(),
+        }
+    }
+
+    pub fn new_for(
+        loc: Loc,
+        init: Option<Node>,
+        cond: Option<Node>,
+        inc: Option<Node>,
+        body: Node,
+    ) -> Node {
+        Self {
+            kind: NodeKind::For,
+            loc,
+            ty: None,
+            init: init.and_then(Node::make_ref),
+            cond: cond.and_then(Node::make_ref),
+            inc: inc.and_then(Node::make_ref),
+            lhs: body.make_ref(),
+            rhs: None,
+            stmts: Vec::new(),
         }
     }
 
     pub fn make_ref(self) -> Option<NodeRef> {
         Some(Box::new(self))
     }
+
+    /// Folds the tree bottom-up, calling `leaf` on nodes without children and `combine`
+    /// on the others with the results of their children, in the order of `children`.
+    ///
+    /// Runs iteratively, so that deep trees do not overflow the stack.
+    pub fn fold<T>(
+        &self,
+        leaf: impl FnMut(&Node) -> T,
+        combine: impl FnMut(&Node, Vec<T>) -> T,
+    ) -> T {
+        self.fold_pruned(|_| false, leaf, combine)
+    }
+
+    /// Same as `fold`, but also calls `leaf` on the nodes for which `prune` holds, leaving
+    /// their children to `leaf`.
+    pub fn fold_pruned<T>(
+        &self,
+        mut prune: impl FnMut(&Node) -> bool,
+        mut leaf: impl FnMut(&Node) -> T,
+        mut combine: impl FnMut(&Node, Vec<T>) -> T,
+    ) -> T {
+        let mut stack = vec![(self, false)];
+        let mut values: Vec<T> = Vec::new();
+        while let Some((node, visited)) = stack.pop() {
+            let count = children(node).count();
+            if count == 0 || !visited && prune(node) {
+                values.push(leaf(node));
+            } else if !visited {
+                stack.push((node, true));
+                let first = stack.len();
+                stack.extend(children(node).map(|child| (child, false)));
+                stack[first..].reverse();
+            } else {
+                let args = values.split_off(values.len() - count);
+                values.push(combine(node, args));
+            }
+        }
+        values.pop().unwrap()
+    }
+
+    /// Takes the children out of the node.
+    fn take_children(&mut self) -> impl Iterator<Item = NodeRef> {
+        self.init
+            .take()
+            .into_iter()
+            .chain(self.cond.take())
+            .chain(self.inc.take())
+            .chain(self.lhs.take())
+            .chain(self.rhs.take())
+            .chain(std::mem::take(&mut self.stmts))
+    }
+}
+
+impl Drop for Node {
+    /// Drops the children iteratively so that deep trees do not overflow the stack.
+    fn drop(&mut self) {
+        let mut stack: Vec<NodeRef> = self.take_children().collect();
+        while let Some(mut node) = stack.pop() {
+            stack.extend(node.take_children());
+        }
+    }
+}
+
+/// Returns the children of `node`: `init`, `cond`, `inc`, `lhs`, `rhs` and `stmts` in this
+/// order, if present.
+pub fn children(node: &Node) -> impl Iterator<Item = &Node> {
+    node.init
+        .iter()
+        .chain(node.cond.iter())
+        .chain(node.inc.iter())
+        .chain(node.lhs.iter())
+        .chain(node.rhs.iter())
+        .chain(node.stmts.iter())
+        .map(|child| child.as_ref())
 }
 
-/// expr    = equality
-fn expr<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Node>
+/// Returns whether nodes of `kind` run their `lhs` and then their `rhs`, like statements in
+/// sequence and binary operators.
+///
+/// Programs and expressions make long chains of them down the `lhs`, which passes go along
+/// in a loop rather than recursively, and which count once toward the depth of the tree.
+pub fn is_chained(kind: NodeKind) -> bool {
+    matches!(
+        kind,
+        NodeKind::Seq
+            | NodeKind::Add
+            | NodeKind::Sub
+            | NodeKind::Mul
+            | NodeKind::Div
+            | NodeKind::Eq
+            | NodeKind::Neq
+            | NodeKind::Lt
+            | NodeKind::Leq
+            | NodeKind::Gt
+            | NodeKind::Geq
+            | NodeKind::BitAnd
+            | NodeKind::BitOr
+            | NodeKind::BitXor
+            | NodeKind::Shl
+            | NodeKind::Shr
+            | NodeKind::LogAnd
+            | NodeKind::LogOr
+    )
+}
+
+/// Calls `f` on every node of the tree rooted at `node`, children before their parent,
+/// so that `f` can rewrite a node in place after its children are rewritten.
+pub fn walk_mut(node: &mut Node, f: &mut impl FnMut(&mut Node)) {
+    // Detaches the chain down the `lhs`, so that long chains do not recurse.
+    let mut chain: Vec<NodeRef> = Vec::new();
+    if is_chained(node.kind) {
+        loop {
+            let lhs = match chain.last_mut() {
+                Some(link) => &mut link.lhs,
+                None => &mut node.lhs,
+            };
+            match lhs.take() {
+                Some(link) if is_chained(link.kind) => chain.push(link),
+                other => {
+                    *lhs = other;
+                    break;
+                }
+            }
+        }
+    }
+    // Puts each link back from the bottom, before calling `f` on it.
+    let mut below = None;
+    while let Some(mut link) = chain.pop() {
+        walk_children(&mut link, f);
+        if below.is_some() {
+            link.lhs = below;
+        }
+        f(&mut link);
+        below = Some(link);
+    }
+    walk_children(node, f);
+    if below.is_some() {
+        node.lhs = below;
+    }
+    f(node);
+}
+
+/// Calls `walk_mut` on each child of `node`.
+fn walk_children(node: &mut Node, f: &mut impl FnMut(&mut Node)) {
+    if let Some(init) = node.init.as_mut() {
+        walk_mut(init, f);
+    }
+    if let Some(cond) = node.cond.as_mut() {
+        walk_mut(cond, f);
+    }
+    if let Some(inc) = node.inc.as_mut() {
+        walk_mut(inc, f);
+    }
+    if let Some(lhs) = node.lhs.as_mut() {
+        walk_mut(lhs, f);
+    }
+    if let Some(rhs) = node.rhs.as_mut() {
+        walk_mut(rhs, f);
+    }
+    for stmt in node.stmts.iter_mut() {
+        walk_mut(stmt, f);
+    }
+}
+
+/// Counts the nodes in the tree rooted at `node`.
+pub fn node_count(node: &Node) -> usize {
+    node.fold(|_| 1, |_, counts| counts.iter().sum::<usize>() + 1)
+}
+
+/// Returns how deeply `node` nests: the number of nodes on the longest path to a leaf,
+/// where a chain of `is_chained` nodes down the `lhs` counts as one.
+///
+/// Long programs and expressions are chains, so that only real nesting adds up, such as
+/// parentheses, nested statements and unary operators.
+pub fn ast_depth(node: &Node) -> usize {
+    node.fold(
+        |_| 1,
+        |node, mut depths| {
+            // The `lhs` comes first, as chained nodes have no other children before it.
+            if is_chained(node.kind) && node.lhs.as_ref().is_some_and(|lhs| is_chained(lhs.kind)) {
+                depths[0] -= 1;
+            }
+            depths.iter().max().unwrap() + 1
+        },
+    )
+}
+
+/// Compares the kinds and the shapes of two trees, ignoring the locations.
+pub fn structurally_eq(a: &Node, b: &Node) -> bool {
+    let mut stack = vec![(a, b)];
+    while let Some((a, b)) = stack.pop() {
+        if a.kind != b.kind
+            || a.init.is_some() != b.init.is_some()
+            || a.cond.is_some() != b.cond.is_some()
+            || a.inc.is_some() != b.inc.is_some()
+            || a.lhs.is_some() != b.lhs.is_some()
+            || a.rhs.is_some() != b.rhs.is_some()
+            || a.stmts.len() != b.stmts.len()
+        {
+            return false;
+        }
+        stack.extend(children(a).zip(children(b)));
+    }
+    true
+}
+
+/// Returns the location of the next token.
+fn peek_loc<Tokens>(tokens: &mut Tokens) -> Result<Loc>
+where
+    Tokens: TokenCursor,
+{
+    Ok(tokens.peek().context("Not peekable.")?.loc)
+}
+
+/// program = (function | stmt) (function | stmt)*
+///
+/// Fails on functions defined twice.
+fn program<Tokens>(tokens: &mut Tokens) -> Result<Node>
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: TokenCursor,
 {
-    let mut node = equality(tokens)?;
+    let mut defined = HashSet::new();
+    let mut node = top_level(&mut defined, tokens)?;
     loop {
-        if consume(TokenKind::Plus, tokens) {
-            node = Node::new(NodeKind::Add, node.make_ref(), equality(tokens)?.make_ref());
-        } else if consume(TokenKind::Minus, tokens) {
-            node = Node::new(NodeKind::Sub, node.make_ref(), equality(tokens)?.make_ref());
-        } else {
+        let loc = peek_loc(tokens)?;
+        if consume(TokenKind::Eof, tokens) {
             break;
         }
+        node = Node::new(
+            NodeKind::Seq,
+            loc,
+            node.make_ref(),
+            top_level(&mut defined, tokens)?.make_ref(),
+        );
     }
     Ok(node)
 }
 
-/// equality   = relational ("==" relational | "!=" relational)*
-fn equality<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Node>
+/// function = "void"? ident "(" (ident ("," ident)*)? ")" "{" stmt* "}"
+///
+/// Cursors may only look one token ahead, so a statement starting with a call is parsed
+/// as an expression first, and becomes a function if a block follows it. The names of
+/// the functions are added to `defined`, and the `ty` of a function is `void` if declared
+/// so, and `None` otherwise.
+fn top_level<Tokens>(defined: &mut HashSet<Name>, tokens: &mut Tokens) -> Result<Node>
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: TokenCursor,
 {
-    let mut node = relational(tokens)?;
-    loop {
-        if consume(TokenKind::Eq, tokens) {
-            node = Node::new(
-                NodeKind::Eq,
-                node.make_ref(),
-                relational(tokens)?.make_ref(),
-            );
-        } else if consume(TokenKind::Neq, tokens) {
-            node = Node::new(
-                NodeKind::Neq,
-                node.make_ref(),
-                relational(tokens)?.make_ref(),
-            );
-        } else {
-            break;
+    let returns_void = consume(TokenKind::Void, tokens);
+    if !matches!(
+        tokens.peek().map(|token| token.kind),
+        Some(TokenKind::Func(_))
+    ) {
+        if returns_void {
+            let message = "expected a function name".to_string();
+            return Err(CompileError::Parse(message, peek_loc(tokens)?).into());
         }
+        return stmt(tokens);
     }
+    let mut node = expr(tokens)?;
+    let loc = peek_loc(tokens)?;
+    if !consume(TokenKind::LBrace, tokens) {
+        if returns_void {
+            let message = "expected a function body".to_string();
+            return Err(CompileError::Parse(message, loc).into());
+        }
+        expect_at(
+            TokenKind::Semicolon,
+            "expected ';' after expression",
+            tokens,
+        )?;
+        return Ok(node);
+    }
+    let name = match node.kind {
+        NodeKind::Call { name } => name,
+        _ => {
+            let message = "expected ';' after expression".to_string();
+            return Err(CompileError::Parse(message, loc).into());
+        }
+    };
+    let mut params = HashSet::new();
+    for param in node.stmts.iter() {
+        let offset = match param.kind {
+            NodeKind::LVar { offset } => offset,
+            _ => {
+                let message = "expected a parameter name".to_string();
+                return Err(CompileError::Parse(message, param.loc).into());
+            }
+        };
+        if !params.insert(offset) {
+            let message = format!("duplicate parameter '{}'", lvar_name(offset));
+            return Err(CompileError::Parse(message, param.loc).into());
+        }
+    }
+    if !defined.insert(name) {
+        let message = format!("redefinition of '{}'", name);
+        return Err(CompileError::Parse(message, node.loc).into());
+    }
+    let body = block_stmt(loc, tokens)?;
+    let params = std::mem::take(&mut node.stmts);
+    let params = params.into_iter().map(|param| *param).collect();
+    let mut function = Node::new_function(node.loc, name, params, body);
+    if returns_void {
+        function.ty = Some(Ty::Void);
+    }
+    Ok(function)
+}
+
+/// stmt    = "if" "(" expr ")" stmt ("else" stmt)?
+///         | "while" "(" expr ")" stmt
+///         | "for" "(" expr? ";" expr? ";" expr? ")" stmt
+///         | "do" stmt "while" "(" expr ")" ";"
+///         | "{" stmt* "}"
+///         | "break" ";"
+///         | "continue" ";"
+///         | "switch" "(" expr ")" "{" arm* "}"
+///         | "return" expr? ";"
+///         | ("int" | "char" | "_Bool") "*"* ident ("=" assign)? ";"
+///         | expr ";"
+/// arm     = ("case" unary | "default") ":" stmt*
+///
+/// Each kind of statement has its own function, so that nested statements take little stack.
+fn stmt<Tokens>(tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    let loc = peek_loc(tokens)?;
+    if consume(TokenKind::If, tokens) {
+        return if_stmt(loc, tokens);
+    }
+    if consume(TokenKind::While, tokens) {
+        return while_stmt(loc, tokens);
+    }
+    if consume(TokenKind::For, tokens) {
+        return for_stmt(loc, tokens);
+    }
+    if consume(TokenKind::Do, tokens) {
+        return do_while_stmt(loc, tokens);
+    }
+    if consume(TokenKind::LBrace, tokens) {
+        return block_stmt(loc, tokens);
+    }
+    if consume(TokenKind::Break, tokens) {
+        return jump_stmt(TokenKind::Break, NodeKind::Break, loc, tokens);
+    }
+    if consume(TokenKind::Continue, tokens) {
+        return jump_stmt(TokenKind::Continue, NodeKind::Continue, loc, tokens);
+    }
+    if consume(TokenKind::Switch, tokens) {
+        return switch_stmt(loc, tokens);
+    }
+    if consume(TokenKind::Return, tokens) {
+        return return_stmt(loc, tokens);
+    }
+    if consume(TokenKind::Int, tokens) {
+        return decl_stmt(loc, Ty::Int, tokens);
+    }
+    if consume(TokenKind::Char, tokens) {
+        return decl_stmt(loc, Ty::Char, tokens);
+    }
+    if consume(TokenKind::Bool, tokens) {
+        return decl_stmt(loc, Ty::Bool, tokens);
+    }
+    expr_stmt(tokens)
+}
+
+/// Parses the rest of a declaration at `loc`, after the name of the type `base`.
+fn decl_stmt<Tokens>(loc: Loc, base: Ty, tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    let mut ty = base;
+    while consume(TokenKind::Mul, tokens) {
+        ty = Ty::pointer_to(ty);
+    }
+    let var_loc = peek_loc(tokens)?;
+    let name = consume_ident(tokens)
+        .ok_or_else(|| CompileError::Parse("expected a variable name".to_string(), var_loc))?;
+    let kind = NodeKind::LVar {
+        offset: lvar_offset(name),
+    };
+    let assign_loc = peek_loc(tokens)?;
+    let init = if consume(TokenKind::Assign, tokens) {
+        let target = Node::new(kind, var_loc, None, None);
+        let value = assign(tokens)?;
+        Node::new(
+            NodeKind::Assign,
+            assign_loc,
+            target.make_ref(),
+            value.make_ref(),
+        )
+        .make_ref()
+    } else {
+        None
+    };
+    expect_at(
+        TokenKind::Semicolon,
+        "expected ';' after the declaration",
+        tokens,
+    )?;
+    let mut var = Node::new(kind, var_loc, None, None);
+    var.ty = Some(ty);
+    Ok(Node::new(NodeKind::Decl, loc, var.make_ref(), init))
+}
+
+/// Parses the rest of a return statement at `loc`, after `return`.
+fn return_stmt<Tokens>(loc: Loc, tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    if consume(TokenKind::Semicolon, tokens) {
+        return Ok(Node::new(NodeKind::Return, loc, None, None));
+    }
+    let value = expr(tokens)?;
+    expect_at(TokenKind::Semicolon, "expected ';' after 'return'", tokens)?;
+    Ok(Node::new(NodeKind::Return, loc, value.make_ref(), None))
+}
+
+/// Parses the rest of the `keyword` statement at `loc` into a `kind` node, which is `;`.
+fn jump_stmt<Tokens>(
+    keyword: TokenKind,
+    kind: NodeKind,
+    loc: Loc,
+    tokens: &mut Tokens,
+) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    let message = format!("expected ';' after '{}'", keyword.text());
+    expect_at(TokenKind::Semicolon, &message, tokens)?;
+    Ok(Node::new(kind, loc, None, None))
+}
+
+/// Parses an expression statement, `expr ";"`.
+fn expr_stmt<Tokens>(tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    let node = expr(tokens)?;
+    expect_at(
+        TokenKind::Semicolon,
+        "expected ';' after expression",
+        tokens,
+    )?;
     Ok(node)
 }
 
-/// relational = add ("<" add | "<=" add | ">" add | ">=" add)*
-fn relational<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Node>
+/// Expects a token of `kind`, failing with `message` at the token found instead.
+fn expect_at<Tokens>(kind: TokenKind, message: &str, tokens: &mut Tokens) -> Result<()>
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: TokenCursor,
 {
-    let mut node = add(tokens)?;
-    loop {
-        if consume(TokenKind::Lt, tokens) {
-            node = Node::new(NodeKind::Lt, node.make_ref(), add(tokens)?.make_ref());
-        } else if consume(TokenKind::Leq, tokens) {
-            node = Node::new(NodeKind::Leq, node.make_ref(), add(tokens)?.make_ref());
-        } else if consume(TokenKind::Gt, tokens) {
-            node = Node::new(NodeKind::Gt, node.make_ref(), add(tokens)?.make_ref());
-        } else if consume(TokenKind::Geq, tokens) {
-            node = Node::new(NodeKind::Geq, node.make_ref(), add(tokens)?.make_ref());
-        } else {
-            break;
+    let loc = peek_loc(tokens)?;
+    if !consume(kind, tokens) {
+        return Err(CompileError::Parse(message.to_string(), loc).into());
+    }
+    Ok(())
+}
+
+/// Parses the rest of an if statement at `loc`, after `if`.
+fn if_stmt<Tokens>(loc: Loc, tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    let cond = paren_expr(tokens)?.make_ref();
+    let then = nested_stmt(tokens)?;
+    // An `else` binds to the nearest `if`, which is the innermost one still parsing.
+    let mut els = None;
+    if consume(TokenKind::Else, tokens) {
+        els = Some(nested_stmt(tokens)?);
+    }
+    // Keeps the statements boxed instead of going through `new_if`, which moves them
+    // through the frame recursing on them.
+    Ok(Node {
+        kind: NodeKind::If,
+        loc,
+        ty: None,
+        init: None,
+        cond,
+        inc: None,
+        lhs: Some(then),
+        rhs: els,
+        stmts: Vec::new(),
+    })
+}
+
+/// Parses the rest of a while statement at `loc`, after `while`.
+fn while_stmt<Tokens>(loc: Loc, tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    let cond = paren_expr(tokens)?;
+    let body = *nested_stmt(tokens)?;
+    Ok(Node::new_while(loc, cond, body))
+}
+
+/// Parses the rest of a for statement at `loc`, after `for`.
+fn for_stmt<Tokens>(loc: Loc, tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    expect_at(TokenKind::LParen, "expected '(' after 'for'", tokens)?;
+    let init = optional_expr(
+        TokenKind::Semicolon,
+        "expected ';' after the initializer",
+        tokens,
+    )?;
+    let cond = optional_expr(
+        TokenKind::Semicolon,
+        "expected ';' after the condition",
+        tokens,
+    )?;
+    let inc = optional_expr(
+        TokenKind::RParen,
+        "expected ')' after the increment",
+        tokens,
+    )?;
+    let body = *nested_stmt(tokens)?;
+    Ok(Node::new_for(loc, init, cond, inc, body))
+}
+
+/// Parses the rest of a do-while statement at `loc`, after `do`.
+fn do_while_stmt<Tokens>(loc: Loc, tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    let body = *nested_stmt(tokens)?;
+    expect_at(
+        TokenKind::While,
+        "expected 'while' after the body of 'do'",
+        tokens,
+    )?;
+    let cond = paren_expr(tokens)?;
+    expect_at(TokenKind::Semicolon, "expected ';' after do-while", tokens)?;
+    Ok(Node::new_do_while(loc, body, cond))
+}
+
+/// Parses the rest of a block at `loc`, after `{`.
+fn block_stmt<Tokens>(loc: Loc, tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    let mut stmts = Vec::new();
+    while !consume(TokenKind::RBrace, tokens) {
+        let token = tokens.peek().context("Not peekable.")?;
+        if token.kind == TokenKind::Eof {
+            return Err(CompileError::Parse("expected '}'".to_string(), token.loc).into());
         }
+        stmts.push(*nested_stmt(tokens)?);
+    }
+    Ok(Node::new_block(loc, stmts))
+}
+
+/// Parses the rest of a switch statement at `loc`, after `switch`.
+fn switch_stmt<Tokens>(loc: Loc, tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    let cond = paren_expr(tokens)?;
+    expect_at(
+        TokenKind::LBrace,
+        "expected '{' after the condition of 'switch'",
+        tokens,
+    )?;
+    let mut arms = Vec::new();
+    while !consume(TokenKind::RBrace, tokens) {
+        arms.push(switch_arm(tokens)?);
     }
+    Ok(Node::new_switch(loc, cond, arms))
+}
+
+/// Parses an arm of a switch, up to the next arm or the end of the switch.
+fn switch_arm<Tokens>(tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    let loc = peek_loc(tokens)?;
+    let kind = if consume(TokenKind::Case, tokens) {
+        let value = unary(tokens)?;
+        match value.kind {
+            NodeKind::Num(num) => NodeKind::Case(num),
+            _ => {
+                let message = "case label is not a number".to_string();
+                return Err(CompileError::Parse(message, value.loc).into());
+            }
+        }
+    } else if consume(TokenKind::Default, tokens) {
+        NodeKind::Default
+    } else {
+        let message = "expected 'case' or 'default'".to_string();
+        return Err(CompileError::Parse(message, loc).into());
+    };
+    expect_at(TokenKind::Colon, "expected ':' after the label", tokens)?;
+    let mut stmts = Vec::new();
+    while !matches!(
+        tokens.peek().map(|token| token.kind),
+        Some(TokenKind::Case) | Some(TokenKind::Default) | Some(TokenKind::RBrace) | None
+    ) {
+        stmts.push(*nested_stmt(tokens)?);
+    }
+    Ok(Node::new_arm(kind, loc, stmts))
+}
+
+/// Parses `expr? end`, returning the expression if any, or failing with `message`
+/// without `end`.
+fn optional_expr<Tokens>(end: TokenKind, message: &str, tokens: &mut Tokens) -> Result<Option<Node>>
+where
+    Tokens: TokenCursor,
+{
+    if consume(end, tokens) {
+        return Ok(None);
+    }
+    let node = expr(tokens)?;
+    expect_at(end, message, tokens)?;
+    Ok(Some(node))
+}
+
+/// Parses a statement nested in another one, which counts towards the nesting limit.
+///
+/// The statement is boxed, so that the frames recursing on nested statements stay small.
+fn nested_stmt<Tokens>(tokens: &mut Tokens) -> Result<NodeRef>
+where
+    Tokens: TokenCursor,
+{
+    tokens.enter();
+    let node = stmt(tokens).map(Box::new);
+    tokens.leave();
+    node
+}
+
+/// Parses the condition of a statement, `"(" expr ")"`.
+fn paren_expr<Tokens>(tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    expect_at(
+        TokenKind::LParen,
+        "expected '(' before the condition",
+        tokens,
+    )?;
+    let node = expr(tokens)?;
+    expect_at(
+        TokenKind::RParen,
+        "expected ')' after the condition",
+        tokens,
+    )?;
     Ok(node)
 }
 
-/// add        = mul ("+" mul | "-" mul)*
-fn add<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Node>
+/// expr    = assign
+fn expr<Tokens>(tokens: &mut Tokens) -> Result<Node>
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: TokenCursor,
 {
-    let mut node = mul(tokens)?;
+    assign(tokens)
+}
+
+/// Returns whether `node` is a variable or a dereference, which have an address.
+fn is_lvalue(node: &Node) -> bool {
+    matches!(node.kind, NodeKind::LVar { .. } | NodeKind::Deref)
+}
+
+/// assign  = logor ("=" assign)?
+fn assign<Tokens>(tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    // Collects the left sides first, so that long chains do not recurse.
+    let mut lvalues = Vec::new();
+    let mut node = binary(0, tokens)?;
     loop {
-        if consume(TokenKind::Plus, tokens) {
-            node = Node::new(NodeKind::Add, node.make_ref(), mul(tokens)?.make_ref());
-        } else if consume(TokenKind::Minus, tokens) {
-            node = Node::new(NodeKind::Sub, node.make_ref(), mul(tokens)?.make_ref());
-        } else {
+        let loc = peek_loc(tokens)?;
+        if !consume(TokenKind::Assign, tokens) {
             break;
         }
+        if !is_lvalue(&node) {
+            let message = "left side of assignment is not an lvalue".to_string();
+            return Err(CompileError::Parse(message, loc).into());
+        }
+        lvalues.push((node, loc));
+        node = binary(0, tokens)?;
     }
-    return Ok(node);
+    while let Some((lvalue, loc)) = lvalues.pop() {
+        node = Node::new(NodeKind::Assign, loc, lvalue.make_ref(), node.make_ref());
+    }
+    Ok(node)
 }
 
-/// mul     = unary ("*" unary | "/" unary)*
-fn mul<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Node>
+/// Binary operators by precedence level, loosest first. All of them associate left.
+///
+/// logor      = logand ("||" logand)*
+/// logand     = bitor ("&&" bitor)*
+/// bitor      = bitxor ("|" bitxor)*
+/// bitxor     = bitand ("^" bitand)*
+/// bitand     = equality ("&" equality)*
+/// equality   = relational ("==" relational | "!=" relational)*
+/// relational = shift ("<" shift | "<=" shift | ">" shift | ">=" shift)*
+/// shift      = add ("<<" add | ">>" add)*
+/// add        = mul ("+" mul | "-" mul)*
+/// mul        = unary ("*" unary | "/" unary)*
+const BINARY_LEVELS: [&[(TokenKind, NodeKind)]; 10] = [
+    &[(TokenKind::OrOr, NodeKind::LogOr)],
+    &[(TokenKind::AndAnd, NodeKind::LogAnd)],
+    &[(TokenKind::Pipe, NodeKind::BitOr)],
+    &[(TokenKind::Caret, NodeKind::BitXor)],
+    &[(TokenKind::Amp, NodeKind::BitAnd)],
+    &[
+        (TokenKind::Eq, NodeKind::Eq),
+        (TokenKind::Neq, NodeKind::Neq),
+    ],
+    &[
+        (TokenKind::Lt, NodeKind::Lt),
+        (TokenKind::Leq, NodeKind::Leq),
+        (TokenKind::Gt, NodeKind::Gt),
+        (TokenKind::Geq, NodeKind::Geq),
+    ],
+    &[
+        (TokenKind::Shl, NodeKind::Shl),
+        (TokenKind::Shr, NodeKind::Shr),
+    ],
+    &[
+        (TokenKind::Plus, NodeKind::Add),
+        (TokenKind::Minus, NodeKind::Sub),
+    ],
+    &[
+        (TokenKind::Mul, NodeKind::Mul),
+        (TokenKind::Div, NodeKind::Div),
+    ],
+];
+
+/// Returns the node kind and level of a binary operator.
+fn binary_op(kind: TokenKind) -> Option<(NodeKind, usize)> {
+    BINARY_LEVELS.iter().enumerate().find_map(|(level, ops)| {
+        ops.iter()
+            .find(|(token, _)| *token == kind)
+            .map(|(_, node)| (*node, level))
+    })
+}
+
+/// Parses the operators of `level` and tighter by precedence climbing, so that
+/// the stack grows with the levels used rather than with all of them.
+fn binary<Tokens>(level: usize, tokens: &mut Tokens) -> Result<Node>
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: TokenCursor,
 {
     let mut node = unary(tokens)?;
     loop {
-        if consume(TokenKind::Mul, tokens) {
-            node = Node::new(NodeKind::Mul, node.make_ref(), unary(tokens)?.make_ref());
-        } else if consume(TokenKind::Div, tokens) {
-            node = Node::new(NodeKind::Div, node.make_ref(), unary(tokens)?.make_ref());
-        } else {
-            break;
+        let loc = peek_loc(tokens)?;
+        let op = consume_map(tokens, |kind| {
+            binary_op(kind).filter(|(_, op_level)| *op_level >= level)
+        });
+        match op {
+            Some((kind, op_level)) => {
+                let rhs = binary(op_level + 1, tokens)?;
+                node = Node::new(kind, loc, node.make_ref(), rhs.make_ref());
+            }
+            None => break,
         }
     }
-    return Ok(node);
+    Ok(node)
 }
 
-/// unary = ("+" | "-")? primary
-fn unary<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Node>
+/// Magnitude of `i64::MIN`, the only literal out of range which is accepted, after `-`.
+const I64_MIN_MAGNITUDE: u64 = 1 << 63;
+
+/// unary = ("+" | "-") unary
+///       | "&" unary
+///       | "*" unary
+///       | "!" unary
+///       | "~" unary
+///       | primary
+fn unary<Tokens>(tokens: &mut Tokens) -> Result<Node>
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: TokenCursor,
 {
-    if consume(TokenKind::Plus, tokens) {
-        primary(tokens)
+    let loc = peek_loc(tokens)?;
+    if consume(TokenKind::Amp, tokens) {
+        let operand = nested_unary(tokens)?;
+        if !is_lvalue(&operand) {
+            let message = "operand of '&' is not an lvalue".to_string();
+            return Err(CompileError::Parse(message, loc).into());
+        }
+        Ok(Node::new(NodeKind::Addr, loc, operand.make_ref(), None))
+    } else if let Some(kind) = consume_map(tokens, |kind| match kind {
+        TokenKind::Mul => Some(NodeKind::Deref),
+        TokenKind::Not => Some(NodeKind::Not),
+        TokenKind::Tilde => Some(NodeKind::BitNot),
+        _ => None,
+    }) {
+        Ok(Node::new(kind, loc, nested_unary(tokens)?.make_ref(), None))
+    } else if consume(TokenKind::Plus, tokens) {
+        nested_unary(tokens)
     } else if consume(TokenKind::Minus, tokens) {
+        // Folds into a negative literal, which may be i64::MIN.
+        if let Some(TokenKind::Num(num)) = tokens.peek().map(|token| token.kind) {
+            if num <= I64_MIN_MAGNITUDE {
+                tokens.advance();
+                return Ok(Node::new_num((num as i64).wrapping_neg(), loc));
+            }
+        }
         let node = Node::new(
             NodeKind::Sub,
-            Node::new_num(0).make_ref(),
-            primary(tokens)?.make_ref(),
+            loc,
+            Node::new_num(0, loc).make_ref(),
+            nested_unary(tokens)?.make_ref(),
         );
         Ok(node)
     } else {
@@ -167,31 +1110,967 @@ where
     }
 }
 
-/// primary = num | "(" expr ")"
-fn primary<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Node>
+/// Parses the operand of a unary operator, which counts towards the nesting limit.
+fn nested_unary<Tokens>(tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    tokens.enter();
+    let node = unary(tokens);
+    tokens.leave();
+    node
+}
+
+/// Parses the rest of a call of `name` at `loc`, `"(" (assign ("," assign)*)? ")"`.
+fn call<Tokens>(name: Name, loc: Loc, tokens: &mut Tokens) -> Result<Node>
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: TokenCursor,
 {
+    expect_at(
+        TokenKind::LParen,
+        "expected '(' after the function name",
+        tokens,
+    )?;
+    let mut args = Vec::new();
+    if !consume(TokenKind::RParen, tokens) {
+        loop {
+            args.push(assign(tokens)?);
+            if consume(TokenKind::RParen, tokens) {
+                break;
+            }
+            expect_at(
+                TokenKind::Comma,
+                "expected ',' or ')' after the argument",
+                tokens,
+            )?;
+        }
+    }
+    Ok(Node::new_call(loc, name, args))
+}
+
+/// primary = num | ident | ident "(" (assign ("," assign)*)? ")" | "(" expr ")"
+fn primary<Tokens>(tokens: &mut Tokens) -> Result<Node>
+where
+    Tokens: TokenCursor,
+{
+    let loc = peek_loc(tokens)?;
     let node = if consume(TokenKind::LParen, tokens) {
         let node = expr(tokens)?;
-        expect(TokenKind::RParen, tokens)?;
+        expect_at(TokenKind::RParen, "expected ')'", tokens)?;
         node
+    } else if let Some(name) = consume_func(tokens) {
+        call(name, loc, tokens)?
+    } else if let Some(name) = consume_ident(tokens) {
+        let kind = NodeKind::LVar {
+            offset: lvar_offset(name),
+        };
+        Node::new(kind, loc, None, None)
     } else {
-        let num = expect_number(tokens)?;
-        Node::new_num(num)
+        let num = match tokens.peek().context("Not peekable.")?.kind {
+            TokenKind::Num(num) => num,
+            _ => {
+                let message = "expected an expression".to_string();
+                return Err(CompileError::Parse(message, loc).into());
+            }
+        };
+        tokens.advance();
+        if num > i64::MAX as u64 {
+            let message = "integer literal out of range".to_string();
+            return Err(CompileError::Parse(message, loc).into());
+        }
+        Node::new_num(num as i64, loc)
     };
     Ok(node)
 }
 
 /// Parses tokens into AST.
-pub fn parse_into_ast<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<Node>
+pub fn parse_into_ast<Tokens>(tokens: &mut Tokens) -> Result<Node>
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: TokenCursor,
 {
-    let node = expr(tokens)?;
-    let token = tokens.peek().unwrap();
-    if token.kind != TokenKind::Eof {
-        return Err(anyhow!(format!("Unexpected token {:?}", token)));
+    let mut node = program(tokens)?;
+    add_types(&mut node)?;
+    Ok(node)
+}
+
+/// Parses a token slice into AST, within the default limits.
+pub fn parse_slice(tokens: &[Token]) -> Result<Node> {
+    parse_slice_with_limits(tokens, &ResourceLimits::default())
+}
+
+/// Same as `parse_slice`, failing when `limits` are exceeded.
+pub fn parse_slice_with_limits(tokens: &[Token], limits: &ResourceLimits) -> Result<Node> {
+    let mut cursor = LimitedCursor::new(SliceCursor::new(tokens), limits);
+    let res = parse_into_ast(&mut cursor);
+    if let Some(err) = cursor.take_error() {
+        return Err(err);
     }
+    let node = res?;
+    limits.check_ast(&node)?;
     Ok(node)
 }
+
+/// Parses `input` into AST, tokenizing it lazily instead of collecting all the tokens first.
+pub fn parse_streaming(input: &str) -> Result<Node> {
+    parse_streaming_with_limits(input, &ResourceLimits::default())
+}
+
+/// Same as `parse_streaming`, failing when `limits` are exceeded.
+pub fn parse_streaming_with_limits(input: &str, limits: &ResourceLimits) -> Result<Node> {
+    limits.check_input(input)?;
+    parse_stream_with_limits(TokenStream::new(input), limits)
+}
+
+/// Parses the tokens of `stream` into AST, failing when `limits` are exceeded.
+pub fn parse_stream_with_limits(mut stream: TokenStream, limits: &ResourceLimits) -> Result<Node> {
+    let mut cursor = LimitedCursor::new(stream.by_ref().peekable(), limits);
+    let res = parse_into_ast(&mut cursor).and_then(|node| {
+        limits.check_ast(&node)?;
+        Ok(node)
+    });
+    if let Some(err) = cursor.take_error() {
+        return Err(err);
+    }
+    if res.is_err() {
+        // A tokenize error takes precedence, as if the input was tokenized up front.
+        stream.by_ref().for_each(drop);
+    }
+    match stream.take_error() {
+        Some(err) => Err(err),
+        None => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error_at;
+    use crate::parse::*;
+
+    /// Parses `input` without typing it, so that variables need not be declared.
+    fn parse_grammar(input: &str) -> Result<Node> {
+        program(&mut TokenStream::new(input).peekable())
+    }
+
+    #[test]
+    fn test_parse_slice() -> Result<()> {
+        for input in [
+            "1;",
+            "2 * (1+23) - 456 / 7 <= 3;",
+            "-(1 == 2) != +3 > 4; 5;",
+        ]
+        .iter()
+        {
+            let tokens = tokenize(input)?;
+            let expected = parse_into_ast(&mut tokens.iter().copied().peekable())?;
+            assert!(structurally_eq(&parse_slice(&tokens)?, &expected));
+        }
+        assert!(parse_slice(&tokenize("1 +;")?).is_err());
+        assert!(parse_slice(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_structurally_eq() -> Result<()> {
+        let a = parse_streaming("1+2;")?;
+        let b = parse_streaming(" 1 + 2;")?;
+        assert!(structurally_eq(&a, &b));
+        assert!(a != b);
+
+        assert!(!structurally_eq(&a, &parse_streaming("1-2;")?));
+        assert!(!structurally_eq(&a, &parse_streaming("1+2+3;")?));
+        assert!(!structurally_eq(&a, &parse_streaming("(1+2)*1;")?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fold() -> Result<()> {
+        let root = parse_grammar("1+2*3;")?;
+        assert_eq!(root.fold(|_| 1, |_, counts| counts[0] + counts[1] + 1), 5);
+
+        let value = root.fold(
+            |node| match node.kind {
+                NodeKind::Num(num) => num,
+                _ => unreachable!(),
+            },
+            |node, values| match node.kind {
+                NodeKind::Add => values[0] + values[1],
+                NodeKind::Mul => values[0] * values[1],
+                _ => unreachable!(),
+            },
+        );
+        assert_eq!(value, 7);
+
+        // Unary minus on a non-literal is a subtraction from 0.
+        let sexpr = parse_grammar("-(1);")?.fold(
+            |node| format!("{:?}", node.kind),
+            |node, children| format!("({:?} {})", node.kind, children.join(" ")),
+        );
+        assert_eq!(sexpr, "(Sub Num(0) Num(1))");
+
+        // Conditions come first.
+        let sexpr = parse_grammar("if (a) b;")?.fold(
+            |node| format!("{:?}", node.kind),
+            |node, children| format!("({:?} {})", node.kind, children.join(" ")),
+        );
+        assert_eq!(sexpr, "(If LVar { offset: 8 } LVar { offset: 16 })");
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_mut() -> Result<()> {
+        // Replaces `x + 0` and `0 + x` by `x`.
+        let mut root = parse_grammar("(a + 0) * (0 + (b + 0));")?;
+        walk_mut(&mut root, &mut |node| {
+            if node.kind != NodeKind::Add {
+                return;
+            }
+            let is_zero = |child: &Option<NodeRef>| {
+                child.as_ref().map(|child| child.kind) == Some(NodeKind::Num(0))
+            };
+            let other = if is_zero(&node.rhs) {
+                node.lhs.take()
+            } else if is_zero(&node.lhs) {
+                node.rhs.take()
+            } else {
+                return;
+            };
+            if let Some(mut other) = other {
+                node.kind = other.kind;
+                node.loc = other.loc;
+                node.lhs = other.lhs.take();
+                node.rhs = other.rhs.take();
+            }
+        });
+        assert!(structurally_eq(&root, &parse_grammar("a * b;")?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_streaming() -> Result<()> {
+        let input = "2 * (1+23) - 456 / 7 <= 3;";
+        let tokens = tokenize(input)?;
+        let expected = parse_into_ast(&mut tokens.into_iter().peekable())?;
+        assert!(structurally_eq(&parse_streaming(input)?, &expected));
+
+        let err = parse_streaming("1 + ) $;").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<crate::CompileError>(),
+            Some(crate::CompileError::Tokenize(_, _))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_program() -> Result<()> {
+        let root = parse_streaming("1+2; 3*4;")?;
+        assert_eq!(root.kind, NodeKind::Seq);
+        assert_eq!(root.loc, Loc { line: 1, col: 6 });
+        assert_eq!(root.rhs.as_ref().context("No rhs")?.kind, NodeKind::Mul);
+        assert_eq!(tokenize("1+2; 3*4;")?.len(), 9);
+        assert_eq!(node_count(&root), 7);
+
+        for (input, col) in [("1+2; 3*4", 9), ("1 2;", 3)].iter() {
+            assert_eq!(
+                error_at(parse_streaming(input)).1,
+                Loc { line: 1, col: *col },
+                "{}",
+                input
+            );
+        }
+        assert!(parse_streaming("").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_if() -> Result<()> {
+        let root = parse_grammar("if (a < 1) b = 2; c;")?;
+        let stmt = root.lhs.as_ref().context("No lhs")?;
+        assert_eq!(stmt.kind, NodeKind::If);
+        assert_eq!(stmt.loc, Loc { line: 1, col: 1 });
+        assert_eq!(stmt.cond.as_ref().context("No cond")?.kind, NodeKind::Lt);
+        assert_eq!(stmt.lhs.as_ref().context("No lhs")?.kind, NodeKind::Assign);
+        assert!(stmt.rhs.is_none());
+        assert_eq!(node_count(&root), 9);
+
+        for input in ["if a < 1 b;", "if (a < 1 b;", "if (a) ;", "if (a)"].iter() {
+            assert!(parse_grammar(input).is_err(), "{}", input);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_while() -> Result<()> {
+        let root = parse_grammar("while (a < 10) a = a + 1;")?;
+        assert_eq!(root.kind, NodeKind::While);
+        assert_eq!(root.cond.as_ref().context("No cond")?.kind, NodeKind::Lt);
+        assert_eq!(root.lhs.as_ref().context("No lhs")?.kind, NodeKind::Assign);
+        assert!(root.rhs.is_none());
+
+        for input in ["while a b;", "while (a) ;", "while (a) b; else c;"].iter() {
+            assert!(parse_grammar(input).is_err(), "{}", input);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_for() -> Result<()> {
+        let root = parse_grammar("for (i = 0; i < 5; i = i + 1) s = s + i;")?;
+        assert_eq!(root.kind, NodeKind::For);
+        assert_eq!(
+            root.init.as_ref().context("No init")?.kind,
+            NodeKind::Assign
+        );
+        assert_eq!(root.cond.as_ref().context("No cond")?.kind, NodeKind::Lt);
+        assert_eq!(root.inc.as_ref().context("No inc")?.kind, NodeKind::Assign);
+        assert_eq!(root.lhs.as_ref().context("No lhs")?.kind, NodeKind::Assign);
+        assert_eq!(node_count(&root), 17);
+
+        let root = parse_grammar("for (;;) 1;")?;
+        assert!(root.init.is_none() && root.cond.is_none() && root.inc.is_none());
+        assert_eq!(children(&root).count(), 1);
+
+        // Each clause is optional on its own.
+        let root = parse_grammar("for (i = 0;; i = i + 1) 1;")?;
+        assert!(root.init.is_some() && root.cond.is_none() && root.inc.is_some());
+        let root = parse_grammar("for (; i < 3;) 1;")?;
+        assert!(root.init.is_none() && root.cond.is_some() && root.inc.is_none());
+
+        for input in [
+            "for (;) a;",
+            "for (a; b) c;",
+            "for a; b; c d;",
+            "for (;;;) a;",
+        ]
+        .iter()
+        {
+            assert!(parse_grammar(input).is_err(), "{}", input);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_do_while() -> Result<()> {
+        let root = parse_grammar("do i = i + 1; while (i < 3);")?;
+        assert_eq!(root.kind, NodeKind::DoWhile);
+        assert_eq!(root.lhs.as_ref().context("No lhs")?.kind, NodeKind::Assign);
+        assert_eq!(root.cond.as_ref().context("No cond")?.kind, NodeKind::Lt);
+
+        for (input, message, col) in [
+            ("do a; while (a)", "expected ';' after do-while", 16),
+            ("do a; while (a) b;", "expected ';' after do-while", 17),
+            ("do a; b;", "expected 'while' after the body of 'do'", 7),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                error_at(parse_grammar(input)),
+                (message.to_string(), Loc { line: 1, col: *col }),
+                "{}",
+                input
+            );
+        }
+        assert!(parse_grammar("do while (a);").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_block() -> Result<()> {
+        let root = parse_grammar("{ a = 1; if (a) { b = 2; } }")?;
+        assert_eq!(root.kind, NodeKind::Block);
+        assert_eq!(root.stmts.len(), 2);
+        assert_eq!(root.stmts[0].kind, NodeKind::Assign);
+        let then = root.stmts[1].lhs.as_ref().context("No lhs")?;
+        assert_eq!(then.kind, NodeKind::Block);
+        assert_eq!(then.loc, Loc { line: 1, col: 17 });
+        assert_eq!(children(&root).count(), 2);
+        assert_eq!(node_count(&root), 10);
+
+        assert!(parse_grammar("{}")?.stmts.is_empty());
+        for input in ["{ a; ", "{ a }", "a; }", "{ a; }}"].iter() {
+            assert!(parse_grammar(input).is_err(), "{}", input);
+        }
+
+        let root = parse_grammar("{ 1; { 2; } }")?;
+        assert_eq!(root.stmts[0].kind, NodeKind::Num(1));
+        let inner = &root.stmts[1];
+        assert_eq!(inner.kind, NodeKind::Block);
+        assert_eq!(inner.stmts[0].kind, NodeKind::Num(2));
+        for (input, col) in [("{ 1; { 2; }", 12), ("{", 2), ("f() { 1;", 9)].iter() {
+            assert_eq!(
+                error_at(parse_grammar(input)),
+                ("expected '}'".to_string(), Loc { line: 1, col: *col }),
+                "{}",
+                input
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_break() -> Result<()> {
+        let root = parse_streaming("while (1) break;")?;
+        let body = root.lhs.as_ref().context("No lhs")?;
+        assert_eq!(body.kind, NodeKind::Break);
+        assert_eq!(body.loc, Loc { line: 1, col: 11 });
+        assert_eq!(children(body).count(), 0);
+        // Parsed anywhere, since codegen reports it outside of loops.
+        assert_eq!(parse_streaming("break;")?.kind, NodeKind::Break);
+
+        let err = parse_streaming("while (1) break").unwrap_err();
+        assert_eq!(err.to_string(), "expected ';' after 'break'");
+        assert!(parse_streaming("break = 1;").is_err());
+
+        let root = parse_streaming("while (1) continue;")?;
+        assert_eq!(
+            root.lhs.as_ref().context("No lhs")?.kind,
+            NodeKind::Continue
+        );
+        let err = parse_streaming("while (1) continue 1;").unwrap_err();
+        assert_eq!(err.to_string(), "expected ';' after 'continue'");
+        Ok(())
+    }
+
+    #[test]
+    fn test_return() -> Result<()> {
+        let root = parse_grammar("a = 1; return a + 2;")?;
+        let ret = root.rhs.as_ref().context("No rhs")?;
+        assert_eq!(ret.kind, NodeKind::Return);
+        assert_eq!(ret.loc, Loc { line: 1, col: 8 });
+        assert_eq!(ret.lhs.as_ref().context("No lhs")?.kind, NodeKind::Add);
+        assert_eq!(children(ret).count(), 1);
+
+        // The value is checked against the function when typing.
+        let ret = parse_grammar("return;")?;
+        assert_eq!(ret.kind, NodeKind::Return);
+        assert_eq!(children(&ret).count(), 0);
+
+        for (input, message, col) in [
+            ("return 1", "expected ';' after 'return'", 9),
+            ("return +;", "expected an expression", 9),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                error_at(parse_grammar(input)),
+                (message.to_string(), Loc { line: 1, col: *col }),
+                "{}",
+                input
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_decl() -> Result<()> {
+        let root = parse_streaming("int **p;")?;
+        assert_eq!(root.kind, NodeKind::Decl);
+        let var = root.lhs.as_ref().context("No lhs")?;
+        assert_eq!(
+            var.kind,
+            NodeKind::LVar {
+                offset: lvar_offset('p')
+            }
+        );
+        assert_eq!(var.loc, Loc { line: 1, col: 7 });
+        assert_eq!(var.ty, Some(Ty::pointer_to(Ty::pointer_to(Ty::Int))));
+        assert_eq!(children(&root).count(), 1);
+
+        let root = parse_streaming("char *s;")?;
+        let var = root.lhs.as_ref().context("No lhs")?;
+        assert_eq!(var.ty, Some(Ty::pointer_to(Ty::Char)));
+
+        let root = parse_streaming("_Bool b;")?;
+        let var = root.lhs.as_ref().context("No lhs")?;
+        assert_eq!(var.ty, Some(Ty::Bool));
+
+        // The initializer is an assignment to the variable.
+        let root = parse_streaming("int a = 1; int *p = &a;")?;
+        let decl = root.rhs.as_ref().context("No rhs")?;
+        let init = decl.rhs.as_ref().context("No rhs")?;
+        assert_eq!(init.kind, NodeKind::Assign);
+        assert_eq!(init.loc, Loc { line: 1, col: 19 });
+        assert_eq!(init.ty, Some(Ty::pointer_to(Ty::Int)));
+        assert_eq!(init.rhs.as_ref().context("No rhs")?.kind, NodeKind::Addr);
+        assert_eq!(children(decl).count(), 2);
+
+        for (input, message, col) in [
+            ("int;", "expected a variable name", 4),
+            ("char 1;", "expected a variable name", 6),
+            ("int 1;", "expected a variable name", 5),
+            ("int * ;", "expected a variable name", 7),
+            ("int a", "expected ';' after the declaration", 6),
+            ("int a = 1", "expected ';' after the declaration", 10),
+            ("int a = ;", "expected an expression", 9),
+            ("a = 3;", "use of undeclared variable 'a'", 1),
+            ("int a; a + b;", "use of undeclared variable 'b'", 12),
+            ("int a; f(b) { a; }", "use of undeclared variable 'a'", 15),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                error_at(parse_streaming(input)),
+                (message.to_string(), Loc { line: 1, col: *col }),
+                "{}",
+                input
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_addr_deref() -> Result<()> {
+        let root = parse_grammar("*&a;")?;
+        assert_eq!(root.kind, NodeKind::Deref);
+        let addr = root.lhs.as_ref().context("No lhs")?;
+        assert_eq!(addr.kind, NodeKind::Addr);
+        assert_eq!(addr.loc, Loc { line: 1, col: 2 });
+        assert_eq!(children(addr).count(), 1);
+
+        // `*` right after an operand is still a multiplication.
+        let root = parse_grammar("a * *b;")?;
+        assert_eq!(root.kind, NodeKind::Mul);
+        assert_eq!(root.rhs.as_ref().context("No rhs")?.kind, NodeKind::Deref);
+
+        // Dereferences can be assigned to.
+        let root = parse_grammar("*(a + 1) = 2;")?;
+        assert_eq!(root.kind, NodeKind::Assign);
+        assert_eq!(root.lhs.as_ref().context("No lhs")?.kind, NodeKind::Deref);
+
+        for (input, message, col) in [
+            ("&1;", "operand of '&' is not an lvalue", 1),
+            ("&(a + 1);", "operand of '&' is not an lvalue", 1),
+            ("& &a;", "operand of '&' is not an lvalue", 1),
+            ("&&a;", "expected an expression", 1),
+            ("*;", "expected an expression", 2),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                error_at(parse_grammar(input)),
+                (message.to_string(), Loc { line: 1, col: *col }),
+                "{}",
+                input
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_not() -> Result<()> {
+        let root = parse_grammar("!!a == 1;")?;
+        assert_eq!(root.kind, NodeKind::Eq);
+        let not = root.lhs.as_ref().context("No lhs")?;
+        assert_eq!(not.kind, NodeKind::Not);
+        assert_eq!(not.loc, Loc { line: 1, col: 1 });
+        let inner = not.lhs.as_ref().context("No lhs")?;
+        assert_eq!(inner.kind, NodeKind::Not);
+        assert_eq!(inner.loc, Loc { line: 1, col: 2 });
+        assert_eq!(children(inner).count(), 1);
+
+        // `!=` is still a comparison.
+        let root = parse_grammar("a != !b;")?;
+        assert_eq!(root.kind, NodeKind::Neq);
+        assert_eq!(root.rhs.as_ref().context("No rhs")?.kind, NodeKind::Not);
+
+        let err = parse_grammar("!;").unwrap_err();
+        assert_eq!(err.to_string(), "expected an expression");
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitwise() -> Result<()> {
+        // a | (b ^ (c & (d == e)))
+        let root = parse_grammar("a | b ^ c & d == e;")?;
+        assert_eq!(root.kind, NodeKind::BitOr);
+        assert_eq!(root.loc, Loc { line: 1, col: 3 });
+        let xor = root.rhs.as_ref().context("No rhs")?;
+        assert_eq!(xor.kind, NodeKind::BitXor);
+        let and = xor.rhs.as_ref().context("No rhs")?;
+        assert_eq!(and.kind, NodeKind::BitAnd);
+        assert_eq!(and.rhs.as_ref().context("No rhs")?.kind, NodeKind::Eq);
+
+        // `&` after an operand is the bitwise and, and before one takes the address.
+        let root = parse_grammar("a & &b;")?;
+        assert_eq!(root.kind, NodeKind::BitAnd);
+        assert_eq!(root.rhs.as_ref().context("No rhs")?.kind, NodeKind::Addr);
+
+        // (a << b) < (c >> (d + 1))
+        let root = parse_grammar("a << b < c >> d + 1;")?;
+        assert_eq!(root.kind, NodeKind::Lt);
+        assert_eq!(root.lhs.as_ref().context("No lhs")?.kind, NodeKind::Shl);
+        let shr = root.rhs.as_ref().context("No rhs")?;
+        assert_eq!(shr.kind, NodeKind::Shr);
+        assert_eq!(shr.loc, Loc { line: 1, col: 12 });
+        assert_eq!(shr.rhs.as_ref().context("No rhs")?.kind, NodeKind::Add);
+
+        // (a || (b && (c | d))) || e
+        let root = parse_grammar("a || b && c | d || e;")?;
+        assert_eq!(root.kind, NodeKind::LogOr);
+        assert_eq!(root.loc, Loc { line: 1, col: 17 });
+        let or = root.lhs.as_ref().context("No lhs")?;
+        assert_eq!(or.kind, NodeKind::LogOr);
+        let and = or.rhs.as_ref().context("No rhs")?;
+        assert_eq!(and.kind, NodeKind::LogAnd);
+        assert_eq!(and.rhs.as_ref().context("No rhs")?.kind, NodeKind::BitOr);
+
+        // (a == ((b * c) + d)) != e
+        let root = parse_grammar("a == b * c + d != e;")?;
+        assert_eq!(root.kind, NodeKind::Neq);
+        let eq = root.lhs.as_ref().context("No lhs")?;
+        assert_eq!(eq.kind, NodeKind::Eq);
+        let add = eq.rhs.as_ref().context("No rhs")?;
+        assert_eq!(add.kind, NodeKind::Add);
+        assert_eq!(add.lhs.as_ref().context("No lhs")?.kind, NodeKind::Mul);
+
+        let root = parse_grammar("~~a;")?;
+        assert_eq!(root.kind, NodeKind::BitNot);
+        assert_eq!(root.lhs.as_ref().context("No lhs")?.kind, NodeKind::BitNot);
+        Ok(())
+    }
+
+    #[test]
+    fn test_function() -> Result<()> {
+        let root = parse_streaming("sub(a, b) { return a - b; } sub(7, 3);")?;
+        let function = root.lhs.as_ref().context("No lhs")?;
+        let name = Name::new("sub").context("Not a name")?;
+        assert_eq!(function.kind, NodeKind::Function { name });
+        assert_eq!(function.loc, Loc { line: 1, col: 1 });
+        let params: Vec<NodeKind> = function.stmts.iter().map(|param| param.kind).collect();
+        assert_eq!(
+            params,
+            vec![
+                NodeKind::LVar {
+                    offset: lvar_offset('a')
+                },
+                NodeKind::LVar {
+                    offset: lvar_offset('b')
+                },
+            ]
+        );
+        assert_eq!(
+            function.lhs.as_ref().context("No body")?.kind,
+            NodeKind::Block
+        );
+        assert_eq!(
+            root.rhs.as_ref().context("No rhs")?.kind,
+            NodeKind::Call { name }
+        );
+
+        // Calls followed by something else are still expressions.
+        assert_eq!(parse_streaming("f() + 1;")?.kind, NodeKind::Add);
+        assert_eq!(parse_streaming("f() {}")?.stmts.len(), 0);
+
+        for (input, message, col) in [
+            ("f(1) {}", "expected a parameter name", 3),
+            ("f(a, b + 1) {}", "expected a parameter name", 8),
+            ("f(a, a) {}", "duplicate parameter 'a'", 6),
+            ("f() {} f(a) {}", "redefinition of 'f'", 8),
+            ("f() + 1 {}", "expected ';' after expression", 9),
+            ("f(a) { a;", "expected '}'", 10),
+            ("f(a)", "expected ';' after expression", 5),
+            ("void a;", "expected a function name", 6),
+            ("void f();", "expected a function body", 9),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                error_at(parse_streaming(input)),
+                (message.to_string(), Loc { line: 1, col: *col }),
+                "{}",
+                input
+            );
+        }
+
+        // Definitions are only parsed at the top level.
+        assert!(parse_streaming("{ f() {} }").is_err());
+        assert!(parse_streaming("{ void f() {} }").is_err());
+
+        let root = parse_streaming("void f(p) { *p = 1; return; }")?;
+        assert_eq!(root.ty, Some(Ty::Void));
+        let body = root.lhs.as_ref().context("No body")?;
+        let ret = body.stmts.last().context("No return")?;
+        assert_eq!(ret.kind, NodeKind::Return);
+        assert_eq!(children(ret).count(), 0);
+        assert_eq!(parse_streaming("f() {}")?.ty, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_switch() -> Result<()> {
+        let root = parse_grammar("switch (a) { case 1: b; c; case -2: default: d; }")?;
+        assert_eq!(root.kind, NodeKind::Switch);
+        assert_eq!(
+            root.cond.as_ref().context("No cond")?.kind,
+            NodeKind::LVar { offset: 8 }
+        );
+        let arms: Vec<(NodeKind, usize)> = root
+            .stmts
+            .iter()
+            .map(|arm| (arm.kind, arm.stmts.len()))
+            .collect();
+        assert_eq!(
+            arms,
+            vec![
+                (NodeKind::Case(1), 2),
+                (NodeKind::Case(-2), 0),
+                (NodeKind::Default, 1)
+            ]
+        );
+        assert_eq!(root.stmts[1].loc, Loc { line: 1, col: 28 });
+        assert!(parse_grammar("switch (a) {}")?.stmts.is_empty());
+
+        for (input, message, col) in [
+            ("switch (a) { case b: }", "case label is not a number", 19),
+            ("switch (a) { b; }", "expected 'case' or 'default'", 14),
+            (
+                "switch (a) { case 1 b; }",
+                "expected ':' after the label",
+                21,
+            ),
+            (
+                "switch (a) b;",
+                "expected '{' after the condition of 'switch'",
+                12,
+            ),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                error_at(parse_grammar(input)),
+                (message.to_string(), Loc { line: 1, col: *col }),
+                "{}",
+                input
+            );
+        }
+        assert!(parse_grammar("switch (a) { case 1: b;").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_call() -> Result<()> {
+        let root = parse_grammar("a = foo () + 1;")?;
+        let call = root
+            .rhs
+            .as_ref()
+            .and_then(|rhs| rhs.lhs.as_ref())
+            .context("No call")?;
+        let name = Name::new("foo").context("Not a name")?;
+        assert_eq!(call.kind, NodeKind::Call { name });
+        assert_eq!(call.loc, Loc { line: 1, col: 5 });
+        assert_eq!(children(call).count(), 0);
+
+        // Arguments are assignments, in order.
+        let root = parse_grammar("f(a = 1, 2 * 3, g());")?;
+        let args: Vec<NodeKind> = root.stmts.iter().map(|arg| arg.kind).collect();
+        let g = Name::new("g").context("Not a name")?;
+        assert_eq!(
+            args,
+            vec![NodeKind::Assign, NodeKind::Mul, NodeKind::Call { name: g }]
+        );
+        assert_eq!(root.stmts[1].loc, Loc { line: 1, col: 12 });
+
+        for (input, message, col) in [
+            ("foo(;", "expected an expression", 5),
+            ("foo(", "expected an expression", 5),
+            ("foo(1;", "expected ',' or ')' after the argument", 6),
+            ("foo(1,);", "expected an expression", 7),
+            ("foo(1 2);", "expected ',' or ')' after the argument", 7),
+            ("(1 + 2;", "expected ')'", 7),
+            ("(1 + 2", "expected ')'", 7),
+            ("1 +", "expected an expression", 4),
+            ("1 + );", "expected an expression", 5),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                error_at(parse_grammar(input)),
+                (message.to_string(), Loc { line: 1, col: *col }),
+                "{}",
+                input
+            );
+        }
+
+        // A trailing operator is a parse error at the end of the input.
+        let err = parse_grammar("1 +").unwrap_err();
+        match err.downcast_ref::<CompileError>() {
+            Some(CompileError::Parse(message, loc)) => {
+                assert_eq!(message, "expected an expression");
+                assert_eq!(*loc, Loc { line: 1, col: 4 });
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_statement_syntax_errors() {
+        for (input, message, col) in [
+            ("if (1 2;", "expected ')' after the condition", 7),
+            (
+                "if (1 < 1 return 2;",
+                "expected ')' after the condition",
+                11,
+            ),
+            ("while 1;", "expected '(' before the condition", 7),
+            ("for a;", "expected '(' after 'for'", 5),
+            ("for (a = 0 a;", "expected ';' after the initializer", 12),
+            ("for (; a < 1 a;", "expected ';' after the condition", 14),
+            ("for (;; a = 1 {}", "expected ')' after the increment", 15),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                error_at(parse_grammar(input)),
+                (message.to_string(), Loc { line: 1, col: *col }),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_else() -> Result<()> {
+        let root = parse_grammar("if (a) b; else if (c) d; else e;")?;
+        assert_eq!(root.kind, NodeKind::If);
+        let els = root.rhs.as_ref().context("No rhs")?;
+        assert_eq!(els.kind, NodeKind::If);
+        assert_eq!(els.loc, Loc { line: 1, col: 16 });
+        assert!(els.rhs.is_some());
+
+        // An `else` binds to the nearest `if`.
+        let root = parse_grammar("if (a) if (b) c; else d;")?;
+        assert!(root.rhs.is_none());
+        assert!(root.lhs.as_ref().context("No lhs")?.rhs.is_some());
+
+        for input in [
+            "else a;",
+            "if (a) b else c;",
+            "if (a) b; else;",
+            "if (a) b; else",
+        ]
+        .iter()
+        {
+            assert!(parse_grammar(input).is_err(), "{}", input);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_assign() -> Result<()> {
+        let root = parse_grammar("a = b = 3;")?;
+        assert_eq!(root.kind, NodeKind::Assign);
+        assert_eq!(root.loc, Loc { line: 1, col: 3 });
+        let rhs = root.rhs.as_ref().context("No rhs")?;
+        assert_eq!(rhs.kind, NodeKind::Assign);
+        assert_eq!(rhs.rhs.as_ref().context("No rhs")?.kind, NodeKind::Num(3));
+
+        assert_eq!(parse_grammar("a == 3;")?.kind, NodeKind::Eq);
+        assert_eq!(parse_grammar("*a = 3;")?.kind, NodeKind::Assign);
+        for (input, col) in [
+            ("1 = 2;", 3),
+            ("a + 1 = 2;", 7),
+            ("a = 1 = 2;", 7),
+            ("&a = 2;", 4),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                error_at(parse_grammar(input)),
+                (
+                    "left side of assignment is not an lvalue".to_string(),
+                    Loc { line: 1, col: *col }
+                ),
+                "{}",
+                input
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_negative_literal() -> Result<()> {
+        let num = |num| Node::new_num(num, Loc { line: 1, col: 1 }).make_ref();
+        let sub = |lhs, rhs| Node::new(NodeKind::Sub, Loc { line: 1, col: 1 }, lhs, rhs);
+        let var = || {
+            Node::new(
+                NodeKind::LVar { offset: 24 },
+                Loc { line: 1, col: 1 },
+                None,
+                None,
+            )
+            .make_ref()
+        };
+        for (input, expected) in vec![
+            ("-5;", *num(-5).unwrap()),
+            ("-(5);", sub(num(0), num(5))),
+            ("-c;", sub(num(0), var())),
+            ("3--5;", sub(num(3), num(-5))),
+            ("- -5;", sub(num(0), num(-5))),
+            ("+ -5;", *num(-5).unwrap()),
+            ("- -c;", sub(num(0), sub(num(0), var()).make_ref())),
+        ]
+        .into_iter()
+        {
+            assert!(
+                structurally_eq(&parse_grammar(input)?, &expected),
+                "{}",
+                input
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_unary_operand() -> Result<()> {
+        for (input, kind) in [
+            ("-*p;", NodeKind::Deref),
+            ("-!1;", NodeKind::Not),
+            ("-~1;", NodeKind::BitNot),
+        ]
+        .iter()
+        {
+            let node = parse_grammar(input)?;
+            assert_eq!(node.kind, NodeKind::Sub, "{}", input);
+            assert_eq!(
+                node.rhs.as_ref().context("No rhs")?.kind,
+                *kind,
+                "{}",
+                input
+            );
+        }
+        assert_eq!(parse_grammar("!-1;")?.kind, NodeKind::Not);
+        assert_eq!(parse_grammar("+*p;")?.kind, NodeKind::Deref);
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal_range() -> Result<()> {
+        let min = parse_streaming("-9223372036854775808;")?;
+        assert_eq!(min.kind, NodeKind::Num(i64::MIN));
+        assert_eq!(
+            crate::eval::eval(&parse_streaming("9223372036854775807;")?)?,
+            i64::MAX
+        );
+
+        for (input, col) in [
+            ("9223372036854775808;", 1),
+            ("1 + 9223372036854775808;", 5),
+            ("-(9223372036854775808);", 3),
+            ("18446744073709551615;", 1),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                error_at(parse_streaming(input)),
+                (
+                    "integer literal out of range".to_string(),
+                    Loc { line: 1, col: *col }
+                ),
+                "{}",
+                input
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use std::io::{self, BufRead, Write};
+
+use crate::eval::eval;
+use crate::parse::parse_slice;
+use crate::token::{dump_tokens, tokenize, RenderOptions};
+use crate::unparse::sexpr;
+
+const HELP: &str = "\
+:ast     toggle printing the AST of each line
+:tokens  toggle printing the tokens of each line
+:help    show this help
+:quit    exit";
+
+/// Interactive evaluator reading one line of statements at a time.
+#[derive(Debug, Default)]
+pub struct Repl {
+    show_ast: bool,
+    show_tokens: bool,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads lines from `input` until EOF or `:quit`.
+    pub fn run(&mut self, input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        write!(out, "> ")?;
+        out.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.starts_with(':') {
+                if !self.run_command(line, out)? {
+                    return Ok(());
+                }
+            } else if !line.is_empty() {
+                self.run_expr(line, out)?;
+            }
+            write!(out, "> ")?;
+            out.flush()?;
+        }
+        writeln!(out)
+    }
+
+    /// Runs a meta-command, returning false to quit.
+    fn run_command(&mut self, command: &str, out: &mut impl Write) -> io::Result<bool> {
+        match command {
+            ":ast" => {
+                self.show_ast = !self.show_ast;
+                writeln!(out, "ast: {}", if self.show_ast { "on" } else { "off" })?;
+            }
+            ":tokens" => {
+                self.show_tokens = !self.show_tokens;
+                writeln!(
+                    out,
+                    "tokens: {}",
+                    if self.show_tokens { "on" } else { "off" }
+                )?;
+            }
+            ":help" => writeln!(out, "{}", HELP)?,
+            ":quit" => return Ok(false),
+            _ => writeln!(out, "unknown command {} (try :help)", command)?,
+        }
+        Ok(true)
+    }
+
+    fn run_expr(&self, line: &str, out: &mut impl Write) -> io::Result<()> {
+        let tokens = match tokenize(line) {
+            Ok(tokens) => tokens,
+            Err(err) => return writeln!(out, "error: {}", err),
+        };
+        if self.show_tokens {
+            dump_tokens(&tokens, RenderOptions::default(), out)?;
+        }
+        let root = match parse_slice(&tokens) {
+            Ok(root) => root,
+            Err(err) => return writeln!(out, "error: {}", err),
+        };
+        if self.show_ast {
+            writeln!(out, "{}", sexpr(&root))?;
+        }
+        match eval(&root) {
+            Ok(value) => writeln!(out, "{}", value),
+            Err(err) => writeln!(out, "error: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repl::*;
+
+    fn run(lines: &[&str]) -> String {
+        let mut out = Vec::new();
+        Repl::new()
+            .run(lines.join("\n").as_bytes(), &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_repl() {
+        let out = run(&[":tokens", "1+2;", ":tokens", ":ast", "2*3;", "1/0;", "1"]);
+        assert!(out.contains("1:2\tPlus\n"));
+        assert!(out.contains("\n3\n"));
+        assert_eq!(out.matches("Plus").count(), 1);
+        assert!(out.contains("(* 2 3)\n6\n"));
+        assert!(out.contains("error: division by zero"));
+        assert!(out.contains("error: expected ';' after expression"));
+    }
+
+    #[test]
+    fn test_repl_commands() {
+        let out = run(&[":help", ":foo", ":quit", "1+2;"]);
+        assert!(out.contains(":tokens"));
+        assert!(out.contains("unknown command :foo"));
+        assert!(!out.contains('3'));
+    }
+}
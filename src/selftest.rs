@@ -0,0 +1,280 @@
+//! Differential testing against the system C compiler.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::compile_to_asm;
+
+/// Small xorshift generator, so that runs are reproducible from a seed.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Xorshift gets stuck at zero.
+        Rng(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
+
+/// Source of the random choices `gen_expr` makes.
+///
+/// Property-based tests replay recorded choices, so that shrinking the choices
+/// shrinks the expression.
+pub trait Choices {
+    /// Returns a number in `0..n`.
+    fn below(&mut self, n: u64) -> u64;
+}
+
+impl Choices for Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Neq,
+    Lt,
+    Leq,
+    Gt,
+    Geq,
+}
+
+const OPS: [Op; 10] = [
+    Op::Add,
+    Op::Sub,
+    Op::Mul,
+    Op::Div,
+    Op::Eq,
+    Op::Neq,
+    Op::Lt,
+    Op::Leq,
+    Op::Gt,
+    Op::Geq,
+];
+
+impl Op {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::Eq => "==",
+            Op::Neq => "!=",
+            Op::Lt => "<",
+            Op::Leq => "<=",
+            Op::Gt => ">",
+            Op::Geq => ">=",
+        }
+    }
+}
+
+/// Expression built independently of the compiler's own AST.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Num(u64),
+    Neg(Box<Expr>),
+    Bin(Op, Box<Expr>, Box<Expr>),
+}
+
+/// Prints the expression fully parenthesized.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Num(num) => write!(f, "{}", num),
+            Expr::Neg(expr) => write!(f, "-({})", expr),
+            Expr::Bin(op, lhs, rhs) => write!(f, "({} {} {})", lhs, op.symbol(), rhs),
+        }
+    }
+}
+
+/// Generates a random expression of at most `depth` levels together with its value.
+///
+/// Every intermediate value fits in a 32-bit `int` and no division is by zero,
+/// so that the expression has no undefined behavior in C. A choice of zero always
+/// picks the simplest expression.
+pub fn gen_expr(choices: &mut impl Choices, depth: u32) -> (Expr, i64) {
+    if depth > 0 && choices.below(4) != 0 {
+        // Retries until the constraints hold, falling back to a literal.
+        for _ in 0..8 {
+            if let Some(expr) = try_gen_expr(choices, depth) {
+                return expr;
+            }
+        }
+    }
+    let num = choices.below(100);
+    (Expr::Num(num), num as i64)
+}
+
+fn try_gen_expr(choices: &mut impl Choices, depth: u32) -> Option<(Expr, i64)> {
+    let fits = |value: i64| -> Option<i64> {
+        if i32::MIN as i64 <= value && value <= i32::MAX as i64 {
+            Some(value)
+        } else {
+            None
+        }
+    };
+    let (lhs, l) = gen_expr(choices, depth - 1);
+    let op = choices.below(OPS.len() as u64 + 1) as usize;
+    if op == OPS.len() {
+        return Some((Expr::Neg(Box::new(lhs)), fits(-l)?));
+    }
+    let (rhs, r) = gen_expr(choices, depth - 1);
+    let value = match OPS[op] {
+        Op::Add => l + r,
+        Op::Sub => l - r,
+        Op::Mul => l.checked_mul(r)?,
+        Op::Div => l.checked_div(r)?,
+        Op::Eq => (l == r) as i64,
+        Op::Neq => (l != r) as i64,
+        Op::Lt => (l < r) as i64,
+        Op::Leq => (l <= r) as i64,
+        Op::Gt => (l > r) as i64,
+        Op::Geq => (l >= r) as i64,
+    };
+    let expr = Expr::Bin(OPS[op], Box::new(lhs), Box::new(rhs));
+    Some((expr, fits(value)?))
+}
+
+/// Settings of a differential test run.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub iterations: usize,
+    pub seed: u64,
+    /// Directory to write mismatching expressions to.
+    pub dump_dir: PathBuf,
+}
+
+impl Config {
+    /// Reads `RUST9CC_SELFTEST_ITERATIONS`, `RUST9CC_SELFTEST_SEED` and
+    /// `RUST9CC_SELFTEST_DIR`, falling back to defaults.
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        Config {
+            iterations: var("RUST9CC_SELFTEST_ITERATIONS", 200) as usize,
+            seed: var("RUST9CC_SELFTEST_SEED", 14),
+            dump_dir: env::var_os("RUST9CC_SELFTEST_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| env::temp_dir().join("rust9cc-mismatches")),
+        }
+    }
+}
+
+/// Expression whose executables exited differently.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub expr: String,
+    pub expected: i32,
+    pub actual: i32,
+}
+
+/// Compares rust9cc against `cc` on random expressions.
+///
+/// Sources and assemblies of mismatching expressions are written to the dump directory.
+pub fn run(config: &Config) -> Result<Vec<Mismatch>> {
+    let (seed, dump_dir) = (config.seed, &config.dump_dir);
+    let work = env::temp_dir().join(format!("rust9cc-selftest-{}", std::process::id()));
+    fs::create_dir_all(&work)?;
+    let mut rng = Rng::new(seed);
+    let mut mismatches = Vec::new();
+    for i in 0..config.iterations {
+        let expr = gen_expr(&mut rng, 4).0.to_string();
+        let c_src = work.join("expr.c");
+        fs::write(&c_src, format!("int main(){{return ({});}}\n", expr))?;
+        let asm_src = work.join("expr.s");
+        fs::write(&asm_src, compile_to_asm(&format!("{};", expr))?)?;
+
+        let expected = build_and_run(&c_src, &work.join("expected"))?;
+        let actual = build_and_run(&asm_src, &work.join("actual"))?;
+        if expected != actual {
+            fs::create_dir_all(dump_dir)?;
+            let base = dump_dir.join(format!("mismatch-{}-{}", seed, i));
+            fs::copy(&c_src, base.with_extension("c"))?;
+            fs::copy(&asm_src, base.with_extension("s"))?;
+            let cc_asm: PathBuf = base.with_extension("cc.s");
+            Command::new("cc")
+                .args(["-S", "-masm=intel", "-o"])
+                .arg(&cc_asm)
+                .arg(&c_src)
+                .status()?;
+            mismatches.push(Mismatch {
+                expr,
+                expected,
+                actual,
+            });
+        }
+    }
+    fs::remove_dir_all(&work)?;
+    Ok(mismatches)
+}
+
+/// Builds `src` with `cc` into `exe`, runs it and returns the exit status.
+fn build_and_run(src: &Path, exe: &Path) -> Result<i32> {
+    let output = Command::new("cc").arg("-o").arg(exe).arg(src).output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "cc failed on {}: {}",
+            src.display(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Command::new(exe)
+        .status()?
+        .code()
+        .context("Killed by signal.")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::eval::eval;
+    use crate::parse::parse_streaming;
+    use crate::selftest::*;
+
+    #[test]
+    fn test_gen_expr() -> Result<()> {
+        let mut rng = Rng::new(14);
+        for _ in 0..1000 {
+            let (expr, value) = gen_expr(&mut rng, 4);
+            assert_eq!(
+                eval(&parse_streaming(&format!("{};", expr))?)?,
+                value,
+                "{}",
+                expr
+            );
+        }
+
+        // Zero choices pick a literal.
+        struct Zeros;
+        impl Choices for Zeros {
+            fn below(&mut self, _: u64) -> u64 {
+                0
+            }
+        }
+        assert_eq!(gen_expr(&mut Zeros, 4), (Expr::Num(0), 0));
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+
+use crate::parse::{children, Node, NodeKind};
+use crate::{CompileError, CompileOptions, ARG_REGS};
+
+/// Where a node is, which decides the statements it may hold.
+#[derive(Debug, Copy, Clone, Default)]
+struct Context {
+    in_loop: bool,
+    in_switch: bool,
+    in_function: bool,
+}
+
+/// Rejects the programs which parse but cannot be compiled, so that checking a program
+/// fails whenever compiling it does.
+///
+/// These are jumps out of nothing, calls and functions with more arguments than there are
+/// registers to pass them, `return` without an entry function to return from, and switches
+/// with two arms for the same value or two defaults.
+pub fn check_semantics(node: &Node, options: &CompileOptions) -> Result<()> {
+    let mut stack = vec![(node, Context::default())];
+    while let Some((node, context)) = stack.pop() {
+        let loc = node.loc;
+        let error = |message: String| Err(CompileError::Semantic(message, loc).into());
+        let mut inner = context;
+        match node.kind {
+            NodeKind::Break if !context.in_loop && !context.in_switch => {
+                return error("'break' outside of a loop".to_string());
+            }
+            NodeKind::Continue if !context.in_loop => {
+                return error("'continue' outside of a loop".to_string());
+            }
+            NodeKind::Return if !context.in_function && options.entry.is_none() => {
+                return error("'return' needs an entry function".to_string());
+            }
+            NodeKind::Call { .. } if node.stmts.len() > ARG_REGS.len() => {
+                let message = format!(
+                    "calls with more than {} arguments are not supported",
+                    ARG_REGS.len()
+                );
+                return Err(CompileError::Semantic(message, node.stmts[ARG_REGS.len()].loc).into());
+            }
+            NodeKind::Function { .. } => {
+                if let Some(param) = node.stmts.get(ARG_REGS.len()) {
+                    let message = format!(
+                        "functions with more than {} parameters are not supported",
+                        ARG_REGS.len()
+                    );
+                    return Err(CompileError::Semantic(message, param.loc).into());
+                }
+                inner = Context {
+                    in_function: true,
+                    ..Context::default()
+                };
+            }
+            NodeKind::While | NodeKind::For | NodeKind::DoWhile => inner.in_loop = true,
+            NodeKind::Switch => {
+                check_arms(node)?;
+                inner.in_switch = true;
+            }
+            _ => (),
+        }
+        // Pushed in reverse, so that the first error in the source is reported.
+        let mut nested: Vec<(&Node, Context)> =
+            children(node).map(|child| (child, inner)).collect();
+        nested.reverse();
+        stack.extend(nested);
+    }
+    Ok(())
+}
+
+/// Fails on arms of the switch `node` labeled with the same value, and on more than one
+/// `default`.
+fn check_arms(node: &Node) -> Result<()> {
+    let mut values = HashSet::new();
+    let mut has_default = false;
+    for arm in node.stmts.iter() {
+        let message = match arm.kind {
+            NodeKind::Case(value) if !values.insert(value) => {
+                format!("duplicate case value {}", value)
+            }
+            NodeKind::Default if std::mem::replace(&mut has_default, true) => {
+                "multiple default labels in one switch".to_string()
+            }
+            _ => continue,
+        };
+        return Err(CompileError::Semantic(message, arm.loc).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error_at;
+    use crate::parse::parse_streaming;
+    use crate::sema::*;
+    use crate::token::Loc;
+
+    /// Checks `input` with `options`, returning the error message and location.
+    fn check_err(input: &str, options: &CompileOptions) -> (String, Loc) {
+        error_at(check_semantics(&parse_streaming(input).unwrap(), options))
+    }
+
+    #[test]
+    fn test_check_semantics() -> Result<()> {
+        let options = CompileOptions::default();
+        for input in [
+            "while (1) { if (1) break; continue; }",
+            "switch (1) { case 1: break; }",
+            "int i; for (;;) switch (i) { default: continue; }",
+            "f(a, b, c, d, e, g) { return g; } f(1, 2, 3, 4, 5, 6);",
+            "return 1;",
+        ]
+        .iter()
+        {
+            check_semantics(&parse_streaming(input)?, &options)?;
+        }
+
+        let at = |line, col| Loc { line, col };
+        for (input, message, loc) in [
+            ("1;\nbreak;", "'break' outside of a loop", at(2, 1)),
+            (
+                "switch (1) { case 1: continue; }",
+                "'continue' outside of a loop",
+                at(1, 22),
+            ),
+            (
+                "while (1) 1; f() { break; }",
+                "'break' outside of a loop",
+                at(1, 20),
+            ),
+            (
+                "g(1, 2, 3, 4, 5, 6, 7);",
+                "calls with more than 6 arguments are not supported",
+                at(1, 21),
+            ),
+            (
+                "f(a, b, c, d, e, g, h) { return a; }",
+                "functions with more than 6 parameters are not supported",
+                at(1, 21),
+            ),
+            (
+                "int a; switch (a) { case 1: case 1: }",
+                "duplicate case value 1",
+                at(1, 29),
+            ),
+            (
+                "int a; switch (a) { default: default: }",
+                "multiple default labels in one switch",
+                at(1, 30),
+            ),
+        ]
+        .iter()
+        {
+            let (actual, actual_loc) = check_err(input, &options);
+            assert_eq!(actual, *message, "{}", input);
+            assert_eq!(actual_loc, *loc, "{}", input);
+        }
+
+        // Without an entry function, only the functions defined can return.
+        let options = CompileOptions {
+            entry: None,
+            ..CompileOptions::default()
+        };
+        check_semantics(&parse_streaming("f() { return 1; } 2;")?, &options)?;
+        let (message, loc) = check_err("1;\nif (1) return 2;", &options);
+        assert_eq!(message, "'return' needs an entry function");
+        assert_eq!(loc, at(2, 8));
+        Ok(())
+    }
+}
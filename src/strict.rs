@@ -0,0 +1,148 @@
+use anyhow::{Context, Result};
+
+use crate::eval::{apply_binop, apply_unop, Overflow};
+use crate::parse::{children, is_chained, Node, NodeKind};
+
+/// Rejects constructs whose behavior depends on the implementation.
+///
+/// Constant subtrees are checked for overflows including `i64::MIN / -1`, and division
+/// by zero.
+pub fn check_strict(node: &Node) -> Result<()> {
+    constant(node)?;
+    Ok(())
+}
+
+/// Returns the value of `node` if it is a constant.
+fn constant(node: &Node) -> Result<Option<i64>> {
+    if let NodeKind::Num(num) = node.kind {
+        return Ok(Some(num));
+    }
+    if let NodeKind::LVar { .. } | NodeKind::Break | NodeKind::Continue = node.kind {
+        return Ok(None);
+    }
+    if let NodeKind::Not | NodeKind::BitNot = node.kind {
+        let operand = constant(
+            node.lhs
+                .as_ref()
+                .context("Expect non null lhs, but is null.")?,
+        )?;
+        return operand
+            .map(|operand| apply_unop(node.kind, operand))
+            .transpose();
+    }
+    if matches!(
+        node.kind,
+        NodeKind::If
+            | NodeKind::While
+            | NodeKind::For
+            | NodeKind::DoWhile
+            | NodeKind::Block
+            | NodeKind::Switch
+            | NodeKind::Case(_)
+            | NodeKind::Default
+            | NodeKind::Call { .. }
+            | NodeKind::Function { .. }
+            | NodeKind::Return
+            | NodeKind::Addr
+            | NodeKind::Deref
+            | NodeKind::Decl
+    ) {
+        for child in children(node) {
+            constant(child)?;
+        }
+        return Ok(None);
+    }
+
+    // Goes down the chain of operators on the left in a loop, so that long expressions do
+    // not overflow the stack.
+    let mut chain = vec![node];
+    let mut first = node
+        .lhs
+        .as_deref()
+        .context("Expect non null lhs, but is null.")?;
+    while is_chained(first.kind) {
+        chain.push(first);
+        first = first
+            .lhs
+            .as_deref()
+            .context("Expect non null lhs, but is null.")?;
+    }
+    let mut value = constant(first)?;
+    for node in chain.into_iter().rev() {
+        let rhs = constant(
+            node.rhs
+                .as_ref()
+                .context("Expect non null rhs, but is null.")?,
+        )?;
+        value = match (value, rhs) {
+            (Some(lhs), Some(rhs)) => Some(apply_binop(
+                node.kind,
+                lhs,
+                rhs,
+                node.loc,
+                Overflow::Checked,
+            )?),
+            _ => None,
+        };
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::parse_streaming;
+    use crate::strict::*;
+    use crate::token::Loc;
+    use crate::{compile_with_options, error_at, CompileOptions};
+
+    #[test]
+    fn test_check_strict() -> Result<()> {
+        assert!(check_strict(&parse_streaming("2*(1+23)-456/7;")?).is_ok());
+        assert!(check_strict(&parse_streaming("int a; if (a) 1;")?).is_ok());
+
+        for (input, message, loc) in [
+            (
+                "-9223372036854775808 / -1;",
+                "integer overflow in division",
+                Loc { line: 1, col: 22 },
+            ),
+            (
+                "1 + 4294967296 * 4294967296;",
+                "integer overflow in multiplication",
+                Loc { line: 1, col: 16 },
+            ),
+            ("10 / (3 - 3);", "division by zero", Loc { line: 1, col: 4 }),
+            ("10 / !1;", "division by zero", Loc { line: 1, col: 4 }),
+            (
+                "10 / (~0 + 1);",
+                "division by zero",
+                Loc { line: 1, col: 4 },
+            ),
+            (
+                "if (1) 1 / 0;",
+                "division by zero",
+                Loc { line: 1, col: 10 },
+            ),
+            (
+                "int a; while (a) 1 / 0;",
+                "division by zero",
+                Loc { line: 1, col: 20 },
+            ),
+        ]
+        .iter()
+        {
+            let mut options = CompileOptions::default();
+            assert!(compile_with_options(input, &options).is_ok(), "{}", input);
+
+            options.strict = true;
+            assert_eq!(
+                error_at(compile_with_options(input, &options)),
+                (message.to_string(), *loc),
+                "{}",
+                input
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use std::fmt;
+
+use anyhow::Result;
+
+use crate::parse::{Node, NodeKind};
+use crate::CompileError;
+
+/// Machine that code is generated for.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Target {
+    X86_64,
+    /// Backend without comparisons, so that the check has something to reject.
+    #[cfg(test)]
+    NoCompare,
+}
+
+impl Target {
+    pub fn name(self) -> &'static str {
+        match self {
+            Target::X86_64 => "x86-64",
+            #[cfg(test)]
+            Target::NoCompare => "no-compare",
+        }
+    }
+
+    /// Returns whether the backend for this target can generate code for `kind`.
+    pub fn supports(self, kind: NodeKind) -> bool {
+        match (self, kind) {
+            #[cfg(test)]
+            (
+                Target::NoCompare,
+                NodeKind::Eq
+                | NodeKind::Neq
+                | NodeKind::Lt
+                | NodeKind::Leq
+                | NodeKind::Gt
+                | NodeKind::Geq,
+            ) => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Fails on the first node in `root` that `target` cannot generate code for.
+pub fn check_supported(root: &Node, target: Target) -> Result<()> {
+    root.fold(
+        |node| check_node(node, target),
+        |node, children| {
+            children.into_iter().collect::<Result<()>>()?;
+            check_node(node, target)
+        },
+    )
+}
+
+fn check_node(node: &Node, target: Target) -> Result<()> {
+    if target.supports(node.kind) {
+        return Ok(());
+    }
+    let message = format!(
+        "node kind {:?} not supported by target {}",
+        node.kind, target
+    );
+    Err(CompileError::Semantic(message, node.loc).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::parse_streaming;
+    use crate::target::*;
+    use crate::token::Loc;
+
+    #[test]
+    fn test_check_supported() -> Result<()> {
+        check_supported(&parse_streaming("1 + 2 * -3;")?, Target::X86_64)?;
+        check_supported(&parse_streaming("1 + (2 < 3) == 1;")?, Target::X86_64)?;
+
+        check_supported(&parse_streaming("1 + 2 * -3;")?, Target::NoCompare)?;
+        // Reports the first unsupported node in the source.
+        let err =
+            check_supported(&parse_streaming("1 + (2 < 3) == 1;")?, Target::NoCompare).unwrap_err();
+        match err.downcast_ref::<CompileError>() {
+            Some(CompileError::Semantic(message, loc)) => {
+                assert_eq!(message, "node kind Lt not supported by target no-compare");
+                assert_eq!(*loc, Loc { line: 1, col: 8 });
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,9 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Write};
 use std::iter::Peekable;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
 
 use anyhow::{anyhow, Context, Result};
 
@@ -7,12 +12,99 @@ use crate::CompileError;
 const BASE10: u32 = 10;
 
 /// Represents location in a file (line, column).
+///
+/// Both are 1-based, like in editors. Show them to users through `display`.
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Loc {
     pub line: usize,
     pub col: usize,
 }
 
+/// Options changing how locations are shown to users.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct RenderOptions {
+    /// Shows locations 0-based, as they were shown before.
+    pub zero_based_locs: bool,
+}
+
+/// Location as shown to users, formatted as `line:col`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DisplayLoc {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for DisplayLoc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+impl Loc {
+    /// Returns the location to show to users, which is 1-based unless
+    /// `options.zero_based_locs` is set.
+    pub fn display(self, options: RenderOptions) -> DisplayLoc {
+        let offset = if options.zero_based_locs { 1 } else { 0 };
+        DisplayLoc {
+            line: self.line.saturating_sub(offset),
+            col: self.col.saturating_sub(offset),
+        }
+    }
+}
+
+/// Name of a function, interned so that tokens and nodes stay `Copy` however long it is.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Name(u32);
+
+/// Table of the names interned so far, each kept for the rest of the process.
+#[derive(Default)]
+struct Names {
+    ids: HashMap<&'static str, u32>,
+    names: Vec<&'static str>,
+}
+
+fn names() -> MutexGuard<'static, Names> {
+    static NAMES: OnceLock<Mutex<Names>> = OnceLock::new();
+    NAMES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Name {
+    /// Returns `name` as a `Name`, or `None` if it is not ASCII.
+    pub fn new(name: &str) -> Option<Name> {
+        if !name.is_ascii() {
+            return None;
+        }
+        let mut names = names();
+        if let Some(id) = names.ids.get(name) {
+            return Some(Name(*id));
+        }
+        let id = u32::try_from(names.names.len()).ok()?;
+        let name: &'static str = Box::leak(name.into());
+        names.ids.insert(name, id);
+        names.names.push(name);
+        Some(Name(id))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        names().names[self.0 as usize]
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum TokenKind {
     Num(u64),
@@ -20,38 +112,125 @@ pub enum TokenKind {
     Minus,
     Mul,
     Div,
-    LParen, // (
-    RParen, // )
-    Eq,     // ==
-    Neq,    // !=
-    Lt,     // <
-    Leq,    // <=
-    Gt,     // >
-    Geq,    // >=
+    LParen,      // (
+    RParen,      // )
+    LBrace,      // {
+    RBrace,      // }
+    Eq,          // ==
+    Neq,         // !=
+    Lt,          // <
+    Leq,         // <=
+    Gt,          // >
+    Geq,         // >=
+    Assign,      // =
+    Semicolon,   // ;
+    If,          // if
+    Else,        // else
+    While,       // while
+    For,         // for
+    Do,          // do
+    Break,       // break
+    Continue,    // continue
+    Switch,      // switch
+    Case,        // case
+    Default,     // default
+    Return,      // return
+    Int,         // int
+    Char,        // char
+    Bool,        // _Bool
+    Void,        // void
+    Colon,       // :
+    Comma,       // ,
+    Amp,         // &
+    Not,         // !
+    Pipe,        // |
+    Caret,       // ^
+    Tilde,       // ~
+    Shl,         // <<
+    Shr,         // >>
+    AndAnd,      // &&
+    OrOr,        // ||
+    Ident(char), // a-z
+    Func(Name),  // name followed by (
     Eof,
 }
 
+impl TokenKind {
+    /// Returns the source text of the token, which is empty for EOF.
+    pub fn text(&self) -> String {
+        let text = match self {
+            TokenKind::Num(num) => return num.to_string(),
+            TokenKind::Ident(name) => return name.to_string(),
+            TokenKind::Func(name) => return name.to_string(),
+            TokenKind::Plus => "+",
+            TokenKind::Minus => "-",
+            TokenKind::Mul => "*",
+            TokenKind::Div => "/",
+            TokenKind::LParen => "(",
+            TokenKind::RParen => ")",
+            TokenKind::LBrace => "{",
+            TokenKind::RBrace => "}",
+            TokenKind::Eq => "==",
+            TokenKind::Neq => "!=",
+            TokenKind::Lt => "<",
+            TokenKind::Leq => "<=",
+            TokenKind::Gt => ">",
+            TokenKind::Geq => ">=",
+            TokenKind::Assign => "=",
+            TokenKind::Semicolon => ";",
+            TokenKind::If => "if",
+            TokenKind::Else => "else",
+            TokenKind::While => "while",
+            TokenKind::For => "for",
+            TokenKind::Do => "do",
+            TokenKind::Break => "break",
+            TokenKind::Continue => "continue",
+            TokenKind::Switch => "switch",
+            TokenKind::Case => "case",
+            TokenKind::Default => "default",
+            TokenKind::Return => "return",
+            TokenKind::Int => "int",
+            TokenKind::Char => "char",
+            TokenKind::Bool => "_Bool",
+            TokenKind::Void => "void",
+            TokenKind::Colon => ":",
+            TokenKind::Comma => ",",
+            TokenKind::Amp => "&",
+            TokenKind::Not => "!",
+            TokenKind::Pipe => "|",
+            TokenKind::Caret => "^",
+            TokenKind::Tilde => "~",
+            TokenKind::Shl => "<<",
+            TokenKind::Shr => ">>",
+            TokenKind::AndAnd => "&&",
+            TokenKind::OrOr => "||",
+            TokenKind::Eof => "",
+        };
+        text.to_string()
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Token {
     pub kind: TokenKind,
     pub loc: Loc,
 }
 
+/// Cursor over the input, which keeps the rest of it as a slice so that every step is O(1).
 struct InputReader<'a> {
     reader: &'a str,
     pub loc: Loc,
+    /// Whether the last character was `\r`, so that a following `\n` ends the same line.
+    after_cr: bool,
 }
 
 impl<'a> Iterator for InputReader<'a> {
     type Item = char;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.reader.len() == 0 {
-            return None;
-        }
-        let res = self.peek();
-        self.advance(1).unwrap();
-        res
+        let c = self.peek()?;
+        self.advance(c.len_utf8()).ok()?;
+        Some(c)
     }
 }
 
@@ -59,7 +238,8 @@ impl<'a> InputReader<'a> {
     fn new(input: &'a str) -> Self {
         InputReader {
             reader: input,
-            loc: Loc { line: 0, col: 0 },
+            loc: Loc { line: 1, col: 1 },
+            after_cr: false,
         }
     }
 
@@ -67,46 +247,108 @@ impl<'a> InputReader<'a> {
         self.reader.len()
     }
 
-    fn starts_with(&self, pat: &str) -> bool {
-        self.reader.starts_with(pat)
-    }
-
+    /// Skips `n` bytes, which must end at a character boundary.
     pub fn advance(&mut self, n: usize) -> Result<()> {
+        if !self.reader.is_char_boundary(n) {
+            return Err(anyhow!(
+                "cannot advance {} bytes in the middle of a character",
+                n
+            ));
+        }
         let (head, tail) = self.reader.split_at(n);
         self.reader = tail;
-        self.loc = if head.contains("\n") {
-            Loc {
-                col: 0,
-                line: self.loc.line + 1,
-            }
-        } else {
-            Loc {
-                col: self.loc.col + 1,
-                line: self.loc.line,
+        for c in head.chars() {
+            // "\r\n" and a lone "\r" end a line as well as "\n".
+            if c == '\n' && self.after_cr {
+                self.after_cr = false;
+                continue;
             }
-        };
+            self.after_cr = c == '\r';
+            self.loc = if c == '\n' || c == '\r' {
+                Loc {
+                    col: 1,
+                    line: self.loc.line + 1,
+                }
+            } else {
+                Loc {
+                    col: self.loc.col + 1,
+                    line: self.loc.line,
+                }
+            };
+        }
         Ok(())
     }
 
     fn consume_number(&mut self) -> Result<u64> {
-        let mut buf: Vec<String> = Vec::new();
+        let loc = self.loc;
+        let prefix = self.head(2).unwrap_or("").to_string();
+        let radix = match prefix.as_str() {
+            "0x" | "0X" => 16,
+            "0o" | "0O" => 8,
+            "0b" | "0B" => 2,
+            _ => BASE10,
+        };
+        if radix != BASE10 {
+            self.advance(2)?;
+        }
+        let mut buf = String::new();
         while let Some(c) = self.peek() {
-            if !c.is_digit(BASE10) {
+            if c == '_' {
+                // Separators must sit between two digits, e.g. `1_000` but not `1_` or `1__0`.
+                let next = self.reader[1..].chars().next();
+                if buf.is_empty() || !next.is_some_and(|next| next.is_digit(radix)) {
+                    return Err(CompileError::Tokenize(c.to_string(), self.loc).into());
+                }
+                self.advance(1)?;
+                continue;
+            }
+            if !c.is_digit(radix) {
                 break;
             }
-            buf.push(c.to_string());
+            buf.push(c);
             self.advance(1)?;
         }
-        let num: u64 = buf.join("").parse()?;
+        if radix != BASE10 {
+            // Rejects a prefix without digits and digits out of the radix, e.g. `0xG`.
+            match self.peek() {
+                Some(c) if c.is_ascii_alphanumeric() && !"uUlL".contains(c) => {
+                    return Err(CompileError::Tokenize(c.to_string(), self.loc).into());
+                }
+                _ if buf.is_empty() => return Err(CompileError::Tokenize(prefix, loc).into()),
+                _ => {}
+            }
+        }
+        let num = u64::from_str_radix(&buf, radix)
+            .map_err(|_| CompileError::NumberOverflow(buf.clone(), loc))?;
+        // Ignores the suffixes of C, e.g. `10u` or `3UL`.
+        let len = self
+            .reader
+            .find(|c| !"uUlL".contains(c))
+            .unwrap_or(self.reader.len());
+        let suffix = &self.reader[..len];
+        if !is_int_suffix(suffix) {
+            return Err(CompileError::Tokenize(suffix.to_string(), self.loc).into());
+        }
+        self.advance(len)?;
         Ok(num)
     }
 
     fn peek(&self) -> Option<char> {
-        self.reader.chars().nth(0)
+        self.reader.chars().next()
+    }
+
+    /// Returns whether the input starts with the word `word`, which is not followed by
+    /// another letter, digit or underscore.
+    fn starts_with_word(&self, word: &str) -> bool {
+        self.reader.starts_with(word)
+            && !self.reader[word.len()..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
     }
 
     fn head(&self, n: usize) -> Option<&str> {
-        if self.reader.len() < n {
+        if self.reader.len() < n || !self.reader.is_char_boundary(n) {
             return None;
         }
         let (head, _) = self.reader.split_at(n);
@@ -114,17 +356,149 @@ impl<'a> InputReader<'a> {
     }
 }
 
+/// Words which are tokenized as keywords instead of variables.
+const KEYWORDS: [(&str, TokenKind); 15] = [
+    ("if", TokenKind::If),
+    ("else", TokenKind::Else),
+    ("while", TokenKind::While),
+    ("for", TokenKind::For),
+    ("do", TokenKind::Do),
+    ("break", TokenKind::Break),
+    ("continue", TokenKind::Continue),
+    ("switch", TokenKind::Switch),
+    ("case", TokenKind::Case),
+    ("default", TokenKind::Default),
+    ("return", TokenKind::Return),
+    ("int", TokenKind::Int),
+    ("char", TokenKind::Char),
+    ("_Bool", TokenKind::Bool),
+    ("void", TokenKind::Void),
+];
+
+/// Whether `suffix` is empty or an integer suffix of C, such as `u`, `LL` or `llU` but not `lL`.
+fn is_int_suffix(suffix: &str) -> bool {
+    let long = suffix
+        .strip_prefix(['u', 'U'])
+        .or_else(|| suffix.strip_suffix(['u', 'U']))
+        .unwrap_or(suffix);
+    matches!(long, "" | "l" | "L" | "ll" | "LL")
+}
+
+/// Splits `input` into lines the same way as locations count them.
+pub fn lines(input: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut rest = input;
+    while let Some(end) = rest.find(['\n', '\r']) {
+        lines.push(&rest[..end]);
+        let len = if rest[end..].starts_with("\r\n") {
+            2
+        } else {
+            1
+        };
+        rest = &rest[end + len..];
+    }
+    lines.push(rest);
+    lines
+}
+
+/// Prints tokens one per line.
+pub fn dump_tokens(
+    tokens: &[Token],
+    options: RenderOptions,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    for token in tokens.iter() {
+        writeln!(out, "{}\t{:?}", token.loc.display(options), token.kind)?;
+    }
+    Ok(())
+}
+
 pub fn tokenize(input: &str) -> Result<Vec<Token>> {
     let mut tokens: Vec<Token> = Vec::new();
-    // let mut stream = input.chars().into_iter().peekable()
-    let mut reader = InputReader::new(input);
+    tokenize_into(input, &mut tokens)?;
+    Ok(tokens)
+}
 
-    while reader.len() > 0 {
-        if reader.starts_with(" ") {
-            reader.advance(1)?;
-            continue;
+/// Tokenizes `input` into `tokens`, which is cleared first so its allocation can be reused.
+pub fn tokenize_into(input: &str, tokens: &mut Vec<Token>) -> Result<()> {
+    tokens.clear();
+    for token in Tokenizer::new(input) {
+        tokens.push(token?);
+    }
+    Ok(())
+}
+
+/// Options changing how the input is tokenized.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct TokenizeOptions {
+    /// Treats `#` up to the end of the line as a comment.
+    pub hash_comments: bool,
+}
+
+/// Lazily tokenizes an input, yielding tokens up to and including `Eof`.
+///
+/// Iteration stops after the first error.
+pub struct Tokenizer<'a> {
+    reader: InputReader<'a>,
+    options: TokenizeOptions,
+    /// Location just past the last token.
+    end: Loc,
+    done: bool,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self::with_options(input, TokenizeOptions::default())
+    }
+
+    pub fn with_options(input: &'a str, options: TokenizeOptions) -> Self {
+        Tokenizer {
+            reader: InputReader::new(input),
+            options,
+            end: Loc { line: 1, col: 1 },
+            done: false,
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Token> {
+        let reader = &mut self.reader;
+        let mut in_comment = false;
+        while let Some(c) = reader.peek() {
+            if c == '\n' || c == '\r' {
+                in_comment = false;
+            } else if c == '#' && self.options.hash_comments {
+                in_comment = true;
+            } else if !in_comment && !c.is_whitespace() {
+                break;
+            }
+            reader.advance(c.len_utf8())?;
         }
         let loc = reader.loc;
+        if reader.len() == 0 {
+            // Points at the end of the last token rather than at trailing whitespace.
+            return Ok(Token {
+                kind: TokenKind::Eof,
+                loc: self.end,
+            });
+        }
+
+        // Magic identifiers expand to their own location.
+        for (name, value) in [("__LINE__", loc.line), ("__COL__", loc.col)].iter() {
+            if reader.starts_with_word(name) {
+                reader.advance(name.len())?;
+                return Ok(Token {
+                    kind: TokenKind::Num(*value as u64),
+                    loc,
+                });
+            }
+        }
+
+        for (keyword, kind) in KEYWORDS.iter() {
+            if reader.starts_with_word(keyword) {
+                reader.advance(keyword.len())?;
+                return Ok(Token { kind: *kind, loc });
+            }
+        }
 
         if let Some(head) = reader.head(2) {
             if let Some(kind) = match head {
@@ -132,11 +506,14 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>> {
                 "!=" => Some(TokenKind::Neq),
                 "<=" => Some(TokenKind::Leq),
                 ">=" => Some(TokenKind::Geq),
+                "<<" => Some(TokenKind::Shl),
+                ">>" => Some(TokenKind::Shr),
+                "&&" => Some(TokenKind::AndAnd),
+                "||" => Some(TokenKind::OrOr),
                 _ => None,
             } {
-                tokens.push(Token { kind, loc });
                 reader.advance(2)?;
-                continue;
+                return Ok(Token { kind, loc });
             }
         }
 
@@ -148,86 +525,265 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>> {
                 "/" => Some(TokenKind::Div),
                 "(" => Some(TokenKind::LParen),
                 ")" => Some(TokenKind::RParen),
+                "{" => Some(TokenKind::LBrace),
+                "}" => Some(TokenKind::RBrace),
                 "<" => Some(TokenKind::Lt),
                 ">" => Some(TokenKind::Gt),
+                "=" => Some(TokenKind::Assign),
+                ";" => Some(TokenKind::Semicolon),
+                ":" => Some(TokenKind::Colon),
+                "," => Some(TokenKind::Comma),
+                "&" => Some(TokenKind::Amp),
+                "!" => Some(TokenKind::Not),
+                "|" => Some(TokenKind::Pipe),
+                "^" => Some(TokenKind::Caret),
+                "~" => Some(TokenKind::Tilde),
                 _ => None,
             } {
-                tokens.push(Token { kind, loc });
                 reader.advance(1)?;
-                continue;
+                return Ok(Token { kind, loc });
             }
         }
 
-        if let Ok(num) = reader.consume_number() {
-            tokens.push(Token {
-                kind: TokenKind::Num(num),
+        let c = reader.peek().context("Not peekable.")?;
+        // Words are names of functions when called, and otherwise letters of variables.
+        if c.is_ascii_alphabetic() || c == '_' {
+            let rest = reader.reader;
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            if rest[len..].trim_start().starts_with('(') {
+                let name = Name::new(&rest[..len]).context("Expect an ASCII name.")?;
+                reader.advance(len)?;
+                return Ok(Token {
+                    kind: TokenKind::Func(name),
+                    loc,
+                });
+            }
+        }
+        if c.is_digit(BASE10) {
+            return Ok(Token {
+                kind: TokenKind::Num(reader.consume_number()?),
+                loc,
+            });
+        }
+        if c.is_ascii_lowercase() {
+            reader.advance(1)?;
+            return Ok(Token {
+                kind: TokenKind::Ident(c),
                 loc,
             });
-            continue;
         }
 
-        return Err(CompileError::Tokenize(
-            reader.peek().unwrap().to_string(),
-            loc,
-        ))?;
+        Err(CompileError::Tokenize(c.to_string(), loc).into())
     }
-    let token = Token {
-        kind: TokenKind::Eof,
-        loc: reader.loc,
-    };
-    tokens.push(token);
+}
 
-    Ok(tokens)
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Result<Token>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let res = self.next_token();
+        self.end = self.reader.loc;
+        self.done = match &res {
+            Ok(token) => token.kind == TokenKind::Eof,
+            Err(_) => true,
+        };
+        Some(res)
+    }
 }
 
-// Consumes if the current token is expected one.
-pub fn consume<Tokens>(expected_kind: TokenKind, tokens: &mut Peekable<Tokens>) -> bool
+/// Iterates over the tokens of a `Tokenizer`, keeping its error aside so that
+/// the parser can consume tokens directly.
+pub struct TokenStream<'a> {
+    tokenizer: Tokenizer<'a>,
+    error: Option<anyhow::Error>,
+}
+
+impl<'a> TokenStream<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self::from_tokenizer(Tokenizer::new(input))
+    }
+
+    pub fn from_tokenizer(tokenizer: Tokenizer<'a>) -> Self {
+        TokenStream {
+            tokenizer,
+            error: None,
+        }
+    }
+
+    /// Takes the tokenize error, if any occurred so far.
+    pub fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.error.take()
+    }
+}
+
+impl<'a> Iterator for TokenStream<'a> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.tokenizer.next()? {
+            Ok(token) => Some(token),
+            Err(err) => {
+                self.error = Some(err);
+                None
+            }
+        }
+    }
+}
+
+/// Position in a token sequence which the parser reads from.
+pub trait TokenCursor {
+    /// Returns the `n`-th token ahead without consuming anything.
+    ///
+    /// Cursors over iterators only look one token ahead and return `None` for `n > 0`.
+    fn peek_nth(&mut self, n: usize) -> Option<&Token>;
+
+    /// Consumes the current token.
+    fn advance(&mut self) -> Option<Token>;
+
+    /// Called when the parser recurses into a nested statement or operand, so that cursors
+    /// can bound the recursion.
+    fn enter(&mut self) {}
+
+    /// Called when the parser returns from a nested statement.
+    fn leave(&mut self) {}
+
+    fn peek(&mut self) -> Option<&Token> {
+        self.peek_nth(0)
+    }
+}
+
+impl<Tokens> TokenCursor for Peekable<Tokens>
 where
     Tokens: Iterator<Item = Token>,
 {
-    if let Some(token) = tokens.peek() {
-        if token.kind == expected_kind {
-            tokens.next();
-            return true;
+    fn peek_nth(&mut self, n: usize) -> Option<&Token> {
+        if n == 0 {
+            Peekable::peek(self)
+        } else {
+            None
         }
     }
-    false
+
+    fn advance(&mut self) -> Option<Token> {
+        self.next()
+    }
+}
+
+/// Cursor over a token slice, which can look ahead any number of tokens.
+pub struct SliceCursor<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> SliceCursor<'a> {
+    pub fn new(tokens: &'a [Token]) -> Self {
+        SliceCursor { tokens, pos: 0 }
+    }
 }
 
-// Expects a given kind of token and read next.
-pub fn expect<Tokens>(expected_kind: TokenKind, tokens: &mut Peekable<Tokens>) -> Result<()>
+impl<'a> TokenCursor for SliceCursor<'a> {
+    fn peek_nth(&mut self, n: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + n)
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = *self.tokens.get(self.pos)?;
+        self.pos += 1;
+        Some(token)
+    }
+}
+
+// Consumes if the current token is expected one.
+pub fn consume<Tokens>(expected_kind: TokenKind, tokens: &mut Tokens) -> bool
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: TokenCursor,
 {
-    let actual_kind = tokens.peek().context("Not peekable.")?.kind;
-    if actual_kind != expected_kind {
-        return Err(anyhow!(
-            "Expect {:?}, but got {:?}",
-            expected_kind,
-            actual_kind
-        ));
-    }
-    tokens.next();
+    consume_if(tokens, |kind| kind == expected_kind)
+}
+
+// Consumes the current token if its kind satisfies `pred`.
+pub fn consume_if<Tokens>(tokens: &mut Tokens, pred: impl Fn(TokenKind) -> bool) -> bool
+where
+    Tokens: TokenCursor,
+{
+    consume_map(tokens, |kind| if pred(kind) { Some(()) } else { None }).is_some()
+}
+
+// Consumes the current token if `f` maps its kind to something.
+pub fn consume_map<Tokens, R>(tokens: &mut Tokens, f: impl Fn(TokenKind) -> Option<R>) -> Option<R>
+where
+    Tokens: TokenCursor,
+{
+    let mapped = f(tokens.peek()?.kind)?;
+    tokens.advance();
+    Some(mapped)
+}
+
+// Expects a given kind of token and read next, failing at the current token otherwise.
+pub fn expect<Tokens>(expected_kind: TokenKind, tokens: &mut Tokens) -> Result<()>
+where
+    Tokens: TokenCursor,
+{
+    let token = tokens.peek().context("Not peekable.")?;
+    if token.kind != expected_kind {
+        let message = format!("expected '{}'", expected_kind.text());
+        return Err(CompileError::Parse(message, token.loc).into());
+    }
+    tokens.advance();
     Ok(())
 }
 
-// Expects a number and read next.
-pub fn expect_number<Tokens>(tokens: &mut Peekable<Tokens>) -> Result<u64>
+// Expects a number and read next, failing at the current token otherwise.
+pub fn expect_number<Tokens>(tokens: &mut Tokens) -> Result<u64>
 where
-    Tokens: Iterator<Item = Token>,
+    Tokens: TokenCursor,
 {
-    let kind = tokens.peek().context("Not peekable.")?.kind;
-    match kind {
+    let token = tokens.peek().context("Not peekable.")?;
+    match token.kind {
         TokenKind::Num(num) => {
-            tokens.next();
+            tokens.advance();
             Ok(num)
         }
-        _ => Err(anyhow!("Expected num, but found {:?}", kind)),
+        _ => Err(CompileError::Parse("expected a number".to_string(), token.loc).into()),
+    }
+}
+
+// Consumes an identifier if the current token is one.
+pub fn consume_ident<Tokens>(tokens: &mut Tokens) -> Option<char>
+where
+    Tokens: TokenCursor,
+{
+    match tokens.peek()?.kind {
+        TokenKind::Ident(name) => {
+            tokens.advance();
+            Some(name)
+        }
+        _ => None,
+    }
+}
+
+// Consumes the name of a called function if the current token is one.
+pub fn consume_func<Tokens>(tokens: &mut Tokens) -> Option<Name>
+where
+    Tokens: TokenCursor,
+{
+    match tokens.peek()?.kind {
+        TokenKind::Func(name) => {
+            tokens.advance();
+            Some(name)
+        }
+        _ => None,
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::error_at;
     use crate::token::*;
     use anyhow::Context;
 
@@ -237,14 +793,14 @@ mod tests {
 
         let head = reader.head(4);
         assert_eq!(head.unwrap(), "123a");
-        assert_eq!(reader.loc, Loc { line: 0, col: 0 });
+        assert_eq!(reader.loc, Loc { line: 1, col: 1 });
 
         let head = reader.head(10);
-        assert_eq!(head.is_none(), true);
+        assert!(head.is_none());
 
         let num = reader.consume_number()?;
         assert_eq!(num, 123);
-        assert_eq!(reader.loc, Loc { line: 0, col: 3 });
+        assert_eq!(reader.loc, Loc { line: 1, col: 4 });
 
         let peek = reader.peek().context("Not peekable")?;
         assert_eq!(peek, 'a');
@@ -256,17 +812,86 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_multibyte_reader() -> Result<()> {
+        let mut reader = InputReader::new("é€1");
+        assert_eq!(reader.next(), Some('é'));
+        assert!(reader.advance(1).is_err());
+        assert_eq!(reader.next(), Some('€'));
+        assert_eq!(reader.loc, Loc { line: 1, col: 3 });
+        assert_eq!(reader.consume_number()?, 1);
+        assert_eq!(reader.next(), None);
+
+        let options = TokenizeOptions {
+            hash_comments: true,
+        };
+        let tokens =
+            Tokenizer::with_options("1 # é€\n+ 23", options).collect::<Result<Vec<_>>>()?;
+        assert_eq!(tokens[1].loc, Loc { line: 2, col: 1 });
+        assert_eq!(
+            tokens[2],
+            Token {
+                kind: TokenKind::Num(23),
+                loc: Loc { line: 2, col: 3 }
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_input() -> Result<()> {
+        // Takes time linear in the length of the input.
+        let input = "12345+".repeat(20_000) + "1";
+        let tokens = tokenize(&input)?;
+        assert_eq!(tokens.len(), 40_002);
+        assert_eq!(
+            tokens[39_999].loc,
+            Loc {
+                line: 1,
+                col: 120_000
+            }
+        );
+        assert_eq!(tokens[40_000].kind, TokenKind::Num(1));
+        Ok(())
+    }
+
     #[test]
     fn test_multiline_reader() -> Result<()> {
-        let input = vec!["a", "bc"].join("\n");
+        let input = ["a", "bc"].join("\n");
         let mut reader = InputReader::new(input.as_str());
 
         reader.advance(1)?;
-        assert_eq!(reader.loc, Loc { line: 0, col: 1 });
+        assert_eq!(reader.loc, Loc { line: 1, col: 2 });
 
         reader.advance(1)?;
         assert_eq!(reader.peek().context("Not peekable")?, 'b');
-        assert_eq!(reader.loc, Loc { line: 1, col: 0 });
+        assert_eq!(reader.loc, Loc { line: 2, col: 1 });
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_endings() -> Result<()> {
+        let expected = tokenize("1 +\n  23 $")
+            .unwrap_err()
+            .downcast::<CompileError>()?
+            .loc();
+        assert_eq!(expected, Some(Loc { line: 2, col: 6 }));
+
+        let input = "1 +\n  23\n";
+        for newline in ["\r\n", "\r"].iter() {
+            let other = input.replace('\n', newline);
+            assert_eq!(tokenize(&other)?, tokenize(input)?, "{:?}", other);
+            assert_eq!(lines(&other), lines(input));
+
+            let other = "1 +\n  23 $".replace('\n', newline);
+            let loc = tokenize(&other)
+                .unwrap_err()
+                .downcast::<CompileError>()?
+                .loc();
+            assert_eq!(loc, expected, "{:?}", other);
+        }
+        assert_eq!(lines("1\r\n\n2\r"), vec!["1", "", "2", ""]);
 
         Ok(())
     }
@@ -277,32 +902,32 @@ mod tests {
             .into_iter()
             .map(|x| Token {
                 kind: x.kind,
-                loc: Loc { col: 0, line: 0 },
+                loc: Loc { col: 1, line: 1 },
             })
             .collect()
     }
 
     #[test]
     fn test_tokenize() -> Result<()> {
-        let loc = Loc { line: 0, col: 0 };
+        let loc = Loc { line: 1, col: 1 };
         assert_eq!(
             tokenize("(2)")?,
             vec![
                 Token {
                     kind: TokenKind::LParen,
-                    loc: Loc { line: 0, col: 0 },
+                    loc: Loc { line: 1, col: 1 },
                 },
                 Token {
                     kind: TokenKind::Num(2),
-                    loc: Loc { line: 0, col: 1 },
+                    loc: Loc { line: 1, col: 2 },
                 },
                 Token {
                     kind: TokenKind::RParen,
-                    loc: Loc { line: 0, col: 2 },
+                    loc: Loc { line: 1, col: 3 },
                 },
                 Token {
                     kind: TokenKind::Eof,
-                    loc: Loc { line: 0, col: 3 },
+                    loc: Loc { line: 1, col: 4 },
                 },
             ]
         );
@@ -311,57 +936,57 @@ mod tests {
             vec![
                 Token {
                     kind: TokenKind::Num(2),
-                    loc: Loc { line: 0, col: 2 },
+                    loc: Loc { line: 1, col: 3 },
                 },
                 Token {
                     kind: TokenKind::Mul,
-                    loc: Loc { line: 0, col: 4 },
+                    loc: Loc { line: 1, col: 5 },
                 },
                 Token {
                     kind: TokenKind::LParen,
-                    loc: Loc { line: 0, col: 6 },
+                    loc: Loc { line: 1, col: 7 },
                 },
                 Token {
                     kind: TokenKind::Num(1),
-                    loc: Loc { line: 0, col: 7 },
+                    loc: Loc { line: 1, col: 8 },
                 },
                 Token {
                     kind: TokenKind::Plus,
-                    loc: Loc { line: 0, col: 8 },
+                    loc: Loc { line: 1, col: 9 },
                 },
                 Token {
                     kind: TokenKind::Num(23),
-                    loc: Loc { line: 0, col: 9 },
+                    loc: Loc { line: 1, col: 10 },
                 },
                 Token {
                     kind: TokenKind::RParen,
-                    loc: Loc { line: 0, col: 11 },
+                    loc: Loc { line: 1, col: 12 },
                 },
                 Token {
                     kind: TokenKind::Minus,
-                    loc: Loc { line: 0, col: 13 },
+                    loc: Loc { line: 1, col: 14 },
                 },
                 Token {
                     kind: TokenKind::Num(456),
-                    loc: Loc { line: 0, col: 15 },
+                    loc: Loc { line: 1, col: 16 },
                 },
                 Token {
                     kind: TokenKind::Div,
-                    loc: Loc { line: 0, col: 19 },
+                    loc: Loc { line: 1, col: 20 },
                 },
                 Token {
                     kind: TokenKind::Num(7),
-                    loc: Loc { line: 0, col: 21 },
+                    loc: Loc { line: 1, col: 22 },
                 },
                 Token {
                     kind: TokenKind::Eof,
-                    loc: Loc { line: 0, col: 22 },
+                    loc: Loc { line: 1, col: 23 },
                 },
             ]
         );
 
         assert_eq!(
-            remove_loc(tokenize("== != <= >= < >")?),
+            remove_loc(tokenize("== != <= >= < > = ===")?),
             vec![
                 Token {
                     kind: TokenKind::Eq,
@@ -387,6 +1012,18 @@ mod tests {
                     kind: TokenKind::Gt,
                     loc
                 },
+                Token {
+                    kind: TokenKind::Assign,
+                    loc
+                },
+                Token {
+                    kind: TokenKind::Eq,
+                    loc
+                },
+                Token {
+                    kind: TokenKind::Assign,
+                    loc
+                },
                 Token {
                     kind: TokenKind::Eof,
                     loc
@@ -396,4 +1033,494 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_eof_loc() -> Result<()> {
+        let eof = |input| -> Result<Loc> { Ok(tokenize(input)?.last().context("No token")?.loc) };
+        assert_eq!(eof("1+2\n")?, Loc { line: 1, col: 4 });
+        assert_eq!(eof("1+2  \n\n  ")?, Loc { line: 1, col: 4 });
+        assert_eq!(eof("1+\n 2\n")?, Loc { line: 2, col: 3 });
+        assert_eq!(eof("1 ==\t2")?, Loc { line: 1, col: 7 });
+        assert_eq!(eof("\n")?, Loc { line: 1, col: 1 });
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_comments() -> Result<()> {
+        let options = TokenizeOptions {
+            hash_comments: true,
+        };
+        let kinds = |input| -> Result<Vec<TokenKind>> {
+            Tokenizer::with_options(input, options)
+                .map(|token| token.map(|token| token.kind))
+                .collect()
+        };
+        let expected = vec![
+            TokenKind::Num(1),
+            TokenKind::Plus,
+            TokenKind::Num(2),
+            TokenKind::Eof,
+        ];
+        assert_eq!(kinds("1 + 2 # note")?, expected);
+        assert_eq!(kinds("# sum\n1 +#\n 2#")?, expected);
+
+        let err = tokenize("1 + 2 # note").unwrap_err();
+        match err.downcast_ref::<CompileError>() {
+            Some(CompileError::Tokenize(text, loc)) => {
+                assert_eq!(text, "#");
+                assert_eq!(*loc, Loc { line: 1, col: 7 });
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_text() -> Result<()> {
+        for input in [
+            "1+2*3;",
+            "int a; int *b; (a - 12) / 3 <= 4 != (5 == *b);",
+            "1>2>=3<4; 5;",
+        ]
+        .iter()
+        {
+            let tokens = tokenize(input)?;
+            let text: Vec<String> = tokens.iter().map(|token| token.kind.text()).collect();
+            let text = text.join(" ");
+            let kinds = |tokens: Vec<Token>| -> Vec<TokenKind> {
+                tokens.into_iter().map(|token| token.kind).collect()
+            };
+            assert_eq!(kinds(tokenize(&text)?), kinds(tokens), "{}", input);
+            assert!(crate::parse::structurally_eq(
+                &crate::parse::parse_streaming(&text)?,
+                &crate::parse::parse_streaming(input)?
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_display_loc() {
+        let one_based = RenderOptions::default();
+        let zero_based = RenderOptions {
+            zero_based_locs: true,
+        };
+        assert_eq!(
+            Loc { line: 1, col: 2 }.display(one_based),
+            DisplayLoc { line: 1, col: 2 }
+        );
+        assert_eq!(
+            Loc { line: 3, col: 1 }.display(one_based).to_string(),
+            "3:1"
+        );
+        assert_eq!(
+            Loc { line: 3, col: 1 }.display(zero_based).to_string(),
+            "2:0"
+        );
+        assert_eq!(
+            Loc { line: 0, col: 0 }.display(zero_based).to_string(),
+            "0:0"
+        );
+    }
+
+    #[test]
+    fn test_literal_suffixes() -> Result<()> {
+        let kinds = |input| -> Result<Vec<TokenKind>> {
+            Ok(tokenize(input)?
+                .into_iter()
+                .map(|token| token.kind)
+                .collect())
+        };
+        for (input, num) in [
+            ("10u", 10),
+            ("5L", 5),
+            ("3UL", 3),
+            ("1lu", 1),
+            ("2ull", 2),
+            ("4LLu", 4),
+            ("0x1fuL", 31),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                kinds(input)?,
+                vec![TokenKind::Num(*num), TokenKind::Eof],
+                "{}",
+                input
+            );
+        }
+        assert_eq!(
+            kinds("10x")?,
+            vec![TokenKind::Num(10), TokenKind::Ident('x'), TokenKind::Eof]
+        );
+
+        for (input, suffix) in [
+            ("1ulul;", "ulul"),
+            ("1uu", "uu"),
+            ("1lll", "lll"),
+            ("1lL", "lL"),
+            ("1ulu", "ulu"),
+            ("1lul", "lul"),
+        ]
+        .iter()
+        {
+            let err = tokenize(input).unwrap_err();
+            match err.downcast_ref::<CompileError>() {
+                Some(CompileError::Tokenize(text, loc)) => {
+                    assert_eq!(text, suffix, "{}", input);
+                    assert_eq!(*loc, Loc { line: 1, col: 2 }, "{}", input);
+                }
+                _ => panic!("unexpected error {} for {}", err, input),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_radix_prefixes() -> Result<()> {
+        let kinds = |input| -> Result<Vec<TokenKind>> {
+            Ok(tokenize(input)?
+                .into_iter()
+                .map(|token| token.kind)
+                .collect())
+        };
+        for (input, num) in [
+            ("0", 0),
+            ("0xff", 255),
+            ("0XfF", 255),
+            ("0o17", 15),
+            ("0b1010", 10),
+            ("0B0", 0),
+            ("0x10u", 16),
+            ("017", 17),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                kinds(input)?,
+                vec![TokenKind::Num(*num), TokenKind::Eof],
+                "{}",
+                input
+            );
+        }
+
+        for (input, text, col) in [
+            ("1 + 0xG", "G", 7),
+            ("0b12", "2", 4),
+            ("0o8", "8", 3),
+            ("(0x)", "0x", 2),
+        ]
+        .iter()
+        {
+            let err = tokenize(input).unwrap_err();
+            match err.downcast_ref::<CompileError>() {
+                Some(CompileError::Tokenize(actual, loc)) => {
+                    assert_eq!(actual, text, "{}", input);
+                    assert_eq!(*loc, Loc { line: 1, col: *col }, "{}", input);
+                }
+                _ => panic!("unexpected error {} for {}", err, input),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_digit_separators() -> Result<()> {
+        for (input, num) in [
+            ("1_000", 1000),
+            ("1_000_000", 1_000_000),
+            ("0xff_ff", 0xffff),
+        ]
+        .iter()
+        {
+            assert_eq!(tokenize(input)?[0].kind, TokenKind::Num(*num), "{}", input);
+        }
+
+        for (input, col) in [
+            ("_1", 1),
+            ("1_", 2),
+            ("1__0", 2),
+            ("2 * 0x_1", 7),
+            ("1_;", 2),
+        ]
+        .iter()
+        {
+            let err = tokenize(input).unwrap_err();
+            match err.downcast_ref::<CompileError>() {
+                Some(CompileError::Tokenize(text, loc)) => {
+                    assert_eq!(text, "_", "{}", input);
+                    assert_eq!(*loc, Loc { line: 1, col: *col }, "{}", input);
+                }
+                _ => panic!("unexpected error {} for {}", err, input),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_number_overflow() {
+        let input = "1 + 123456789012345678901234567890;";
+        assert_eq!(
+            error_at(tokenize(input)),
+            (
+                "integer literal '123456789012345678901234567890' is too large".to_string(),
+                Loc { line: 1, col: 5 }
+            )
+        );
+
+        // The largest literal still fits.
+        assert!(tokenize("18446744073709551615").is_ok());
+        assert!(tokenize("0x1_0000_0000_0000_0000").is_err());
+    }
+
+    #[test]
+    fn test_keywords() -> Result<()> {
+        let kinds = |input| -> Result<Vec<TokenKind>> {
+            Ok(tokenize(input)?
+                .into_iter()
+                .map(|token| token.kind)
+                .collect())
+        };
+        assert_eq!(
+            kinds("if(a)")?,
+            vec![
+                TokenKind::If,
+                TokenKind::LParen,
+                TokenKind::Ident('a'),
+                TokenKind::RParen,
+                TokenKind::Eof
+            ]
+        );
+        // Keywords end at a word boundary.
+        assert_eq!(
+            kinds("ifa")?,
+            vec![
+                TokenKind::Ident('i'),
+                TokenKind::Ident('f'),
+                TokenKind::Ident('a'),
+                TokenKind::Eof
+            ]
+        );
+        assert_eq!(kinds("if1")?[0], TokenKind::Ident('i'));
+        assert_eq!(TokenKind::If.text(), "if");
+        assert_eq!(kinds("else")?[0], TokenKind::Else);
+        assert_eq!(kinds("while")?[0], TokenKind::While);
+        assert_eq!(kinds("for")?[0], TokenKind::For);
+        assert_eq!(kinds("do")?[0], TokenKind::Do);
+        assert_eq!(kinds("break")?[0], TokenKind::Break);
+        assert_eq!(kinds("continue")?[0], TokenKind::Continue);
+        assert_eq!(kinds("return(1)")?[0], TokenKind::Return);
+        assert_eq!(kinds("int*p")?[0], TokenKind::Int);
+        assert_eq!(kinds("char c")?[0], TokenKind::Char);
+        assert_eq!(kinds("chars")?[0], TokenKind::Ident('c'));
+        assert_eq!(kinds("void f()")?[0], TokenKind::Void);
+        assert_eq!(
+            kinds("_Bool b")?,
+            vec![TokenKind::Bool, TokenKind::Ident('b'), TokenKind::Eof]
+        );
+        assert_eq!(kinds("i n t")?[0], TokenKind::Ident('i'));
+        assert_eq!(
+            kinds("switch case default:")?,
+            vec![
+                TokenKind::Switch,
+                TokenKind::Case,
+                TokenKind::Default,
+                TokenKind::Colon,
+                TokenKind::Eof
+            ]
+        );
+        assert_eq!(
+            kinds("&*a")?,
+            vec![
+                TokenKind::Amp,
+                TokenKind::Mul,
+                TokenKind::Ident('a'),
+                TokenKind::Eof
+            ]
+        );
+        // `!=` is still one token, and `!` alone is the logical not.
+        assert_eq!(
+            kinds("!!a != !b")?,
+            vec![
+                TokenKind::Not,
+                TokenKind::Not,
+                TokenKind::Ident('a'),
+                TokenKind::Neq,
+                TokenKind::Not,
+                TokenKind::Ident('b'),
+                TokenKind::Eof
+            ]
+        );
+        // `<<` and `>>` are matched before `<` and `>`.
+        assert_eq!(
+            kinds("<<>> > >=<")?,
+            vec![
+                TokenKind::Shl,
+                TokenKind::Shr,
+                TokenKind::Gt,
+                TokenKind::Geq,
+                TokenKind::Lt,
+                TokenKind::Eof
+            ]
+        );
+        assert_eq!(
+            kinds("a&&b||c&d|&&")?,
+            vec![
+                TokenKind::Ident('a'),
+                TokenKind::AndAnd,
+                TokenKind::Ident('b'),
+                TokenKind::OrOr,
+                TokenKind::Ident('c'),
+                TokenKind::Amp,
+                TokenKind::Ident('d'),
+                TokenKind::Pipe,
+                TokenKind::AndAnd,
+                TokenKind::Eof
+            ]
+        );
+        assert_eq!(
+            kinds("a&b|~c^d")?,
+            vec![
+                TokenKind::Ident('a'),
+                TokenKind::Amp,
+                TokenKind::Ident('b'),
+                TokenKind::Pipe,
+                TokenKind::Tilde,
+                TokenKind::Ident('c'),
+                TokenKind::Caret,
+                TokenKind::Ident('d'),
+                TokenKind::Eof
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_names() -> Result<()> {
+        let tokens = tokenize("foo_1 ();ab")?;
+        let name = Name::new("foo_1").context("Not a name")?;
+        assert_eq!(tokens[0].kind, TokenKind::Func(name));
+        assert_eq!(tokens[1].loc, Loc { line: 1, col: 7 });
+        assert_eq!(tokens[4].kind, TokenKind::Ident('a'));
+        assert_eq!(tokens[0].kind.text(), "foo_1");
+        // Keywords still come first.
+        assert_eq!(tokenize("while(1)")?[0].kind, TokenKind::While);
+        assert_eq!(tokenize("f(1,2)")?[3].kind, TokenKind::Comma);
+
+        // Names are not limited in length.
+        let long = "f".repeat(100);
+        let tokens = tokenize(&format!("{}(); {}();", long, long))?;
+        assert_eq!(tokens[0].kind.text(), long);
+        assert_eq!(tokens[0].kind, tokens[4].kind);
+        assert_ne!(tokens[0].kind, TokenKind::Func(name));
+        Ok(())
+    }
+
+    #[test]
+    fn test_magic_identifiers() -> Result<()> {
+        // Lines and columns are 1-based like `Loc`.
+        let tokens = tokenize("1 +\n2 +\n  __LINE__ * __COL__")?;
+        assert_eq!(tokens[4].kind, TokenKind::Num(3));
+        assert_eq!(tokens[6].kind, TokenKind::Num(14));
+        assert!(tokenize("__FILE__").is_err());
+        // Only whole words expand.
+        for input in ["__LINE__x;", "__COL__1;", "__LINE___;"].iter() {
+            assert!(tokenize(input).is_err(), "{}", input);
+        }
+        let tokens = tokenize("__LINE__+1")?;
+        assert_eq!(tokens[0].kind, TokenKind::Num(1));
+        assert_eq!(tokens[1].kind, TokenKind::Plus);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenize_no_panic() {
+        // Inputs found by fuzzing.
+        for input in ["é", "1é", "1+é2", "99999999999999999999999", "$", "A"].iter() {
+            assert!(tokenize(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_slice_cursor() -> Result<()> {
+        let tokens = tokenize("1+2")?;
+        let mut cursor = SliceCursor::new(&tokens);
+        assert_eq!(
+            cursor.peek_nth(2).context("No token")?.kind,
+            TokenKind::Num(2)
+        );
+        assert!(consume(TokenKind::Num(1), &mut cursor));
+        assert_eq!(cursor.peek_nth(2).context("No token")?.kind, TokenKind::Eof);
+        assert!(cursor.peek_nth(3).is_none());
+        assert_eq!(cursor.advance().context("No token")?.kind, TokenKind::Plus);
+        Ok(())
+    }
+
+    #[test]
+    fn test_consume_map() -> Result<()> {
+        let tokens = tokenize("1+2")?;
+        let mut cursor = SliceCursor::new(&tokens);
+        let number = |kind| match kind {
+            TokenKind::Num(num) => Some(num),
+            _ => None,
+        };
+        assert_eq!(consume_map(&mut cursor, number), Some(1));
+        assert_eq!(consume_map(&mut cursor, number), None);
+        assert!(!consume_if(&mut cursor, |kind| kind == TokenKind::Minus));
+        assert!(consume_if(&mut cursor, |kind| kind == TokenKind::Plus));
+        assert_eq!(consume_map(&mut cursor, number), Some(2));
+        assert_eq!(cursor.peek().context("No token")?.kind, TokenKind::Eof);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expect() -> Result<()> {
+        let tokens = tokenize("1 +")?;
+        let mut cursor = SliceCursor::new(&tokens);
+        assert_eq!(expect_number(&mut cursor)?, 1);
+        let err = expect(TokenKind::Minus, &mut cursor).unwrap_err();
+        let loc = Loc { line: 1, col: 3 };
+        match err.downcast_ref::<CompileError>() {
+            Some(CompileError::Parse(message, at)) => {
+                assert_eq!(message, "expected '-'");
+                assert_eq!(*at, loc);
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+        expect(TokenKind::Plus, &mut cursor)?;
+        let err = expect_number(&mut cursor).unwrap_err();
+        assert_eq!(err.to_string(), "expected a number");
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenizer() -> Result<()> {
+        let mut tokenizer = Tokenizer::new("1 $");
+        assert_eq!(
+            tokenizer.next().context("No token")??.kind,
+            TokenKind::Num(1)
+        );
+        assert!(tokenizer.next().context("No token")?.is_err());
+        assert!(tokenizer.next().is_none());
+
+        let kinds: Vec<TokenKind> = Tokenizer::new("1+2 ")
+            .map(|token| token.map(|token| token.kind))
+            .collect::<Result<_>>()?;
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Num(1),
+                TokenKind::Plus,
+                TokenKind::Num(2),
+                TokenKind::Eof
+            ]
+        );
+
+        let mut stream = TokenStream::new("1 $");
+        assert_eq!(stream.by_ref().count(), 1);
+        assert!(stream.take_error().is_some());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,400 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use anyhow::Result;
+
+use crate::parse::{lvar_name, Node, NodeKind};
+use crate::token::Name;
+use crate::{functions, CompileError};
+
+/// Type of a variable or an expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ty {
+    Int,
+    Char,
+    /// Holds only 0 or 1, to which assigned values are converted.
+    Bool,
+    /// Returned by functions which return nothing, and by calls to them.
+    Void,
+    Ptr(Box<Ty>),
+}
+
+impl Ty {
+    /// Returns the type of pointers to `base`.
+    pub fn pointer_to(base: Ty) -> Ty {
+        Ty::Ptr(Box::new(base))
+    }
+
+    /// Returns the type pointed to, if this is a pointer.
+    pub fn base(&self) -> Option<&Ty> {
+        match self {
+            Ty::Ptr(base) => Some(base),
+            Ty::Int | Ty::Char | Ty::Bool | Ty::Void => None,
+        }
+    }
+
+    /// Returns the number of bytes a value takes in memory.
+    ///
+    /// Variables still take a slot of 8 bytes in the frame, of which a `char` or a `_Bool`
+    /// uses the first. `void` counts as 1 byte, as in GNU C.
+    pub fn size(&self) -> usize {
+        match self {
+            Ty::Char | Ty::Bool | Ty::Void => 1,
+            Ty::Int | Ty::Ptr(_) => 8,
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ty::Int => f.write_str("int"),
+            Ty::Char => f.write_str("char"),
+            Ty::Bool => f.write_str("_Bool"),
+            Ty::Void => f.write_str("void"),
+            Ty::Ptr(base) => write!(f, "{}*", base),
+        }
+    }
+}
+
+/// Sets the `ty` of every expression in `root`.
+///
+/// Variables take the type they are declared with in their function, where parameters are
+/// `int`. Calls are `void` if they call a `void` function, and `int` otherwise. Fails on
+/// variables used before they are declared, and declared twice in the same function, on
+/// values of `void` calls which are used, and on a `return` whose value does not match
+/// the function.
+///
+/// The tree is walked without recursion, so that deep trees do not overflow the stack.
+pub fn add_types(root: &mut Node) -> Result<()> {
+    let void_functions: HashSet<Name> = functions(root)
+        .into_iter()
+        .filter(|function| function.ty == Some(Ty::Void))
+        .filter_map(|function| match function.kind {
+            NodeKind::Function { name } => Some(name),
+            _ => None,
+        })
+        .collect();
+    // Types of the variables declared at the top level and in each function, by offset.
+    let mut scopes: Vec<HashMap<usize, Ty>> = vec![HashMap::new()];
+    // Whether the top level and each function return nothing, by scope.
+    let mut returns_void = vec![false];
+    // Kind, number of children and type of each node, parents before their children.
+    let mut nodes = Vec::new();
+    // Nodes to visit, with their scope and whether their value is used.
+    let mut stack = vec![(root, 0, false)];
+    while let Some((node, scope, used)) = stack.pop() {
+        let Node {
+            kind,
+            loc,
+            ty,
+            init,
+            cond,
+            inc,
+            lhs,
+            rhs,
+            stmts,
+        } = node;
+        let scope = match *kind {
+            NodeKind::Function { .. } => {
+                let params = stmts.iter().filter_map(|param| match param.kind {
+                    NodeKind::LVar { offset } => Some((offset, Ty::Int)),
+                    _ => None,
+                });
+                scopes.push(params.collect());
+                returns_void.push(*ty == Some(Ty::Void));
+                scopes.len() - 1
+            }
+            _ => scope,
+        };
+        match (*kind, lhs.as_deref()) {
+            (NodeKind::LVar { offset }, _) => match scopes[scope].get(&offset) {
+                Some(declared) => *ty = Some(declared.clone()),
+                None => {
+                    let message = format!("use of undeclared variable '{}'", lvar_name(offset));
+                    return Err(CompileError::Semantic(message, *loc).into());
+                }
+            },
+            (
+                NodeKind::Decl,
+                Some(Node {
+                    kind: NodeKind::LVar { offset },
+                    ty: Some(declared),
+                    ..
+                }),
+            ) => {
+                let redeclared = scopes[scope].insert(*offset, declared.clone()).is_some();
+                if redeclared {
+                    let message = format!("redeclaration of '{}'", lvar_name(*offset));
+                    return Err(CompileError::Semantic(message, *loc).into());
+                }
+            }
+            (NodeKind::Call { name }, _) => {
+                if !void_functions.contains(&name) {
+                    *ty = Some(Ty::Int);
+                } else if used {
+                    let message = format!("value of void function '{}' is used", name);
+                    return Err(CompileError::Semantic(message, *loc).into());
+                } else {
+                    *ty = Some(Ty::Void);
+                }
+            }
+            (NodeKind::Return, value) => match (value, returns_void[scope]) {
+                (None, false) => {
+                    let message = "expected a value after 'return'".to_string();
+                    return Err(CompileError::Semantic(message, *loc).into());
+                }
+                (Some(_), true) => {
+                    let message = "'return' with a value in a void function".to_string();
+                    return Err(CompileError::Semantic(message, *loc).into());
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+
+        // Statements discard the values of their children, but for their conditions.
+        let discards = matches!(
+            *kind,
+            NodeKind::Seq
+                | NodeKind::If
+                | NodeKind::While
+                | NodeKind::For
+                | NodeKind::DoWhile
+                | NodeKind::Block
+                | NodeKind::Switch
+                | NodeKind::Case(_)
+                | NodeKind::Default
+                | NodeKind::Function { .. }
+                | NodeKind::Decl
+        );
+        let children: Vec<(&mut Node, bool)> = init
+            .as_deref_mut()
+            .map(|init| (init, !discards))
+            .into_iter()
+            .chain(cond.as_deref_mut().map(|cond| (cond, true)))
+            .chain(inc.as_deref_mut().map(|inc| (inc, !discards)))
+            .chain(lhs.as_deref_mut().map(|lhs| (lhs, !discards)))
+            .chain(rhs.as_deref_mut().map(|rhs| (rhs, !discards)))
+            .chain(stmts.iter_mut().map(|stmt| (&mut **stmt, !discards)))
+            .collect();
+        nodes.push((*kind, children.len(), ty));
+        // Visits the children in order, so that declarations come before the uses.
+        stack.extend(
+            children
+                .into_iter()
+                .rev()
+                .map(|(child, used)| (child, scope, used)),
+        );
+    }
+
+    // Children come before their parents in reverse, with their types in reverse order.
+    let mut types: Vec<Option<Ty>> = Vec::new();
+    for (kind, count, ty) in nodes.into_iter().rev() {
+        let mut operands = types.split_off(types.len() - count);
+        operands.reverse();
+        if let Some(new) = expr_type(kind, &operands) {
+            *ty = Some(new);
+        }
+        types.push(ty.clone());
+    }
+    Ok(())
+}
+
+/// Returns the type of an expression of `kind` with the `operands` types, and `None` for
+/// statements, variables and calls, which are typed already.
+fn expr_type(kind: NodeKind, operands: &[Option<Ty>]) -> Option<Ty> {
+    let lhs = operands.first().cloned().flatten();
+    let rhs = operands.get(1).cloned().flatten();
+    match kind {
+        NodeKind::Num(_)
+        | NodeKind::Mul
+        | NodeKind::Div
+        | NodeKind::Eq
+        | NodeKind::Neq
+        | NodeKind::Lt
+        | NodeKind::Leq
+        | NodeKind::Gt
+        | NodeKind::Geq
+        | NodeKind::Not
+        | NodeKind::BitAnd
+        | NodeKind::BitOr
+        | NodeKind::BitXor
+        | NodeKind::Shl
+        | NodeKind::Shr
+        | NodeKind::BitNot
+        | NodeKind::LogAnd
+        | NodeKind::LogOr => Some(Ty::Int),
+        NodeKind::Add => match (lhs, rhs) {
+            (Some(ty @ Ty::Ptr(_)), _) | (_, Some(ty @ Ty::Ptr(_))) => Some(ty),
+            _ => Some(Ty::Int),
+        },
+        NodeKind::Sub => match (lhs, rhs) {
+            (Some(ty @ Ty::Ptr(_)), Some(Ty::Int)) => Some(ty),
+            _ => Some(Ty::Int),
+        },
+        NodeKind::Assign => lhs,
+        NodeKind::Addr => Some(Ty::pointer_to(lhs.unwrap_or(Ty::Int))),
+        // Variables declared as `int` may still hold addresses.
+        NodeKind::Deref => Some(lhs.as_ref().and_then(Ty::base).cloned().unwrap_or(Ty::Int)),
+        NodeKind::Seq
+        | NodeKind::If
+        | NodeKind::While
+        | NodeKind::For
+        | NodeKind::DoWhile
+        | NodeKind::Block
+        | NodeKind::Break
+        | NodeKind::Continue
+        | NodeKind::Switch
+        | NodeKind::Return
+        | NodeKind::Case(_)
+        | NodeKind::Default
+        | NodeKind::LVar { .. }
+        | NodeKind::Call { .. }
+        | NodeKind::Function { .. }
+        | NodeKind::Decl => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::{parse_streaming, NodeKind};
+    use crate::token::Loc;
+    use crate::ty::*;
+
+    use anyhow::Context;
+
+    #[test]
+    fn test_add_types() -> Result<()> {
+        let root = parse_streaming("int **p; int x; *p + x;")?;
+        let add = root.rhs.as_ref().context("No rhs")?;
+        let int_ptr = Ty::pointer_to(Ty::Int);
+        assert_eq!(add.ty, Some(int_ptr.clone()));
+        let deref = add.lhs.as_ref().context("No lhs")?;
+        assert_eq!(deref.kind, NodeKind::Deref);
+        assert_eq!(
+            deref.lhs.as_ref().context("No lhs")?.ty,
+            Some(Ty::pointer_to(int_ptr.clone()))
+        );
+        assert_eq!(add.rhs.as_ref().context("No rhs")?.ty, Some(Ty::Int));
+
+        for (input, expected) in [
+            ("1 + 2;", Some(Ty::Int)),
+            ("int a; a;", Some(Ty::Int)),
+            ("int a; &a;", Some(int_ptr.clone())),
+            ("int a = 1;", None),
+            ("int *p; p - 1;", Some(int_ptr.clone())),
+            ("int *p; 1 + p;", Some(int_ptr.clone())),
+            ("int *p; int *q; p - q;", Some(Ty::Int)),
+            ("int *p; p == 0;", Some(Ty::Int)),
+            ("int *p; *p;", Some(Ty::Int)),
+            ("int a; int *p; a = p;", Some(Ty::Int)),
+            ("int a; int *p; p = &a;", Some(int_ptr.clone())),
+            ("char c; c;", Some(Ty::Char)),
+            ("char c; c + 1;", Some(Ty::Int)),
+            ("char c; c = 300;", Some(Ty::Char)),
+            ("char *s; s + 1;", Some(Ty::pointer_to(Ty::Char))),
+            ("char *s; *s;", Some(Ty::Char)),
+            ("_Bool b; b = 5;", Some(Ty::Bool)),
+            ("_Bool b; b + 1;", Some(Ty::Int)),
+            ("if (1) 2;", None),
+        ]
+        .iter()
+        {
+            let root = parse_streaming(input)?;
+            let last = match root.kind {
+                NodeKind::Seq => root.rhs.as_deref().context("No rhs")?,
+                _ => &root,
+            };
+            assert_eq!(last.ty, *expected, "{}", input);
+        }
+        assert_eq!(int_ptr.to_string(), "int*");
+        assert_eq!(Ty::pointer_to(Ty::Char).to_string(), "char*");
+        assert_eq!(Ty::Char.size(), 1);
+        assert_eq!(Ty::pointer_to(Ty::Char).size(), 8);
+        assert_eq!(Ty::pointer_to(Ty::Bool).to_string(), "_Bool*");
+        assert_eq!(Ty::Bool.size(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scopes() -> Result<()> {
+        // Each function declares variables of its own.
+        let root = parse_streaming("int *p; f(p) { p; } p;")?;
+        let f = root.lhs.as_ref().and_then(|lhs| lhs.rhs.as_ref());
+        let body = f.and_then(|f| f.lhs.as_ref()).context("No body")?;
+        assert_eq!(body.stmts[0].ty, Some(Ty::Int));
+        let p = root.rhs.as_ref().context("No rhs")?;
+        assert_eq!(p.ty, Some(Ty::pointer_to(Ty::Int)));
+
+        for (input, message, col) in [
+            ("int a; int *a;", "redeclaration of 'a'", 8),
+            ("int a; b;", "use of undeclared variable 'b'", 8),
+        ]
+        .iter()
+        {
+            let err = parse_streaming(input).unwrap_err();
+            match err.downcast_ref::<CompileError>() {
+                Some(CompileError::Semantic(actual, loc)) => {
+                    assert_eq!(actual, message);
+                    assert_eq!(*loc, Loc { line: 1, col: *col });
+                }
+                _ => panic!("unexpected error {:?}", err),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_void() -> Result<()> {
+        // Calls to void functions may be statements, even before the definition.
+        let root = parse_streaming("f(); void f() { return; } g();")?;
+        let call = root.lhs.as_ref().and_then(|lhs| lhs.lhs.as_ref());
+        assert_eq!(call.context("No call")?.ty, Some(Ty::Void));
+        assert_eq!(root.rhs.as_ref().context("No rhs")?.ty, Some(Ty::Int));
+        assert!(parse_streaming("void f() {} int a; if (a) f(); else { f(); }").is_ok());
+        assert!(parse_streaming("void f() {} for (f(); 0; f()) f();").is_ok());
+
+        for (input, message, col) in [
+            (
+                "void f() {} int x = f();",
+                "value of void function 'f' is used",
+                21,
+            ),
+            (
+                "void f() {} f() + 1;",
+                "value of void function 'f' is used",
+                13,
+            ),
+            (
+                "void f() {} if (f()) 1;",
+                "value of void function 'f' is used",
+                17,
+            ),
+            (
+                "void f() {} g(f());",
+                "value of void function 'f' is used",
+                15,
+            ),
+            (
+                "void f() { return 1; }",
+                "'return' with a value in a void function",
+                12,
+            ),
+            ("f() { return; }", "expected a value after 'return'", 7),
+            ("return;", "expected a value after 'return'", 1),
+        ]
+        .iter()
+        {
+            let err = parse_streaming(input).unwrap_err();
+            match err.downcast_ref::<CompileError>() {
+                Some(CompileError::Semantic(actual, loc)) => {
+                    assert_eq!(actual, message, "{}", input);
+                    assert_eq!(*loc, Loc { line: 1, col: *col }, "{}", input);
+                }
+                _ => panic!("unexpected error {:?}", err),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,746 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+use crate::parse::{children, lvar_name, Node, NodeKind, NodeRef};
+use crate::ty::Ty;
+
+/// Where to put parentheses when printing AST back into source.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Parens {
+    /// Around every binary operation.
+    Full,
+    /// Only where precedence and associativity require them.
+    Minimal,
+}
+
+impl FromStr for Parens {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full" => Ok(Parens::Full),
+            "minimal" => Ok(Parens::Minimal),
+            _ => Err(anyhow!("unknown parenthesization '{}'", s)),
+        }
+    }
+}
+
+/// Returns the binary operator symbol of `kind`.
+pub(crate) fn symbol(kind: NodeKind) -> Option<&'static str> {
+    let symbol = match kind {
+        NodeKind::Add => "+",
+        NodeKind::Sub => "-",
+        NodeKind::Mul => "*",
+        NodeKind::Div => "/",
+        NodeKind::Eq => "==",
+        NodeKind::Neq => "!=",
+        NodeKind::Lt => "<",
+        NodeKind::Leq => "<=",
+        NodeKind::Gt => ">",
+        NodeKind::Geq => ">=",
+        NodeKind::BitAnd => "&",
+        NodeKind::BitOr => "|",
+        NodeKind::BitXor => "^",
+        NodeKind::Shl => "<<",
+        NodeKind::Shr => ">>",
+        NodeKind::LogAnd => "&&",
+        NodeKind::LogOr => "||",
+        NodeKind::Assign => "=",
+        NodeKind::Seq => ";",
+        NodeKind::Num(_)
+        | NodeKind::LVar { .. }
+        | NodeKind::If
+        | NodeKind::While
+        | NodeKind::For
+        | NodeKind::DoWhile
+        | NodeKind::Block
+        | NodeKind::Break
+        | NodeKind::Continue
+        | NodeKind::Return
+        | NodeKind::Switch
+        | NodeKind::Case(_)
+        | NodeKind::Default
+        | NodeKind::Call { .. }
+        | NodeKind::Function { .. }
+        | NodeKind::Addr
+        | NodeKind::Deref
+        | NodeKind::Not
+        | NodeKind::BitNot
+        | NodeKind::Decl => return None,
+    };
+    Some(symbol)
+}
+
+/// Binding strength of `kind`; larger binds tighter.
+fn precedence(kind: NodeKind) -> u8 {
+    match kind {
+        NodeKind::Seq
+        | NodeKind::If
+        | NodeKind::While
+        | NodeKind::For
+        | NodeKind::DoWhile
+        | NodeKind::Block
+        | NodeKind::Break
+        | NodeKind::Continue
+        | NodeKind::Return
+        | NodeKind::Switch
+        | NodeKind::Case(_)
+        | NodeKind::Default
+        | NodeKind::Function { .. }
+        | NodeKind::Decl => 0,
+        NodeKind::Assign => 1,
+        NodeKind::LogOr => 2,
+        NodeKind::LogAnd => 3,
+        NodeKind::BitOr => 4,
+        NodeKind::BitXor => 5,
+        NodeKind::BitAnd => 6,
+        NodeKind::Eq | NodeKind::Neq => 7,
+        NodeKind::Lt | NodeKind::Leq | NodeKind::Gt | NodeKind::Geq => 8,
+        NodeKind::Shl | NodeKind::Shr => 9,
+        NodeKind::Add | NodeKind::Sub => 10,
+        NodeKind::Mul | NodeKind::Div => 11,
+        NodeKind::Num(_)
+        | NodeKind::LVar { .. }
+        | NodeKind::Call { .. }
+        | NodeKind::Addr
+        | NodeKind::Deref
+        | NodeKind::Not
+        | NodeKind::BitNot => 12,
+    }
+}
+
+/// Prints AST back into source with as few parentheses as possible.
+///
+/// Unary minus on anything but a literal is printed as the subtraction it is parsed into,
+/// e.g. `-(1)` as `0 - 1`.
+pub fn unparse(node: &Node) -> String {
+    unparse_with(node, Parens::Minimal)
+}
+
+/// Prints AST back into source with parentheses following `parens`.
+pub fn unparse_with(node: &Node, parens: Parens) -> String {
+    let mut out = String::new();
+    match parens {
+        Parens::Full => do_unparse_stmt(node, &mut out, do_unparse_full),
+        Parens::Minimal => do_unparse_stmt(node, &mut out, do_unparse),
+    }
+    out
+}
+
+/// Prints the statement `node` with its last `;`, printing expressions with `unparse`.
+///
+/// An `if` lacking `else` right before an `else` takes it, as when parsed.
+fn do_unparse_stmt(node: &Node, out: &mut String, unparse: fn(&Node, &mut String)) {
+    let stmt = |child: &Option<NodeRef>, out: &mut String| {
+        if let Some(child) = child.as_ref() {
+            do_unparse_stmt(child, out, unparse);
+        }
+    };
+    let expr = |child: &Option<NodeRef>, out: &mut String| {
+        if let Some(child) = child.as_ref() {
+            unparse(child, out);
+        }
+    };
+    match node.kind {
+        NodeKind::Seq => {
+            // Sequences lean left; print the first statement, found at the bottom, first.
+            let mut rest = Vec::new();
+            let mut first = node;
+            while let (NodeKind::Seq, Some(lhs)) = (first.kind, first.lhs.as_deref()) {
+                rest.push(&first.rhs);
+                first = lhs;
+            }
+            do_unparse_stmt(first, out, unparse);
+            for rhs in rest.into_iter().rev() {
+                out.push(' ');
+                stmt(rhs, out);
+            }
+        }
+        NodeKind::Block => {
+            out.push('{');
+            for child in node.stmts.iter() {
+                out.push(' ');
+                do_unparse_stmt(child, out, unparse);
+            }
+            out.push_str(if node.stmts.is_empty() { "}" } else { " }" });
+        }
+        NodeKind::Switch => {
+            out.push_str("switch (");
+            expr(&node.cond, out);
+            out.push_str(") {");
+            for arm in node.stmts.iter() {
+                out.push(' ');
+                do_unparse_stmt(arm, out, unparse);
+            }
+            out.push_str(if node.stmts.is_empty() { "}" } else { " }" });
+        }
+        NodeKind::Case(_) | NodeKind::Default => {
+            match node.kind {
+                NodeKind::Case(value) => out.push_str(&format!("case {}:", value)),
+                _ => out.push_str("default:"),
+            }
+            for child in node.stmts.iter() {
+                out.push(' ');
+                do_unparse_stmt(child, out, unparse);
+            }
+        }
+        NodeKind::If => {
+            out.push_str("if (");
+            expr(&node.cond, out);
+            out.push_str(") ");
+            stmt(&node.lhs, out);
+            if node.rhs.is_some() {
+                out.push_str(" else ");
+                stmt(&node.rhs, out);
+            }
+        }
+        NodeKind::While => {
+            out.push_str("while (");
+            expr(&node.cond, out);
+            out.push_str(") ");
+            stmt(&node.lhs, out);
+        }
+        NodeKind::For => {
+            out.push_str("for (");
+            expr(&node.init, out);
+            for clause in [&node.cond, &node.inc].iter() {
+                out.push(';');
+                if clause.is_some() {
+                    out.push(' ');
+                    expr(clause, out);
+                }
+            }
+            out.push_str(") ");
+            stmt(&node.lhs, out);
+        }
+        NodeKind::DoWhile => {
+            out.push_str("do ");
+            stmt(&node.lhs, out);
+            out.push_str(" while (");
+            expr(&node.cond, out);
+            out.push_str(");");
+        }
+        NodeKind::Break => out.push_str("break;"),
+        NodeKind::Continue => out.push_str("continue;"),
+        NodeKind::Return => {
+            out.push_str("return");
+            if node.lhs.is_some() {
+                out.push(' ');
+                expr(&node.lhs, out);
+            }
+            out.push(';');
+        }
+        NodeKind::Function { .. } => {
+            if let Some(ty) = node.ty.as_ref() {
+                out.push_str(&format!("{} ", ty));
+            }
+            do_unparse_leaf(node, out, unparse);
+            out.push(' ');
+            stmt(&node.lhs, out);
+        }
+        NodeKind::Decl => {
+            if let Some(var) = node.lhs.as_ref() {
+                out.push_str(&declaration(var));
+                if let Some(init) = node.rhs.as_ref() {
+                    out.push_str(" = ");
+                    expr(&init.rhs, out);
+                }
+                out.push(';');
+            }
+        }
+        _ => {
+            unparse(node, out);
+            out.push(';');
+        }
+    }
+}
+
+/// Returns the declaration of the variable `var` with its type, e.g. `int **p`.
+fn declaration(var: &Node) -> String {
+    let mut ty = var.ty.as_ref().unwrap_or(&Ty::Int);
+    let mut stars = String::new();
+    while let Some(base) = ty.base() {
+        stars.push('*');
+        ty = base;
+    }
+    let name = match var.kind {
+        NodeKind::LVar { offset } => lvar_name(offset),
+        _ => '?',
+    };
+    format!("{} {}{}", ty, stars, name)
+}
+
+/// Returns the prefix operator symbol of `kind`.
+fn unary_symbol(kind: NodeKind) -> Option<char> {
+    match kind {
+        NodeKind::Addr => Some('&'),
+        NodeKind::Deref => Some('*'),
+        NodeKind::Not => Some('!'),
+        NodeKind::BitNot => Some('~'),
+        _ => None,
+    }
+}
+
+fn do_unparse_full(node: &Node, out: &mut String) {
+    if let (Some(symbol), Some(operand)) = (unary_symbol(node.kind), node.lhs.as_ref()) {
+        out.push(symbol);
+        do_unparse_full(operand, out);
+        return;
+    }
+    let (links, first) = left_chain(node);
+    if links.is_empty() {
+        do_unparse_leaf(node, out, do_unparse_full);
+        return;
+    }
+    out.push_str(&"(".repeat(links.len()));
+    do_unparse_full(first, out);
+    for (symbol, link) in links.into_iter().rev() {
+        out.push(' ');
+        out.push_str(symbol);
+        out.push(' ');
+        if let Some(rhs) = link.rhs.as_ref() {
+            do_unparse_full(rhs, out);
+        }
+        out.push(')');
+    }
+}
+
+/// Splits the binary operators down the left side of `node` into their symbols and nodes,
+/// from the top, and the operand they start from.
+///
+/// Operators are walked in a loop so that long left associative chains, like a sum of
+/// thousands of terms, do not overflow the stack.
+fn left_chain(node: &Node) -> (Vec<(&'static str, &Node)>, &Node) {
+    let mut links = Vec::new();
+    let mut first = node;
+    while let (Some(symbol), Some(lhs)) = (symbol(first.kind), first.lhs.as_deref()) {
+        links.push((symbol, first));
+        first = lhs;
+    }
+    (links, first)
+}
+
+fn do_unparse(node: &Node, out: &mut String) {
+    if let (Some(symbol), Some(operand)) = (unary_symbol(node.kind), node.lhs.as_ref()) {
+        out.push(symbol);
+        do_unparse_operand(
+            operand,
+            precedence(operand.kind) < precedence(node.kind),
+            out,
+        );
+        return;
+    }
+    let (links, first) = left_chain(node);
+    if links.is_empty() {
+        do_unparse_leaf(node, out, do_unparse);
+        return;
+    }
+    // All the binary operators but assignment are left associative.
+    let lhs_parens = |link: &Node, lhs: &Node| {
+        let (prec, lhs_prec) = (precedence(link.kind), precedence(lhs.kind));
+        lhs_prec < prec || link.kind == NodeKind::Assign && lhs_prec == prec
+    };
+    // The left sides all start where `first` does, so open their parentheses up front.
+    let opened = links
+        .iter()
+        .zip(links.iter().skip(1).map(|(_, lhs)| *lhs).chain(Some(first)))
+        .map(|((_, link), lhs)| lhs_parens(link, lhs))
+        .collect::<Vec<bool>>();
+    out.push_str(&"(".repeat(opened.iter().filter(|&&parens| parens).count()));
+    do_unparse(first, out);
+    for ((symbol, link), parens) in links.into_iter().zip(opened).rev() {
+        if parens {
+            out.push(')');
+        }
+        out.push(' ');
+        out.push_str(symbol);
+        out.push(' ');
+        if let Some(rhs) = link.rhs.as_ref() {
+            let (prec, rhs_prec) = (precedence(link.kind), precedence(rhs.kind));
+            let right_assoc = link.kind == NodeKind::Assign;
+            do_unparse_operand(
+                rhs,
+                rhs_prec < prec || !right_assoc && rhs_prec == prec,
+                out,
+            );
+        }
+    }
+}
+
+/// Prints a node without operators, printing the arguments of calls with `unparse`.
+///
+/// Prints only the name and the parameters of a function.
+fn do_unparse_leaf(node: &Node, out: &mut String, unparse: fn(&Node, &mut String)) {
+    match node.kind {
+        NodeKind::Num(num) => out.push_str(&num.to_string()),
+        NodeKind::LVar { offset } => out.push(lvar_name(offset)),
+        NodeKind::Call { name } | NodeKind::Function { name } => {
+            out.push_str(name.as_str());
+            out.push('(');
+            for (i, arg) in node.stmts.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                unparse(arg, out);
+            }
+            out.push(')');
+        }
+        _ => (),
+    }
+}
+
+fn do_unparse_operand(node: &Node, parens: bool, out: &mut String) {
+    if parens {
+        out.push('(');
+    }
+    do_unparse(node, out);
+    if parens {
+        out.push(')');
+    }
+}
+
+/// Prints AST as an indented tree, one node per line.
+pub fn tree(node: &Node) -> String {
+    let mut out = String::new();
+    do_tree(node, 0, &mut out);
+    out
+}
+
+fn do_tree(node: &Node, depth: usize, out: &mut String) {
+    // Nodes still to print, the next one on top.
+    let mut stack = vec![(node, depth)];
+    while let Some((node, depth)) = stack.pop() {
+        out.push_str(&"  ".repeat(depth));
+        match node.kind {
+            NodeKind::LVar { offset } => out.push(lvar_name(offset)),
+            kind => out.push_str(&format!("{:?}", kind)),
+        }
+        out.push('\n');
+        let first = stack.len();
+        stack.extend(children(node).map(|child| (child, depth + 1)));
+        stack[first..].reverse();
+    }
+}
+
+/// Prints AST as an S-expression, e.g. `(+ 1 (* 2 3))`.
+pub fn sexpr(node: &Node) -> String {
+    match symbol(node.kind) {
+        Some(symbol) if node.lhs.is_none() => format!(
+            "({}  {})",
+            symbol,
+            node.rhs.as_deref().map_or_else(String::new, sexpr)
+        ),
+        Some(_) => {
+            let (links, first) = left_chain(node);
+            let mut out = String::new();
+            for (symbol, _) in links.iter() {
+                out.push_str(&format!("({} ", symbol));
+            }
+            out.push_str(&sexpr(first));
+            for (_, link) in links.into_iter().rev() {
+                out.push_str(&format!(
+                    " {})",
+                    link.rhs.as_deref().map_or_else(String::new, sexpr)
+                ));
+            }
+            out
+        }
+        None => match node.kind {
+            NodeKind::If => format!(
+                "(if {} {}{})",
+                node.cond.as_deref().map_or_else(String::new, sexpr),
+                node.lhs.as_deref().map_or_else(String::new, sexpr),
+                node.rhs
+                    .as_deref()
+                    .map_or_else(String::new, |els| format!(" {}", sexpr(els)))
+            ),
+            NodeKind::While => format!(
+                "(while {} {})",
+                node.cond.as_deref().map_or_else(String::new, sexpr),
+                node.lhs.as_deref().map_or_else(String::new, sexpr)
+            ),
+            // Missing clauses are printed as `()`.
+            NodeKind::For => format!(
+                "(for {} {} {} {})",
+                node.init.as_deref().map_or_else(|| "()".to_string(), sexpr),
+                node.cond.as_deref().map_or_else(|| "()".to_string(), sexpr),
+                node.inc.as_deref().map_or_else(|| "()".to_string(), sexpr),
+                node.lhs.as_deref().map_or_else(String::new, sexpr)
+            ),
+            NodeKind::DoWhile => format!(
+                "(do {} {})",
+                node.lhs.as_deref().map_or_else(String::new, sexpr),
+                node.cond.as_deref().map_or_else(String::new, sexpr)
+            ),
+            NodeKind::Block => {
+                let stmts: Vec<String> = node.stmts.iter().map(|stmt| sexpr(stmt)).collect();
+                format!(
+                    "(block{})",
+                    stmts
+                        .iter()
+                        .map(|stmt| format!(" {}", stmt))
+                        .collect::<String>()
+                )
+            }
+            NodeKind::Switch => format!(
+                "(switch {}{})",
+                node.cond.as_deref().map_or_else(String::new, sexpr),
+                node.stmts
+                    .iter()
+                    .map(|arm| format!(" {}", sexpr(arm)))
+                    .collect::<String>()
+            ),
+            NodeKind::Case(_) | NodeKind::Default => format!(
+                "({}{})",
+                match node.kind {
+                    NodeKind::Case(value) => format!("case {}", value),
+                    _ => "default".to_string(),
+                },
+                node.stmts
+                    .iter()
+                    .map(|stmt| format!(" {}", sexpr(stmt)))
+                    .collect::<String>()
+            ),
+            NodeKind::Break => "(break)".to_string(),
+            NodeKind::Continue => "(continue)".to_string(),
+            NodeKind::Return => format!(
+                "(return{})",
+                node.lhs
+                    .as_deref()
+                    .map_or_else(String::new, |value| format!(" {}", sexpr(value)))
+            ),
+            NodeKind::Num(num) => num.to_string(),
+            NodeKind::LVar { offset } => lvar_name(offset).to_string(),
+            NodeKind::Decl => format!(
+                "(decl {}{})",
+                node.lhs.as_deref().map_or_else(String::new, declaration),
+                node.rhs
+                    .as_deref()
+                    .map_or_else(String::new, |init| format!(" {}", sexpr(init)))
+            ),
+            NodeKind::Addr => format!(
+                "(addr {})",
+                node.lhs.as_deref().map_or_else(String::new, sexpr)
+            ),
+            NodeKind::Deref => format!(
+                "(deref {})",
+                node.lhs.as_deref().map_or_else(String::new, sexpr)
+            ),
+            NodeKind::Not => format!(
+                "(not {})",
+                node.lhs.as_deref().map_or_else(String::new, sexpr)
+            ),
+            NodeKind::BitNot => format!(
+                "(~ {})",
+                node.lhs.as_deref().map_or_else(String::new, sexpr)
+            ),
+            NodeKind::Call { name } => format!(
+                "(call {}{})",
+                name,
+                node.stmts
+                    .iter()
+                    .map(|arg| format!(" {}", sexpr(arg)))
+                    .collect::<String>()
+            ),
+            NodeKind::Function { name } => format!(
+                "(function {}{} ({}) {})",
+                node.ty
+                    .as_ref()
+                    .map_or_else(String::new, |ty| format!("{} ", ty)),
+                name,
+                node.stmts
+                    .iter()
+                    .map(|param| sexpr(param))
+                    .collect::<Vec<String>>()
+                    .join(" "),
+                node.lhs.as_deref().map_or_else(String::new, sexpr)
+            ),
+            _ => unreachable!(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::{parse_streaming, structurally_eq};
+    use crate::unparse::*;
+
+    use anyhow::Context;
+
+    /// Declarations of the variables the tests use, as printed.
+    const DECLS: &str = "int a; int b; int c; int d; int e; int i; int s;";
+
+    /// Returns the s-expression of the last statement of `input`, after its declarations.
+    fn last_sexpr(input: &str) -> Result<String> {
+        let root = parse_streaming(input)?;
+        Ok(sexpr(root.rhs.as_deref().context("No rhs")?))
+    }
+
+    #[test]
+    fn test_unparse() -> Result<()> {
+        for (input, expected) in [
+            ("1+2*3;", "1 + 2 * 3;"),
+            ("(1+2)*3;", "(1 + 2) * 3;"),
+            ("1-(2-3);", "1 - (2 - 3);"),
+            ("(1-2)-3;", "1 - 2 - 3;"),
+            ("-3;", "-3;"),
+            ("-(3);", "0 - 3;"),
+            ("3 - -5;", "3 - -5;"),
+            ("1 < 2 == (3 >= 4);", "1 < 2 == 3 >= 4;"),
+            ("1 == (2 == 3);", "1 == (2 == 3);"),
+            ("1;(2);3+4;", "1; 2; 3 + 4;"),
+            ("a = b = 1 + 2;", "a = b = 1 + 2;"),
+            ("a = (b == 1);", "a = b == 1;"),
+            ("if(a<1)b=(2);c;", "if (a < 1) b = 2; c;"),
+            ("if (a) if (b) (c);", "if (a) if (b) c;"),
+            (
+                "if(a)b;else if(c)d;else(e);",
+                "if (a) b; else if (c) d; else e;",
+            ),
+            ("while(a<3)a=a+1;", "while (a < 3) a = a + 1;"),
+            (
+                "for(i=0;i<5;i=i+1)s=s+i;",
+                "for (i = 0; i < 5; i = i + 1) s = s + i;",
+            ),
+            ("for(;;)a;", "for (;;) a;"),
+            ("for(;a;)b;", "for (; a;) b;"),
+            ("do a=a+1;while(a<3);", "do a = a + 1; while (a < 3);"),
+            ("if(1){a=1;b=2;}a+b;", "if (1) { a = 1; b = 2; } a + b;"),
+            ("while(a){}", "while (a) {}"),
+            ("{{a;}}", "{ { a; } }"),
+            ("while(1)break;", "while (1) break;"),
+            ("for(;;){continue;}", "for (;;) { continue; }"),
+            (
+                "switch(a){case 1:b;c;case -2:default:d;}",
+                "switch (a) { case 1: b; c; case -2: default: d; }",
+            ),
+            ("switch(a){}", "switch (a) {}"),
+            ("return(1+2)*3;", "return (1 + 2) * 3;"),
+            ("f()+g(1,a=2*3);", "f() + g(1, a = 2 * 3);"),
+            ("*&a=*b**c;", "*&a = *b * *c;"),
+            ("*(a+1)=-3;", "*(a + 1) = -3;"),
+            ("&*(a);", "&*a;"),
+            ("!(!a)==(!(b+1));", "!!a == !(b + 1);"),
+            ("(a|b)^(c&d)|~(a+1);", "(a | b) ^ c & d | ~(a + 1);"),
+            ("a&(b==c);", "a & b == c;"),
+            ("(a<<b+1)<(c>>2);", "a << b + 1 < c >> 2;"),
+            ("a<<(b<<c);", "a << (b << c);"),
+            ("(a||b&&c)&&(d|e);", "(a || b && c) && d | e;"),
+            ("int**p;int x=(1);", "int **p; int x = 1;"),
+            ("int*q=&a+1;", "int *q = &a + 1;"),
+            ("char*t;char x=(65);", "char *t; char x = 65;"),
+            ("_Bool*u;_Bool y=(2);", "_Bool *u; _Bool y = 2;"),
+            (
+                "sub(a,b){return a-b;}sub(7,3);",
+                "sub(a, b) { return a - b; } sub(7, 3);",
+            ),
+            ("void f(){return;}f();", "void f() { return; } f();"),
+        ]
+        .iter()
+        {
+            let root = parse_streaming(&format!("{} {}", DECLS, input))?;
+            assert_eq!(unparse(&root), format!("{} {}", DECLS, expected));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_unparse_with() -> Result<()> {
+        for (input, full, minimal) in [
+            ("1+2*3;", "(1 + (2 * 3));", "1 + 2 * 3;"),
+            ("(1-2)-3;", "((1 - 2) - 3);", "1 - 2 - 3;"),
+            ("1-(2-3);", "(1 - (2 - 3));", "1 - (2 - 3);"),
+            ("1 < 2 == 3;", "((1 < 2) == 3);", "1 < 2 == 3;"),
+            ("(42);", "42;", "42;"),
+            ("a*(b+c);", "(a * (b + c));", "a * (b + c);"),
+            ("1; (2)*3;", "1; (2 * 3);", "1; 2 * 3;"),
+            (
+                "if (a+1) 2*3;",
+                "if ((a + 1)) (2 * 3);",
+                "if (a + 1) 2 * 3;",
+            ),
+            ("f(1+2, g());", "f((1 + 2), g());", "f(1 + 2, g());"),
+            ("*(a+1)*2;", "(*(a + 1) * 2);", "*(a + 1) * 2;"),
+        ]
+        .iter()
+        {
+            let node = parse_streaming(&format!("{} {}", DECLS, input))?;
+            for (parens, expected) in [(Parens::Full, full), (Parens::Minimal, minimal)].iter() {
+                let printed = unparse_with(&node, *parens);
+                assert_eq!(printed, format!("{} {}", DECLS, expected));
+                assert!(structurally_eq(&parse_streaming(&printed)?, &node));
+            }
+        }
+
+        assert_eq!("full".parse::<Parens>()?, Parens::Full);
+        assert!("some".parse::<Parens>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree() -> Result<()> {
+        assert_eq!(
+            tree(&parse_streaming("int a; 1+a*3;")?),
+            "Seq\n  Decl\n    a\n  Add\n    Num(1)\n    Mul\n      a\n      Num(3)\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sexpr() -> Result<()> {
+        assert_eq!(sexpr(&parse_streaming("1+2*3;")?), "(+ 1 (* 2 3))");
+        assert_eq!(sexpr(&parse_streaming("-(4);")?), "(- 0 4)");
+        assert_eq!(sexpr(&parse_streaming("1; 2;")?), "(; 1 2)");
+        let decls = "int a; int b; int c;";
+        assert_eq!(
+            last_sexpr(&format!("{} if (a < 1) b = 2;", decls))?,
+            "(if (< a 1) (= b 2))"
+        );
+        assert_eq!(
+            last_sexpr(&format!("{} if (a) b; else c;", decls))?,
+            "(if a b c)"
+        );
+        assert_eq!(
+            last_sexpr(&format!("{} while (a) b;", decls))?,
+            "(while a b)"
+        );
+        assert_eq!(
+            last_sexpr(&format!("{} do b; while (a);", decls))?,
+            "(do b a)"
+        );
+        assert_eq!(
+            last_sexpr(&format!("{} {{ a; b; }}", decls))?,
+            "(block a b)"
+        );
+        assert_eq!(
+            last_sexpr(&format!("{} switch (a) {{ case 1: b; default: }}", decls))?,
+            "(switch a (case 1 b) (default))"
+        );
+        assert_eq!(
+            last_sexpr(&format!("{} for (; a; b = 1) c;", decls))?,
+            "(for () a (= b 1) c)"
+        );
+        assert_eq!(
+            sexpr(&parse_streaming("f(1, g());")?),
+            "(call f 1 (call g))"
+        );
+        assert_eq!(
+            sexpr(&parse_streaming("f(a, b) { return a; }")?),
+            "(function f (a b) (block (return a)))"
+        );
+        assert_eq!(
+            sexpr(&parse_streaming("void f(p) { *p = 1; return; }")?),
+            "(function void f (p) (block (= (deref p) 1) (return)))"
+        );
+        assert_eq!(last_sexpr("int a; *&a = 1;")?, "(= (deref (addr a)) 1)");
+        assert_eq!(last_sexpr("int a; !!a;")?, "(not (not a))");
+        assert_eq!(last_sexpr("int a; ~a & 1;")?, "(& (~ a) 1)");
+        assert_eq!(sexpr(&parse_streaming("int *p;")?), "(decl int *p)");
+        assert_eq!(
+            sexpr(&parse_streaming("int a = 2;")?),
+            "(decl int a (= a 2))"
+        );
+        Ok(())
+    }
+}
@@ -1,11 +1,16 @@
 #!/bin/bash
 
+cat <<EOF | cc -xc -c -o tmp2.o -
+int foo() { return 42; }
+int add6(int a, int b, int c, int d, int e, int f) { return a + b + c + d + e + f; }
+EOF
+
 assert() {
   expected="$1"
   input="$2"
 
   ./target/debug/rust9cc "$input" > tmp.s
-  cc -o tmp tmp.s
+  cc -o tmp tmp.s tmp2.o
   ./tmp
   actual="$?"
 
@@ -20,12 +25,42 @@ assert() {
 cargo build || exit 1
 cargo test || exit 1
 
-assert 0 0
-assert 42 42
-assert 47 '5+6*7'
-assert 15 '5*(9-6)'
-assert 4 '(3+5)/2'
-assert 4 "5-(-1+2)"
-assert 3 "+5+(-2)"
+assert 0 '0;'
+assert 42 '42;'
+assert 47 '5+6*7;'
+assert 15 '5*(9-6);'
+assert 4 '(3+5)/2;'
+assert 4 '5-(-1+2);'
+assert 3 '+5+(-2);'
+assert 33 'int a = 3; int b = 5 * 6; a + b;'
+assert 5 'int a = 3; if (1 < 2) a = 5; a;'
+assert 3 'int a = 3; if (2 < 1) a = 5; a;'
+assert 3 'int a = 0; if (0) a = 1; else if (0) a = 2; else a = 3; a;'
+assert 10 'int i = 0; while (i < 10) i = i + 1; i;'
+assert 10 'int s = 0; int i; for (i = 0; i < 5; i = i + 1) s = s + i; s;'
+assert 1 'int i = 0; do i = i + 1; while (i < 0); i;'
+assert 3 'int a; int b; if (1) { a = 1; b = 2; } a + b;'
+assert 7 'int i; for (i = 0;; i = i + 1) if (i == 7) break; i;'
+assert 6 'int x = 0; switch (2) { case 1: x = x + 1; case 2: x = x + 2; case 3: x = x + 4; } x;'
+assert 2 'int x = 0; switch (2) { case 1: x = x + 1; break; case 2: x = x + 2; break; case 3: x = x + 4; } x;'
+assert 9 'int s = 0; int i; for (i = 0; i < 10; i = i + 1) { if (i == 5) continue; s = s + 1; } s;'
+assert 5 'return 5; 6;'
+assert 42 'foo();'
+assert 43 'int a = 1; foo() + a;'
+assert 21 'add6(1, 2, 3, 4, 5, 6);'
+assert 4 'sub(a, b) { return a - b; } sub(7, 3);'
+assert 55 'fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } fib(10);'
+assert 3 'int x = 3; int *y = &x; return *y;'
+assert 5 'int x = 3; int *y = &x; *y = 5; x;'
+assert 7 'int x; int *p; p = &x; *p = 7; return x;'
+assert 3 'int a = 1; int b = a + 1; a + b;'
+assert 65 'char c; c = 65; return c;'
+assert 1 '_Bool b; b = 5; return b;'
+assert 1 'int a = 0; return !a;'
+assert 4 'int x = 4; int *p = &x; return - -*p + -!x + ~-1;'
+assert 6 'int a = 12; return a & 7 | 2 ^ ~0 & 0;'
+assert 20 'int a = 5; return a << 4 >> 2;'
+assert 1 'int a = 0; return a == 0 || 1 / a;'
+assert 9 'void set(p) { *p = 9; } int a = 0; set(&a); return a;'
 
 echo OK
\ No newline at end of file
@@ -0,0 +1,480 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+use rust9cc::ir::{parse_ir, Instr, Mem, Reg};
+
+fn rust9cc(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rust9cc"))
+        .args(args)
+        .output()
+        .expect("failed to run rust9cc")
+}
+
+#[test]
+fn test_dump_tokens_on_error() {
+    let output = rust9cc(&["--dump-tokens-on-error", "1 + )"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("1:1\tNum(1)\n"));
+    assert!(stderr.contains("1:5\tRParen\n"));
+    assert!(stderr.contains("\tEof\n"));
+
+    let output = rust9cc(&["1 + )"]);
+    assert!(!output.status.success());
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn test_parse_error() {
+    let output = rust9cc(&["1 +"]);
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Compile error at line 1\n1 +\n   ^ expected an expression\n"
+    );
+
+    // Variables must be declared before they are used.
+    let output = rust9cc(&["a = 3;"]);
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Compile error at line 1\na = 3;\n^ use of undeclared variable 'a'\n"
+    );
+}
+
+#[test]
+fn test_timings() {
+    let output = rust9cc(&["--timings", "1+2*3;"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().contains("main:"));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let labels: Vec<&str> = stderr
+        .lines()
+        .map(|line| line.split(": ").next().unwrap())
+        .collect();
+    assert_eq!(
+        labels,
+        vec!["tokenize", "parse", "optimize", "codegen", "tokens", "ast", "asm", "total"]
+    );
+}
+
+#[test]
+fn test_opt_report_modes() {
+    let output = rust9cc(&["-O", "1", "--stats", "return 1+2;"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(!stdout.contains("push 1"), "{}", stdout);
+
+    for args in [
+        &["--stats"][..],
+        &["--summary"],
+        &["--timings"],
+        &["--mode", "ir"],
+        &["--mode", "pretty"],
+        &["--mode", "listing"],
+        &["--multi"],
+    ]
+    .iter()
+    {
+        let output = rust9cc(&[&["-O", "1", "--opt-report"], *args, &["(1+2)*3;"]].concat());
+        assert!(output.status.success(), "{:?}", args);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.contains("constant-folding: 2 nodes folded"),
+            "{:?}: {}",
+            args,
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_eval_with_vars() {
+    let output = rust9cc(&[
+        "--eval",
+        "int a; int b; int c; a*b+c;",
+        "--var",
+        "a=3",
+        "--var",
+        "b=4",
+        "--var",
+        "c=1",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "13\n");
+
+    let input = "int a; int b; int c; a*b+c;";
+    let output = rust9cc(&["--eval", input, "--var", "a=3", "--var", "c=1"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("undefined variable 'b'"), "{}", stdout);
+}
+
+#[test]
+fn test_mode_eval() {
+    for (input, expected) in [
+        ("2*(1+23)-456/7;", "-17\n"),
+        ("1==1;", "1\n"),
+        ("int a = 3; a;", "3\n"),
+        ("int a; int b; a = b = 2; a * b;", "4\n"),
+    ]
+    .iter()
+    {
+        let output = rust9cc(&["--mode", "eval", input]);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), *expected);
+    }
+
+    let output = rust9cc(&["--mode", "eval", "int a; a + 1;", "--var", "a=2"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\n");
+
+    let output = rust9cc(&["--mode", "eval", "1 / (2 - 2);"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("division by zero"), "{}", stdout);
+}
+
+#[test]
+fn test_eval_follows_options() {
+    for args in [
+        vec!["--eval", "1+2; # x", "--hash-comments"],
+        vec!["--mode", "eval", "--hash-comments", "1+2; # x"],
+    ]
+    .iter()
+    {
+        let output = rust9cc(args);
+        assert!(output.status.success(), "{:?}", args);
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\n");
+    }
+
+    for args in [
+        vec!["--eval", "((1));", "--limits", "ast-depth=4"],
+        vec!["--mode", "eval", "--limits", "ast-depth=4", "((1));"],
+    ]
+    .iter()
+    {
+        let output = rust9cc(args);
+        assert!(!output.status.success(), "{:?}", args);
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains("ast-depth"), "{}", stdout);
+    }
+}
+
+#[test]
+fn test_mode_const() {
+    let output = rust9cc(&["--mode", "const", "(1 + 2) * 3;"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "9\n");
+
+    for (input, expected) in [
+        ("if (1) 2;", "^ 'if' is not a constant\n"),
+        ("{1;}", "^ a block is not a constant\n"),
+        ("1; return 3;", "   ^ 'return' is not a constant\n"),
+    ]
+    .iter()
+    {
+        let output = rust9cc(&["--mode", "const", input]);
+        assert_eq!(output.status.code(), Some(1), "{}", input);
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.ends_with(expected), "{}", stdout);
+    }
+}
+
+#[test]
+fn test_mode_ir() {
+    let ir = |opt_level| {
+        let output = rust9cc(&["--mode", "ir", "-O", opt_level, "(1-2)*3;"]);
+        assert!(output.status.success());
+        parse_ir(&String::from_utf8(output.stdout).unwrap()).unwrap()
+    };
+    let expected = "
+        Push Imm(1)
+        Push Imm(2)
+        Pop Rdi
+        Pop Rax
+        Sub Rax, Rdi
+        Push Rax
+        Push Imm(3)
+        Pop Rdi
+        Pop Rax
+        Imul Rax, Rdi
+        Push Rax
+        Pop Rax
+        Ret
+    ";
+    assert_eq!(ir("0"), parse_ir(expected).unwrap());
+    let expected = "
+        Mov Rax, Imm(-3)
+        Ret
+    ";
+    assert_eq!(ir("1"), parse_ir(expected).unwrap());
+
+    let output = rust9cc(&["--mode", "ir", "--optimize", "(1-2)*3;"]);
+    assert_eq!(
+        parse_ir(&String::from_utf8(output.stdout).unwrap()).unwrap(),
+        ir("1")
+    );
+
+    // Functions follow the entry, each after its name.
+    let output = rust9cc(&["--mode", "ir", "f(a) { return a; } f(3);"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let (entry, function) = stdout.split_once("\nf:\n").unwrap();
+    assert!(entry.contains("Call Name(f)\n"), "{}", stdout);
+    assert!(parse_ir(entry).is_ok());
+    let store = Instr::Store(
+        Mem {
+            base: Reg::Rbp,
+            disp: -8,
+        },
+        Reg::Rdi,
+    );
+    assert!(parse_ir(function).unwrap().contains(&store));
+}
+
+#[test]
+fn test_mode_listing() {
+    let output = rust9cc(&["--mode", "listing", "f(a) {\n  return a;\n}\nf(3);"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let (entry, function) = stdout.split_once(".globl f\n").unwrap();
+    assert!(entry.contains("# line 4, col 1-3: f(3);\n"), "{}", stdout);
+    assert!(function.contains("f:\n"), "{}", stdout);
+    assert!(
+        function.contains("# line 2, col 3-10:   return a;\n"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn test_multi() {
+    let output = rust9cc(&["--multi", "f(a) { return a; } f(3);", "2;"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for label in ["expr0:\n", "expr1:\n", "f:\n"].iter() {
+        assert!(stdout.contains(label), "{}", stdout);
+    }
+
+    // Functions are global, so each is defined by one input only.
+    let output = rust9cc(&["--multi", "f(a) { return a; } 1;", "f(b) { return b; } 2;"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("^ redefinition of 'f'"), "{}", stdout);
+}
+
+#[test]
+fn test_one_based_locs() {
+    let stdout = |args: &[&str]| String::from_utf8(rust9cc(args).stdout).unwrap();
+
+    // The error is on the second line.
+    let input = "1 +\n  2 / (3 - 3);";
+    assert!(stdout(&["--strict", input]).starts_with("Compile error at line 2\n"));
+    assert!(stdout(&["--mode", "check", "1 +\n  $;"]).starts_with("Compile error at line 2\n"));
+    assert!(stdout(&["--mode", "token", input]).contains("2:3\tNum(2)\n"));
+    assert!(stdout(&["--mode", "listing", input]).contains("# line 2, col 3-12:"));
+    assert!(stdout(&["--mode", "lint", "1 +\n (2);"]).contains("at line 2, col 2:"));
+    let output = rust9cc(&["-O", "1", "--opt-report", "1 +\n  2 * (3 - 3);"]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("at lines 2, 2, 1"), "{}", stderr);
+
+    let output = stdout(&["--zero-based-locs", "--strict", input]);
+    assert!(
+        output.starts_with("Compile error at line 1\n"),
+        "{}",
+        output
+    );
+    assert!(stdout(&["--zero-based-locs", "--mode", "token", input]).contains("1:2\tNum(2)\n"));
+}
+
+#[test]
+fn test_wrapping() {
+    let output = rust9cc(&["--eval", "9223372036854775807 + 1;", "--wrapping"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "-9223372036854775808\n"
+    );
+
+    let output = rust9cc(&["--eval", "9223372036854775807 + 1;"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_check() {
+    for args in [
+        vec!["--check", "1 + 2*3;"],
+        vec!["--mode", "check", "(1 + 2) * 3;"],
+    ]
+    .iter()
+    {
+        let output = rust9cc(args);
+        assert!(output.status.success(), "{:?}", args);
+        assert!(output.stdout.is_empty(), "{:?}", args);
+    }
+
+    let output = rust9cc(&["--check", "1 +\n  2 $;"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.starts_with("Compile error at line 2\n"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("unable to tokenize '$'"), "{}", stdout);
+
+    let output = rust9cc(&["--check", "--strict", "1 / 0;"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_lint() {
+    let output = rust9cc(&["--mode", "lint", "--hash-comments", "(1)+2; # x"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "warning at line 1, col 1: redundant parentheses\n"
+    );
+
+    let output = rust9cc(&["--mode", "lint", "--limits", "ast-depth=4", "((1));"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("ast-depth"), "{}", stdout);
+}
+
+#[test]
+fn test_output_file() {
+    let path = std::env::temp_dir().join(format!("rust9cc-cli-{}.s", std::process::id()));
+    let path_str = path.to_str().unwrap();
+    let output = rust9cc(&["-o", path_str, "1+2;"]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    let asm = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(asm, String::from_utf8(rust9cc(&["1+2;"]).stdout).unwrap());
+
+    let output = rust9cc(&["--output", path_str, "--mode", "token", "1"]);
+    assert!(output.status.success());
+    let tokens = std::fs::read_to_string(&path).unwrap();
+    assert!(tokens.contains("1:1\tNum(1)\n"), "{}", tokens);
+
+    // A failed compilation leaves the previous output as it was.
+    for input in ["1 +;", "1 / 0;"].iter() {
+        let output = rust9cc(&["-o", path_str, "--strict", input]);
+        assert!(!output.status.success(), "{}", input);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), tokens, "{}", input);
+    }
+    std::fs::remove_file(&path).unwrap();
+
+    let output = rust9cc(&["-o", "/nonexistent/out.s", "1;"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.starts_with("Cannot open /nonexistent/out.s"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn test_input_file() {
+    let path = std::env::temp_dir().join(format!("rust9cc-cli-{}.c", std::process::id()));
+    let path_str = path.to_str().unwrap();
+    std::fs::write(&path, "1 + 2;\n").unwrap();
+    let output = rust9cc(&["--file", path_str]);
+    assert!(output.status.success());
+    let asm = String::from_utf8(output.stdout).unwrap();
+    assert!(asm.contains("  add rax, rdi\n"), "{}", asm);
+    assert_eq!(asm, String::from_utf8(rust9cc(&["1 + 2;"]).stdout).unwrap());
+
+    // Both an input and a file, or neither.
+    assert!(!rust9cc(&["--file", path_str, "1;"]).status.success());
+    assert!(!rust9cc(&[]).status.success());
+    std::fs::remove_file(&path).unwrap();
+
+    let output = rust9cc(&["--file", path_str]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("cannot read"), "{}", stdout);
+}
+
+#[test]
+fn test_input_stdin() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rust9cc"))
+        .args(["--file", "-", "--mode", "eval"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run rust9cc");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"int a = 3;\na * 4;\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "12\n");
+}
+
+#[test]
+fn test_deep_nesting() {
+    // Too long for a command line argument, so read from a file.
+    let path = std::env::temp_dir().join(format!("rust9cc-deep-{}.c", std::process::id()));
+    let path_str = path.to_str().unwrap();
+    let inputs = [
+        "(".repeat(100_000) + "1" + &")".repeat(100_000) + ";",
+        "if (1) ".repeat(9000) + "1;",
+    ];
+    for input in inputs.iter() {
+        std::fs::write(&path, input).unwrap();
+        for mode in ["x86", "eval", "ast", "pretty", "lint"].iter() {
+            let output = rust9cc(&["--mode", mode, "--file", path_str]);
+            assert_eq!(output.status.code(), Some(1), "{}", mode);
+            let stdout = String::from_utf8(output.stdout).unwrap();
+            assert!(
+                stdout.contains("resource limit exceeded: ast-depth"),
+                "{}: {}",
+                mode,
+                stdout
+            );
+        }
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_long_programs() {
+    // Long sequences and operator chains are not nested, so they stay within the default
+    // limits however long they get.
+    let path = std::env::temp_dir().join(format!("rust9cc-long-{}.c", std::process::id()));
+    let path_str = path.to_str().unwrap();
+    let inputs = [
+        "1;".repeat(10_000),
+        vec!["1"; 10_000].join("+") + ";",
+        "int a;\n".to_string() + &"a = a + 1;\n".repeat(10_000),
+        // Deeply nested, yet within the default limit.
+        "if (1) ".repeat(4000) + "1;",
+    ];
+    for input in inputs.iter() {
+        std::fs::write(&path, input).unwrap();
+        for args in [
+            &["--mode", "x86"][..],
+            &["-O", "1"],
+            &["--mode", "ast"],
+            &["--mode", "pretty"],
+            &["--mode", "lint"],
+            &["--mode", "ir"],
+        ]
+        .iter()
+        {
+            let output = rust9cc(&[args, &["--file", path_str][..]].concat());
+            assert!(
+                output.status.success(),
+                "{:?}: {}",
+                args,
+                String::from_utf8_lossy(&output.stdout)
+            );
+        }
+    }
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,20 @@
+//! Compares rust9cc against the system C compiler on random expressions.
+//!
+//! Run with `cargo test --test differential -- --ignored`. The iteration count
+//! and seed come from `RUST9CC_SELFTEST_ITERATIONS` and `RUST9CC_SELFTEST_SEED`,
+//! and mismatches are written to `RUST9CC_SELFTEST_DIR`.
+
+use rust9cc::selftest;
+
+#[test]
+#[ignore]
+fn differential() {
+    let config = selftest::Config::from_env();
+    let mismatches = selftest::run(&config).unwrap();
+    assert!(
+        mismatches.is_empty(),
+        "{:#?}\nwritten to {}",
+        mismatches,
+        config.dump_dir.display()
+    );
+}
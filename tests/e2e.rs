@@ -0,0 +1,487 @@
+//! Compiles expressions, links them with `cc` and checks the exit status.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rust9cc::parse::parse_streaming;
+use rust9cc::{compile_to_asm, compile_with_options, gen_multi, CompileOptions};
+#[cfg(feature = "obj")]
+use rust9cc::{compile_to_obj, gen_multi_obj};
+
+/// Expected exit status and input. Add a line here to add a case.
+const CASES: &[(i32, &str)] = &[
+    (0, "0;"),
+    (42, "42;"),
+    (255, "255;"),
+    (21, "5+20-4;"),
+    (41, " 12 + 34 - 5 ;"),
+    (47, "5+6*7;"),
+    (15, "5*(9-6);"),
+    (4, "(3+5)/2;"),
+    (4, "5-(-1+2);"),
+    (3, "+5+(-2);"),
+    (10, "-10+20;"),
+    (10, "-(-10);"),
+    (10, "-(+(-10));"),
+    (3, "10-4-3;"),
+    (9, "10-(4-3);"),
+    (2, "12/3/2;"),
+    (12, "12/(3/2);"),
+    (7, "1+2*3;"),
+    (9, "(1+2)*3;"),
+    (14, "2*3+4*2;"),
+    (5, "20/4;"),
+    (3, "10/3;"),
+    (3, "-10/-3;"),
+    (1, "7-2*3;"),
+    (24, "2*3*4;"),
+    (6, "2*(3*(4-3));"),
+    (100, "10*10;"),
+    (0, "5-5;"),
+    (6, "-(-6);"),
+    (2, "-(1-3);"),
+    (5, "(((5)));"),
+    (13, "1+2+3+(4+3);"),
+    (31, "2*(1+23)-456/7+48;"),
+    (1, "1000-999;"),
+    (25, "(2+3)*(2+3);"),
+    (4, "100/25;"),
+    (20, "100/(2+3);"),
+    (11, "3*3+8/4;"),
+    (250, "255-5;"),
+    (200, "10*(25-5);"),
+    (2, "(9-5)/(3-1);"),
+    (50, "(1+1)*(1+2+3+4+5+5)+10;"),
+    (1, "-9223372036854775808 / 4611686018427387904 + 3;"),
+    (1, "9223372036854775807 / 4611686018427387904;"),
+    (7, "(9223372036854775807 - 9223372036854775806) * 7;"),
+    (2, "4294967296 / 2147483648;"),
+    (1, "2 < 3;"),
+    (0, "3 < 2;"),
+    (1, "5 >= 5;"),
+    (0, "4 >= 5;"),
+    (1, "6 > 5;"),
+    (0, "5 > 5;"),
+    (1, "5 <= 5;"),
+    (0, "6 <= 5;"),
+    (1, "1 != 2;"),
+    (0, "2 != 2;"),
+    (1, "3 == 3;"),
+    (0, "3 == 4;"),
+    (12, "1+2; 3*4;"),
+    (3, "5 * 5; 2 < 1; 3;"),
+    (33, "int a = 3; int b = 5 * 6; a + b;"),
+    (14, "int a; int b; a = b = 7; a + b;"),
+    (6, "int a = 1; a = a + 2; a * 2;"),
+    (10, "int z = 10; int y = z / 2; z - y + 5;"),
+    (2, "(1 < 2) + (2 < 3);"),
+    (1, "-1 < 0;"),
+    (17, "0x10 + 1;"),
+    (25, "0b1010 + 0o17;"),
+    (5, "int a = 3; if (1 < 2) a = 5; a;"),
+    (3, "int a = 3; if (2 < 1) a = 5; a;"),
+    (7, "int a = 0; if (1) if (2) a = 7; a;"),
+    (0, "if (0) 1;"),
+    (9, "if (4 - 3) 9;"),
+    (2, "if (1) 2; else 3;"),
+    (3, "int a = 0; if (0) a = 1; else if (0) a = 2; else a = 3; a;"),
+    (2, "int a = 0; if (0) a = 1; else if (1) a = 2; else a = 3; a;"),
+    (4, "int a = 4; if (1) if (0) a = 5; else a = a; a;"),
+    (10, "int i = 0; while (i < 10) i = i + 1; i;"),
+    (0, "while (0) 1;"),
+    (10, "int s = 0; int i; for (i = 0; i < 5; i = i + 1) s = s + i; s;"),
+    (3, "int i = 0; for (; i < 3;) i = i + 1; i;"),
+    (0, "int i; for (i = 0; i < 3; i = i + 1) 1;"),
+    (1, "int i = 0; do i = i + 1; while (i < 0); i;"),
+    (4, "int i = 0; do i = i + 2; while (i < 3); i;"),
+    (3, "int a; int b; if (1) { a = 1; b = 2; } a + b;"),
+    (
+        6,
+        "int s = 0; int i = 0; while (i < 3) { i = i + 1; s = s + i; } s;",
+    ),
+    (0, "{}"),
+    (7, "int i; for (i = 0;; i = i + 1) if (i == 7) break; i;"),
+    (
+        6,
+        "int x = 0; switch (2) { case 1: x = x + 1; case 2: x = x + 2; case 3: x = x + 4; } x;",
+    ),
+    (
+        2,
+        "int x = 0; switch (2) { case 1: x = x + 1; break; case 2: x = x + 2; break; case 3: x = x + 4; } x;",
+    ),
+    (
+        9,
+        "int x = 0; switch (7) { case 1: x = 1; default: x = 9; } x;",
+    ),
+    (
+        9,
+        "int s = 0; int i; for (i = 0; i < 10; i = i + 1) { if (i == 5) continue; s = s + 1; } s;",
+    ),
+    (
+        13,
+        "int s = 0; int i = 0; while (i < 5) { i = i + 1; if (i == 2) continue; s = s + i; } s;",
+    ),
+    (
+        3,
+        "int i = 0; do { i = i + 1; if (i > 2) break; } while (1); i;",
+    ),
+    (
+        12,
+        "int i = 0; int s = 0; while (i < 3) if (i = i + 1) s = s + i * 2; s;",
+    ),
+    (5, "return 5; 6;"),
+    (8, "1 + (2 + 3); return 8;"),
+    (
+        4,
+        "int i; for (i = 0;; i = i + 1) { switch (i) { case 4: return i; } }",
+    ),
+    (3, "int x = 3; int *y = &x; return *y;"),
+    (5, "int x = 3; int *y = &x; *y = 5; x;"),
+    (9, "int a = 1; int *b = &a; int **c = &b; **c = 9; a;"),
+    (14, "int x = 2; *&x = 7; x + *&*&x;"),
+    (7, "int x; int *p; p = &x; *p = 7; return x;"),
+    (3, "int x; int *p; int **q; q = &p; *q = &x; **q = 3; x;"),
+    // Variables are 8 bytes apart, a right above b.
+    (4, "int a = 3; int b = 4; *(&a - 1);"),
+    (5, "int a = 5; int b; int *p = &b + 1; *p;"),
+    (5, "int a = 5; int b; int *p = &b; *(1 + p);"),
+    (3, "int a; int *p = &a; int *q = p - 3; p - q;"),
+    (65, "char c; c = 65; return c;"),
+    (44, "char c = 300; c;"),
+    (0, "char c = 255; c + 1;"),
+    (7, "char c = 1; char *p = &c; *p = 7; c;"),
+    (1, "_Bool b; b = 5; return b;"),
+    (0, "_Bool b; b = 0; return b;"),
+    (1, "_Bool b = 256; b;"),
+    (2, "_Bool b; (b = 7) + b;"),
+    (1, "_Bool b; _Bool *p = &b; *p = 3; b;"),
+    (1, "!0;"),
+    (0, "!5;"),
+    (1, "int a = 0; !a;"),
+    (3, "int a = 7; !!a + 2;"),
+    (1, "int a = 2; !(a != 2);"),
+    (2, "6 & 3;"),
+    (7, "5 | 2;"),
+    (4, "5 ^ 1;"),
+    (1, "~0 + 2;"),
+    (7, "int a = 6; int b = 3; a & b | a ^ b;"),
+    (254, "int a = 1; ~a & 255;"),
+    (16, "1 << 4;"),
+    (64, "256 >> 2;"),
+    (1, "int a = -8; a >> 3 == -1;"),
+    (40, "int a = 5; int b = 3; a << b;"),
+    (1, "2 && 3;"),
+    (0, "2 && 0;"),
+    (1, "0 || 7;"),
+    (0, "0 || 0;"),
+    (0, "int a = 0; a && 1 / a;"),
+    (1, "int a = 0; !a || 1 / a;"),
+    (5, "int a = 5; 0 && (a = 1); 1 || (a = 2); a;"),
+    (3, "int a = 0; 1 && (a = 3); a;"),
+    // Never evaluated, so folding leaves them alone.
+    (0, "0 && 1/0;"),
+    (3, "if (0) 1/0; 3;"),
+    (1, "return 0 || 9223372036854775807+1;"),
+    // Only the low byte of the slot is stored.
+    (3, "int a = 258; char *p = &a; *p = 3; a - 256;"),
+];
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns why executables cannot be built and run here, if so.
+fn unsupported() -> Option<&'static str> {
+    if !cfg!(all(target_arch = "x86_64", unix)) {
+        return Some("the host is not x86-64");
+    }
+    match Command::new("cc").arg("--version").output() {
+        Ok(output) if output.status.success() => None,
+        _ => Some("cc is not available"),
+    }
+}
+
+/// Compiles `input` into an executable, runs it and returns the exit status.
+fn run(input: &str) -> Result<i32, String> {
+    let asm = compile_to_asm(input).map_err(|err| err.to_string())?;
+    link_and_run(&[("s", asm.as_bytes())])
+}
+
+/// Same as `run`, with the optimization passes enabled.
+fn run_optimized(input: &str) -> Result<i32, String> {
+    let options = CompileOptions {
+        opt_level: 1,
+        ..CompileOptions::default()
+    };
+    let asm = compile_with_options(input, &options).map_err(|err| err.to_string())?;
+    link_and_run(&[("s", asm.as_bytes())])
+}
+
+/// Same as `run`, going through the object file instead of the assembly.
+#[cfg(feature = "obj")]
+fn run_obj(input: &str) -> Result<i32, String> {
+    let obj = compile_to_obj(input).map_err(|err| err.to_string())?;
+    link_and_run(&[("o", &obj)])
+}
+
+/// Links the sources, given by extension and contents, into an executable with `cc`,
+/// runs it and returns the exit status.
+fn link_and_run(sources: &[(&str, &[u8])]) -> Result<i32, String> {
+    let id = COUNTER.fetch_add(1, Ordering::SeqCst);
+    let base: PathBuf = env::temp_dir().join(format!("rust9cc-e2e-{}-{}", std::process::id(), id));
+    let mut srcs = Vec::new();
+    for (i, (extension, contents)) in sources.iter().enumerate() {
+        let src = base.with_extension(format!("{}.{}", i, extension));
+        fs::write(&src, contents).map_err(|err| err.to_string())?;
+        srcs.push(src);
+    }
+
+    let output = Command::new("cc")
+        .arg("-o")
+        .arg(&base)
+        .args(&srcs)
+        .output()
+        .map_err(|err| err.to_string())?;
+    let status = if output.status.success() {
+        Command::new(&base)
+            .status()
+            .map_err(|err| err.to_string())
+            .and_then(|status| status.code().ok_or_else(|| "killed by signal".to_string()))
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).into_owned())
+    };
+
+    for src in srcs.iter() {
+        let _ = fs::remove_file(src);
+    }
+    let _ = fs::remove_file(&base);
+    status
+}
+
+/// Runs every case with `run` and returns the descriptions of the failures.
+fn check_cases(run: fn(&str) -> Result<i32, String>) -> Vec<String> {
+    CASES
+        .iter()
+        .filter_map(|(expected, input)| match run(input) {
+            Ok(actual) if actual == *expected => None,
+            Ok(actual) => Some(format!(
+                "{} => {} expected, but got {}",
+                input, expected, actual
+            )),
+            Err(err) => Some(format!("{} => {}", input, err)),
+        })
+        .collect()
+}
+
+#[test]
+fn e2e() {
+    if let Some(reason) = unsupported() {
+        eprintln!("skipping end-to-end tests: {}", reason);
+        return;
+    }
+
+    let failures = check_cases(run);
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn e2e_optimized() {
+    if let Some(reason) = unsupported() {
+        eprintln!("skipping end-to-end tests: {}", reason);
+        return;
+    }
+
+    let failures = check_cases(run_optimized);
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[cfg(feature = "obj")]
+#[test]
+fn e2e_obj() {
+    if let Some(reason) = unsupported() {
+        eprintln!("skipping end-to-end tests: {}", reason);
+        return;
+    }
+
+    let failures: Vec<String> = CASES
+        .iter()
+        .filter_map(|(_, input)| match (run(input), run_obj(input)) {
+            (Ok(asm), Ok(obj)) if asm == obj => None,
+            (asm, obj) => Some(format!(
+                "{} => {:?} from asm, but {:?} from obj",
+                input, asm, obj
+            )),
+        })
+        .collect();
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn e2e_entry() {
+    if let Some(reason) = unsupported() {
+        eprintln!("skipping end-to-end tests: {}", reason);
+        return;
+    }
+
+    let options = CompileOptions {
+        entry: Some("helper".to_string()),
+        ..CompileOptions::default()
+    };
+    let asm = compile_with_options("5*(9-6);", &options).unwrap();
+    let main = b"long helper(void);\nint main(void) { return helper() + 1; }\n";
+    assert_eq!(link_and_run(&[("s", asm.as_bytes()), ("c", main)]), Ok(16));
+}
+
+#[test]
+fn e2e_label_prefix() {
+    if let Some(reason) = unsupported() {
+        eprintln!("skipping end-to-end tests: {}", reason);
+        return;
+    }
+
+    // Both units have the same local labels but for their prefixes, so they can share a file.
+    let mut asm = String::new();
+    for (i, input) in [
+        "int s = 0; int i = 0; while (i < 4) { if (i > 1) s = s + i; i = i + 1; } s;",
+        "int n = 7; if (n < 5) 1; else { while (n > 2) n = n - 2; n; }",
+    ]
+    .iter()
+    .enumerate()
+    {
+        let options = CompileOptions {
+            label_prefix: format!("unit{}_", i),
+            entry: Some(format!("unit{}", i)),
+            ..CompileOptions::default()
+        };
+        asm.push_str(&compile_with_options(input, &options).unwrap());
+    }
+    let main = concat!(
+        "long unit0(void);\n",
+        "long unit1(void);\n",
+        "int main(void) { return unit0() * 10 + unit1(); }\n"
+    );
+    assert_eq!(
+        link_and_run(&[("s", asm.as_bytes()), ("c", main.as_bytes())]),
+        Ok(51)
+    );
+}
+
+#[test]
+fn e2e_call() {
+    if let Some(reason) = unsupported() {
+        eprintln!("skipping end-to-end tests: {}", reason);
+        return;
+    }
+
+    // `aligned` returns whether the stack was aligned to 16 bytes at the call.
+    let helper = concat!(
+        "int foo() { return 42; }\n",
+        "int aligned() { return (long)__builtin_frame_address(0) % 16 == 0; }\n",
+        "int add6(int a, int b, int c, int d, int e, int f) { return a + b + c + d + e + f; }\n",
+        "int sub(int a, int b) { return a - b; }\n",
+        "char *str() { return \"abc\"; }\n",
+        "long *alloc4(long a, long b, long c, long d) {\n",
+        "  static long array[4];\n",
+        "  array[0] = a; array[1] = b; array[2] = c; array[3] = d;\n",
+        "  return array;\n",
+        "}\n"
+    );
+    for (expected, input) in [
+        (42, "foo();"),
+        (43, "int a = 1; foo() + a;"),
+        (1, "aligned();"),
+        (2, "1 + aligned();"),
+        (4, "1 + (2 + aligned());"),
+        (
+            3,
+            "int s = 0; int i; for (i = 0; i < 3; i = i + 1) s = s + aligned(); s;",
+        ),
+        (21, "add6(1, 2, 3, 4, 5, 6);"),
+        (7, "sub(10, 3);"),
+        (36, "add6(1, 2, 3, 4, 5, add6(1, 2, 3, 4, 5, 6));"),
+        (1, "int a = 3; sub(a = a + 10, a - 1);"),
+        (1, "1 + sub(aligned(), 1);"),
+        (4, "int *p = alloc4(1, 2, 4, 8); *(p + 2);"),
+        (8, "int *p = alloc4(1, 2, 4, 8); p = p + 3; *p;"),
+        (2, "int *p = alloc4(1, 2, 4, 8); int *q = p + 3; *(q - 2);"),
+        (3, "int *p = alloc4(1, 2, 4, 8); int *q = p + 3; q - p;"),
+        (99, "char *s = str(); *(s + 2);"),
+        (2, "char *s = str(); char *t = s + 2; t - s;"),
+    ]
+    .iter()
+    {
+        let asm = compile_to_asm(input).unwrap();
+        let status = link_and_run(&[("s", asm.as_bytes()), ("c", helper.as_bytes())]);
+        assert_eq!(status, Ok(*expected), "{}", input);
+    }
+}
+
+#[test]
+fn e2e_function() {
+    if let Some(reason) = unsupported() {
+        eprintln!("skipping end-to-end tests: {}", reason);
+        return;
+    }
+
+    for (expected, input) in [
+        (4, "sub(a, b) { return a - b; } sub(7, 3);"),
+        (6, "three() { 3; } three() + three();"),
+        (
+            21,
+            "sum(a, b, c, d, e, f) { a + b + c + d + e + f; } sum(1, 2, 3, 4, 5, 6);",
+        ),
+        // Each call has slots of its own, so `n` is intact after the recursive calls.
+        (
+            55,
+            "fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } fib(10);",
+        ),
+        (13, "twice(a) { a = a * 2; } int a = 5; twice(4) + a;"),
+        (
+            3,
+            "int a = 1; f(a) { return a; } int b = f(2); a + b - a + a;",
+        ),
+        (4, "set(p, v) { *p = v; } int x = 1; set(&x, 4); x;"),
+        (
+            4,
+            "void set(p, v) { *p = v; return; *p = 0; } int x = 1; set(&x, 4); x;",
+        ),
+        (
+            7,
+            "void inc(p) { *p = *p + 1; } int x = 5; inc(&x); inc(&x); x;",
+        ),
+    ]
+    .iter()
+    {
+        assert_eq!(run(input), Ok(*expected), "{}", input);
+        assert_eq!(run_optimized(input), Ok(*expected), "{}", input);
+    }
+}
+
+#[test]
+fn e2e_multi() {
+    if let Some(reason) = unsupported() {
+        eprintln!("skipping end-to-end tests: {}", reason);
+        return;
+    }
+
+    let mut nodes = vec![
+        parse_streaming("5*(9-6);").unwrap(),
+        parse_streaming("100/25;").unwrap(),
+    ];
+    let options = CompileOptions::default();
+    let asm = gen_multi(&nodes, &options).unwrap();
+    let main = b"long expr0(void);\nlong expr1(void);\nint main(void) { return expr0() * 10 + expr1(); }\n";
+    assert_eq!(link_and_run(&[("s", asm.as_bytes()), ("c", main)]), Ok(154));
+    #[cfg(feature = "obj")]
+    {
+        let obj = gen_multi_obj(&nodes, &options).unwrap();
+        assert_eq!(link_and_run(&[("o", &obj), ("c", main)]), Ok(154));
+    }
+
+    // Functions defined by the inputs come along.
+    nodes.push(parse_streaming("sq(a) { return a * a; } sq(3);").unwrap());
+    let asm = gen_multi(&nodes, &options).unwrap();
+    let main = b"long expr0(void);\nlong expr1(void);\nlong expr2(void);\nint main(void) { return expr0() * 10 + expr1() - expr2(); }\n";
+    assert_eq!(link_and_run(&[("s", asm.as_bytes()), ("c", main)]), Ok(145));
+}
@@ -0,0 +1,38 @@
+mod support;
+
+use proptest::prelude::*;
+
+use rust9cc::eval::eval;
+use rust9cc::opt::fold_constants;
+use rust9cc::parse::parse_streaming;
+use rust9cc::token::tokenize;
+use rust9cc::unparse::{sexpr, unparse};
+use support::{arb_expr, reference_eval};
+
+proptest! {
+    #[test]
+    fn unparse_reparses_to_same_tree(expr in arb_expr()) {
+        let node = parse_streaming(&format!("{};", expr)).unwrap();
+        let reparsed = parse_streaming(&unparse(&node)).unwrap();
+        prop_assert_eq!(sexpr(&reparsed), sexpr(&node));
+    }
+
+    #[test]
+    fn eval_matches_reference(expr in arb_expr()) {
+        let node = parse_streaming(&format!("{};", expr)).unwrap();
+        prop_assert_eq!(eval(&node).ok(), reference_eval(&expr));
+    }
+
+    #[test]
+    fn constant_folding_preserves_eval(expr in arb_expr()) {
+        let node = parse_streaming(&format!("{};", expr)).unwrap();
+        let expected = eval(&node).ok();
+        let (folded, _) = fold_constants(node);
+        prop_assert_eq!(eval(&folded).ok(), expected);
+    }
+
+    #[test]
+    fn tokenize_never_panics(input in ".*") {
+        let _ = tokenize(&input);
+    }
+}
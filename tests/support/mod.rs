@@ -0,0 +1,47 @@
+//! Strategies over the expression generator of `rust9cc::selftest`, shared by
+//! property-based tests.
+
+use proptest::prelude::*;
+
+use rust9cc::selftest::{gen_expr, Choices, Expr, Op};
+
+/// Replays recorded choices, then zeros, which pick the simplest expressions.
+struct Replay(std::vec::IntoIter<u64>);
+
+impl Choices for Replay {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0.next().unwrap_or(0) % n
+    }
+}
+
+/// Expressions of bounded depth with nonzero divisors.
+///
+/// Proptest shrinks the choices behind an expression toward fewer and smaller ones,
+/// which shrinks the expression toward a literal.
+pub fn arb_expr() -> impl Strategy<Value = Expr> {
+    prop::collection::vec(any::<u64>(), 0..64)
+        .prop_map(|choices| gen_expr(&mut Replay(choices.into_iter()), 4).0)
+}
+
+/// Reference evaluator, returning `None` on overflow.
+pub fn reference_eval(expr: &Expr) -> Option<i64> {
+    match expr {
+        Expr::Num(num) => Some(*num as i64),
+        Expr::Neg(expr) => 0i64.checked_sub(reference_eval(expr)?),
+        Expr::Bin(op, lhs, rhs) => {
+            let (lhs, rhs) = (reference_eval(lhs)?, reference_eval(rhs)?);
+            match op {
+                Op::Add => lhs.checked_add(rhs),
+                Op::Sub => lhs.checked_sub(rhs),
+                Op::Mul => lhs.checked_mul(rhs),
+                Op::Div => lhs.checked_div(rhs),
+                Op::Eq => Some((lhs == rhs) as i64),
+                Op::Neq => Some((lhs != rhs) as i64),
+                Op::Lt => Some((lhs < rhs) as i64),
+                Op::Leq => Some((lhs <= rhs) as i64),
+                Op::Gt => Some((lhs > rhs) as i64),
+                Op::Geq => Some((lhs >= rhs) as i64),
+            }
+        }
+    }
+}